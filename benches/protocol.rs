@@ -0,0 +1,155 @@
+//! Benchmarks for the chuniio protocol hot path
+//!
+//! Covers serialization and deserialization of every message type, plus a
+//! request/response round trip over a loopback socket that mirrors what the
+//! slider and JVS polling threads do every tick.
+
+use std::{
+    hint::black_box,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+use chuniio_backflow::protocol::ChuniMessage;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+/// One instance of every message type, with realistic payload sizes
+fn sample_messages() -> Vec<(&'static str, ChuniMessage)> {
+    vec![
+        ("JvsPoll", ChuniMessage::JvsPoll),
+        (
+            "JvsPollResponse",
+            ChuniMessage::JvsPollResponse {
+                opbtn: 0x03,
+                beams: 0x2A,
+            },
+        ),
+        ("CoinCounterRead", ChuniMessage::CoinCounterRead),
+        (
+            "CoinCounterReadResponse",
+            ChuniMessage::CoinCounterReadResponse { count: 12 },
+        ),
+        (
+            "SliderInput",
+            ChuniMessage::SliderInput {
+                pressure: [0x80; 32],
+            },
+        ),
+        ("SliderStateRead", ChuniMessage::SliderStateRead),
+        (
+            "SliderStateReadResponse",
+            ChuniMessage::SliderStateReadResponse {
+                pressure: [0x80; 32],
+            },
+        ),
+        (
+            "SliderLedUpdate",
+            ChuniMessage::SliderLedUpdate {
+                rgb_data: vec![0x7F; 93],
+            },
+        ),
+        (
+            "LedUpdate",
+            ChuniMessage::LedUpdate {
+                board: 1,
+                rgb_data: vec![0x7F; 189],
+            },
+        ),
+        ("Ping", ChuniMessage::Ping),
+        ("Pong", ChuniMessage::Pong),
+        ("JvsFullStateRead", ChuniMessage::JvsFullStateRead),
+        (
+            "JvsFullStateReadResponse",
+            ChuniMessage::JvsFullStateReadResponse {
+                opbtn: 0x03,
+                beams: 0x2A,
+                pressure: [0x80; 32],
+                coin_counter: 12,
+            },
+        ),
+        ("RemoteConfigRead", ChuniMessage::RemoteConfigRead),
+        (
+            "RemoteConfigResponse",
+            ChuniMessage::RemoteConfigResponse {
+                slider_poll_rate_hz: Some(500),
+                idle_poll_rate_hz: None,
+                led_brightness: Some([80, 80, 100]),
+                coin_mode: Some(1),
+            },
+        ),
+    ]
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize_into");
+    let mut buffer = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+    for (name, message) in sample_messages() {
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(&message).serialize_into(&mut buffer))
+        });
+    }
+    group.finish();
+}
+
+fn bench_deserialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deserialize");
+    for (name, message) in sample_messages() {
+        let mut buffer = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+        let len = message.serialize_into(&mut buffer);
+        let data = &buffer[..len];
+        group.bench_function(name, |b| {
+            b.iter(|| ChuniMessage::deserialize(black_box(data)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Full-state poll round trip against a loopback server answering every request
+fn bench_round_trip(c: &mut Criterion) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let response = ChuniMessage::JvsFullStateReadResponse {
+        opbtn: 0,
+        beams: 0,
+        pressure: [0; 32],
+        coin_counter: 0,
+    };
+    let mut response_buffer = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+    let response_len = response.serialize_into(&mut response_buffer);
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        let mut request = [0u8; 1];
+        while stream.read_exact(&mut request).is_ok() {
+            if stream.write_all(&response_buffer[..response_len]).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut buffer = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+
+    c.bench_function("round_trip/JvsFullStateRead", |b| {
+        b.iter(|| {
+            stream
+                .write_all(&ChuniMessage::JVS_FULL_STATE_READ_FRAME)
+                .unwrap();
+            stream.read_exact(&mut buffer[..response_len]).unwrap();
+            ChuniMessage::deserialize(&buffer[..response_len]).unwrap()
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_serialize,
+    bench_deserialize,
+    bench_round_trip
+);
+criterion_main!(benches);
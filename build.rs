@@ -0,0 +1,51 @@
+//! Generates `chuniio_backflow.h`, the C header for every export, from the
+//! crate sources
+//!
+//! The header goes to `OUT_DIR`; the checked-in copy under `include/` is only
+//! replaced when `CHUNIIO_UPDATE_HEADER` is set, so a build never writes into
+//! the source tree unasked.
+
+use std::{env, fs, path::PathBuf};
+
+/// File name of the generated header
+const HEADER_NAME: &str = "chuniio_backflow.h";
+
+/// Checked-in header, relative to the crate root
+const HEADER_PATH: &str = "include/chuniio_backflow.h";
+
+/// Environment variable that has the build copy the header to `HEADER_PATH`
+const UPDATE_HEADER_ENV: &str = "CHUNIIO_UPDATE_HEADER";
+
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("set by cargo"));
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("set by cargo"));
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed={}", HEADER_PATH);
+    println!("cargo:rerun-if-env-changed={}", UPDATE_HEADER_ENV);
+
+    let config = match cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("cargo:warning=Cannot read cbindgen.toml: {}", e);
+            return;
+        }
+    };
+    // A header that cannot be generated should not stop the DLL from building
+    let header = out_dir.join(HEADER_NAME);
+    match cbindgen::generate_with_config(&crate_dir, config) {
+        Ok(bindings) => {
+            bindings.write_to_file(&header);
+        }
+        Err(e) => {
+            println!("cargo:warning=Cannot generate {}: {}", HEADER_NAME, e);
+            return;
+        }
+    }
+
+    if env::var_os(UPDATE_HEADER_ENV).is_some_and(|value| !value.is_empty() && value != "0") {
+        if let Err(e) = fs::copy(&header, crate_dir.join(HEADER_PATH)) {
+            println!("cargo:warning=Cannot update {}: {}", HEADER_PATH, e);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+/* Generated by cbindgen from the crate sources; do not edit. */
+
+#ifndef CHUNIIO_BACKFLOW_H
+#define CHUNIIO_BACKFLOW_H
+
+#include <windows.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Initialize JVS subsystem
+HRESULT chuni_io_jvs_init(void);
+
+// Poll JVS inputs (operator buttons and IR beams)
+void chuni_io_jvs_poll(uint8_t *opbtn, uint8_t *beams);
+
+// Read coin counter
+void chuni_io_jvs_read_coin_counter(uint16_t *total);
+
+// Initialize slider subsystem
+HRESULT chuni_io_slider_init(void);
+
+// Start slider input polling with callback
+void chuni_io_slider_start(const void *callback);
+
+// Stop slider input polling
+void chuni_io_slider_stop(void);
+
+// Initialize LED subsystem
+HRESULT chuni_io_led_init(void);
+
+// Set slider LED colors
+void chuni_io_slider_set_leds(const uint8_t *rgb);
+
+// Set LED board colors
+void chuni_io_led_set_colors(uint8_t board, const uint8_t *rgb);
+
+// Get API version - required by chunithm games to determine compatibility
+uint16_t chuni_io_get_api_version(void);
+
+// Get the extension ABI version, so loaders can check that the
+// `chuniio_backflow_*` exports they use are compatible before calling them
+uint32_t chuniio_backflow_get_abi_version(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CHUNIIO_BACKFLOW_H */
@@ -0,0 +1,582 @@
+//! Brokenithm server
+//!
+//! Phones and tablets running a Brokenithm controller app can connect straight
+//! to the DLL as a slider and air source, over UDP (Wi-Fi) or TCP (USB with
+//! port forwarding) on the same port. Packets are a length byte followed by a
+//! three-letter tag and its data:
+//!
+//! - `INP`: 6 air bytes, 32 slider pressure bytes, then optionally TEST and
+//!   SERVICE (non-zero while held)
+//! - `FNC`: a function key, 1 for a coin
+//! - `HEL`: hello, answered with `HEL`
+//! - `PIN`: ping, answered with `PON` and the same data
+//! - `DSC`: the controller disconnects
+//!
+//! A TCP connection that starts with an HTTP request is upgraded to a
+//! WebSocket, for browser controllers; each message then carries packets as on
+//! the stream.
+//!
+//! The controller's input either adds to the proxy's or, in `replace` mode,
+//! takes the place of the proxy's slider and air while it is connected.
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config,
+    input::{BEAM_COUNT, SLIDER_CELLS},
+    threading,
+    wsproto::{
+        self, push_header, OPCODE_BINARY, OPCODE_CLOSE, OPCODE_CONTINUATION, OPCODE_PING,
+        OPCODE_PONG, OPCODE_TEXT,
+    },
+};
+
+/// Default port, as used by the Brokenithm apps
+pub const DEFAULT_PORT: u16 = 52468;
+
+/// How long a controller's last input counts without a new one
+const INPUT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the server threads check for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long `stop` waits for the server threads to exit
+const STOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Largest WebSocket handshake request accepted
+const MAX_HANDSHAKE_LEN: usize = 4096;
+
+/// Largest WebSocket message accepted
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Function key data for a coin in `FNC` packets
+const FUNCTION_COIN: u8 = 1;
+
+/// How the controller's input combines with the proxy's
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BrokenithmMode {
+    /// No server
+    #[default]
+    Off,
+    /// Add to the proxy's input
+    Merge,
+    /// Take the place of the proxy's slider and air while connected
+    Replace,
+}
+
+impl BrokenithmMode {
+    /// Parse a mode name (`off`, `merge`, `replace`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "merge" => Some(Self::Merge),
+            "replace" => Some(Self::Replace),
+            _ => None,
+        }
+    }
+
+    /// Mode name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Merge => "merge",
+            Self::Replace => "replace",
+        }
+    }
+}
+
+/// Latest input from a controller
+#[derive(Debug, Clone, Copy)]
+struct Input {
+    air: [u8; BEAM_COUNT],
+    slider: [u8; SLIDER_CELLS],
+    test: bool,
+    service: bool,
+    received: Instant,
+}
+
+static INPUT: Mutex<Option<Input>> = Mutex::new(None);
+
+/// Set by a coin function key until the coin is counted
+static COIN_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Set while the server is running, cleared to stop it
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Server and client threads that may still be running
+static THREADS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Latest input, unless it is too old to count
+fn input() -> Option<Input> {
+    INPUT
+        .lock()
+        .ok()?
+        .filter(|input| input.received.elapsed() < INPUT_TIMEOUT)
+}
+
+/// Whether a controller's input currently takes the place of the proxy's
+/// slider and air
+pub fn replaces_proxy() -> bool {
+    config::get().brokenithm == BrokenithmMode::Replace && input().is_some()
+}
+
+/// Act as if a controller sent the `air` and `slider` input just now, or
+/// disconnected with `None`, for tests of the sources it feeds
+#[cfg(test)]
+pub fn set_for_test(input: Option<([u8; BEAM_COUNT], [u8; SLIDER_CELLS])>) {
+    if let Ok(mut latest) = INPUT.lock() {
+        *latest = input.map(|(air, slider)| Input {
+            air,
+            slider,
+            test: false,
+            service: false,
+            received: Instant::now(),
+        });
+    }
+}
+
+/// Operator button bits (TEST, SERVICE) held on the controller
+pub fn opbtn() -> u8 {
+    input().map_or(0, |input| {
+        u8::from(input.test) | u8::from(input.service) << 1
+    })
+}
+
+/// Whether a coin has been inserted from the controller since the previous
+/// check
+pub fn coin_pressed() -> bool {
+    COIN_PENDING.swap(false, Ordering::Relaxed)
+}
+
+/// IR beam bits blocked on the controller
+pub fn beams() -> u8 {
+    input().map_or(0, |input| {
+        input
+            .air
+            .iter()
+            .enumerate()
+            .filter(|(_, &air)| air != 0)
+            .fold(0, |beams, (beam, _)| beams | 1 << beam)
+    })
+}
+
+/// Slider pressure from the controller, `None` while none is connected
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    input().map(|input| input.slider)
+}
+
+/// Start the server if it is enabled and not already running
+pub fn start() {
+    let config = config::get();
+    if config.brokenithm == BrokenithmMode::Off || RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let port = config.brokenithm_port;
+
+    let udp = UdpSocket::bind(("0.0.0.0", port)).and_then(|socket| {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(socket)
+    });
+    let tcp = TcpListener::bind(("0.0.0.0", port)).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    });
+    match (udp, tcp) {
+        (Ok(udp), Ok(tcp)) => {
+            spawn("chuniio-brokenithm-udp", move || serve_udp(udp));
+            spawn("chuniio-brokenithm-tcp", move || serve_tcp(tcp));
+            info!("Brokenithm server listening on port {}", port);
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Cannot start Brokenithm server on port {}: {}", port, e);
+            RUNNING.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Stop the server threads and wait up to `STOP_TIMEOUT` for them to exit;
+/// they notice within `POLL_INTERVAL`
+pub fn stop() {
+    RUNNING.store(false, Ordering::SeqCst);
+    let threads = THREADS
+        .lock()
+        .map(|mut threads| std::mem::take(&mut *threads))
+        .unwrap_or_default();
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    let stuck = threads
+        .iter()
+        .filter(|thread| !threading::wait_for_exit(thread, deadline, || {}))
+        .count();
+    if stuck > 0 {
+        warn!(
+            "{} Brokenithm thread(s) did not stop within {:?}, detaching them",
+            stuck, STOP_TIMEOUT
+        );
+    }
+}
+
+fn spawn(name: &str, body: impl FnOnce() + Send + 'static) {
+    match thread::Builder::new().name(name.into()).spawn(body) {
+        Ok(thread) => {
+            if let Ok(mut threads) = THREADS.lock() {
+                threads.retain(|thread| !thread.is_finished());
+                threads.push(thread);
+            }
+        }
+        Err(e) => error!("Failed to spawn {} thread: {:?}", name, e),
+    }
+}
+
+fn serve_udp(socket: UdpSocket) {
+    let mut buffer = [0u8; 512];
+    let mut last_peer: Option<SocketAddr> = None;
+    while RUNNING.load(Ordering::SeqCst) {
+        let (len, peer) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => {
+                debug!("Brokenithm UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        if last_peer != Some(peer) {
+            info!("Brokenithm controller at {} (UDP)", peer);
+            last_peer = Some(peer);
+        }
+        let mut packets = PacketBuffer::default();
+        packets.extend(&buffer[..len]);
+        while let Some(packet) = packets.next_packet() {
+            if let Some(reply) = handle_packet(&packet) {
+                let _ = socket.send_to(&reply, peer);
+            }
+        }
+    }
+}
+
+fn serve_tcp(listener: TcpListener) {
+    while RUNNING.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                info!("Brokenithm controller at {} (TCP)", peer);
+                spawn("chuniio-brokenithm-client", move || {
+                    if let Err(e) = serve_client(stream) {
+                        debug!("Brokenithm controller {} disconnected: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                warn!("Brokenithm accept failed: {}", e);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Serve one TCP connection, as a packet stream or a WebSocket
+fn serve_client(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    stream.set_nodelay(true)?;
+
+    let mut reader = StreamReader::default();
+    reader.fill(&mut stream, 4)?;
+    if reader.pending.starts_with(b"GET ") {
+        return serve_websocket(stream, reader);
+    }
+
+    let mut packets = PacketBuffer::default();
+    loop {
+        packets.extend(&reader.pending);
+        reader.pending.clear();
+        while let Some(packet) = packets.next_packet() {
+            if let Some(reply) = handle_packet(&packet) {
+                stream.write_all(&reply)?;
+            }
+        }
+        reader.fill(&mut stream, 1)?;
+    }
+}
+
+/// Complete the WebSocket handshake, then handle each message's packets
+fn serve_websocket(mut stream: TcpStream, mut reader: StreamReader) -> io::Result<()> {
+    let end = loop {
+        if let Some(end) = reader.pending.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if reader.pending.len() > MAX_HANDSHAKE_LEN {
+            return Err(invalid_data("WebSocket handshake too long"));
+        }
+        reader.fill(&mut stream, reader.pending.len() + 1)?;
+    };
+    let request = String::from_utf8_lossy(&reader.pending[..end]).into_owned();
+    reader.pending.drain(..end);
+
+    let key = request
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-key"))
+        .map(|(_, value)| value.trim())
+        .ok_or_else(|| invalid_data("not a WebSocket request"))?;
+    let accept = wsproto::accept_key(key);
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    )?;
+
+    let mut packets = PacketBuffer::default();
+    loop {
+        let (opcode, payload) = read_frame(&mut stream, &mut reader)?;
+        match opcode {
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                packets.extend(&payload);
+                while let Some(packet) = packets.next_packet() {
+                    if let Some(reply) = handle_packet(&packet) {
+                        write_frame(&mut stream, OPCODE_BINARY, &reply)?;
+                    }
+                }
+            }
+            OPCODE_CLOSE => {
+                write_frame(&mut stream, OPCODE_CLOSE, &[])?;
+                return Ok(());
+            }
+            OPCODE_PING => write_frame(&mut stream, OPCODE_PONG, &payload)?,
+            _ => {}
+        }
+    }
+}
+
+/// Read one complete WebSocket frame from a client, unmasking its payload
+fn read_frame(stream: &mut TcpStream, reader: &mut StreamReader) -> io::Result<(u8, Vec<u8>)> {
+    reader.fill(stream, 2)?;
+    let header_len = wsproto::header_len([reader.pending[0], reader.pending[1]]);
+    reader.fill(stream, header_len)?;
+    let header = wsproto::parse_header(&reader.pending)
+        .ok_or_else(|| invalid_data("short WebSocket frame header"))?;
+    let len = usize::try_from(header.len)
+        .ok()
+        .filter(|&len| len <= MAX_MESSAGE_LEN)
+        .ok_or_else(|| invalid_data("WebSocket message too long"))?;
+
+    let end = header_len + len;
+    reader.fill(stream, end)?;
+    let mut payload = Vec::with_capacity(len);
+    wsproto::push_payload(&mut payload, &reader.pending[header_len..end], header.mask);
+    reader.pending.drain(..end);
+    Ok((header.opcode, payload))
+}
+
+/// Write one unfragmented, unmasked WebSocket frame
+fn write_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(wsproto::MAX_HEADER_LEN + payload.len());
+    push_header(&mut frame, opcode, payload.len(), None);
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Bytes read from a stream and not yet used
+#[derive(Default)]
+struct StreamReader {
+    pending: Vec<u8>,
+}
+
+impl StreamReader {
+    /// Read until at least `len` bytes are pending, giving up if the server
+    /// stops or the peer disconnects
+    fn fill(&mut self, stream: &mut TcpStream, len: usize) -> io::Result<()> {
+        let mut buffer = [0u8; 512];
+        while self.pending.len() < len {
+            if !RUNNING.load(Ordering::SeqCst) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.pending.extend_from_slice(&buffer[..read]),
+                Err(e) if is_timeout(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits received bytes into length-prefixed packets
+#[derive(Default)]
+struct PacketBuffer(Vec<u8>);
+
+impl PacketBuffer {
+    fn extend(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+
+    /// The next complete packet without its length byte
+    fn next_packet(&mut self) -> Option<Vec<u8>> {
+        let len = usize::from(*self.0.first()?);
+        if self.0.len() < len + 1 {
+            return None;
+        }
+        let packet = self.0[1..len + 1].to_vec();
+        self.0.drain(..len + 1);
+        Some(packet)
+    }
+}
+
+/// Apply one packet, returning the reply to send (with its length byte)
+fn handle_packet(packet: &[u8]) -> Option<Vec<u8>> {
+    let (tag, data) = packet.split_at_checked(3)?;
+    match tag {
+        b"INP" if data.len() >= BEAM_COUNT + SLIDER_CELLS => {
+            let (air, rest) = data.split_at(BEAM_COUNT);
+            let (slider, buttons) = rest.split_at(SLIDER_CELLS);
+            let input = Input {
+                air: air.try_into().ok()?,
+                slider: slider.try_into().ok()?,
+                test: buttons.first().is_some_and(|&held| held != 0),
+                service: buttons.get(1).is_some_and(|&held| held != 0),
+                received: Instant::now(),
+            };
+            if let Ok(mut latest) = INPUT.lock() {
+                *latest = Some(input);
+            }
+            None
+        }
+        b"FNC" => {
+            if data.first() == Some(&FUNCTION_COIN) {
+                COIN_PENDING.store(true, Ordering::Relaxed);
+            }
+            None
+        }
+        b"HEL" => Some(reply(b"HEL", &[])),
+        b"PIN" => Some(reply(b"PON", data)),
+        b"DSC" => {
+            info!("Brokenithm controller disconnected");
+            if let Ok(mut latest) = INPUT.lock() {
+                *latest = None;
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+fn reply(tag: &[u8; 3], data: &[u8]) -> Vec<u8> {
+    let len = (tag.len() + data.len()).min(usize::from(u8::MAX));
+    let mut packet = Vec::with_capacity(len + 1);
+    packet.push(len as u8);
+    packet.extend_from_slice(tag);
+    packet.extend_from_slice(&data[..len - tag.len()]);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(tag: &[u8; 3], data: &[u8]) -> Vec<u8> {
+        let mut packet = tag.to_vec();
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn splits_length_prefixed_packets_across_reads() {
+        let mut packets = PacketBuffer::default();
+        packets.extend(&[4, b'P', b'I', b'N', 7, 3, b'H']);
+        assert_eq!(packets.next_packet(), Some(b"PIN\x07".to_vec()));
+        assert_eq!(packets.next_packet(), None, "second packet is cut short");
+        packets.extend(b"EL");
+        assert_eq!(packets.next_packet(), Some(b"HEL".to_vec()));
+        assert_eq!(packets.next_packet(), None);
+
+        packets.extend(&[0, 3]);
+        assert_eq!(packets.next_packet(), Some(Vec::new()), "empty packet");
+        assert_eq!(packets.next_packet(), None);
+    }
+
+    #[test]
+    fn splits_input_into_air_slider_and_buttons() {
+        let _globals = crate::lock_globals();
+        let mut air = [0u8; BEAM_COUNT];
+        air[0] = 1;
+        air[5] = 0x80;
+        let mut slider = [0u8; SLIDER_CELLS];
+        slider[0] = 10;
+        slider[SLIDER_CELLS - 1] = 200;
+
+        let cases = [
+            (vec![], 0),
+            (vec![1], 0b01),
+            (vec![0, 1], 0b10),
+            (vec![1, 1], 0b11),
+        ];
+        for (buttons, opbtn_bits) in cases {
+            let data = [&air[..], &slider[..], &buttons[..]].concat();
+            assert_eq!(handle_packet(&packet(b"INP", &data)), None);
+            assert_eq!(beams(), 0b10_0001, "buttons {:?}", buttons);
+            assert_eq!(slider_pressure(), Some(slider), "buttons {:?}", buttons);
+            assert_eq!(opbtn(), opbtn_bits, "buttons {:?}", buttons);
+        }
+
+        // Too short to hold the air and slider: ignored
+        let short = [&air[..], &slider[..SLIDER_CELLS - 1]].concat();
+        handle_packet(&packet(b"DSC", &[]));
+        assert_eq!(handle_packet(&packet(b"INP", &short)), None);
+        assert_eq!(slider_pressure(), None);
+    }
+
+    #[test]
+    fn answers_and_applies_other_packets() {
+        let _globals = crate::lock_globals();
+        assert_eq!(handle_packet(b"HEL"), Some(b"\x03HEL".to_vec()));
+        assert_eq!(
+            handle_packet(&packet(b"PIN", &[1, 2, 3])),
+            Some(b"\x06PON\x01\x02\x03".to_vec())
+        );
+        assert_eq!(handle_packet(b"XYZ"), None);
+        assert_eq!(handle_packet(b"HE"), None);
+
+        coin_pressed();
+        assert_eq!(handle_packet(&packet(b"FNC", &[2])), None);
+        assert!(!coin_pressed(), "other function keys are not coins");
+        handle_packet(&packet(b"FNC", &[FUNCTION_COIN]));
+        assert!(coin_pressed());
+        assert!(!coin_pressed(), "a coin counts once");
+
+        set_for_test(Some(([1; BEAM_COUNT], [1; SLIDER_CELLS])));
+        assert!(slider_pressure().is_some());
+        handle_packet(b"DSC");
+        assert_eq!(slider_pressure(), None);
+        assert_eq!(beams(), 0);
+    }
+
+    #[test]
+    fn truncates_replies_to_one_length_byte() {
+        let reply = reply(b"PON", &[0xAA; 300]);
+        assert_eq!(reply.len(), 256);
+        assert_eq!(reply[0], 255);
+        assert_eq!(&reply[1..4], b"PON");
+    }
+}
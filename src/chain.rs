@@ -0,0 +1,232 @@
+//! Chain-loaded chuniio
+//!
+//! Another chuniio implementation, such as the IO DLL of a hardware
+//! controller, can be loaded next to this one and called alongside it. Its
+//! operator buttons, air and slider pressure are merged as one more input
+//! source, its coins are added to ours, and the game's LED data is forwarded
+//! to it.
+//!
+//! The DLL is loaded when the game first calls into ours and stays loaded for
+//! the life of the process, since it may keep threads that call back into us.
+//! Exports it doesn't have are skipped.
+
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU16, AtomicU8, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use tracing::{info, warn};
+
+use windows::{
+    core::{s, HRESULT, HSTRING, PCSTR},
+    Win32::{
+        Foundation::HMODULE,
+        System::LibraryLoader::{GetProcAddress, LoadLibraryW},
+    },
+};
+
+use crate::{config, input::SLIDER_CELLS};
+
+type InitFn = unsafe extern "C" fn() -> HRESULT;
+type JvsPollFn = unsafe extern "C" fn(opbtn: *mut u8, beams: *mut u8);
+type ReadCoinCounterFn = unsafe extern "C" fn(total: *mut u16);
+type SliderCallbackFn = unsafe extern "C" fn(data: *const u8);
+type SliderStartFn = unsafe extern "C" fn(callback: SliderCallbackFn);
+type StopFn = unsafe extern "C" fn();
+type SetLedsFn = unsafe extern "C" fn(rgb: *const u8);
+type SetColorsFn = unsafe extern "C" fn(board: u8, rgb: *const u8);
+type ApiVersionFn = unsafe extern "C" fn() -> u16;
+
+/// LED board the game sets through `chuni_io_slider_set_leds`
+const SLIDER_BOARD: u8 = 2;
+
+/// Exports of the chained DLL
+struct Chained {
+    jvs_init: Option<InitFn>,
+    jvs_poll: Option<JvsPollFn>,
+    jvs_read_coin_counter: Option<ReadCoinCounterFn>,
+    slider_init: Option<InitFn>,
+    slider_start: Option<SliderStartFn>,
+    slider_stop: Option<StopFn>,
+    slider_set_leds: Option<SetLedsFn>,
+    led_init: Option<InitFn>,
+    led_set_colors: Option<SetColorsFn>,
+}
+
+static CHAINED: OnceLock<Option<Chained>> = OnceLock::new();
+
+/// Operator buttons and beams from the last JVS poll of the chained DLL
+static OPBTN: AtomicU8 = AtomicU8::new(0);
+static BEAMS: AtomicU8 = AtomicU8::new(0);
+
+/// Chained DLL's coin counter at the previous read
+static LAST_COIN_COUNTER: AtomicU16 = AtomicU16::new(0);
+
+/// Pressure last passed to our callback by the chained DLL's slider, `None`
+/// while its slider is stopped
+static PRESSURE: Mutex<Option<[u8; SLIDER_CELLS]>> = Mutex::new(None);
+
+/// The chained DLL, loaded on first use; `None` if none is configured or it
+/// failed to load
+fn chained() -> Option<&'static Chained> {
+    CHAINED.get_or_init(load).as_ref()
+}
+
+fn load() -> Option<Chained> {
+    let path = config::get().chain_dll.clone();
+    if path.is_empty() {
+        return None;
+    }
+    let module = match unsafe { LoadLibraryW(&HSTRING::from(path.as_str())) } {
+        Ok(module) => module,
+        Err(e) => {
+            warn!("Cannot load chained chuniio \"{}\": {}", path, e);
+            return None;
+        }
+    };
+    unsafe {
+        let version = export::<ApiVersionFn>(module, s!("chuni_io_get_api_version"))
+            .map_or(0x0100, |version| version());
+        info!(
+            "Chain-loaded chuniio \"{}\" (API version {}.{})",
+            path,
+            version >> 8,
+            version & 0xFF
+        );
+        Some(Chained {
+            jvs_init: export(module, s!("chuni_io_jvs_init")),
+            jvs_poll: export(module, s!("chuni_io_jvs_poll")),
+            jvs_read_coin_counter: export(module, s!("chuni_io_jvs_read_coin_counter")),
+            slider_init: export(module, s!("chuni_io_slider_init")),
+            slider_start: export(module, s!("chuni_io_slider_start")),
+            slider_stop: export(module, s!("chuni_io_slider_stop")),
+            slider_set_leds: export(module, s!("chuni_io_slider_set_leds")),
+            led_init: export(module, s!("chuni_io_led_init")),
+            led_set_colors: export(module, s!("chuni_io_led_set_colors")),
+        })
+    }
+}
+
+/// Look up an export as a function of type `F`
+unsafe fn export<F: Copy>(module: HMODULE, name: PCSTR) -> Option<F> {
+    let address = GetProcAddress(module, name)?;
+    Some(mem::transmute_copy(&address))
+}
+
+/// Call an init export, logging a failure; ours goes on either way
+unsafe fn init(name: &str, function: Option<InitFn>) {
+    if let Some(function) = function {
+        let result = function();
+        if result.is_err() {
+            warn!("Chained chuniio {} failed: {}", name, result);
+        }
+    }
+}
+
+/// Load the chained DLL, if one is configured, and initialize its JVS
+pub fn jvs_init() {
+    if let Some(chained) = chained() {
+        unsafe { init("chuni_io_jvs_init", chained.jvs_init) };
+    }
+}
+
+/// Poll the chained DLL's JVS input, for `opbtn` and `beams`
+pub fn jvs_poll() {
+    let Some(jvs_poll) = chained().and_then(|chained| chained.jvs_poll) else {
+        return;
+    };
+    let (mut opbtn, mut beams) = (0, 0);
+    unsafe { jvs_poll(&mut opbtn, &mut beams) };
+    OPBTN.store(opbtn, Ordering::Relaxed);
+    BEAMS.store(beams, Ordering::Relaxed);
+}
+
+/// Operator button bits from the chained DLL's last JVS poll
+pub fn opbtn() -> u8 {
+    OPBTN.load(Ordering::Relaxed)
+}
+
+/// IR beam bits from the chained DLL's last JVS poll
+pub fn beams() -> u8 {
+    BEAMS.load(Ordering::Relaxed)
+}
+
+/// Coins the chained DLL counted since the previous call
+pub fn new_coins() -> u16 {
+    let Some(read) = chained().and_then(|chained| chained.jvs_read_coin_counter) else {
+        return 0;
+    };
+    let mut counter = 0;
+    unsafe { read(&mut counter) };
+    let last = LAST_COIN_COUNTER.swap(counter, Ordering::Relaxed);
+    // A counter that went backwards was reset
+    if counter < last {
+        counter
+    } else {
+        counter - last
+    }
+}
+
+pub fn slider_init() {
+    if let Some(chained) = chained() {
+        unsafe { init("chuni_io_slider_init", chained.slider_init) };
+    }
+}
+
+/// Start the chained DLL's slider, reporting to our own callback
+pub fn slider_start() {
+    if let Some(start) = chained().and_then(|chained| chained.slider_start) {
+        unsafe { start(slider_callback) };
+    }
+}
+
+pub fn slider_stop() {
+    if let Some(stop) = chained().and_then(|chained| chained.slider_stop) {
+        unsafe { stop() };
+    }
+    if let Ok(mut pressure) = PRESSURE.lock() {
+        *pressure = None;
+    }
+}
+
+unsafe extern "C" fn slider_callback(data: *const u8) {
+    if data.is_null() {
+        return;
+    }
+    let data = std::slice::from_raw_parts(data, SLIDER_CELLS);
+    if let (Ok(mut pressure), Ok(data)) = (PRESSURE.lock(), data.try_into()) {
+        *pressure = Some(data);
+    }
+}
+
+/// Slider pressure from the chained DLL, `None` while its slider is stopped
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    *PRESSURE.lock().ok()?
+}
+
+pub fn led_init() {
+    if let Some(chained) = chained() {
+        unsafe { init("chuni_io_led_init", chained.led_init) };
+    }
+}
+
+/// Forward slider LED data from the game
+pub unsafe fn slider_set_leds(rgb: *const u8) {
+    if let Some(set_leds) = chained().and_then(|chained| chained.slider_set_leds) {
+        set_leds(rgb);
+    }
+}
+
+/// Forward LED board data from the game; the slider board goes through
+/// `slider_set_leds` instead, as the game sends it
+pub unsafe fn led_set_colors(board: u8, rgb: *const u8) {
+    if board == SLIDER_BOARD {
+        return;
+    }
+    if let Some(set_colors) = chained().and_then(|chained| chained.led_set_colors) {
+        set_colors(board, rgb);
+    }
+}
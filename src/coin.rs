@@ -0,0 +1,354 @@
+//! Coin counter
+//!
+//! The game reads a running coin total and credits every increase. Besides
+//! the coins counted from the proxy, coins can be added locally for home
+//! setups without any coin input: `freeplay` keeps adding coins so credits never
+//! run out, and `auto` adds one whenever the player starts touching the slider.
+//!
+//! The proxy's counter is not passed through as is but counted: increases are
+//! divided by the configured step and capped per update, and with an `edge`
+//! signal each rise from zero counts once, for coin mechs whose pulses reach the
+//! proxy as a held level rather than a running total.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{brokenithm, chain, config, dinput, hid, keyboard, timing::Periodic, xinput};
+
+/// Time between coins added in `freeplay` mode
+const FREEPLAY_COIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shortest time between two coins added in `auto` mode, so that play itself
+/// doesn't keep inserting coins
+const AUTO_COIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How coins reach the game
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinMode {
+    /// Report the proxy's coin counter unchanged
+    #[default]
+    Normal,
+    /// Add a coin every `FREEPLAY_COIN_INTERVAL`, keeping credits topped up
+    Freeplay,
+    /// Add a coin when the slider is touched after being released, at most once
+    /// per `AUTO_COIN_INTERVAL`
+    Auto,
+}
+
+impl CoinMode {
+    /// Parse a mode name (`normal`, `freeplay`, `auto`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "normal" => Some(Self::Normal),
+            "freeplay" => Some(Self::Freeplay),
+            "auto" => Some(Self::Auto),
+            _ => None,
+        }
+    }
+
+    /// Mode for its number in a `RemoteConfigResponse` (0 = normal,
+    /// 1 = freeplay, 2 = auto)
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Normal),
+            1 => Some(Self::Freeplay),
+            2 => Some(Self::Auto),
+            _ => None,
+        }
+    }
+
+    /// Name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Freeplay => "freeplay",
+            Self::Auto => "auto",
+        }
+    }
+}
+
+/// How the proxy's coin counter is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinSignal {
+    /// The counter is a running total; every increase is new coins
+    #[default]
+    Level,
+    /// The counter is non-zero while a coin pulse is active; every change from
+    /// zero to non-zero is one coin
+    Edge,
+}
+
+impl CoinSignal {
+    /// Parse a signal name (`level`, `edge`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "level" => Some(Self::Level),
+            "edge" => Some(Self::Edge),
+            _ => None,
+        }
+    }
+
+    /// Name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Level => "level",
+            Self::Edge => "edge",
+        }
+    }
+}
+
+/// Counts coins out of the counter values reported by the proxy
+struct PulseCounter {
+    /// Counter value at the previous update
+    last: u16,
+    /// Counted increases not yet making up a full step
+    pending: u32,
+}
+
+impl PulseCounter {
+    const NEW: Self = Self {
+        last: 0,
+        pending: 0,
+    };
+
+    /// Coins in the step from the previous counter value to `proxy_counter`,
+    /// read as `config` says
+    fn count(&mut self, proxy_counter: u16, config: &config::Config) -> u16 {
+        let increase = match config.coin_signal {
+            // A counter that went backwards was reset (e.g. the proxy restarted),
+            // so everything since counts
+            CoinSignal::Level if proxy_counter < self.last => proxy_counter,
+            CoinSignal::Level => proxy_counter - self.last,
+            CoinSignal::Edge => u16::from(self.last == 0 && proxy_counter != 0),
+        };
+        self.last = proxy_counter;
+        if increase == 0 {
+            return 0;
+        }
+
+        self.pending += u32::from(increase);
+        let step = u32::from(config.coin_step);
+        let coins = self.pending / step;
+        self.pending %= step;
+        if config.coin_cap > 0 && coins > u32::from(config.coin_cap) {
+            debug!(
+                "Capping {} coins from one update to {}",
+                coins, config.coin_cap
+            );
+            return config.coin_cap;
+        }
+        coins as u16
+    }
+}
+
+static PULSES: Mutex<PulseCounter> = Mutex::new(PulseCounter::NEW);
+
+/// Decides when `auto` mode adds a coin
+struct AutoCoin {
+    /// Whether the slider was touched when the counter was last read
+    touched_at_read: bool,
+    /// When the last coin was added
+    last_coin: Option<Instant>,
+}
+
+impl AutoCoin {
+    const NEW: Self = Self {
+        touched_at_read: false,
+        last_coin: None,
+    };
+
+    /// Whether to add a coin for a counter read at `now` with the slider
+    /// `touched`
+    fn read(&mut self, touched: bool, now: Instant) -> bool {
+        let newly_touched = touched && !self.touched_at_read;
+        self.touched_at_read = touched;
+        if !newly_touched
+            || self
+                .last_coin
+                .is_some_and(|at| now.saturating_duration_since(at) < AUTO_COIN_INTERVAL)
+        {
+            return false;
+        }
+        self.last_coin = Some(now);
+        true
+    }
+}
+
+/// Only read by the game thread, so never waited on
+static AUTO: Mutex<AutoCoin> = Mutex::new(AutoCoin::NEW);
+
+/// Coins counted from the proxy's counter
+///
+/// Kept outside `GLOBAL_STATE` so `chuni_io_jvs_read_coin_counter` can read it
+/// without taking the lock; updated by every full-state sync.
+static PROXY_COINS: AtomicU16 = AtomicU16::new(0);
+
+/// Whether any slider cell was touched at the last full-state sync
+static TOUCHED: AtomicBool = AtomicBool::new(false);
+
+/// Coins added locally (coin modes, coin keys and buttons), on top of `PROXY_COINS`
+static LOCAL_COINS: AtomicU16 = AtomicU16::new(0);
+
+static FREEPLAY_COIN: Periodic = Periodic::new();
+
+/// Count the proxy's coin counter and record the slider touch state from a
+/// full-state sync
+pub fn update(proxy_counter: u16, touched: bool) {
+    if let Ok(mut pulses) = PULSES.lock() {
+        let coins = pulses.count(proxy_counter, config::get());
+        PROXY_COINS.fetch_add(coins, Ordering::Relaxed);
+    }
+    TOUCHED.store(touched, Ordering::Relaxed);
+}
+
+/// Forget every coin counted, for tests that go through the counters
+#[cfg(test)]
+pub fn reset() {
+    if let Ok(mut pulses) = PULSES.lock() {
+        *pulses = PulseCounter::NEW;
+    }
+    if let Ok(mut auto) = AUTO.lock() {
+        *auto = AutoCoin::NEW;
+    }
+    PROXY_COINS.store(0, Ordering::Relaxed);
+    TOUCHED.store(false, Ordering::Relaxed);
+    LOCAL_COINS.store(0, Ordering::Relaxed);
+}
+
+/// Coin total to report to the game, adding local coins as the mode requires
+pub fn total() -> u16 {
+    let add_coin = match config::get().coin_mode {
+        CoinMode::Normal => false,
+        CoinMode::Freeplay => FREEPLAY_COIN.ready(FREEPLAY_COIN_INTERVAL),
+        CoinMode::Auto => AUTO
+            .try_lock()
+            .is_ok_and(|mut auto| auto.read(TOUCHED.load(Ordering::Relaxed), Instant::now())),
+    };
+    // Every source is checked so each sees its coin input released
+    let local_coin = [
+        keyboard::coin_pressed(),
+        xinput::coin_pressed(),
+        dinput::coin_pressed(),
+        brokenithm::coin_pressed(),
+        hid::coin_pressed(),
+    ];
+    if add_coin || local_coin.contains(&true) {
+        LOCAL_COINS.fetch_add(1, Ordering::Relaxed);
+    }
+    LOCAL_COINS.fetch_add(chain::new_coins(), Ordering::Relaxed);
+
+    PROXY_COINS
+        .load(Ordering::Relaxed)
+        .wrapping_add(LOCAL_COINS.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn counts_coins_from_the_proxy_counter() {
+        // (signal, step, cap, counter at each update, coins counted each time)
+        let cases = [
+            (CoinSignal::Level, 1, 0, vec![0, 1, 3, 3], vec![0, 1, 2, 0]),
+            // A counter that went backwards restarted from zero
+            (CoinSignal::Level, 1, 0, vec![5, 7, 2], vec![5, 2, 2]),
+            (
+                CoinSignal::Level,
+                1,
+                0,
+                vec![65535, 0, 1],
+                vec![65535, 0, 1],
+            ),
+            // Increases short of a step carry over
+            (CoinSignal::Level, 2, 0, vec![1, 2, 5, 6], vec![0, 1, 1, 1]),
+            // Coins past the cap are dropped, not carried over
+            (CoinSignal::Level, 1, 2, vec![5, 6, 9], vec![2, 1, 2]),
+            // Each rise from zero is one coin, however long it is held
+            (
+                CoinSignal::Edge,
+                1,
+                0,
+                vec![0, 1, 1, 0, 5, 3, 0, 1],
+                vec![0, 1, 0, 0, 1, 0, 0, 1],
+            ),
+            (
+                CoinSignal::Edge,
+                2,
+                0,
+                vec![1, 0, 1, 0, 1],
+                vec![0, 0, 1, 0, 0],
+            ),
+        ];
+        for (signal, step, cap, counters, coins) in cases {
+            let config = Config {
+                coin_signal: signal,
+                coin_step: step,
+                coin_cap: cap,
+                ..Config::default()
+            };
+            let mut pulses = PulseCounter::NEW;
+            let counted: Vec<_> = counters
+                .iter()
+                .map(|&counter| pulses.count(counter, &config))
+                .collect();
+            assert_eq!(
+                counted,
+                coins,
+                "{} step {} cap {} counters {:?}",
+                signal.name(),
+                step,
+                cap,
+                counters
+            );
+        }
+    }
+
+    #[test]
+    fn auto_coin_on_a_new_touch_at_most_once_per_interval() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        // (seconds since start, slider touched, coin added)
+        let reads = [
+            (0, false, false),
+            (1, true, true),
+            // Held, not a new touch
+            (2, true, false),
+            (3, false, false),
+            // Too soon after the last coin, and the touch is used up
+            (4, true, false),
+            (14, true, false),
+            (15, false, false),
+            (16, true, true),
+        ];
+        let mut auto = AutoCoin::NEW;
+        for (secs, touched, coin) in reads {
+            assert_eq!(auto.read(touched, at(secs)), coin, "at {}s", secs);
+        }
+    }
+
+    #[test]
+    fn total_adds_local_coins_to_the_proxy_coins() {
+        let _globals = crate::lock_globals();
+        config::set_for_test(Config {
+            coin_mode: CoinMode::Auto,
+            ..Config::default()
+        });
+
+        update(3, false);
+        assert_eq!(total(), 3);
+        update(4, true);
+        assert_eq!(total(), 5);
+        assert_eq!(total(), 5);
+
+        config::set_for_test(Config::default());
+    }
+}
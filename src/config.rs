@@ -0,0 +1,2153 @@
+//! Runtime configuration
+//!
+//! Settings are read the first time they are needed. Each setting is named by
+//! an environment variable;
+//! the optional `chuniio_backflow.toml` next to the DLL can provide the same
+//! settings, as can the `[chuniio]` section of `segatools.ini` (see
+//! [`segatools`](crate::segatools)) and the registry (see
+//! [`registry`](crate::registry)). Environment variables override the TOML
+//! file, which overrides segatools.ini, which overrides the registry, which
+//! overrides settings sent by the proxy when it is asked for them on connect.
+//!
+//! File keys map onto variable names by dropping the `CHUNIIO_` prefix, with
+//! tables adding their name as a prefix: `[led] max_rate = [30, 30, 60]` sets
+//! `CHUNIIO_LED_MAX_RATE` to `30,30,60`. A `[profile."<exe name>"]` table
+//! overrides the file's settings when loaded into that executable.
+//!
+//! The IO thread watches these sources and reloads the configuration when
+//! one changes (see [`maybe_reload`]). Code that reads [`get`] each time it
+//! needs a value picks up the change on its own; state derived from the
+//! configuration is rebuilt when [`generation`] changes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicU32, Ordering},
+        Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use tracing::{info, warn};
+
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Foundation::HMODULE,
+        System::{
+            Environment::{ExpandEnvironmentStringsW, GetEnvironmentVariableW},
+            LibraryLoader::{
+                GetModuleFileNameW, GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+                GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            },
+        },
+    },
+};
+
+use crate::{
+    brokenithm::{BrokenithmMode, DEFAULT_PORT as DEFAULT_BROKENITHM_PORT},
+    coin::{CoinMode, CoinSignal},
+    dinput::DinputMap,
+    hid::{DeviceId, HidMap},
+    input::{
+        BitMap, CellMask, JvsContention, SliderMap, BEAM_COUNT, MAX_TOUCH_WIDTH, OPBTN_COUNT,
+        SLIDER_CELLS,
+    },
+    io::{default_socket_path, SOCKET_PATH_ENV},
+    keyboard::{KeyMap, VirtualKey},
+    led::{ColorOrder, LED_BOARD_COUNT},
+    logging::{
+        DEFAULT_LOG_MAX_FILES, DEFAULT_LOG_MAX_SIZE_MB, DEFAULT_LOG_PATH, DEFAULT_LOG_SINKS,
+        LOG_FILTER_ENV, LOG_MAX_FILES_ENV, LOG_MAX_SIZE_MB_ENV, LOG_PATH_ENV, LOG_ROTATION_ENV,
+        LOG_SINKS_ENV,
+    },
+    mouse::MouseAir,
+    protocol::Framing,
+    registry, segatools,
+    serial::DEFAULT_BAUD_RATE,
+    sources::{MergePolicy, SourceOrder},
+    threading::{ThreadConfig, ThreadPriority},
+    timing::{Periodic, WaitStrategy},
+    transport::{Endpoint, Fingerprint, VsockAddress, DEFAULT_PROXY_PORT},
+};
+
+/// Config file name, looked up in the directory containing the DLL
+const CONFIG_FILE_NAME: &str = "chuniio_backflow.toml";
+
+/// Environment variable holding the per-user application data directory
+const LOCAL_APP_DATA_ENV: &str = "LOCALAPPDATA";
+
+/// Name of this bridge's directory under `LOCAL_APP_DATA_ENV`
+const USER_DIR_NAME: &str = "chuniio-backflow";
+
+/// Table of per-executable profiles in the config file, keyed by process name
+const PROFILE_TABLE: &str = "profile";
+
+/// Prefix shared by every setting name
+const SETTING_PREFIX: &str = "CHUNIIO";
+
+/// Environment variable for the per-board LED update rate limit in Hz
+///
+/// Either a single value applied to every board (`60`) or one value per board
+/// (`30,30,60`). `0` disables rate limiting for that board.
+const LED_MAX_RATE_ENV: &str = "CHUNIIO_LED_MAX_RATE";
+
+/// Environment variable to enable or disable forwarding per LED board, either
+/// one switch for all boards (`1`) or one per board (`0,0,1`)
+const LED_ENABLE_ENV: &str = "CHUNIIO_LED_ENABLE";
+
+/// Environment variable for the byte order each LED board expects (`rgb`, `grb`,
+/// ...), either one order for all boards or one per board
+const LED_COLOR_ORDER_ENV: &str = "CHUNIIO_LED_COLOR_ORDER";
+
+/// Environment variable for the LED brightness in percent, either one value for
+/// all boards or one per board
+pub const LED_BRIGHTNESS_ENV: &str = "CHUNIIO_LED_BRIGHTNESS";
+
+/// Environment variable for the LED gamma correction exponent (1 = none), either
+/// one value for all boards or one per board
+const LED_GAMMA_ENV: &str = "CHUNIIO_LED_GAMMA";
+
+/// Largest accepted gamma exponent
+const MAX_LED_GAMMA: f32 = 5.0;
+
+/// Environment variable to coalesce LED frames that are due together into one send
+const LED_COALESCE_ENV: &str = "CHUNIIO_LED_COALESCE";
+
+/// Environment variable for the slider polling rate in Hz
+pub const SLIDER_POLL_RATE_ENV: &str = "CHUNIIO_SLIDER_POLL_RATE";
+
+/// Default slider polling rate (~1000Hz)
+const DEFAULT_SLIDER_POLL_RATE_HZ: u32 = 1000;
+
+/// Highest accepted polling rate in Hz; anything faster only burns CPU
+const MAX_POLL_RATE_HZ: u32 = 10_000;
+
+/// Environment variable to only call the slider callback when the pressure changes
+const SLIDER_CALLBACK_ON_CHANGE_ENV: &str = "CHUNIIO_SLIDER_CALLBACK_ON_CHANGE";
+
+/// Environment variable for the longest time in milliseconds between slider
+/// callbacks when only calling on change
+const SLIDER_MAX_QUIET_MS_ENV: &str = "CHUNIIO_SLIDER_MAX_QUIET_MS";
+
+/// Default longest time between slider callbacks when only calling on change
+const DEFAULT_SLIDER_MAX_QUIET_MS: u64 = 100;
+
+/// Environment variable for how coins reach the game (`normal`, `freeplay`, `auto`)
+pub const COIN_MODE_ENV: &str = "CHUNIIO_COIN_MODE";
+
+/// Environment variable for how the proxy's coin counter is read (`level`, `edge`)
+const COIN_SIGNAL_ENV: &str = "CHUNIIO_COIN_SIGNAL";
+
+/// Environment variable for the counter increases that make up one coin
+const COIN_STEP_ENV: &str = "CHUNIIO_COIN_STEP";
+
+/// Environment variable for the most coins credited from one update (0 = no cap)
+const COIN_CAP_ENV: &str = "CHUNIIO_COIN_CAP";
+
+/// Environment variable for what JVS polls report while the state is being
+/// updated (`last`, `zero`)
+const JVS_CONTENTION_ENV: &str = "CHUNIIO_JVS_CONTENTION";
+
+/// Environment variable for the keyboard key pressing TEST
+const KEY_TEST_ENV: &str = "CHUNIIO_KEY_TEST";
+
+/// Environment variable for the keyboard key pressing SERVICE
+const KEY_SERVICE_ENV: &str = "CHUNIIO_KEY_SERVICE";
+
+/// Environment variable for the keyboard key inserting a coin
+const KEY_COIN_ENV: &str = "CHUNIIO_KEY_COIN";
+
+/// Environment variable to play the slider from the keyboard
+const KEYBOARD_SLIDER_ENV: &str = "CHUNIIO_KEYBOARD_SLIDER";
+
+/// Environment variable for the keyboard key of each slider cell
+const KEY_SLIDER_ENV: &str = "CHUNIIO_KEY_SLIDER";
+
+/// Environment variable for the keyboard key blocking each IR beam
+const KEY_AIR_ENV: &str = "CHUNIIO_KEY_AIR";
+
+/// Environment variable to play the slider with the mouse
+const MOUSE_SLIDER_ENV: &str = "CHUNIIO_MOUSE_SLIDER";
+
+/// Environment variable for how many slider columns the mouse touches
+const MOUSE_SLIDER_WIDTH_ENV: &str = "CHUNIIO_MOUSE_SLIDER_WIDTH";
+
+/// Environment variable for how the mouse drives the IR beams (`off`,
+/// `position`, `wheel`)
+const MOUSE_AIR_ENV: &str = "CHUNIIO_MOUSE_AIR";
+
+/// Environment variable to read an XInput controller
+const XINPUT_ENV: &str = "CHUNIIO_XINPUT";
+
+/// Environment variable for how many slider columns the controller touches
+const XINPUT_SLIDER_WIDTH_ENV: &str = "CHUNIIO_XINPUT_SLIDER_WIDTH";
+
+/// Environment variable for the product name of the DirectInput device to read
+const DINPUT_DEVICE_ENV: &str = "CHUNIIO_DINPUT_DEVICE";
+
+/// Environment variable for what the DirectInput device's inputs press
+const DINPUT_MAP_ENV: &str = "CHUNIIO_DINPUT_MAP";
+
+/// Environment variable to play the slider on a touchscreen
+const TOUCH_SLIDER_ENV: &str = "CHUNIIO_TOUCH_SLIDER";
+
+/// Environment variable for the percentage of the game window's height, from
+/// the bottom, that acts as the touch slider
+const TOUCH_SLIDER_HEIGHT_ENV: &str = "CHUNIIO_TOUCH_SLIDER_HEIGHT";
+
+/// Environment variable for how a Brokenithm controller's input combines with
+/// the proxy's (`off`, `merge`, `replace`)
+const BROKENITHM_ENV: &str = "CHUNIIO_BROKENITHM";
+
+/// Environment variable for the Brokenithm server's UDP and TCP port
+const BROKENITHM_PORT_ENV: &str = "CHUNIIO_BROKENITHM_PORT";
+
+/// Environment variable for the COM port of a serial slider
+const SERIAL_SLIDER_ENV: &str = "CHUNIIO_SERIAL_SLIDER";
+
+/// Environment variable for the serial slider's baud rate
+const SERIAL_SLIDER_BAUD_ENV: &str = "CHUNIIO_SERIAL_SLIDER_BAUD";
+
+/// Environment variable to read a Tasoller over raw HID
+const TASOLLER_ENV: &str = "CHUNIIO_TASOLLER";
+
+/// Environment variable for the vendor and product ID of a HID controller
+const HID_DEVICE_ENV: &str = "CHUNIIO_HID_DEVICE";
+
+/// Environment variable for what the HID controller's report bytes press
+const HID_MAP_ENV: &str = "CHUNIIO_HID_MAP";
+
+/// Environment variable for how the slider's sources are combined
+const SLIDER_POLICY_ENV: &str = "CHUNIIO_SLIDER_POLICY";
+
+/// Environment variable for how the air's sources are combined
+const AIR_POLICY_ENV: &str = "CHUNIIO_AIR_POLICY";
+
+/// Environment variable for how the operator buttons' sources are combined
+const BUTTON_POLICY_ENV: &str = "CHUNIIO_BUTTON_POLICY";
+
+/// Environment variable for the priority order of the input sources
+const SOURCE_PRIORITY_ENV: &str = "CHUNIIO_SOURCE_PRIORITY";
+
+/// Environment variable for the path of a chuniio DLL to chain-load
+const CHAIN_DLL_ENV: &str = "CHUNIIO_CHAIN_DLL";
+
+/// Environment variable for the operator button bit order: the input bit
+/// feeding TEST, SERVICE and COIN
+const OPBTN_MAP_ENV: &str = "CHUNIIO_OPBTN_MAP";
+
+/// Environment variable to invert the IR beam bits (active-low sensors)
+const BEAM_INVERT_ENV: &str = "CHUNIIO_BEAM_INVERT";
+
+/// Environment variable for the IR beam bit order: the input bits feeding each
+/// beam
+const BEAM_MAP_ENV: &str = "CHUNIIO_BEAM_MAP";
+
+/// Environment variable for the shortest time an IR beam stays in a state, in
+/// milliseconds (0 = report beams as they are)
+const BEAM_MIN_HOLD_MS_ENV: &str = "CHUNIIO_BEAM_MIN_HOLD_MS";
+
+/// Environment variable for slider cells to force to zero (`5`) or to copy from
+/// another cell (`5=6`), by the proxy's cell numbering
+const SLIDER_MASK_ENV: &str = "CHUNIIO_SLIDER_MASK";
+
+/// Environment variable for the slider cell order: 32 comma-separated input cell
+/// indices, one per output cell
+const SLIDER_MAP_ENV: &str = "CHUNIIO_SLIDER_MAP";
+
+/// Environment variable to reverse the slider cell order
+const SLIDER_REVERSE_ENV: &str = "CHUNIIO_SLIDER_REVERSE";
+
+/// Environment variable for the lowest slider pressure that counts as a touch
+const SLIDER_THRESHOLD_ENV: &str = "CHUNIIO_SLIDER_THRESHOLD";
+
+/// Environment variable for a fixed pressure reported for every touched cell
+const SLIDER_PRESSURE_ENV: &str = "CHUNIIO_SLIDER_PRESSURE";
+
+/// Environment variable for the slider pressure curve exponent (1 = linear)
+const SLIDER_GAMMA_ENV: &str = "CHUNIIO_SLIDER_GAMMA";
+
+/// Largest accepted slider pressure curve exponent
+const MAX_SLIDER_GAMMA: f32 = 5.0;
+
+/// Environment variable for the number of state requests the IO thread keeps in
+/// flight
+const PIPELINE_DEPTH_ENV: &str = "CHUNIIO_PIPELINE_DEPTH";
+
+/// Upper bound for the pipeline depth
+const MAX_PIPELINE_DEPTH: usize = 4;
+
+/// Environment variable for the polling rate in Hz used while the inputs are idle
+/// (0 keeps polling at the full rate)
+pub const IDLE_POLL_RATE_ENV: &str = "CHUNIIO_IDLE_POLL_RATE";
+
+/// Default idle polling rate
+const DEFAULT_IDLE_POLL_RATE_HZ: u32 = 50;
+
+/// Environment variable for how many seconds without input changes count as idle
+const IDLE_AFTER_SECS_ENV: &str = "CHUNIIO_IDLE_AFTER_SECS";
+
+/// Default time without input changes before polling slows down
+const DEFAULT_IDLE_AFTER_SECS: u64 = 30;
+
+/// Environment variable for the polling rate in Hz while the game window is in
+/// the background (0 keeps polling normally)
+const BACKGROUND_POLL_RATE_ENV: &str = "CHUNIIO_BACKGROUND_POLL_RATE";
+
+/// Environment variable to turn the LEDs off while the game window is in the
+/// background
+const BACKGROUND_BLANK_LEDS_ENV: &str = "CHUNIIO_BACKGROUND_BLANK_LEDS";
+
+/// Environment variable for how the IO thread waits between polls (`park`, `spin`)
+const WAIT_STRATEGY_ENV: &str = "CHUNIIO_WAIT_STRATEGY";
+
+/// Environment variable for the spin window in microseconds of the `spin` strategy
+const SPIN_US_ENV: &str = "CHUNIIO_SPIN_US";
+
+/// Default spin window before each poll deadline
+const DEFAULT_SPIN_US: u64 = 200;
+
+/// Environment variables for slider thread scheduling, kept as a fallback for
+/// the IO thread settings
+const SLIDER_THREAD_PRIORITY_ENV: &str = "CHUNIIO_SLIDER_THREAD_PRIORITY";
+const SLIDER_THREAD_CPU_ENV: &str = "CHUNIIO_SLIDER_THREAD_CPU";
+
+/// Environment variables for IO thread scheduling
+const IO_THREAD_PRIORITY_ENV: &str = "CHUNIIO_IO_THREAD_PRIORITY";
+const IO_THREAD_CPU_ENV: &str = "CHUNIIO_IO_THREAD_CPU";
+
+/// Environment variable to reload the configuration when a config file changes
+const CONFIG_RELOAD_ENV: &str = "CHUNIIO_CONFIG_RELOAD";
+
+/// Environment variable to ask the proxy for its settings after connecting
+const REMOTE_CONFIG_ENV: &str = "CHUNIIO_REMOTE_CONFIG";
+
+/// Environment variable for the proxy's TCP host; the Unix domain socket is
+/// used while it is unset
+const PROXY_HOST_ENV: &str = "CHUNIIO_PROXY_HOST";
+
+/// Environment variable for a Windows named pipe to reach the proxy through
+/// instead of a socket
+const PROXY_PIPE_ENV: &str = "CHUNIIO_PROXY_PIPE";
+
+/// Environment variable for the vsock address of a proxy outside the VM the
+/// game runs in
+const PROXY_VSOCK_ENV: &str = "CHUNIIO_PROXY_VSOCK";
+
+/// Environment variable to send LED frames over a second connection to the
+/// proxy
+const SEPARATE_LED_CONNECTION_ENV: &str = "CHUNIIO_SEPARATE_LED_CONNECTION";
+
+/// Environment variable for a UDP port of the proxy to send LED frames to as
+/// datagrams
+const LED_UDP_PORT_ENV: &str = "CHUNIIO_LED_UDP_PORT";
+
+/// Environment variable for a backup proxy endpoint, as a URI, dialled when the
+/// configured one is unreachable
+const PROXY_BACKUP_ENV: &str = "CHUNIIO_PROXY_BACKUP";
+
+/// Environment variable for how often the primary proxy is checked for while
+/// connected to the backup, in seconds (0 = stay on the backup)
+const PROXY_FAILBACK_SECS_ENV: &str = "CHUNIIO_PROXY_FAILBACK_SECS";
+
+/// Default time between checks for the primary proxy
+const DEFAULT_PROXY_FAILBACK_SECS: u64 = 30;
+
+/// Environment variable for a `ws://` or `wss://` URL to reach the proxy at, in builds
+/// with the `websocket` feature
+const PROXY_URL_ENV: &str = "CHUNIIO_PROXY_URL";
+
+/// Environment variable for the proxy's TCP port
+const PROXY_PORT_ENV: &str = "CHUNIIO_PROXY_PORT";
+
+/// Environment variable to disable Nagle's algorithm on the TCP connection
+const TCP_NODELAY_ENV: &str = "CHUNIIO_TCP_NODELAY";
+
+/// Environment variable to wrap the TCP connection to the proxy host in TLS,
+/// in builds with the `tls` feature
+const TLS_ENV: &str = "CHUNIIO_TLS";
+
+/// Environment variable for a PEM file of CA certificates to trust the proxy's
+/// TLS certificate through
+const TLS_CA_ENV: &str = "CHUNIIO_TLS_CA";
+
+/// Environment variable for the SHA-256 fingerprint the proxy's TLS
+/// certificate must have
+const TLS_FINGERPRINT_ENV: &str = "CHUNIIO_TLS_FINGERPRINT";
+
+/// Environment variable for the name to check the proxy's TLS certificate
+/// against, instead of the host connected to
+const TLS_SERVER_NAME_ENV: &str = "CHUNIIO_TLS_SERVER_NAME";
+
+/// Environment variable to exchange versions and capabilities with the proxy
+/// after connecting
+const HANDSHAKE_ENV: &str = "CHUNIIO_HANDSHAKE";
+
+/// Environment variable to have the proxy stream its state instead of polling it
+const PUSH_ENV: &str = "CHUNIIO_PUSH";
+
+/// Environment variable to offer frame checksums in the handshake
+const FRAME_CHECKSUMS_ENV: &str = "CHUNIIO_FRAME_CHECKSUMS";
+
+/// Environment variable for how messages are delimited on the proxy stream
+const PROTOCOL_FRAMING_ENV: &str = "CHUNIIO_PROTOCOL_FRAMING";
+
+/// Environment variable to treat configuration problems as fatal: JVS init
+/// fails and reloads are rejected instead of falling back to defaults
+const CONFIG_STRICT_ENV: &str = "CHUNIIO_CONFIG_STRICT";
+
+/// Environment variable to write a commented `chuniio_backflow.toml` listing
+/// every setting next to the DLL if there is none yet
+const WRITE_DEFAULT_CONFIG_ENV: &str = "CHUNIIO_WRITE_DEFAULT_CONFIG";
+
+/// Header of the config file written by `write_default_config`
+const DEFAULT_CONFIG_HEADER: &str = "\
+# chuniio-backflow configuration
+#
+# Every setting is listed below with its value at the time this file was
+# written, commented out. Uncomment a line and edit the value to change it;
+# environment variables (CHUNIIO_<SETTING>) still override this file. See the
+# README for what each setting does.
+";
+
+/// How often the config sources are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Environment variable for the delay before the first reconnection attempt in
+/// milliseconds, doubled after every further failure
+const RECONNECT_BACKOFF_MS_ENV: &str = "CHUNIIO_RECONNECT_BACKOFF_MS";
+
+/// Default first reconnection delay
+const DEFAULT_RECONNECT_BACKOFF_MS: u64 = 50;
+
+/// Environment variable for the longest delay between reconnection attempts in
+/// milliseconds
+const RECONNECT_MAX_BACKOFF_MS_ENV: &str = "CHUNIIO_RECONNECT_MAX_BACKOFF_MS";
+
+/// Default longest reconnection delay
+const DEFAULT_RECONNECT_MAX_BACKOFF_MS: u64 = 2000;
+
+/// Environment variable for how long the proxy may stay unreachable before
+/// reconnection gives up, in seconds (0 = never)
+const RECONNECT_GIVE_UP_SECS_ENV: &str = "CHUNIIO_RECONNECT_GIVE_UP_SECS";
+
+/// Environment variable for the time between reconnection attempts after giving
+/// up, in seconds (0 = stop trying)
+const RECONNECT_PROBE_SECS_ENV: &str = "CHUNIIO_RECONNECT_PROBE_SECS";
+
+/// Default time between attempts after giving up
+const DEFAULT_RECONNECT_PROBE_SECS: u64 = 30;
+
+/// Environment variable for how far each reconnection delay is randomly
+/// spread, in percent either way
+const RECONNECT_JITTER_ENV: &str = "CHUNIIO_RECONNECT_JITTER";
+
+/// Default reconnection delay spread
+const DEFAULT_RECONNECT_JITTER_PERCENT: u8 = 20;
+
+/// Environment variable for how long a receive from the proxy may wait, in
+/// milliseconds (0 = no limit)
+const RECV_TIMEOUT_MS_ENV: &str = "CHUNIIO_RECV_TIMEOUT_MS";
+
+/// Environment variable for how long a send to the proxy may wait, in
+/// milliseconds (0 = no limit)
+const SEND_TIMEOUT_MS_ENV: &str = "CHUNIIO_SEND_TIMEOUT_MS";
+
+/// Default receive and send timeout
+const DEFAULT_IO_TIMEOUT_MS: u64 = 1000;
+
+/// Environment variable for how long the proxy may go without answering before
+/// the watchdog drops the connection, in milliseconds (0 disables it)
+const WATCHDOG_MS_ENV: &str = "CHUNIIO_WATCHDOG_MS";
+
+/// Default watchdog threshold
+const DEFAULT_WATCHDOG_MS: u64 = 5000;
+
+/// Environment variable for how long the proxy may go unheard before it is
+/// pinged, in milliseconds (0 disables the heartbeat)
+const HEARTBEAT_MS_ENV: &str = "CHUNIIO_HEARTBEAT_MS";
+
+/// Default heartbeat interval
+const DEFAULT_HEARTBEAT_MS: u64 = 1000;
+
+/// Environment variable for how many heartbeat pings in a row may go
+/// unanswered before the connection is dropped
+const HEARTBEAT_MISSES_ENV: &str = "CHUNIIO_HEARTBEAT_MISSES";
+
+/// Default number of missed heartbeats tolerated
+const DEFAULT_HEARTBEAT_MISSES: u32 = 3;
+
+/// Environment variable for how long the last input state from the proxy is
+/// kept while it doesn't answer, in milliseconds (0 = until it answers)
+const STALE_STATE_MS_ENV: &str = "CHUNIIO_STALE_STATE_MS";
+
+/// Environment variable for how long reconnection must keep failing before
+/// going offline, in milliseconds
+const OFFLINE_AFTER_MS_ENV: &str = "CHUNIIO_OFFLINE_AFTER_MS";
+
+/// Default time before going offline
+const DEFAULT_OFFLINE_AFTER_MS: u64 = 3000;
+
+/// Environment variable enabling the ambient LED pattern while offline; it
+/// only goes out as LED datagrams
+const OFFLINE_AMBIENT_ENV: &str = "CHUNIIO_OFFLINE_AMBIENT";
+
+/// Environment variable for the latency report interval in seconds (0 disables)
+const LATENCY_REPORT_SECS_ENV: &str = "CHUNIIO_LATENCY_REPORT_SECS";
+
+/// Default latency report interval
+const DEFAULT_LATENCY_REPORT_SECS: u64 = 30;
+
+/// Environment variable for the statistics summary interval in seconds (0 disables)
+const STATS_REPORT_SECS_ENV: &str = "CHUNIIO_STATS_REPORT_SECS";
+
+/// Default statistics summary interval
+const DEFAULT_STATS_REPORT_SECS: u64 = 30;
+
+/// How one setting is read into a [`Config`] and written back out
+struct Setting {
+    name: &'static str,
+    /// Store a value in the configuration, `None` if it is invalid; settings
+    /// read elsewhere (logging, the socket paths) have none
+    load: Option<fn(&mut Config, &str) -> Option<()>>,
+    /// The effective value, written the way it would be set
+    show: fn(&Config) -> String,
+}
+
+/// A [`Setting`] for a `Config` field: `|value| parse` gives the field from
+/// the text, `None` if it is invalid, and `|field| show` renders it
+macro_rules! setting {
+    ($name:expr, $field:ident, |$value:ident| $parse:expr, |$shown:ident| $show:expr) => {
+        Setting {
+            name: $name,
+            load: Some(|config, $value| {
+                config.$field = $parse?;
+                Some(())
+            }),
+            show: |config| {
+                let $shown = &config.$field;
+                $show
+            },
+        }
+    };
+    (flag $name:expr, $field:ident) => {
+        setting!($name, $field, |value| parse_bool(value), |on| flag(*on))
+    };
+    (text $name:expr, $field:ident) => {
+        setting!(
+            $name,
+            $field,
+            |value| Some(value.trim().to_string()),
+            |text| text.clone()
+        )
+    };
+    (number $name:expr, $field:ident) => {
+        setting!($name, $field, |value| value.trim().parse().ok(), |number| {
+            number.to_string()
+        })
+    };
+    (millis $name:expr, $field:ident) => {
+        setting!(
+            $name,
+            $field,
+            |value| value.trim().parse().ok().map(Duration::from_millis),
+            |duration| duration.as_millis().to_string()
+        )
+    };
+    (secs $name:expr, $field:ident) => {
+        setting!(
+            $name,
+            $field,
+            |value| value.trim().parse().ok().map(Duration::from_secs),
+            |duration| duration.as_secs().to_string()
+        )
+    };
+    (priority $name:expr, $thread:ident) => {
+        Setting {
+            name: $name,
+            load: Some(|config, value| {
+                config.$thread.priority = Some(ThreadPriority::parse(value)?);
+                Some(())
+            }),
+            show: |config| {
+                config
+                    .$thread
+                    .priority
+                    .map_or("unchanged", ThreadPriority::name)
+                    .to_string()
+            },
+        }
+    };
+    (cpu $name:expr, $thread:ident) => {
+        Setting {
+            name: $name,
+            load: Some(|config, value| {
+                config.$thread.cpu = Some(value.trim().parse().ok()?);
+                Some(())
+            }),
+            show: |config| {
+                config
+                    .$thread
+                    .cpu
+                    .map_or("unpinned".to_string(), |cpu| cpu.to_string())
+            },
+        }
+    };
+    (named $name:expr, $field:ident, $type:ident) => {
+        setting!($name, $field, |value| $type::parse(value), |named| named
+            .name()
+            .to_string())
+    };
+}
+
+/// A [`Setting`] read through [`lookup`] by another module, shown with its
+/// default when unset
+macro_rules! read_elsewhere {
+    ($name:expr, $default:expr) => {
+        Setting {
+            name: $name,
+            load: None,
+            show: |_| lookup($name).unwrap_or_else($default),
+        }
+    };
+}
+
+/// Every setting, in the order they are logged and written to the default
+/// config file; defaults are the values in `Config::default()`
+const SETTINGS: &[Setting] = &[
+    read_elsewhere!(SOCKET_PATH_ENV, default_socket_path),
+    read_elsewhere!(LOG_FILTER_ENV, || "RUST_LOG or trace".to_string()),
+    read_elsewhere!(LOG_SINKS_ENV, || DEFAULT_LOG_SINKS.to_string()),
+    Setting {
+        name: LOG_PATH_ENV,
+        load: None,
+        show: |_| {
+            lookup(LOG_PATH_ENV)
+                .map(|path| expand_env(path.trim()))
+                .unwrap_or_else(|| DEFAULT_LOG_PATH.to_string())
+        },
+    },
+    read_elsewhere!(LOG_ROTATION_ENV, || "never".to_string()),
+    read_elsewhere!(LOG_MAX_SIZE_MB_ENV, || DEFAULT_LOG_MAX_SIZE_MB.to_string()),
+    read_elsewhere!(LOG_MAX_FILES_ENV, || DEFAULT_LOG_MAX_FILES.to_string()),
+    setting!(
+        LED_ENABLE_ENV,
+        led_enabled,
+        |value| parse_per_board(value, parse_bool),
+        |enabled| enabled.map(flag).join(",")
+    ),
+    setting!(
+        LED_COLOR_ORDER_ENV,
+        led_color_order,
+        |value| parse_per_board(value, ColorOrder::parse),
+        |orders| orders.map(ColorOrder::name).join(",")
+    ),
+    setting!(
+        LED_BRIGHTNESS_ENV,
+        led_brightness,
+        |value| parse_per_board(value, |v| {
+            v.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|&percent| percent <= 100)
+        }),
+        |brightness| brightness.map(|percent| percent.to_string()).join(",")
+    ),
+    setting!(
+        LED_GAMMA_ENV,
+        led_gamma,
+        |value| parse_per_board(value, |v| {
+            v.trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|gamma| *gamma > 0.0 && *gamma <= MAX_LED_GAMMA)
+        }),
+        |gamma| gamma.map(|gamma| gamma.to_string()).join(",")
+    ),
+    setting!(
+        LED_MAX_RATE_ENV,
+        led_max_rate_hz,
+        |value| parse_per_board(value, |v| v.trim().parse::<u32>().ok()),
+        |rates| rates.map(|hz| hz.to_string()).join(",")
+    ),
+    setting!(flag LED_COALESCE_ENV, led_coalesce),
+    setting!(
+        SLIDER_POLL_RATE_ENV,
+        slider_poll_rate_hz,
+        |value| value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|hz| (1..=MAX_POLL_RATE_HZ).contains(hz)),
+        |hz| hz.to_string()
+    ),
+    setting!(flag SLIDER_CALLBACK_ON_CHANGE_ENV, slider_callback_on_change),
+    setting!(millis SLIDER_MAX_QUIET_MS_ENV, slider_max_quiet),
+    setting!(named COIN_MODE_ENV, coin_mode, CoinMode),
+    setting!(named COIN_SIGNAL_ENV, coin_signal, CoinSignal),
+    setting!(
+        COIN_STEP_ENV,
+        coin_step,
+        |value| value.trim().parse().ok().filter(|&step| step > 0),
+        |step| step.to_string()
+    ),
+    setting!(number COIN_CAP_ENV, coin_cap),
+    setting!(named JVS_CONTENTION_ENV, jvs_contention, JvsContention),
+    setting!(
+        KEY_TEST_ENV,
+        key_test,
+        |value| VirtualKey::parse(value),
+        |key| key.name()
+    ),
+    setting!(
+        KEY_SERVICE_ENV,
+        key_service,
+        |value| VirtualKey::parse(value),
+        |key| key.name()
+    ),
+    setting!(
+        KEY_COIN_ENV,
+        key_coin,
+        |value| VirtualKey::parse(value),
+        |key| key.name()
+    ),
+    setting!(flag KEYBOARD_SLIDER_ENV, keyboard_slider),
+    setting!(
+        KEY_SLIDER_ENV,
+        key_slider,
+        |value| KeyMap::parse(value),
+        |keys| keys.name()
+    ),
+    setting!(KEY_AIR_ENV, key_air, |value| KeyMap::parse(value), |keys| {
+        keys.name()
+    }),
+    setting!(flag MOUSE_SLIDER_ENV, mouse_slider),
+    setting!(
+        MOUSE_SLIDER_WIDTH_ENV,
+        mouse_slider_width,
+        |value| value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|width| (1..=MAX_TOUCH_WIDTH).contains(width)),
+        |width| width.to_string()
+    ),
+    setting!(named MOUSE_AIR_ENV, mouse_air, MouseAir),
+    setting!(flag XINPUT_ENV, xinput),
+    setting!(
+        XINPUT_SLIDER_WIDTH_ENV,
+        xinput_slider_width,
+        |value| value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|width| (1..=MAX_TOUCH_WIDTH).contains(width)),
+        |width| width.to_string()
+    ),
+    setting!(text DINPUT_DEVICE_ENV, dinput_device),
+    setting!(
+        DINPUT_MAP_ENV,
+        dinput_map,
+        |value| DinputMap::parse(value),
+        |map| map.name()
+    ),
+    setting!(flag TOUCH_SLIDER_ENV, touch_slider),
+    setting!(
+        TOUCH_SLIDER_HEIGHT_ENV,
+        touch_slider_height,
+        |value| value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|height| (1..=100).contains(height)),
+        |height| height.to_string()
+    ),
+    setting!(named BROKENITHM_ENV, brokenithm, BrokenithmMode),
+    setting!(
+        BROKENITHM_PORT_ENV,
+        brokenithm_port,
+        |value| value.trim().parse().ok().filter(|&port| port > 0),
+        |port| port.to_string()
+    ),
+    setting!(
+        SERIAL_SLIDER_ENV,
+        serial_slider,
+        |value| Some(value.trim().to_ascii_uppercase()),
+        |port| port.clone()
+    ),
+    setting!(
+        SERIAL_SLIDER_BAUD_ENV,
+        serial_slider_baud,
+        |value| value.trim().parse().ok().filter(|&baud| baud > 0),
+        |baud| baud.to_string()
+    ),
+    setting!(flag TASOLLER_ENV, tasoller),
+    setting!(
+        HID_DEVICE_ENV,
+        hid_device,
+        |value| optional(value, DeviceId::parse),
+        |id| id.map_or(String::new(), |id| id.to_string())
+    ),
+    setting!(HID_MAP_ENV, hid_map, |value| HidMap::parse(value), |map| {
+        map.name()
+    }),
+    setting!(named SLIDER_POLICY_ENV, slider_policy, MergePolicy),
+    setting!(named AIR_POLICY_ENV, air_policy, MergePolicy),
+    setting!(named BUTTON_POLICY_ENV, button_policy, MergePolicy),
+    setting!(
+        SOURCE_PRIORITY_ENV,
+        source_priority,
+        |value| SourceOrder::parse(value),
+        |order| order.name()
+    ),
+    setting!(text CHAIN_DLL_ENV, chain_dll),
+    setting!(
+        OPBTN_MAP_ENV,
+        opbtn_map,
+        |value| BitMap::parse(value),
+        |map| map.name()
+    ),
+    setting!(flag BEAM_INVERT_ENV, beam_invert),
+    setting!(
+        BEAM_MAP_ENV,
+        beam_map,
+        |value| BitMap::parse(value),
+        |map| map.name()
+    ),
+    setting!(millis BEAM_MIN_HOLD_MS_ENV, beam_min_hold),
+    setting!(
+        SLIDER_MASK_ENV,
+        slider_mask,
+        |value| CellMask::parse(value),
+        |mask| mask.name()
+    ),
+    setting!(
+        SLIDER_MAP_ENV,
+        slider_map,
+        |value| SliderMap::parse(value),
+        |map| map.name()
+    ),
+    setting!(flag SLIDER_REVERSE_ENV, slider_reverse),
+    setting!(number SLIDER_THRESHOLD_ENV, slider_threshold),
+    setting!(
+        SLIDER_PRESSURE_ENV,
+        slider_pressure,
+        // 0 follows the proxy's pressure
+        |value| value
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .map(|pressure| (pressure > 0).then_some(pressure)),
+        |pressure| pressure.unwrap_or(0).to_string()
+    ),
+    setting!(
+        SLIDER_GAMMA_ENV,
+        slider_gamma,
+        |value| value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&gamma| gamma > 0.0 && gamma <= MAX_SLIDER_GAMMA),
+        |gamma| gamma.to_string()
+    ),
+    setting!(
+        PIPELINE_DEPTH_ENV,
+        pipeline_depth,
+        |value| value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|depth| (1..=MAX_PIPELINE_DEPTH).contains(depth)),
+        |depth| depth.to_string()
+    ),
+    setting!(
+        IDLE_POLL_RATE_ENV,
+        idle_poll_rate_hz,
+        |value| value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&hz| hz <= MAX_POLL_RATE_HZ),
+        |hz| hz.to_string()
+    ),
+    setting!(secs IDLE_AFTER_SECS_ENV, idle_after),
+    setting!(
+        BACKGROUND_POLL_RATE_ENV,
+        background_poll_rate_hz,
+        |value| value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&hz| hz <= MAX_POLL_RATE_HZ),
+        |hz| hz.to_string()
+    ),
+    setting!(flag BACKGROUND_BLANK_LEDS_ENV, background_blank_leds),
+    setting!(named WAIT_STRATEGY_ENV, wait_strategy, WaitStrategy),
+    setting!(
+        SPIN_US_ENV,
+        spin_window,
+        |value| value.trim().parse().ok().map(Duration::from_micros),
+        |window| window.as_micros().to_string()
+    ),
+    setting!(priority IO_THREAD_PRIORITY_ENV, io_thread),
+    setting!(cpu IO_THREAD_CPU_ENV, io_thread),
+    setting!(priority SLIDER_THREAD_PRIORITY_ENV, slider_thread),
+    setting!(cpu SLIDER_THREAD_CPU_ENV, slider_thread),
+    setting!(millis RECONNECT_BACKOFF_MS_ENV, reconnect_backoff),
+    setting!(millis RECONNECT_MAX_BACKOFF_MS_ENV, reconnect_max_backoff),
+    setting!(secs RECONNECT_GIVE_UP_SECS_ENV, reconnect_give_up),
+    setting!(secs RECONNECT_PROBE_SECS_ENV, reconnect_probe_interval),
+    setting!(
+        RECONNECT_JITTER_ENV,
+        reconnect_jitter_percent,
+        |value| value.trim().parse().ok().filter(|&percent| percent <= 100),
+        |percent| percent.to_string()
+    ),
+    setting!(millis RECV_TIMEOUT_MS_ENV, recv_timeout),
+    setting!(millis SEND_TIMEOUT_MS_ENV, send_timeout),
+    setting!(millis WATCHDOG_MS_ENV, watchdog_timeout),
+    setting!(millis HEARTBEAT_MS_ENV, heartbeat_interval),
+    setting!(
+        HEARTBEAT_MISSES_ENV,
+        heartbeat_misses,
+        |value| value.trim().parse().ok().filter(|&misses| misses > 0),
+        |misses| misses.to_string()
+    ),
+    setting!(millis STALE_STATE_MS_ENV, stale_state_timeout),
+    setting!(millis OFFLINE_AFTER_MS_ENV, offline_after),
+    setting!(flag OFFLINE_AMBIENT_ENV, offline_ambient),
+    setting!(secs LATENCY_REPORT_SECS_ENV, latency_report_interval),
+    setting!(secs STATS_REPORT_SECS_ENV, stats_report_interval),
+    setting!(flag CONFIG_RELOAD_ENV, reload),
+    setting!(flag REMOTE_CONFIG_ENV, remote_config),
+    setting!(text PROXY_HOST_ENV, proxy_host),
+    setting!(
+        PROXY_PORT_ENV,
+        proxy_port,
+        |value| value.trim().parse().ok().filter(|&port| port > 0),
+        |port| port.to_string()
+    ),
+    setting!(text PROXY_PIPE_ENV, proxy_pipe),
+    setting!(text PROXY_URL_ENV, proxy_url),
+    setting!(
+        PROXY_VSOCK_ENV,
+        proxy_vsock,
+        |value| optional(value, VsockAddress::parse),
+        |address| address.map_or(String::new(), |address| address.to_string())
+    ),
+    setting!(
+        PROXY_BACKUP_ENV,
+        proxy_backup,
+        |value| optional(value, Endpoint::parse),
+        |endpoint| endpoint
+            .as_ref()
+            .map_or(String::new(), |endpoint| endpoint.to_string())
+    ),
+    setting!(secs PROXY_FAILBACK_SECS_ENV, failback_interval),
+    setting!(flag TCP_NODELAY_ENV, tcp_nodelay),
+    setting!(flag TLS_ENV, tls),
+    setting!(text TLS_CA_ENV, tls_ca),
+    setting!(
+        TLS_FINGERPRINT_ENV,
+        tls_fingerprint,
+        |value| optional(value, Fingerprint::parse),
+        |fingerprint| fingerprint.map_or(String::new(), |fingerprint| fingerprint.to_string())
+    ),
+    setting!(text TLS_SERVER_NAME_ENV, tls_server_name),
+    setting!(flag SEPARATE_LED_CONNECTION_ENV, separate_led_connection),
+    setting!(number LED_UDP_PORT_ENV, led_udp_port),
+    setting!(flag HANDSHAKE_ENV, handshake),
+    setting!(flag PUSH_ENV, push),
+    setting!(flag FRAME_CHECKSUMS_ENV, frame_checksums),
+    setting!(named PROTOCOL_FRAMING_ENV, framing, Framing),
+    setting!(flag CONFIG_STRICT_ENV, strict),
+    setting!(flag WRITE_DEFAULT_CONFIG_ENV, write_default_config),
+];
+
+/// Effective configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Whether LED frames are forwarded for each board
+    pub led_enabled: [bool; LED_BOARD_COUNT],
+    /// Byte order of the RGB data sent for each board
+    pub led_color_order: [ColorOrder; LED_BOARD_COUNT],
+    /// Brightness in percent applied to each board's RGB data
+    pub led_brightness: [u8; LED_BOARD_COUNT],
+    /// Gamma correction exponent applied to each board's RGB data
+    pub led_gamma: [f32; LED_BOARD_COUNT],
+    /// Maximum LED updates per second for each board (0 = unlimited)
+    pub led_max_rate_hz: [u32; LED_BOARD_COUNT],
+    /// Send the LED frames of all boards that are due in a single write
+    pub led_coalesce: bool,
+    /// Slider polling rate in Hz
+    pub slider_poll_rate_hz: u32,
+    /// Skip the slider callback while the pressure data is unchanged
+    pub slider_callback_on_change: bool,
+    /// Longest time between slider callbacks even without changes
+    pub slider_max_quiet: Duration,
+    /// How coins reach the game
+    pub coin_mode: CoinMode,
+    /// How the proxy's coin counter is read
+    pub coin_signal: CoinSignal,
+    /// Counter increases that make up one coin
+    pub coin_step: u16,
+    /// Most coins credited from one update of the proxy's counter (0 = no cap)
+    pub coin_cap: u16,
+    /// What JVS polls report while the state is being updated
+    pub jvs_contention: JvsContention,
+    /// Keyboard key pressing TEST
+    pub key_test: VirtualKey,
+    /// Keyboard key pressing SERVICE
+    pub key_service: VirtualKey,
+    /// Keyboard key inserting a coin
+    pub key_coin: VirtualKey,
+    /// Play the slider from the keyboard, merged with the proxy's pressure
+    pub keyboard_slider: bool,
+    /// Keyboard key of each slider cell
+    pub key_slider: KeyMap<SLIDER_CELLS>,
+    /// Keyboard key blocking each IR beam
+    pub key_air: KeyMap<BEAM_COUNT>,
+    /// Play the slider with the mouse, merged with the proxy's pressure
+    pub mouse_slider: bool,
+    /// Slider columns touched around the cursor
+    pub mouse_slider_width: u8,
+    /// How the mouse drives the IR beams
+    pub mouse_air: MouseAir,
+    /// Read an XInput controller, merged with the proxy's input
+    pub xinput: bool,
+    /// Slider columns touched around the controller's stick position
+    pub xinput_slider_width: u8,
+    /// Product name (or part of it) of the DirectInput device to read, `any`
+    /// for the first game controller, empty for none
+    pub dinput_device: String,
+    /// What the DirectInput device's inputs press
+    pub dinput_map: DinputMap,
+    /// Play the slider on a touchscreen, merged with the proxy's pressure
+    pub touch_slider: bool,
+    /// Percentage of the game window's height, from the bottom, that acts as
+    /// the touch slider
+    pub touch_slider_height: u8,
+    /// How a Brokenithm controller's input combines with the proxy's
+    pub brokenithm: BrokenithmMode,
+    /// Brokenithm server's UDP and TCP port
+    pub brokenithm_port: u16,
+    /// COM port of a serial slider (e.g. `COM3`), empty for none
+    pub serial_slider: String,
+    /// Serial slider's baud rate
+    pub serial_slider_baud: u32,
+    /// Read a Tasoller plugged into this PC over raw HID
+    pub tasoller: bool,
+    /// HID controller read with `hid_map`, `None` for none
+    pub hid_device: Option<DeviceId>,
+    /// What the HID controller's report bytes and bits press
+    pub hid_map: HidMap,
+    /// How the slider's sources are combined
+    pub slider_policy: MergePolicy,
+    /// How the air's sources are combined
+    pub air_policy: MergePolicy,
+    /// How the operator buttons' sources are combined
+    pub button_policy: MergePolicy,
+    /// Source order for the `priority` and `exclusive` policies
+    pub source_priority: SourceOrder,
+    /// Path of a chuniio DLL called alongside this one, empty for none
+    pub chain_dll: String,
+    /// Input bit each operator button is read from
+    pub opbtn_map: BitMap<OPBTN_COUNT>,
+    /// Invert the IR beam bits before mapping them
+    pub beam_invert: bool,
+    /// Input bit each IR beam is read from
+    pub beam_map: BitMap<BEAM_COUNT>,
+    /// Shortest time a beam is reported in a state before it can change again
+    pub beam_min_hold: Duration,
+    /// Fixes for dead or stuck slider cells, applied before `slider_map`
+    pub slider_mask: CellMask,
+    /// Input cell each slider cell takes its pressure from
+    pub slider_map: SliderMap,
+    /// Reverse the slider cell order (after `slider_map`)
+    pub slider_reverse: bool,
+    /// Pressure below which a cell counts as untouched (0 = no threshold)
+    pub slider_threshold: u8,
+    /// Pressure reported for every touched cell instead of the measured one
+    pub slider_pressure: Option<u8>,
+    /// Exponent of the curve applied to measured pressure (1 = unchanged)
+    pub slider_gamma: f32,
+    /// Full-state requests the IO thread keeps in flight (1 = no pipelining)
+    pub pipeline_depth: usize,
+    /// Polling rate in Hz while the inputs are idle (0 = never slow down)
+    pub idle_poll_rate_hz: u32,
+    /// Time without touches or JVS changes before polling slows down
+    pub idle_after: Duration,
+    /// Polling rate in Hz while the game is in the background (0 = no change)
+    pub background_poll_rate_hz: u32,
+    /// Send blank LED frames while the game is in the background
+    pub background_blank_leds: bool,
+    /// How the IO thread waits between polls
+    pub wait_strategy: WaitStrategy,
+    /// Time spent spinning before each deadline with `WaitStrategy::SpinThenPark`
+    pub spin_window: Duration,
+    /// Scheduling settings from the `CHUNIIO_SLIDER_THREAD_*` variables, which
+    /// predate the single IO thread and now apply to it where `io_thread` is unset
+    pub slider_thread: ThreadConfig,
+    /// Scheduling settings for the IO thread
+    pub io_thread: ThreadConfig,
+    /// Delay before the first reconnection attempt, doubled after each failure
+    pub reconnect_backoff: Duration,
+    /// Longest delay between reconnection attempts
+    pub reconnect_max_backoff: Duration,
+    /// Time the proxy may stay unreachable before giving up (zero = never)
+    pub reconnect_give_up: Duration,
+    /// Time between attempts after giving up (zero = stop trying)
+    pub reconnect_probe_interval: Duration,
+    /// Random spread of each reconnection delay, in percent either way, so
+    /// several cabs don't all retry a restarted proxy at once
+    pub reconnect_jitter_percent: u8,
+    /// Longest wait for data from the proxy before the connection is
+    /// considered failed (zero = no limit)
+    pub recv_timeout: Duration,
+    /// Longest wait for a send to the proxy to go through (zero = no limit)
+    pub send_timeout: Duration,
+    /// Time the proxy may go without answering before the watchdog drops the
+    /// connection (zero = no watchdog)
+    pub watchdog_timeout: Duration,
+    /// Time the proxy may go unheard before it is pinged (zero = never)
+    pub heartbeat_interval: Duration,
+    /// Unanswered heartbeat pings in a row after which the connection is
+    /// dropped and reestablished
+    pub heartbeat_misses: u32,
+    /// Time the last input state is kept while the proxy doesn't answer
+    /// (zero = until it answers)
+    pub stale_state_timeout: Duration,
+    /// Time reconnection must keep failing before offline mode: inputs
+    /// released, quiet logs and reconnection in the background
+    pub offline_after: Duration,
+    /// Send a slow ambient LED pattern as datagrams while offline; does
+    /// nothing without a UDP LED port
+    pub offline_ambient: bool,
+    /// How often request latency percentiles are logged (zero = never)
+    pub latency_report_interval: Duration,
+    /// How often the statistics summary is logged (zero = never)
+    pub stats_report_interval: Duration,
+    /// Reload the configuration when a config file changes
+    pub reload: bool,
+    /// Ask the proxy for its settings after connecting
+    pub remote_config: bool,
+    /// Host to reach the proxy at over TCP; empty for its Unix domain socket
+    pub proxy_host: String,
+    /// TCP port of the proxy
+    pub proxy_port: u16,
+    /// Named pipe to reach the proxy through; empty for a socket
+    pub proxy_pipe: String,
+    /// `ws://` or `wss://` URL to reach the proxy at; empty for a socket
+    pub proxy_url: String,
+    /// vsock address to reach the proxy at, instead of TCP or its Unix domain
+    /// socket
+    pub proxy_vsock: Option<VsockAddress>,
+    /// Proxy to fail over to when the configured one is unreachable
+    pub proxy_backup: Option<Endpoint>,
+    /// Time between checks for the primary proxy while on the backup (zero =
+    /// stay on the backup until it fails)
+    pub failback_interval: Duration,
+    /// Disable Nagle's algorithm on the TCP connection
+    pub tcp_nodelay: bool,
+    /// Wrap the TCP connection to the proxy host in TLS
+    pub tls: bool,
+    /// PEM file of CA certificates the proxy's certificate must chain to;
+    /// empty to trust it by fingerprint alone
+    pub tls_ca: String,
+    /// Fingerprint the proxy's certificate must have
+    pub tls_fingerprint: Option<Fingerprint>,
+    /// Name to check the proxy's certificate against; empty for the host
+    pub tls_server_name: String,
+    /// Send LED frames over a second connection, apart from polling
+    pub separate_led_connection: bool,
+    /// UDP port of the proxy to send LED frames to as datagrams, 0 to send
+    /// them over the connection
+    pub led_udp_port: u16,
+    /// Exchange versions and capabilities with the proxy after connecting
+    pub handshake: bool,
+    /// Have the proxy stream its state instead of polling it
+    pub push: bool,
+    /// Offer frame checksums in the handshake
+    pub frame_checksums: bool,
+    /// How messages are delimited on the proxy stream
+    pub framing: Framing,
+    /// Treat configuration problems as fatal
+    pub strict: bool,
+    /// Write a config file listing every setting if there is none
+    pub write_default_config: bool,
+    /// Invalid values, unknown settings and unreadable sources found while
+    /// loading, as shown in the log
+    pub problems: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            led_enabled: [true; LED_BOARD_COUNT],
+            led_color_order: [ColorOrder::RGB; LED_BOARD_COUNT],
+            led_brightness: [100; LED_BOARD_COUNT],
+            led_gamma: [1.0; LED_BOARD_COUNT],
+            led_max_rate_hz: [0; LED_BOARD_COUNT],
+            led_coalesce: false,
+            slider_poll_rate_hz: DEFAULT_SLIDER_POLL_RATE_HZ,
+            slider_callback_on_change: false,
+            slider_max_quiet: Duration::from_millis(DEFAULT_SLIDER_MAX_QUIET_MS),
+            coin_mode: CoinMode::default(),
+            coin_signal: CoinSignal::default(),
+            coin_step: 1,
+            coin_cap: 0,
+            jvs_contention: JvsContention::default(),
+            key_test: VirtualKey::F1,
+            key_service: VirtualKey::F2,
+            key_coin: VirtualKey::F3,
+            keyboard_slider: false,
+            key_slider: KeyMap::SLIDER_ROWS,
+            key_air: KeyMap::NONE,
+            mouse_slider: false,
+            mouse_slider_width: 2,
+            mouse_air: MouseAir::default(),
+            xinput: false,
+            xinput_slider_width: 4,
+            dinput_device: String::new(),
+            dinput_map: DinputMap::default(),
+            touch_slider: false,
+            touch_slider_height: 25,
+            brokenithm: BrokenithmMode::default(),
+            brokenithm_port: DEFAULT_BROKENITHM_PORT,
+            serial_slider: String::new(),
+            serial_slider_baud: DEFAULT_BAUD_RATE,
+            tasoller: false,
+            hid_device: None,
+            hid_map: HidMap::default(),
+            slider_policy: MergePolicy::default(),
+            air_policy: MergePolicy::default(),
+            button_policy: MergePolicy::default(),
+            source_priority: SourceOrder::default(),
+            chain_dll: String::new(),
+            opbtn_map: BitMap::IDENTITY,
+            beam_invert: false,
+            beam_map: BitMap::IDENTITY,
+            beam_min_hold: Duration::ZERO,
+            slider_mask: CellMask::NONE,
+            slider_map: SliderMap::IDENTITY,
+            slider_reverse: false,
+            slider_threshold: 0,
+            slider_pressure: None,
+            slider_gamma: 1.0,
+            pipeline_depth: 1,
+            idle_poll_rate_hz: DEFAULT_IDLE_POLL_RATE_HZ,
+            idle_after: Duration::from_secs(DEFAULT_IDLE_AFTER_SECS),
+            background_poll_rate_hz: 0,
+            background_blank_leds: false,
+            wait_strategy: WaitStrategy::default(),
+            spin_window: Duration::from_micros(DEFAULT_SPIN_US),
+            slider_thread: ThreadConfig::default(),
+            io_thread: ThreadConfig::default(),
+            reconnect_backoff: Duration::from_millis(DEFAULT_RECONNECT_BACKOFF_MS),
+            reconnect_max_backoff: Duration::from_millis(DEFAULT_RECONNECT_MAX_BACKOFF_MS),
+            reconnect_give_up: Duration::ZERO,
+            reconnect_probe_interval: Duration::from_secs(DEFAULT_RECONNECT_PROBE_SECS),
+            reconnect_jitter_percent: DEFAULT_RECONNECT_JITTER_PERCENT,
+            recv_timeout: Duration::from_millis(DEFAULT_IO_TIMEOUT_MS),
+            send_timeout: Duration::from_millis(DEFAULT_IO_TIMEOUT_MS),
+            watchdog_timeout: Duration::from_millis(DEFAULT_WATCHDOG_MS),
+            heartbeat_interval: Duration::from_millis(DEFAULT_HEARTBEAT_MS),
+            heartbeat_misses: DEFAULT_HEARTBEAT_MISSES,
+            stale_state_timeout: Duration::ZERO,
+            offline_after: Duration::from_millis(DEFAULT_OFFLINE_AFTER_MS),
+            offline_ambient: false,
+            latency_report_interval: Duration::from_secs(DEFAULT_LATENCY_REPORT_SECS),
+            stats_report_interval: Duration::from_secs(DEFAULT_STATS_REPORT_SECS),
+            reload: true,
+            remote_config: false,
+            proxy_host: String::new(),
+            proxy_port: DEFAULT_PROXY_PORT,
+            proxy_pipe: String::new(),
+            proxy_url: String::new(),
+            proxy_vsock: None,
+            proxy_backup: None,
+            failback_interval: Duration::from_secs(DEFAULT_PROXY_FAILBACK_SECS),
+            tcp_nodelay: true,
+            tls: false,
+            tls_ca: String::new(),
+            tls_fingerprint: None,
+            tls_server_name: String::new(),
+            separate_led_connection: false,
+            led_udp_port: 0,
+            handshake: false,
+            push: false,
+            frame_checksums: false,
+            framing: Framing::default(),
+            strict: false,
+            write_default_config: false,
+            problems: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from defaults, overridden by the config file,
+    /// overridden by environment variables
+    fn load() -> Self {
+        let mut config = Self::default();
+
+        if let Ok(sources) = config_sources().read() {
+            for source in sources.iter() {
+                match (source.loaded, &source.error) {
+                    (_, Some(error)) => {
+                        warn!("Ignoring config from {}", error);
+                        config.problems.push(format!("unreadable config {}", error));
+                    }
+                    (true, None) => match &source.profile {
+                        Some(profile) => info!(
+                            "Loaded config from {} (profile {})",
+                            source.location, profile
+                        ),
+                        None => info!("Loaded config from {}", source.location),
+                    },
+                    (false, None) => {}
+                }
+            }
+        }
+
+        for setting in SETTINGS {
+            let Some(load) = setting.load else {
+                continue;
+            };
+            if let Some(value) = lookup(setting.name) {
+                if load(&mut config, &value).is_none() {
+                    config.invalid(setting.name, &value);
+                }
+            }
+        }
+        config.check_unknown_settings();
+
+        info!("Effective configuration:");
+        for (name, value) in config.settings() {
+            info!("  {} = {} ({})", name, value, source(&name));
+        }
+
+        config
+    }
+
+    /// Every setting with its effective value, written the way it would be set
+    fn settings(&self) -> Vec<(String, String)> {
+        SETTINGS
+            .iter()
+            .map(|setting| (setting.name.to_string(), (setting.show)(self)))
+            .collect()
+    }
+
+    /// Record and log a setting whose value could not be used
+    fn invalid(&mut self, name: &str, value: &str) {
+        let problem = format!("invalid {} {:?} from {}", name, value, source(name));
+        warn!("Ignoring {}", problem);
+        self.problems.push(problem);
+    }
+
+    /// Record and log settings in the config sources that no code reads, which
+    /// are usually misspelled keys
+    fn check_unknown_settings(&mut self) {
+        let known: HashSet<String> = self.settings().into_iter().map(|(name, _)| name).collect();
+        let Ok(sources) = config_sources().read() else {
+            return;
+        };
+        for source in sources.iter() {
+            let mut unknown: Vec<_> = source
+                .values
+                .keys()
+                .filter(|name| !known.contains(*name))
+                .collect();
+            unknown.sort();
+            for name in unknown {
+                let problem = format!("unknown setting {} in {}", name, source.position(name));
+                warn!("Ignoring {}", problem);
+                self.problems.push(problem);
+            }
+        }
+    }
+
+    /// Time between two slider polls
+    pub fn slider_poll_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.slider_poll_rate_hz
+    }
+
+    /// Time between two polls while the game is in the background, if the
+    /// background slowdown is enabled
+    pub fn background_poll_interval(&self) -> Option<Duration> {
+        (self.background_poll_rate_hz > 0)
+            .then(|| Duration::from_secs(1) / self.background_poll_rate_hz)
+    }
+
+    /// Whether the background slowdown or LED blanking need the window focus
+    pub fn watch_focus(&self) -> bool {
+        self.background_poll_rate_hz > 0 || self.background_blank_leds
+    }
+
+    /// Time between two polls while idle, if idle slowdown is enabled and
+    /// actually slower than the normal rate
+    pub fn idle_poll_interval(&self) -> Option<Duration> {
+        (self.idle_poll_rate_hz > 0 && self.idle_poll_rate_hz < self.slider_poll_rate_hz)
+            .then(|| Duration::from_secs(1) / self.idle_poll_rate_hz)
+    }
+}
+
+/// Current configuration; replaced configurations are leaked rather than freed,
+/// since references handed out by `get` may still be in use. Reloads only
+/// happen when a file is edited, so the leak stays small.
+static CONFIG: AtomicPtr<Config> = AtomicPtr::new(ptr::null_mut());
+
+/// Incremented every time the configuration is reloaded
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+static RELOAD_CHECK: Periodic = Periodic::new();
+
+/// Get the effective configuration, loading it on first use
+pub fn get() -> &'static Config {
+    let current = CONFIG.load(Ordering::Acquire);
+    if !current.is_null() {
+        return unsafe { &*current };
+    }
+
+    let loaded = Box::into_raw(Box::new(Config::load()));
+    match CONFIG.compare_exchange(ptr::null_mut(), loaded, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {
+            let config = unsafe { &*loaded };
+            if config.write_default_config {
+                write_default_config(config);
+            }
+            config
+        }
+        Err(current) => {
+            // Another thread loaded it first
+            drop(unsafe { Box::from_raw(loaded) });
+            unsafe { &*current }
+        }
+    }
+}
+
+/// Write `CONFIG_FILE_NAME` next to the DLL with every setting commented out, so
+/// the available settings can be found without the README; an existing file is
+/// left alone. When the DLL's directory cannot be written (a read-only game
+/// folder), the file goes to `user_dir()` like the log, to be copied over.
+fn write_default_config(config: &Config) {
+    let path = module_dir().map(|dir| dir.join(CONFIG_FILE_NAME));
+    if path.as_ref().is_some_and(|path| path.exists()) {
+        return;
+    }
+
+    let mut text = DEFAULT_CONFIG_HEADER.to_string();
+    for (name, value) in config.settings() {
+        let key = name
+            .strip_prefix(SETTING_PREFIX)
+            .map_or(name.as_str(), |key| key.trim_start_matches('_'))
+            .to_ascii_lowercase();
+        let value = if value.parse::<i64>().is_ok() || value.parse::<f64>().is_ok() {
+            value
+        } else {
+            format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+        };
+        text.push_str(&format!("\n# {} = {}", key, value));
+    }
+    text.push('\n');
+
+    let problem = match &path {
+        Some(path) => match fs::write(path, &text) {
+            Ok(()) => {
+                info!("Wrote default config to {}", path.display());
+                return;
+            }
+            Err(e) => format!("Cannot write default config {}: {}", path.display(), e),
+        },
+        None => "Cannot find the DLL's directory for the default config".to_string(),
+    };
+    let fallback = user_dir().join(CONFIG_FILE_NAME);
+    if fallback.exists() {
+        return;
+    }
+    match fs::write(&fallback, &text) {
+        Ok(()) => warn!(
+            "{}, wrote it to {} instead; copy it next to the DLL to use it",
+            problem,
+            fallback.display()
+        ),
+        Err(e) => warn!("{}, nor {}: {}", problem, fallback.display(), e),
+    }
+}
+
+/// Identifies the current configuration; changes on every reload
+pub fn generation() -> u32 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Reload the configuration if reloading is enabled and a config source has
+/// been created, changed or removed since it was read; checks at most once per
+/// `RELOAD_CHECK_INTERVAL`. Returns whether the configuration was reloaded.
+pub fn maybe_reload() -> bool {
+    if !get().reload || !RELOAD_CHECK.ready(RELOAD_CHECK_INTERVAL) {
+        return false;
+    }
+
+    let Ok(mut sources) = config_sources().write() else {
+        return false;
+    };
+    if !sources.iter().any(ConfigSource::changed) {
+        return false;
+    }
+    *sources = read_config_sources();
+    drop(sources);
+
+    info!("Config changed, reloading configuration");
+    publish(Config::load())
+}
+
+/// Replace the settings pushed by the proxy, reloading the configuration if
+/// they changed; returns whether the configuration was reloaded
+pub fn set_remote(values: Vec<(String, String)>) -> bool {
+    let Ok(mut remote) = REMOTE_SETTINGS.lock() else {
+        return false;
+    };
+    if *remote == values {
+        return false;
+    }
+    *remote = values;
+    drop(remote);
+
+    let Ok(mut sources) = config_sources().write() else {
+        return false;
+    };
+    sources.retain(|source| !matches!(source.location, Location::Remote));
+    sources.push(ConfigSource::remote());
+    drop(sources);
+
+    info!("Proxy sent new settings, reloading configuration");
+    publish(Config::load())
+}
+
+/// Make `config` the current configuration, unless strict mode rejects it
+fn publish(config: Config) -> bool {
+    if config.strict && !config.problems.is_empty() {
+        warn!(
+            "Keeping the previous configuration: the new one has {} problem(s)",
+            config.problems.len()
+        );
+        return false;
+    }
+    let config = Box::into_raw(Box::new(config));
+    CONFIG.store(config, Ordering::Release);
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    true
+}
+
+/// Make `config` the current configuration, for tests of code that reads it
+#[cfg(test)]
+pub fn set_for_test(config: Config) {
+    publish(config);
+}
+
+/// Look up a setting by its environment variable name, falling back to the
+/// config sources; `${VAR}` references in the value are expanded
+pub fn lookup(name: &str) -> Option<String> {
+    get_env_var(name)
+        .or_else(|| {
+            config_sources()
+                .read()
+                .ok()?
+                .iter()
+                .find_map(|source| source.values.get(name).cloned())
+        })
+        .map(|value| expand_vars(&value))
+}
+
+/// Every value given for a setting, in order of precedence, each with where it
+/// comes from as shown by [`source`]; `${VAR}` references are expanded
+pub fn lookup_all(name: &str) -> Vec<(String, String)> {
+    let mut values: Vec<_> = get_env_var(name)
+        .map(|value| (value, "environment".to_string()))
+        .into_iter()
+        .collect();
+    if let Ok(sources) = config_sources().read() {
+        values.extend(sources.iter().filter_map(|source| {
+            let value = source.values.get(name)?;
+            Some((value.clone(), source.position(name)))
+        }));
+    }
+    values
+        .into_iter()
+        .map(|(value, source)| (expand_vars(&value), source))
+        .collect()
+}
+
+/// Where the value of a setting comes from: the environment, a config file
+/// (with the line, where known), the registry, or the default
+fn source(name: &str) -> String {
+    if get_env_var(name).is_some() {
+        return "environment".to_string();
+    }
+    config_sources()
+        .read()
+        .ok()
+        .and_then(|sources| {
+            sources.iter().find_map(|source| {
+                source
+                    .values
+                    .contains_key(name)
+                    .then(|| source.position(name))
+            })
+        })
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// Read an environment variable through the Win32 API, however long it is
+pub fn get_env_var(name: &str) -> Option<String> {
+    let name_wide: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+    // Asked without a buffer, the call returns the size needed
+    let mut buffer = Vec::new();
+    loop {
+        let len = unsafe {
+            GetEnvironmentVariableW(
+                PCWSTR(name_wide.as_ptr()),
+                (!buffer.is_empty()).then_some(buffer.as_mut_slice()),
+            )
+        } as usize;
+        if len == 0 {
+            return None;
+        }
+        if len < buffer.len() {
+            // With a large enough buffer, `len` leaves out the terminating null
+            return Some(String::from_utf16(&buffer[..len]).unwrap_or_else(|_| {
+                warn!(
+                    "Environment variable {} is not valid Unicode, replacing the invalid parts",
+                    name
+                );
+                String::from_utf16_lossy(&buffer[..len])
+            }));
+        }
+        // Too small, `len` is the size needed with the null; the variable may
+        // have grown in between, so ask again
+        buffer.resize(len, 0);
+    }
+}
+
+/// Expand `${VAR}` environment references in `value`, leaving unknown ones as is
+///
+/// Lets one config file work across users and prefixes, e.g.
+/// `proxy_socket = "${XDG_RUNTIME_DIR}/chuniio_proxy.sock"`.
+fn expand_vars(value: &str) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start..];
+        let Some(end) = reference.find('}') else {
+            rest = reference;
+            break;
+        };
+        match get_env_var(&reference[2..end]) {
+            Some(var) => expanded.push_str(&var),
+            None => expanded.push_str(&reference[..=end]),
+        }
+        rest = &reference[end + 1..];
+    }
+    // Text after the last reference, or an unterminated one
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Expand `%VAR%` environment references in `value`, leaving unknown ones as is
+pub fn expand_env(value: &str) -> String {
+    let source: Vec<u16> = value.encode_utf16().chain(Some(0)).collect();
+    // Asked without a buffer, the call returns the size needed
+    let mut buffer = Vec::new();
+    loop {
+        let len = unsafe {
+            ExpandEnvironmentStringsW(
+                PCWSTR(source.as_ptr()),
+                (!buffer.is_empty()).then_some(buffer.as_mut_slice()),
+            )
+        } as usize;
+        if len == 0 {
+            warn!("Cannot expand environment references in {}", value);
+            return value.to_string();
+        }
+        if !buffer.is_empty() && len <= buffer.len() {
+            // `len` includes the terminating null
+            return String::from_utf16_lossy(&buffer[..len - 1]);
+        }
+        buffer.resize(len, 0);
+    }
+}
+
+/// Parser collecting the settings in a config file's text into `values`, and
+/// the line each one is set on into `lines`; returns the name of the profile
+/// applied, if any
+type ParseFn = fn(
+    text: &str,
+    values: &mut HashMap<String, String>,
+    lines: &mut HashMap<String, usize>,
+) -> Result<Option<String>, String>;
+
+/// Where a set of settings is read from
+enum Location {
+    File(PathBuf),
+    Registry,
+    /// Sent by the proxy (see [`set_remote`])
+    Remote,
+}
+
+impl Location {
+    /// When the settings were last changed, `None` if they do not exist
+    fn modified(&self) -> Option<SystemTime> {
+        match self {
+            Location::File(path) => fs::metadata(path).and_then(|m| m.modified()).ok(),
+            Location::Registry => registry::last_write_time(),
+            // Replaced by `set_remote` rather than watched
+            Location::Remote => None,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::File(path) => write!(f, "{}", path.display()),
+            Location::Registry => f.write_str(registry::KEY_NAME),
+            Location::Remote => f.write_str("proxy"),
+        }
+    }
+}
+
+/// Settings read from one config file or the registry, keyed by setting name
+struct ConfigSource {
+    location: Location,
+    /// Change time when it was read, `None` if it did not exist
+    modified: Option<SystemTime>,
+    /// Whether the settings exist and were read successfully
+    loaded: bool,
+    /// Profile applied on top of the source's base settings
+    profile: Option<String>,
+    values: HashMap<String, String>,
+    /// Line each value is set on, for files
+    lines: HashMap<String, usize>,
+    /// Why the source could not be used; logged once the configuration is
+    /// loaded, since the sources are also read before logging is set up
+    error: Option<String>,
+}
+
+impl ConfigSource {
+    /// Read and parse the file at `path` with `parse`; a missing file is not an
+    /// error
+    fn file(path: PathBuf, parse: ParseFn) -> Self {
+        let mut source = ConfigSource::new(Location::File(path.clone()));
+        match fs::read(&path) {
+            Ok(bytes) => match parse(
+                &String::from_utf8_lossy(&bytes),
+                &mut source.values,
+                &mut source.lines,
+            ) {
+                Ok(profile) => {
+                    source.loaded = true;
+                    source.profile = profile;
+                }
+                Err(e) => source.error = Some(format!("{}: {}", path.display(), e)),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => source.error = Some(format!("{}: {}", path.display(), e)),
+        }
+        source
+    }
+
+    /// Read the registry key; a missing key is not an error
+    fn registry() -> Self {
+        let mut source = ConfigSource::new(Location::Registry);
+        match registry::read(SETTING_PREFIX, &mut source.values) {
+            Ok(exists) => source.loaded = exists,
+            Err(e) => source.error = Some(format!("{}: {}", registry::KEY_NAME, e)),
+        }
+        source
+    }
+
+    /// Settings last sent by the proxy
+    fn remote() -> Self {
+        let mut source = ConfigSource::new(Location::Remote);
+        if let Ok(remote) = REMOTE_SETTINGS.lock() {
+            source.values = remote.iter().cloned().collect();
+            source.loaded = !remote.is_empty();
+        }
+        source
+    }
+
+    fn new(location: Location) -> Self {
+        ConfigSource {
+            modified: location.modified(),
+            location,
+            loaded: false,
+            profile: None,
+            values: HashMap::new(),
+            lines: HashMap::new(),
+            error: None,
+        }
+    }
+
+    /// Location of the setting `name`, with its line if known
+    fn position(&self, name: &str) -> String {
+        match self.lines.get(name) {
+            Some(line) => format!("{}:{}", self.location, line),
+            None => self.location.to_string(),
+        }
+    }
+
+    /// Whether the source has been created, modified or removed since it was read
+    fn changed(&self) -> bool {
+        self.location.modified() != self.modified
+    }
+}
+
+static CONFIG_SOURCES: OnceLock<RwLock<Vec<ConfigSource>>> = OnceLock::new();
+
+/// Settings last sent by the proxy, kept across re-reads of the other sources
+static REMOTE_SETTINGS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+fn config_sources() -> &'static RwLock<Vec<ConfigSource>> {
+    CONFIG_SOURCES.get_or_init(|| RwLock::new(read_config_sources()))
+}
+
+/// Read the config sources in order of precedence: our own TOML file,
+/// segatools.ini, the registry, then the settings sent by the proxy
+fn read_config_sources() -> Vec<ConfigSource> {
+    let mut sources = Vec::new();
+    if let Some(dir) = module_dir() {
+        sources.push(ConfigSource::file(dir.join(CONFIG_FILE_NAME), parse_toml));
+    }
+    sources.push(ConfigSource::file(
+        segatools::path(),
+        |text, values, lines| {
+            segatools::parse(text, SETTING_PREFIX, values, lines);
+            Ok(None)
+        },
+    ));
+    sources.push(ConfigSource::registry());
+    sources.push(ConfigSource::remote());
+    sources
+}
+
+/// Per-user directory for files that cannot be written next to the game, e.g.
+/// when the game directory is read-only: `%LOCALAPPDATA%\chuniio-backflow`,
+/// or the temp directory if that cannot be created
+pub fn user_dir() -> PathBuf {
+    let dir = get_env_var(LOCAL_APP_DATA_ENV)
+        .map(|base| PathBuf::from(base).join(USER_DIR_NAME))
+        .filter(|dir| fs::create_dir_all(dir).is_ok());
+    dir.unwrap_or_else(std::env::temp_dir)
+}
+
+/// Directory containing this DLL
+pub fn module_dir() -> Option<PathBuf> {
+    let mut module = HMODULE::default();
+    let mut buffer = [0u16; 1024];
+    let len = unsafe {
+        GetModuleHandleExW(
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            PCWSTR(module_dir as *const () as *const u16),
+            &mut module,
+        )
+        .ok()?;
+        GetModuleFileNameW(module, &mut buffer) as usize
+    };
+    if len == 0 || len >= buffer.len() {
+        return None;
+    }
+
+    let path = PathBuf::from(String::from_utf16_lossy(&buffer[..len]));
+    path.parent().map(Path::to_path_buf)
+}
+
+/// Name of the executable this DLL was loaded into, e.g. `chusanApp.exe`
+fn process_name() -> Option<String> {
+    let mut buffer = [0u16; 1024];
+    let len = unsafe { GetModuleFileNameW(None, &mut buffer) } as usize;
+    if len == 0 || len >= buffer.len() {
+        return None;
+    }
+
+    let path = PathBuf::from(String::from_utf16_lossy(&buffer[..len]));
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Collect the settings in the TOML config file into `values`, with the profile
+/// for this executable applied on top; returns the name of that profile
+fn parse_toml(
+    text: &str,
+    values: &mut HashMap<String, String>,
+    lines: &mut HashMap<String, usize>,
+) -> Result<Option<String>, String> {
+    let mut table = text.parse::<toml::Table>().map_err(|e| e.to_string())?;
+    let profiles = table.remove(PROFILE_TABLE);
+    flatten_table(SETTING_PREFIX, &table, values);
+
+    let profile = match (profiles, process_name()) {
+        (Some(toml::Value::Table(profiles)), Some(process)) => profiles
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&process)),
+        _ => None,
+    };
+    let profile = match profile {
+        Some((name, toml::Value::Table(settings))) => {
+            flatten_table(SETTING_PREFIX, &settings, values);
+            Some(name)
+        }
+        _ => None,
+    };
+
+    let mut profile_lines = HashMap::new();
+    for (key, line) in toml_key_lines(text) {
+        let (key, lines) = match key.split_first() {
+            Some((table, rest)) if table == PROFILE_TABLE => match rest.split_first() {
+                Some((name, rest)) if Some(name) == profile.as_ref() => (rest, &mut profile_lines),
+                _ => continue,
+            },
+            _ => (&key[..], &mut *lines),
+        };
+        let name = key.iter().fold(SETTING_PREFIX.to_string(), |prefix, part| {
+            setting_name(&prefix, part)
+        });
+        lines.insert(name, line);
+    }
+    // Profile values win wherever they are in the file
+    lines.extend(profile_lines);
+    Ok(profile)
+}
+
+/// Every `key = value` line in a TOML document, with the key's full path
+/// (including the enclosing table) and the line number
+///
+/// A line scan rather than a parse, since the `toml` parser does not report
+/// positions; only used to point log messages at the right line.
+fn toml_key_lines(text: &str) -> Vec<(Vec<String>, usize)> {
+    let mut keys = Vec::new();
+    let mut table = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with("[[") {
+            table = Vec::new();
+        } else if let Some(header) = line.strip_prefix('[') {
+            if let Some(end) = header.rfind(']') {
+                table = split_key(&header[..end]);
+            }
+        } else if let Some(end) = key_end(line) {
+            let mut key = table.clone();
+            key.extend(split_key(&line[..end]));
+            keys.push((key, index + 1));
+        }
+    }
+    keys
+}
+
+/// Position of the `=` ending the key of a `key = value` line
+fn key_end(line: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '=') => return Some(index),
+            (None, '#') => return None,
+            (Some(open), c) if c == open => quote = None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Split a dotted TOML key into its parts, removing quotes
+fn split_key(key: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut quote = None;
+    for c in key.chars() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            (None, '.') => parts.push(std::mem::take(&mut part).trim().to_string()),
+            _ => part.push(c),
+        }
+    }
+    parts.push(part.trim().to_string());
+    parts
+}
+
+/// Setting name for `key` inside the table named `prefix`
+fn setting_name(prefix: &str, key: &str) -> String {
+    format!("{}_{}", prefix, key.to_ascii_uppercase().replace('-', "_"))
+}
+
+/// Add every value in `table` under `<prefix>_<KEY>`, recursing into sub-tables
+fn flatten_table(prefix: &str, table: &toml::Table, values: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let name = setting_name(prefix, key);
+        match value {
+            toml::Value::Table(inner) => flatten_table(&name, inner, values),
+            value => {
+                if let Some(text) = value_to_setting(value) {
+                    values.insert(name, text);
+                }
+            }
+        }
+    }
+}
+
+/// Render a file value the way it would be written in an environment variable
+fn value_to_setting(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(text) => Some(text.clone()),
+        toml::Value::Integer(number) => Some(number.to_string()),
+        toml::Value::Float(number) => Some(number.to_string()),
+        toml::Value::Boolean(flag) => Some(if *flag { "1" } else { "0" }.to_string()),
+        toml::Value::Datetime(datetime) => Some(datetime.to_string()),
+        toml::Value::Array(items) => items
+            .iter()
+            .map(value_to_setting)
+            .collect::<Option<Vec<_>>>()
+            .map(|items| items.join(",")),
+        toml::Value::Table(_) => None,
+    }
+}
+
+/// A boolean switch as written in settings
+fn flag(on: bool) -> String {
+    if on { "1" } else { "0" }.to_string()
+}
+
+/// Parse a value that may be left empty for none
+fn optional<T>(value: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Option<T>> {
+    if value.trim().is_empty() {
+        Some(None)
+    } else {
+        parse(value).map(Some)
+    }
+}
+
+/// Parse a boolean switch (`1`/`0`, `true`/`false`, `on`/`off`, `yes`/`no`)
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "on" | "yes" => Some(true),
+        "0" | "false" | "off" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse either a single value for all boards or a comma-separated value per board
+fn parse_per_board<T: Copy>(
+    value: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<[T; LED_BOARD_COUNT]> {
+    let values = value.split(',').map(parse).collect::<Option<Vec<_>>>()?;
+
+    match values.len() {
+        1 => Some([values[0]; LED_BOARD_COUNT]),
+        LED_BOARD_COUNT => values.try_into().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FILE: &str = r"C:\Games\chuni\chuniio_backflow.toml";
+    const TEST_INI: &str = r"C:\Games\chuni\segatools.ini";
+
+    /// A config file at `path` with the TOML `text`
+    fn toml_source(path: &str, text: &str) -> ConfigSource {
+        let mut source = ConfigSource::new(Location::File(PathBuf::from(path)));
+        source.profile = parse_toml(text, &mut source.values, &mut source.lines).unwrap();
+        source.loaded = true;
+        source
+    }
+
+    /// Load the configuration from `sources` in place of the real ones
+    fn load_from(sources: Vec<ConfigSource>) -> Config {
+        let saved = std::mem::replace(&mut *config_sources().write().unwrap(), sources);
+        let config = Config::load();
+        *config_sources().write().unwrap() = saved;
+        config
+    }
+
+    #[test]
+    fn environment_overrides_files_which_override_defaults() {
+        let _globals = crate::lock_globals();
+        std::env::set_var(COIN_CAP_ENV, "9");
+        let config = load_from(vec![
+            toml_source(TEST_FILE, "[coin]\ncap = 5\nstep = 2\n"),
+            toml_source(TEST_INI, "[coin]\nstep = 3\n[led]\nudp_port = 6000\n"),
+        ]);
+        std::env::remove_var(COIN_CAP_ENV);
+
+        assert_eq!(config.coin_cap, 9);
+        assert_eq!(config.coin_step, 2);
+        assert_eq!(config.led_udp_port, 6000);
+        assert_eq!(config.slider_threshold, Config::default().slider_threshold);
+        assert!(config.problems.is_empty(), "{:?}", config.problems);
+    }
+
+    #[test]
+    fn profile_for_this_executable_overrides_the_base_settings() {
+        let _globals = crate::lock_globals();
+        let process = process_name().unwrap();
+        let text = format!(
+            "[coin]\ncap = 5\nstep = 2\n\
+             [profile.\"{}\".coin]\ncap = 7\n\
+             [profile.\"other.exe\".coin]\nstep = 4\n",
+            process
+        );
+        let source = toml_source(TEST_FILE, &text);
+        assert_eq!(source.profile.as_deref(), Some(process.as_str()));
+        assert_eq!(source.position(COIN_CAP_ENV), format!("{}:5", TEST_FILE));
+
+        let config = load_from(vec![source]);
+        assert_eq!(config.coin_cap, 7);
+        assert_eq!(config.coin_step, 2);
+        assert!(config.problems.is_empty(), "{:?}", config.problems);
+    }
+
+    #[test]
+    fn expands_environment_references() {
+        let _globals = crate::lock_globals();
+        std::env::set_var("CHUNIIO_TEST_HOST", "proxy.local");
+        let config = load_from(vec![toml_source(
+            TEST_FILE,
+            "[proxy]\nhost = \"${CHUNIIO_TEST_HOST}\"\npipe = \"${CHUNIIO_TEST_UNSET}\\\\pipe\"\n",
+        )]);
+        let expanded = expand_env("%CHUNIIO_TEST_HOST%\\logs %CHUNIIO_TEST_UNSET%");
+        std::env::remove_var("CHUNIIO_TEST_HOST");
+
+        assert_eq!(config.proxy_host, "proxy.local");
+        assert_eq!(config.proxy_pipe, "${CHUNIIO_TEST_UNSET}\\pipe");
+        assert_eq!(expanded, "proxy.local\\logs %CHUNIIO_TEST_UNSET%");
+        assert_eq!(expand_vars("${CHUNIIO_TEST_UNSET"), "${CHUNIIO_TEST_UNSET");
+    }
+
+    #[test]
+    fn strict_mode_rejects_invalid_and_unknown_settings() {
+        let _globals = crate::lock_globals();
+        let config = load_from(vec![toml_source(
+            TEST_FILE,
+            "[config]\nstrict = true\n[coin]\ncap = \"lots\"\ncpa = 1\n",
+        )]);
+
+        assert!(config.strict);
+        assert_eq!(
+            config.problems,
+            [
+                format!("invalid CHUNIIO_COIN_CAP \"lots\" from {}:4", TEST_FILE),
+                format!("unknown setting CHUNIIO_COIN_CPA in {}:5", TEST_FILE),
+            ]
+        );
+        assert_eq!(config.coin_cap, Config::default().coin_cap);
+        assert!(!publish(config));
+    }
+}
@@ -0,0 +1,474 @@
+//! DirectInput devices
+//!
+//! Arcade control boards that show up as generic game controllers rather than
+//! XInput ones can be read through DirectInput. A mapping from the device's
+//! buttons and axes to operator buttons, IR beams and slider cells is set in
+//! the configuration, and the device is merged with the proxy's input.
+
+use std::{
+    ffi::c_void,
+    fmt, mem, ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+use windows::{
+    core::{Interface, GUID},
+    Win32::{
+        Devices::HumanInterfaceDevice::{
+            DirectInput8Create, GUID_RxAxis, GUID_RyAxis, GUID_RzAxis, GUID_Slider, GUID_XAxis,
+            GUID_YAxis, GUID_ZAxis, IDirectInput8W, IDirectInputDevice8W, DI8DEVCLASS_GAMECTRL,
+            DIDATAFORMAT, DIDEVICEINSTANCEW, DIDEVICEOBJECTINSTANCEW, DIDFT_ANYINSTANCE,
+            DIDFT_AXIS, DIDFT_BUTTON, DIDF_ABSAXIS, DIEDFL_ATTACHEDONLY, DIENUM_CONTINUE,
+            DIENUM_STOP, DIOBJECTDATAFORMAT, DIPH_BYOFFSET, DIRECTINPUT_VERSION, DISCL_BACKGROUND,
+            DISCL_NONEXCLUSIVE,
+        },
+        Foundation::{BOOL, HINSTANCE},
+        System::LibraryLoader::GetModuleHandleW,
+        UI::WindowsAndMessaging::GetForegroundWindow,
+    },
+};
+
+use crate::{
+    config, focus,
+    input::{InputTarget, SLIDER_CELLS, TOUCH_PRESSURE},
+    timing::Periodic,
+};
+
+/// Axis names, in the order they are read
+const AXIS_NAMES: [&str; AXIS_COUNT] = ["x", "y", "z", "rx", "ry", "rz", "slider1", "slider2"];
+
+const AXIS_COUNT: usize = 8;
+
+const BUTTON_COUNT: usize = 32;
+
+/// Axis value at rest; axes report 0-65535
+const AXIS_CENTER: i32 = 32768;
+
+/// Distance from the center at which an axis direction counts as pressed
+const AXIS_THRESHOLD: i32 = 16384;
+
+/// Data format flag for objects the device may not have
+const DIDFT_OPTIONAL: u32 = 0x8000_0000;
+
+/// How often the device is searched for while it is not open
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A button or an axis direction on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DinputInput {
+    /// Button by index (0-31)
+    Button(u8),
+    /// Axis by index into `AXIS_NAMES`, in the positive or negative direction
+    Axis(u8, bool),
+}
+
+/// Device inputs and what they press
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DinputMap(Vec<(DinputInput, InputTarget)>);
+
+impl DinputInput {
+    /// Parse `button1`-`button32` (numbered as in the Windows game controller
+    /// settings) or an axis name followed by `+` or `-`
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        if let Some(number) = value.strip_prefix("button") {
+            let number = number.parse::<u8>().ok()?;
+            return (1..=BUTTON_COUNT as u8)
+                .contains(&number)
+                .then(|| Self::Button(number - 1));
+        }
+        let (axis, positive) = match (value.strip_suffix('+'), value.strip_suffix('-')) {
+            (Some(axis), _) => (axis, true),
+            (_, Some(axis)) => (axis, false),
+            _ => return None,
+        };
+        let index = AXIS_NAMES.iter().position(|&name| name == axis)?;
+        Some(Self::Axis(index as u8, positive))
+    }
+}
+
+impl fmt::Display for DinputInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Button(index) => write!(f, "button{}", index + 1),
+            Self::Axis(index, positive) => {
+                let sign = if positive { '+' } else { '-' };
+                write!(f, "{}{}", AXIS_NAMES[index as usize], sign)
+            }
+        }
+    }
+}
+
+impl DinputMap {
+    /// Parse comma-separated `<input>=<target>` entries, e.g.
+    /// `button1=test,x-=cell0`; empty for none
+    pub fn parse(value: &str) -> Option<Self> {
+        value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (input, target) = entry.split_once('=')?;
+                Some((DinputInput::parse(input)?, InputTarget::parse(target)?))
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Self)
+    }
+
+    /// Map as accepted by `parse`
+    pub fn name(&self) -> String {
+        self.0
+            .iter()
+            .map(|(input, target)| format!("{}={}", input, target))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Device state in the data format set on the device
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct JoyState {
+    axes: [i32; AXIS_COUNT],
+    buttons: [u8; BUTTON_COUNT],
+}
+
+impl JoyState {
+    fn pressed(&self, input: DinputInput, axes_present: u8) -> bool {
+        match input {
+            DinputInput::Button(index) => self.buttons[usize::from(index)] & 0x80 != 0,
+            DinputInput::Axis(index, positive) => {
+                let offset = self.axes[usize::from(index)] - AXIS_CENTER;
+                axes_present & (1 << index) != 0
+                    && if positive {
+                        offset > AXIS_THRESHOLD
+                    } else {
+                        offset < -AXIS_THRESHOLD
+                    }
+            }
+        }
+    }
+}
+
+/// Open device
+struct Device {
+    device: IDirectInputDevice8W,
+    /// Keeps DirectInput loaded while the device is open
+    _input: IDirectInput8W,
+    /// Bit per axis the device has; absent axes read as 0
+    axes_present: u8,
+}
+
+// DirectInput devices are free-threaded
+unsafe impl Send for Device {}
+
+/// The device in use, and the configuration it was opened for
+struct Reader {
+    device: Option<Device>,
+    generation: u32,
+    reported_missing: bool,
+}
+
+static READER: Mutex<Reader> = Mutex::new(Reader {
+    device: None,
+    generation: 0,
+    reported_missing: false,
+});
+
+static SCAN: Periodic = Periodic::new();
+
+/// Whether the coin input was down at the previous check
+static COIN_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Current state of the configured device, `None` if none is configured or it
+/// is not connected
+fn state() -> Option<(JoyState, u8)> {
+    let config = config::get();
+    if config.dinput_device.is_empty() {
+        return None;
+    }
+    let mut reader = READER.lock().ok()?;
+    if reader.generation != config::generation() {
+        reader.generation = config::generation();
+        reader.device = None;
+        reader.reported_missing = false;
+    }
+
+    if reader.device.is_none() {
+        // The cooperative level needs one of the game's windows
+        if !SCAN.ready(SCAN_INTERVAL) || !focus::is_focused() {
+            return None;
+        }
+        match unsafe { open(&config.dinput_device) } {
+            Ok(Some(device)) => reader.device = Some(device),
+            Ok(None) => {
+                if !reader.reported_missing {
+                    warn!(
+                        "DirectInput device \"{}\" not found, will keep looking",
+                        config.dinput_device
+                    );
+                    reader.reported_missing = true;
+                }
+                return None;
+            }
+            Err(e) => {
+                if !reader.reported_missing {
+                    warn!("Cannot open DirectInput device: {}", e);
+                    reader.reported_missing = true;
+                }
+                return None;
+            }
+        }
+    }
+
+    let device = reader.device.as_ref()?;
+    let mut state = JoyState::default();
+    let read = unsafe {
+        let _ = device.device.Poll();
+        device.device.GetDeviceState(
+            mem::size_of::<JoyState>() as u32,
+            &mut state as *mut JoyState as *mut c_void,
+        )
+    };
+    match read {
+        Ok(()) => Some((state, device.axes_present)),
+        // Lost (e.g. unplugged): acquire again on the next read, or reopen
+        Err(_) => {
+            if unsafe { device.device.Acquire() }.is_err() {
+                warn!("DirectInput device lost");
+                reader.device = None;
+                reader.reported_missing = false;
+            }
+            None
+        }
+    }
+}
+
+/// Open the first attached game controller whose product name contains
+/// `name`, or the first one at all for `any`
+unsafe fn open(name: &str) -> windows::core::Result<Option<Device>> {
+    let instance: HINSTANCE = GetModuleHandleW(None)?.into();
+    let mut raw = ptr::null_mut();
+    DirectInput8Create(
+        instance,
+        DIRECTINPUT_VERSION,
+        &IDirectInput8W::IID,
+        &mut raw,
+        None,
+    )?;
+    let input = IDirectInput8W::from_raw(raw);
+
+    let mut search = Search {
+        name: name.to_lowercase(),
+        found: None,
+    };
+    input.EnumDevices(
+        DI8DEVCLASS_GAMECTRL,
+        Some(enum_device),
+        &mut search as *mut Search as *mut c_void,
+        DIEDFL_ATTACHEDONLY,
+    )?;
+    let Some((guid, product)) = search.found else {
+        return Ok(None);
+    };
+
+    let mut device = None;
+    input.CreateDevice(&guid, &mut device, None)?;
+    let device: IDirectInputDevice8W = device.ok_or_else(windows::core::Error::empty)?;
+
+    let mut objects = data_format_objects();
+    let mut format = DIDATAFORMAT {
+        dwSize: mem::size_of::<DIDATAFORMAT>() as u32,
+        dwObjSize: mem::size_of::<DIOBJECTDATAFORMAT>() as u32,
+        dwFlags: DIDF_ABSAXIS,
+        dwDataSize: mem::size_of::<JoyState>() as u32,
+        dwNumObjs: objects.len() as u32,
+        rgodf: objects.as_mut_ptr(),
+    };
+    device.SetDataFormat(&mut format)?;
+    device.SetCooperativeLevel(GetForegroundWindow(), DISCL_BACKGROUND | DISCL_NONEXCLUSIVE)?;
+    device.Acquire()?;
+
+    let axes_present = (0..AXIS_COUNT as u8)
+        .filter(|&axis| {
+            let mut object = DIDEVICEOBJECTINSTANCEW {
+                dwSize: mem::size_of::<DIDEVICEOBJECTINSTANCEW>() as u32,
+                ..Default::default()
+            };
+            device
+                .GetObjectInfo(&mut object, axis_offset(axis), DIPH_BYOFFSET)
+                .is_ok()
+        })
+        .fold(0u8, |present, axis| present | 1 << axis);
+
+    info!(
+        "Using DirectInput device \"{}\" ({} axes)",
+        product,
+        axes_present.count_ones()
+    );
+    Ok(Some(Device {
+        device,
+        _input: input,
+        axes_present,
+    }))
+}
+
+/// Device search passed through `EnumDevices`
+struct Search {
+    name: String,
+    found: Option<(GUID, String)>,
+}
+
+unsafe extern "system" fn enum_device(
+    instance: *mut DIDEVICEINSTANCEW,
+    context: *mut c_void,
+) -> BOOL {
+    let instance = &*instance;
+    let search = &mut *(context as *mut Search);
+    let len = instance
+        .tszProductName
+        .iter()
+        .position(|&unit| unit == 0)
+        .unwrap_or(instance.tszProductName.len());
+    let product = String::from_utf16_lossy(&instance.tszProductName[..len]);
+    if search.name == "any" || product.to_lowercase().contains(&search.name) {
+        search.found = Some((instance.guidInstance, product));
+        BOOL(DIENUM_STOP as i32)
+    } else {
+        BOOL(DIENUM_CONTINUE as i32)
+    }
+}
+
+fn axis_offset(axis: u8) -> u32 {
+    u32::from(axis) * mem::size_of::<i32>() as u32
+}
+
+/// Objects of the `JoyState` data format: the axes in `AXIS_NAMES` order, then
+/// any buttons
+fn data_format_objects() -> Vec<DIOBJECTDATAFORMAT> {
+    static AXIS_GUIDS: [GUID; AXIS_COUNT] = [
+        GUID_XAxis,
+        GUID_YAxis,
+        GUID_ZAxis,
+        GUID_RxAxis,
+        GUID_RyAxis,
+        GUID_RzAxis,
+        GUID_Slider,
+        GUID_Slider,
+    ];
+    let axes = AXIS_GUIDS
+        .iter()
+        .enumerate()
+        .map(|(axis, guid)| DIOBJECTDATAFORMAT {
+            pguid: guid,
+            dwOfs: axis_offset(axis as u8),
+            dwType: DIDFT_AXIS | DIDFT_ANYINSTANCE | DIDFT_OPTIONAL,
+            dwFlags: 0,
+        });
+    let buttons = (0..BUTTON_COUNT).map(|button| DIOBJECTDATAFORMAT {
+        pguid: ptr::null(),
+        dwOfs: (mem::size_of::<[i32; AXIS_COUNT]>() + button) as u32,
+        dwType: DIDFT_BUTTON | DIDFT_ANYINSTANCE | DIDFT_OPTIONAL,
+        dwFlags: 0,
+    });
+    axes.chain(buttons).collect()
+}
+
+/// Targets of the configured map pressed on the device right now
+fn pressed() -> impl Iterator<Item = InputTarget> {
+    let config = config::get();
+    let state = state();
+    config
+        .dinput_map
+        .0
+        .iter()
+        .filter_map(move |&(input, target)| {
+            let (state, axes_present) = state?;
+            state.pressed(input, axes_present).then_some(target)
+        })
+}
+
+/// Operator button bits (TEST, SERVICE) for the device inputs held down
+pub fn opbtn() -> u8 {
+    pressed().fold(0, |opbtn, target| match target {
+        InputTarget::Opbtn(bit @ 0..=1) => opbtn | 1 << bit,
+        _ => opbtn,
+    })
+}
+
+/// Whether the coin input has been pressed since the previous check
+pub fn coin_pressed() -> bool {
+    let down = pressed().any(|target| target == InputTarget::Opbtn(2));
+    !COIN_DOWN.swap(down, Ordering::Relaxed) && down
+}
+
+/// IR beam bits for the device inputs held down
+pub fn beams() -> u8 {
+    pressed().fold(0, |beams, target| match target {
+        InputTarget::Beam(beam) => beams | 1 << beam,
+        _ => beams,
+    })
+}
+
+/// Slider pressure from the device, `None` unless a device is configured
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    if config::get().dinput_device.is_empty() {
+        return None;
+    }
+    let mut pressure = [0; SLIDER_CELLS];
+    for target in pressed() {
+        if let InputTarget::Cell(cell) = target {
+            pressure[usize::from(cell)] = TOUCH_PRESSURE;
+        }
+    }
+    Some(pressure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dinput_map_parses_and_names_entries() {
+        // (text, parsed entries, name)
+        let cases = [
+            ("", Some(vec![]), ""),
+            (
+                "button1=test, X-=cell0,slider2+=beam5",
+                Some(vec![
+                    (DinputInput::Button(0), InputTarget::Opbtn(0)),
+                    (DinputInput::Axis(0, false), InputTarget::Cell(0)),
+                    (DinputInput::Axis(7, true), InputTarget::Beam(5)),
+                ]),
+                "button1=test,x-=cell0,slider2+=beam5",
+            ),
+            (
+                "button32=coin",
+                Some(vec![(DinputInput::Button(31), InputTarget::Opbtn(2))]),
+                "button32=coin",
+            ),
+            ("button0=test", None, ""),
+            ("button33=test", None, ""),
+            ("x=cell0", None, ""),
+            ("w+=cell0", None, ""),
+            ("x+=cell32", None, ""),
+            ("button1", None, ""),
+            // A multi-byte last character is rejected, not split inside
+            ("xé=cell0", None, ""),
+            ("é=cell0", None, ""),
+        ];
+        for (text, entries, name) in cases {
+            let map = DinputMap::parse(text);
+            assert_eq!(map, entries.map(DinputMap), "{:?}", text);
+            if let Some(map) = map {
+                assert_eq!(map.name(), name);
+                assert_eq!(DinputMap::parse(&map.name()), Some(map));
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Game window focus
+//!
+//! Polling can slow down while the game is in the background. The game counts
+//! as focused while the foreground window belongs to this process and is not
+//! minimized.
+//!
+//! Local input sources also place screen positions over the game window here.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use windows::Win32::{
+    Foundation::{HWND, POINT, RECT},
+    Graphics::Gdi::ScreenToClient,
+    System::Threading::GetCurrentProcessId,
+    UI::WindowsAndMessaging::{
+        GetClientRect, GetForegroundWindow, GetWindowThreadProcessId, IsIconic,
+    },
+};
+
+use crate::timing::Periodic;
+
+/// How often the foreground window is checked
+const FOCUS_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+static FOCUSED: AtomicBool = AtomicBool::new(true);
+
+static FOCUS_CHECK: Periodic = Periodic::new();
+
+/// Whether the game window is focused, checked at most once per
+/// `FOCUS_CHECK_INTERVAL`
+pub fn is_focused() -> bool {
+    if FOCUS_CHECK.ready(FOCUS_CHECK_INTERVAL) {
+        FOCUSED.store(query_focused(), Ordering::Relaxed);
+    }
+    FOCUSED.load(Ordering::Relaxed)
+}
+
+fn query_focused() -> bool {
+    unsafe {
+        let window = GetForegroundWindow();
+        // No foreground window at all (e.g. during a desktop switch): assume
+        // focused rather than slowing the game down by mistake
+        if window.is_invalid() {
+            return true;
+        }
+        let mut process_id = 0;
+        GetWindowThreadProcessId(window, Some(&mut process_id));
+        process_id == GetCurrentProcessId() && !IsIconic(window).as_bool()
+    }
+}
+
+/// Position of the screen point `point` over `window`'s client area, as
+/// fractions of its width and height from the top left; `None` outside it
+pub fn client_position(window: HWND, mut point: POINT) -> Option<(f32, f32)> {
+    let mut client = RECT::default();
+    unsafe {
+        GetClientRect(window, &mut client).ok()?;
+        if !ScreenToClient(window, &mut point).as_bool() {
+            return None;
+        }
+    }
+    let (width, height) = (client.right - client.left, client.bottom - client.top);
+    let inside = (0..width).contains(&point.x) && (0..height).contains(&point.y);
+    inside.then(|| {
+        (
+            point.x as f32 / width as f32,
+            point.y as f32 / height as f32,
+        )
+    })
+}
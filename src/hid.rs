@@ -0,0 +1,539 @@
+//! Raw HID devices
+//!
+//! Controllers plugged into the game PC that report their state in HID input
+//! reports can be read directly, found by vendor and product ID. A reader
+//! thread keeps the device open and holds on to its latest report; the sources
+//! built on it decode the reports.
+//!
+//! Besides the controllers with their own module, any device can be used by
+//! configuring its ID and a mapping from report bytes and bits to operator
+//! buttons, IR beams and slider cells, so DIY controllers need no new code.
+//! Report offsets count from the report ID byte, which is 0 for devices
+//! without numbered reports.
+
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    mem,
+    os::windows::{ffi::OsStringExt, io::AsRawHandle},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{error, info, warn};
+
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Devices::{
+            DeviceAndDriverInstallation::{
+                SetupDiDestroyDeviceInfoList, SetupDiEnumDeviceInterfaces, SetupDiGetClassDevsW,
+                SetupDiGetDeviceInterfaceDetailW, DIGCF_DEVICEINTERFACE, DIGCF_PRESENT, HDEVINFO,
+                SP_DEVICE_INTERFACE_DATA, SP_DEVICE_INTERFACE_DETAIL_DATA_W,
+            },
+            HumanInterfaceDevice::{
+                HidD_FreePreparsedData, HidD_GetAttributes, HidD_GetHidGuid, HidD_GetPreparsedData,
+                HidP_GetCaps, HIDD_ATTRIBUTES, HIDP_CAPS, HIDP_STATUS_SUCCESS,
+                PHIDP_PREPARSED_DATA,
+            },
+        },
+        Foundation::{HANDLE, HWND},
+    },
+};
+
+use crate::{
+    config,
+    input::{InputTarget, SLIDER_CELLS, TOUCH_PRESSURE},
+    threading::{self, PendingRead},
+};
+
+/// How long a device's last report counts without a new one
+const INPUT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Wait between searches for the device while it is not connected
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long `HidReader::stop` waits for the reader thread to exit
+const STOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// USB vendor and product ID of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl DeviceId {
+    /// Parse `<vendor>:<product>` in hex, e.g. `1ccf:2333`
+    pub fn parse(value: &str) -> Option<Self> {
+        let (vendor, product) = value.trim().split_once(':')?;
+        Some(Self {
+            vendor_id: u16::from_str_radix(vendor.trim(), 16).ok()?,
+            product_id: u16::from_str_radix(product.trim(), 16).ok()?,
+        })
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor_id, self.product_id)
+    }
+}
+
+/// A byte of a report, or one bit of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HidInput {
+    /// Byte at an offset: its value is a cell's pressure, and non-zero
+    /// presses any other target
+    Byte(u16),
+    /// Bit (0-7, lowest first) of the byte at an offset
+    Bit(u16, u8),
+}
+
+/// Report bytes and bits and what they press
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HidMap(Vec<(HidInput, InputTarget)>);
+
+impl HidInput {
+    /// Parse `<offset>` or `<offset>.<bit>`
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().split_once('.') {
+            Some((offset, bit)) => Some(Self::Bit(
+                offset.trim().parse().ok()?,
+                bit.trim().parse().ok().filter(|&bit| bit < 8)?,
+            )),
+            None => value.trim().parse().ok().map(Self::Byte),
+        }
+    }
+
+    fn offset(self) -> usize {
+        match self {
+            Self::Byte(offset) | Self::Bit(offset, _) => usize::from(offset),
+        }
+    }
+
+    /// Level in `report`: the byte's value, or a touch's pressure for a set bit
+    fn level(self, report: &[u8]) -> u8 {
+        let byte = report.get(self.offset()).copied().unwrap_or(0);
+        match self {
+            Self::Byte(_) => byte,
+            Self::Bit(_, bit) if byte & 1 << bit != 0 => TOUCH_PRESSURE,
+            Self::Bit(..) => 0,
+        }
+    }
+}
+
+impl fmt::Display for HidInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Byte(offset) => write!(f, "{}", offset),
+            Self::Bit(offset, bit) => write!(f, "{}.{}", offset, bit),
+        }
+    }
+}
+
+impl HidMap {
+    /// Parse comma-separated `<input>=<target>` entries, e.g. `3.0=test`, or
+    /// `<first>-<last>=cell<n>` for a run of bytes feeding cells from `n` on;
+    /// empty for none
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut entries = Vec::new();
+        for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (input, target) = entry.split_once('=')?;
+            let target = InputTarget::parse(target)?;
+            let Some((first, last)) = input.split_once('-') else {
+                entries.push((HidInput::parse(input)?, target));
+                continue;
+            };
+            let (first, last) = (
+                first.trim().parse::<u16>().ok()?,
+                last.trim().parse::<u16>().ok()?,
+            );
+            let InputTarget::Cell(cell) = target else {
+                return None;
+            };
+            if last < first || usize::from(cell) + usize::from(last - first) >= SLIDER_CELLS {
+                return None;
+            }
+            entries.extend(
+                (first..=last)
+                    .zip(cell..)
+                    .map(|(offset, cell)| (HidInput::Byte(offset), InputTarget::Cell(cell))),
+            );
+        }
+        Some(Self(entries))
+    }
+
+    /// Map as accepted by `parse`, with runs of bytes listed one by one
+    pub fn name(&self) -> String {
+        self.0
+            .iter()
+            .map(|(input, target)| format!("{}={}", input, target))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Shortest report holding every mapped byte
+    fn report_len(&self) -> usize {
+        self.0
+            .iter()
+            .map(|(input, _)| input.offset() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Reader thread for one device and the latest report it read
+pub struct HidReader {
+    /// Device name for the log
+    name: &'static str,
+    thread: &'static str,
+    running: AtomicBool,
+    handle: Mutex<Option<JoinHandle<()>>>,
+    /// The open device, so `stop` can cancel a read in progress
+    device: PendingRead,
+    report: Mutex<Option<(Vec<u8>, Instant)>>,
+}
+
+impl HidReader {
+    pub const fn new(name: &'static str, thread: &'static str) -> Self {
+        Self {
+            name,
+            thread,
+            running: AtomicBool::new(false),
+            handle: Mutex::new(None),
+            device: PendingRead::new(),
+            report: Mutex::new(None),
+        }
+    }
+
+    /// Start reading the device with `id` whose input reports are at least
+    /// `min_report_len` bytes, unless the reader is already running
+    pub fn start(&'static self, id: DeviceId, min_report_len: usize) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        match thread::Builder::new()
+            .name(self.thread.into())
+            .spawn(move || self.run(id, min_report_len))
+        {
+            Ok(handle) => {
+                if let Ok(mut slot) = self.handle.lock() {
+                    *slot = Some(handle);
+                }
+            }
+            Err(e) => {
+                error!("Failed to spawn {} reader thread: {:?}", self.name, e);
+                self.running.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Stop the reader thread, cancelling the read it is blocked in, and
+    /// wait up to `STOP_TIMEOUT` for it to exit
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        let Some(handle) = self.handle.lock().ok().and_then(|mut slot| slot.take()) else {
+            return;
+        };
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        if !threading::wait_for_exit(&handle, deadline, || self.device.cancel()) {
+            warn!(
+                "{} reader thread did not stop within {:?}, detaching it",
+                self.name, STOP_TIMEOUT
+            );
+        }
+    }
+
+    /// Apply `decode` to the latest report, `None` while the device is not
+    /// reporting
+    pub fn with_report<T>(&self, decode: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        let report = self.report.lock().ok()?;
+        let (report, received) = report.as_ref()?;
+        (received.elapsed() < INPUT_TIMEOUT).then(|| decode(report))
+    }
+
+    /// Reader thread body: keep the device open and read reports until stopped
+    fn run(&self, id: DeviceId, min_report_len: usize) {
+        let mut reported_missing = false;
+        while self.running.load(Ordering::SeqCst) {
+            match unsafe { open(id, min_report_len) } {
+                Ok(Some((file, report_len))) => {
+                    info!("Reading {} ({}) over raw HID", self.name, id);
+                    reported_missing = false;
+                    match self.read_reports(file, report_len) {
+                        // Cancelled by `stop`
+                        Err(_) if !self.running.load(Ordering::SeqCst) => {}
+                        Err(e) => warn!("{} disconnected: {}", self.name, e),
+                        Ok(()) => {}
+                    }
+                }
+                Ok(None) => {
+                    if !reported_missing {
+                        warn!("{} ({}) not found, will keep looking", self.name, id);
+                        reported_missing = true;
+                    }
+                }
+                Err(e) => {
+                    if !reported_missing {
+                        warn!("Cannot list HID devices: {}", e);
+                        reported_missing = true;
+                    }
+                }
+            }
+            threading::sleep_while(&self.running, SCAN_INTERVAL);
+        }
+    }
+
+    fn read_reports(&self, mut file: File, report_len: usize) -> io::Result<()> {
+        let _reading = self.device.register(file.as_raw_handle());
+        let mut buffer = vec![0u8; report_len];
+        while self.running.load(Ordering::SeqCst) {
+            let len = file.read(&mut buffer)?;
+            if let Ok(mut report) = self.report.lock() {
+                *report = Some((buffer[..len].to_vec(), Instant::now()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Open the first HID collection of the device with `id` that can be read and
+/// has input reports of at least `min_report_len` bytes, returning it with its
+/// report length (including the report ID byte)
+unsafe fn open(id: DeviceId, min_report_len: usize) -> io::Result<Option<(File, usize)>> {
+    let guid = HidD_GetHidGuid();
+    let devices = SetupDiGetClassDevsW(
+        Some(&guid),
+        PCWSTR::null(),
+        HWND::default(),
+        DIGCF_PRESENT | DIGCF_DEVICEINTERFACE,
+    )?;
+    let mut found = None;
+    for index in 0.. {
+        let mut interface = SP_DEVICE_INTERFACE_DATA {
+            cbSize: mem::size_of::<SP_DEVICE_INTERFACE_DATA>() as u32,
+            ..Default::default()
+        };
+        if SetupDiEnumDeviceInterfaces(devices, None, &guid, index, &mut interface).is_err() {
+            break;
+        }
+        found = interface_path(devices, &interface)
+            .and_then(|path| open_collection(path, id, min_report_len));
+        if found.is_some() {
+            break;
+        }
+    }
+    let _ = SetupDiDestroyDeviceInfoList(devices);
+    Ok(found)
+}
+
+/// Device path of a HID interface
+unsafe fn interface_path(
+    devices: HDEVINFO,
+    interface: &SP_DEVICE_INTERFACE_DATA,
+) -> Option<OsString> {
+    let mut size = 0;
+    let _ = SetupDiGetDeviceInterfaceDetailW(devices, interface, None, 0, Some(&mut size), None);
+    if size == 0 {
+        return None;
+    }
+    // The detail is the size field followed by the path
+    let mut buffer = vec![0u32; (size as usize).div_ceil(mem::size_of::<u32>())];
+    let detail = buffer.as_mut_ptr() as *mut SP_DEVICE_INTERFACE_DETAIL_DATA_W;
+    (*detail).cbSize = mem::size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_W>() as u32;
+    SetupDiGetDeviceInterfaceDetailW(devices, interface, Some(detail), size, None, None).ok()?;
+    let path = std::slice::from_raw_parts(
+        ptr::addr_of!((*detail).DevicePath) as *const u16,
+        (size as usize - mem::size_of::<u32>()) / mem::size_of::<u16>(),
+    );
+    let len = path
+        .iter()
+        .position(|&unit| unit == 0)
+        .unwrap_or(path.len());
+    Some(OsString::from_wide(&path[..len]))
+}
+
+/// Open the collection at `path` if it belongs to the device with `id` and its
+/// input reports are long enough
+unsafe fn open_collection(
+    path: OsString,
+    id: DeviceId,
+    min_report_len: usize,
+) -> Option<(File, usize)> {
+    // Collections Windows keeps for itself (keyboards, mice) fail to open
+    let file = OpenOptions::new().read(true).open(path).ok()?;
+    let handle = HANDLE(file.as_raw_handle());
+
+    let mut attributes = HIDD_ATTRIBUTES {
+        Size: mem::size_of::<HIDD_ATTRIBUTES>() as u32,
+        ..Default::default()
+    };
+    if !HidD_GetAttributes(handle, &mut attributes).as_bool()
+        || attributes.VendorID != id.vendor_id
+        || attributes.ProductID != id.product_id
+    {
+        return None;
+    }
+
+    let mut preparsed = PHIDP_PREPARSED_DATA::default();
+    if !HidD_GetPreparsedData(handle, &mut preparsed).as_bool() {
+        return None;
+    }
+    let mut caps = HIDP_CAPS::default();
+    let status = HidP_GetCaps(preparsed, &mut caps);
+    HidD_FreePreparsedData(preparsed);
+    let report_len = usize::from(caps.InputReportByteLength);
+    (status == HIDP_STATUS_SUCCESS && report_len >= min_report_len).then_some((file, report_len))
+}
+
+static READER: HidReader = HidReader::new("HID controller", "chuniio-hid");
+
+/// Whether the coin input was down at the previous check
+static COIN_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Start reading the configured HID controller, if any
+pub fn start() {
+    let config = config::get();
+    if let Some(id) = config.hid_device {
+        READER.start(id, config.hid_map.report_len());
+    }
+}
+
+pub fn stop() {
+    READER.stop();
+}
+
+/// Targets of the configured map with their non-zero levels in the latest
+/// report
+fn pressed() -> Vec<(InputTarget, u8)> {
+    let config = config::get();
+    READER
+        .with_report(|report| {
+            config
+                .hid_map
+                .0
+                .iter()
+                .map(|&(input, target)| (target, input.level(report)))
+                .filter(|&(_, level)| level != 0)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Operator button bits (TEST, SERVICE) for the mapped inputs held down
+pub fn opbtn() -> u8 {
+    pressed()
+        .into_iter()
+        .fold(0, |opbtn, (target, _)| match target {
+            InputTarget::Opbtn(bit @ 0..=1) => opbtn | 1 << bit,
+            _ => opbtn,
+        })
+}
+
+/// Whether the coin input has been pressed since the previous check
+pub fn coin_pressed() -> bool {
+    let down = pressed()
+        .iter()
+        .any(|&(target, _)| target == InputTarget::Opbtn(2));
+    !COIN_DOWN.swap(down, Ordering::Relaxed) && down
+}
+
+/// IR beam bits for the mapped inputs held down
+pub fn beams() -> u8 {
+    pressed()
+        .into_iter()
+        .fold(0, |beams, (target, _)| match target {
+            InputTarget::Beam(beam) => beams | 1 << beam,
+            _ => beams,
+        })
+}
+
+/// Slider pressure from the HID controller, `None` while none is configured
+/// or it is not reporting
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    let config = config::get();
+    config.hid_device?;
+    READER.with_report(|report| {
+        let mut pressure = [0; SLIDER_CELLS];
+        for &(input, target) in &config.hid_map.0 {
+            if let InputTarget::Cell(cell) = target {
+                let cell = &mut pressure[usize::from(cell)];
+                *cell = (*cell).max(input.level(report));
+            }
+        }
+        pressure
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_device_ids() {
+        let id = DeviceId {
+            vendor_id: 0x1ccf,
+            product_id: 0x2333,
+        };
+        let cases = [
+            ("1ccf:2333", Some(id)),
+            (" 1CCF : 2333 ", Some(id)),
+            ("1ccf", None),
+            ("1ccf:12345", None),
+            ("1ccg:2333", None),
+            ("", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(DeviceId::parse(value), expected, "{:?}", value);
+        }
+        assert_eq!(id.to_string(), "1ccf:2333");
+    }
+
+    #[test]
+    fn parses_hid_maps_and_expands_ranges() {
+        let cases = [
+            ("", Some("")),
+            (
+                "3.0=test, 3.1 = service,4=beam0",
+                Some("3.0=test,3.1=service,4=beam0"),
+            ),
+            ("4-7=cell28", Some("4=cell28,5=cell29,6=cell30,7=cell31")),
+            ("4-4=cell0", Some("4=cell0")),
+            ("1=coin,4-5=cell2", Some("1=coin,4=cell2,5=cell3")),
+            // Runs past the last cell
+            ("4-8=cell28", None),
+            // Runs feed cells only
+            ("4-5=test", None),
+            ("4-5=beam0", None),
+            ("5-4=cell0", None),
+            ("4.0-7=cell0", None),
+            ("3.8=test", None),
+            ("3=cell32", None),
+            ("3", None),
+            ("3=test,", Some("3=test")),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                HidMap::parse(value).map(|map| map.name()),
+                expected.map(str::to_string),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn report_covers_every_mapped_byte() {
+        assert_eq!(HidMap::default().report_len(), 0);
+        let map = HidMap::parse("3.7=test,4-7=cell0,1=beam0").unwrap();
+        assert_eq!(map.report_len(), 8);
+    }
+}
@@ -0,0 +1,641 @@
+//! Input mapping
+//!
+//! Corrections applied to the state reported by the proxy before the game sees
+//! it, for controllers whose conventions differ from the game's.
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config;
+
+/// Number of slider pressure cells
+pub const SLIDER_CELLS: usize = 32;
+
+/// Number of slider columns, each a top and a bottom cell
+pub const SLIDER_COLUMNS: usize = SLIDER_CELLS / 2;
+
+/// Pressure of a touch from a local source that can't sense pressure, as
+/// segatools reports for keys
+pub const TOUCH_PRESSURE: u8 = 128;
+
+/// Slider cell order: output cell `i` takes the pressure of input cell `map[i]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SliderMap([u8; SLIDER_CELLS]);
+
+impl SliderMap {
+    /// Cells passed through unchanged
+    pub const IDENTITY: Self = {
+        let mut map = [0u8; SLIDER_CELLS];
+        let mut cell = 0;
+        while cell < SLIDER_CELLS {
+            map[cell] = cell as u8;
+            cell += 1;
+        }
+        Self(map)
+    };
+
+    /// Parse one comma-separated input cell index (0-31) per output cell
+    pub fn parse(value: &str) -> Option<Self> {
+        let cells = value
+            .split(',')
+            .map(|cell| {
+                cell.trim()
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|&cell| (cell as usize) < SLIDER_CELLS)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        cells.try_into().ok().map(Self)
+    }
+
+    /// Map as accepted by `parse`
+    pub fn name(&self) -> String {
+        self.0.map(|cell| cell.to_string()).join(",")
+    }
+
+    /// The same map with the output cells in reverse order
+    fn reversed(mut self) -> Self {
+        self.0.reverse();
+        self
+    }
+
+    fn apply(&self, pressure: &[u8; SLIDER_CELLS]) -> [u8; SLIDER_CELLS] {
+        self.0.map(|cell| pressure[cell as usize])
+    }
+}
+
+/// What `chuni_io_jvs_poll` reports when the IO thread is updating the state
+/// at the same moment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JvsContention {
+    /// The state as of the previous update
+    #[default]
+    Last,
+    /// No buttons pressed and no beams blocked, momentarily releasing held
+    /// inputs (the original behaviour)
+    Zero,
+}
+
+impl JvsContention {
+    /// Parse a behaviour name (`last`, `zero`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "last" => Some(Self::Last),
+            "zero" => Some(Self::Zero),
+            _ => None,
+        }
+    }
+
+    /// Name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Last => "last",
+            Self::Zero => "zero",
+        }
+    }
+}
+
+/// Fixes for damaged slider sensors: cells forced to zero and cells copying
+/// another cell's pressure, by the proxy's cell numbering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellMask {
+    /// Cell each cell takes its pressure from
+    sources: [u8; SLIDER_CELLS],
+    /// Bit `i` set forces cell `i` to zero
+    zeroed: u32,
+}
+
+impl CellMask {
+    /// Every cell left alone
+    pub const NONE: Self = Self {
+        sources: SliderMap::IDENTITY.0,
+        zeroed: 0,
+    };
+
+    /// Parse comma-separated entries, each a cell (0-31) to force to zero or
+    /// `<cell>=<other>` to copy another cell; empty for no fixes
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut mask = Self::NONE;
+        let cell_index = |cell: &str| {
+            cell.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|&cell| (cell as usize) < SLIDER_CELLS)
+        };
+        for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+            match entry.split_once('=') {
+                Some((cell, source)) => {
+                    mask.sources[cell_index(cell)? as usize] = cell_index(source)?
+                }
+                None => mask.zeroed |= 1 << cell_index(entry)?,
+            }
+        }
+        Some(mask)
+    }
+
+    /// Mask as accepted by `parse`
+    pub fn name(&self) -> String {
+        (0..SLIDER_CELLS)
+            .filter_map(|cell| {
+                if self.zeroed & (1 << cell) != 0 {
+                    Some(cell.to_string())
+                } else if self.sources[cell] as usize != cell {
+                    Some(format!("{}={}", cell, self.sources[cell]))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn apply(&self, pressure: &[u8; SLIDER_CELLS]) -> [u8; SLIDER_CELLS] {
+        let mut masked = [0; SLIDER_CELLS];
+        for (cell, level) in masked.iter_mut().enumerate() {
+            if self.zeroed & (1 << cell) == 0 {
+                *level = pressure[self.sources[cell] as usize];
+            }
+        }
+        masked
+    }
+}
+
+/// Operator buttons in `opbtn` bit order (TEST, SERVICE, COIN)
+pub const OPBTN_COUNT: usize = 3;
+
+/// IR beams in `beams`, one bit each
+pub const BEAM_COUNT: usize = 6;
+
+/// What an input of a mapped local source (a DirectInput or HID device)
+/// presses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputTarget {
+    /// Operator button bit (TEST, SERVICE, COIN)
+    Opbtn(u8),
+    /// IR beam (0-5, lowest first)
+    Beam(u8),
+    /// Slider cell (0-31)
+    Cell(u8),
+}
+
+impl InputTarget {
+    /// Parse `test`, `service`, `coin`, `beam0`-`beam5` or `cell0`-`cell31`
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        let index = |prefix: &str, count: usize| {
+            value
+                .strip_prefix(prefix)?
+                .parse::<u8>()
+                .ok()
+                .filter(|&index| usize::from(index) < count)
+        };
+        match value.as_str() {
+            "test" => Some(Self::Opbtn(0)),
+            "service" => Some(Self::Opbtn(1)),
+            "coin" => Some(Self::Opbtn(2)),
+            _ => index("beam", BEAM_COUNT)
+                .map(Self::Beam)
+                .or_else(|| index("cell", SLIDER_CELLS).map(Self::Cell)),
+        }
+    }
+}
+
+impl fmt::Display for InputTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Opbtn(0) => write!(f, "test"),
+            Self::Opbtn(1) => write!(f, "service"),
+            Self::Opbtn(_) => write!(f, "coin"),
+            Self::Beam(beam) => write!(f, "beam{}", beam),
+            Self::Cell(cell) => write!(f, "cell{}", cell),
+        }
+    }
+}
+
+/// Bit order of a set of inputs: output bit `i` is set when any of the input
+/// bits in `map[i]` is; bits above `N` pass through unchanged
+///
+/// Several outputs can share an input (one sensor feeding every beam) and one
+/// output can combine several inputs (six beams collapsed into two zones).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitMap<const N: usize>([u8; N]);
+
+impl<const N: usize> BitMap<N> {
+    /// Bits passed through unchanged
+    pub const IDENTITY: Self = {
+        let mut map = [0u8; N];
+        let mut bit = 0;
+        while bit < N {
+            map[bit] = 1 << bit;
+            bit += 1;
+        }
+        Self(map)
+    };
+
+    /// Parse one comma-separated entry per output bit, each an input bit index
+    /// (0-7), several joined with `+` (any of them), or `-` (always clear)
+    pub fn parse(value: &str) -> Option<Self> {
+        let masks = value
+            .split(',')
+            .map(|entry| match entry.trim() {
+                "-" => Some(0),
+                entry => entry.split('+').try_fold(0u8, |mask, bit| {
+                    let bit = bit.trim().parse::<u8>().ok().filter(|&bit| bit < 8)?;
+                    Some(mask | 1 << bit)
+                }),
+            })
+            .collect::<Option<Vec<_>>>()?;
+        masks.try_into().ok().map(Self)
+    }
+
+    /// Map as accepted by `parse`
+    pub fn name(&self) -> String {
+        self.0
+            .map(|mask| match mask {
+                0 => "-".to_string(),
+                mask => (0..8)
+                    .filter(|bit| mask & (1 << bit) != 0)
+                    .map(|bit| bit.to_string())
+                    .collect::<Vec<_>>()
+                    .join("+"),
+            })
+            .join(",")
+    }
+
+    fn apply(&self, bits: u8) -> u8 {
+        if *self == Self::IDENTITY {
+            return bits;
+        }
+        let mapped_mask = ((1u16 << N) - 1) as u8;
+        self.0
+            .iter()
+            .enumerate()
+            .fold(bits & !mapped_mask, |out, (bit, &mask)| {
+                out | (u8::from(bits & mask != 0) << bit)
+            })
+    }
+}
+
+/// Apply the configured button bit order to `opbtn` from the proxy
+pub fn map_opbtn(opbtn: u8) -> u8 {
+    config::get().opbtn_map.apply(opbtn)
+}
+
+/// Beam bits as last reported to the game, with when each last changed
+struct BeamHold {
+    beams: u8,
+    /// Beam bits as the proxy last reported them, which `beams` follows once
+    /// each beam's hold has run out
+    latest: u8,
+    changed_at: [Option<Instant>; BEAM_COUNT],
+}
+
+impl BeamHold {
+    /// Move every beam that has been held for `min_hold` by `now` to its
+    /// latest state, returning the beams to report
+    fn update(&mut self, now: Instant, min_hold: Duration) -> u8 {
+        for beam in 0..BEAM_COUNT {
+            let bit = 1 << beam;
+            let held = self.changed_at[beam].is_some_and(|at| now - at < min_hold);
+            if (self.latest ^ self.beams) & bit != 0 && !held {
+                self.beams ^= bit;
+                self.changed_at[beam] = Some(now);
+            }
+        }
+        self.beams
+    }
+}
+
+static BEAM_HOLD: Mutex<BeamHold> = Mutex::new(BeamHold {
+    beams: 0,
+    latest: 0,
+    changed_at: [None; BEAM_COUNT],
+});
+
+/// Bits of the beam byte that are beams
+const BEAM_MASK: u8 = (1 << BEAM_COUNT) - 1;
+
+/// Apply the configured polarity, bit order and minimum hold time to `beams`
+/// from the proxy
+pub fn map_beams(beams: u8) -> u8 {
+    let config = config::get();
+    let beams = if config.beam_invert {
+        beams ^ BEAM_MASK
+    } else {
+        beams
+    };
+    let beams = config.beam_map.apply(beams);
+    if config.beam_min_hold.is_zero() {
+        return beams;
+    }
+
+    // Keep every beam in its last state for at least `beam_min_hold`, so that a
+    // crossing seen by a single poll lasts long enough for the game to read it
+    let Ok(mut hold) = BEAM_HOLD.lock() else {
+        return beams;
+    };
+    hold.latest = beams & BEAM_MASK;
+    (beams & !BEAM_MASK) | hold.update(Instant::now(), config.beam_min_hold)
+}
+
+/// Catch `beams` as last mapped up with beam changes whose hold has run out
+/// since, for the game's read; the proxy may not report again for a while,
+/// as in push mode, where it only sends changes
+pub fn expire_beam_hold(beams: u8) -> u8 {
+    let min_hold = config::get().beam_min_hold;
+    if min_hold.is_zero() {
+        return beams;
+    }
+    // The game thread never waits on the IO thread
+    let Ok(mut hold) = BEAM_HOLD.try_lock() else {
+        return beams;
+    };
+    (beams & !BEAM_MASK) | hold.update(Instant::now(), min_hold)
+}
+
+/// Apply the configured dead-cell fixes, cell order, threshold and pressure
+/// curve to slider pressure from the proxy
+pub fn map_pressure(pressure: [u8; SLIDER_CELLS]) -> [u8; SLIDER_CELLS] {
+    let config = config::get();
+    let pressure = if config.slider_mask == CellMask::NONE {
+        pressure
+    } else {
+        config.slider_mask.apply(&pressure)
+    };
+    let map = if config.slider_reverse {
+        config.slider_map.reversed()
+    } else {
+        config.slider_map
+    };
+    let mut pressure = if map == SliderMap::IDENTITY {
+        pressure
+    } else {
+        map.apply(&pressure)
+    };
+
+    if config.slider_threshold > 0 || config.slider_pressure.is_some() || config.slider_gamma != 1.0
+    {
+        for cell in &mut pressure {
+            *cell = map_level(*cell, config);
+        }
+    }
+    pressure
+}
+
+/// Pressure of one cell after the threshold and the fixed value or curve
+fn map_level(level: u8, config: &config::Config) -> u8 {
+    if level == 0 || level < config.slider_threshold {
+        return 0;
+    }
+    match config.slider_pressure {
+        Some(fixed) => fixed,
+        None => ((f32::from(level) / 255.0).powf(config.slider_gamma) * 255.0)
+            .round()
+            .max(1.0) as u8,
+    }
+}
+
+/// Most columns a touch from a local source can cover
+pub const MAX_TOUCH_WIDTH: u8 = SLIDER_COLUMNS as u8;
+
+/// Pressure for a touch `width` columns wide centered at `position`, a
+/// fraction of the slider's width from its left end
+pub fn touch_band(position: f32, width: u8) -> [u8; SLIDER_CELLS] {
+    // Column 0 is at the right end
+    let width = usize::from(width.clamp(1, MAX_TOUCH_WIDTH));
+    let column = SLIDER_COLUMNS
+        - 1
+        - ((position.clamp(0.0, 1.0) * SLIDER_COLUMNS as f32) as usize).min(SLIDER_COLUMNS - 1);
+    let first = column.saturating_sub((width - 1) / 2);
+    let last = (column + width / 2).min(SLIDER_COLUMNS - 1);
+
+    let mut pressure = [0; SLIDER_CELLS];
+    pressure[first * 2..(last + 1) * 2].fill(TOUCH_PRESSURE);
+    pressure
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Pressure with `levels` in the first cells and the rest released
+    fn pressure(levels: &[u8]) -> [u8; SLIDER_CELLS] {
+        let mut pressure = [0; SLIDER_CELLS];
+        pressure[..levels.len()].copy_from_slice(levels);
+        pressure
+    }
+
+    #[test]
+    fn slider_map_parses_and_reorders_cells() {
+        let reversed = (0..SLIDER_CELLS)
+            .rev()
+            .map(|cell| cell.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(
+            SliderMap::parse(&SliderMap::IDENTITY.name()),
+            Some(SliderMap::IDENTITY)
+        );
+        assert_eq!(
+            SliderMap::parse(&reversed),
+            Some(SliderMap::IDENTITY.reversed())
+        );
+
+        let too_short = reversed.rsplit_once(',').unwrap().0;
+        for invalid in [
+            "",
+            too_short,
+            &format!("{},0", reversed),
+            &reversed.replace("31", "32"),
+        ] {
+            assert_eq!(SliderMap::parse(invalid), None, "{:?}", invalid);
+        }
+
+        let reordered = SliderMap::IDENTITY.reversed().apply(&pressure(&[10, 20]));
+        assert_eq!((reordered[31], reordered[30], reordered[0]), (10, 20, 0));
+    }
+
+    #[test]
+    fn cell_mask_zeroes_and_copies_cells() {
+        let cases = [
+            ("", Some(CellMask::NONE)),
+            (" , ", Some(CellMask::NONE)),
+            (
+                "3",
+                Some(CellMask {
+                    zeroed: 1 << 3,
+                    ..CellMask::NONE
+                }),
+            ),
+            ("31,5=4", {
+                let mut mask = CellMask {
+                    zeroed: 1 << 31,
+                    ..CellMask::NONE
+                };
+                mask.sources[5] = 4;
+                Some(mask)
+            }),
+            ("32", None),
+            ("5=32", None),
+            ("5=", None),
+            ("dead", None),
+        ];
+        for (text, mask) in cases {
+            assert_eq!(CellMask::parse(text), mask, "{:?}", text);
+        }
+
+        let mask = CellMask::parse("0, 2=1").unwrap();
+        assert_eq!(mask.name(), "0,2=1");
+        assert_eq!(CellMask::parse(&mask.name()), Some(mask));
+        assert_eq!(
+            mask.apply(&pressure(&[255, 60, 0, 40])),
+            pressure(&[0, 60, 60, 40])
+        );
+    }
+
+    #[test]
+    fn bit_map_reorders_and_combines_bits() {
+        // (map, input bits, output bits)
+        let cases = [
+            ("0,1,2,3,4,5", 0b010_101, 0b010_101),
+            // Reported in the opposite order
+            ("5,4,3,2,1,0", 0b000_001, 0b100_000),
+            ("5,4,3,2,1,0", 0b110_000, 0b000_011),
+            // Six beams collapsed into two zones
+            ("0+1+2,0+1+2,0+1+2,3+4+5,3+4+5,3+4+5", 0b000_010, 0b000_111),
+            ("0+1+2,0+1+2,0+1+2,3+4+5,3+4+5,3+4+5", 0b100_000, 0b111_000),
+            // One sensor spread across every beam
+            ("0,0,0,0,0,0", 0b000_001, 0b111_111),
+            ("0,0,0,0,0,0", 0b111_110, 0b000_000),
+            // Bits past the map pass through
+            ("-,1,2,3,4,5", 0b1100_0001, 0b1100_0000),
+        ];
+        for (text, bits, mapped) in cases {
+            let map = BitMap::<BEAM_COUNT>::parse(text).unwrap();
+            assert_eq!(map.name(), text);
+            assert_eq!(map.apply(bits), mapped, "{} {:#010b}", text, bits);
+        }
+
+        let swapped = BitMap::<OPBTN_COUNT>::parse("1, 0, 2").unwrap();
+        assert_eq!(swapped.apply(0b001), 0b010);
+        assert_eq!(swapped.apply(0b100), 0b100);
+
+        for invalid in [
+            "",
+            "0,1,2,3,4",
+            "0,1,2,3,4,5,6",
+            "0,1,2,3,4,8",
+            "0,1,2,3,4,+",
+        ] {
+            assert_eq!(BitMap::<BEAM_COUNT>::parse(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn map_level_applies_threshold_then_fixed_pressure_or_curve() {
+        // (threshold, fixed pressure, gamma, level, mapped)
+        let cases = [
+            (0, None, 1.0, 0, 0),
+            (0, None, 1.0, 200, 200),
+            (10, None, 1.0, 9, 0),
+            (10, None, 1.0, 10, 10),
+            (0, Some(128), 1.0, 1, 128),
+            (0, Some(128), 1.0, 0, 0),
+            (10, Some(128), 1.0, 9, 0),
+            (0, None, 2.0, 255, 255),
+            (0, None, 2.0, 128, 64),
+            // A light touch stays a touch
+            (0, None, 2.0, 1, 1),
+            (0, None, 0.5, 64, 128),
+        ];
+        for (threshold, fixed, gamma, level, mapped) in cases {
+            let config = Config {
+                slider_threshold: threshold,
+                slider_pressure: fixed,
+                slider_gamma: gamma,
+                ..Config::default()
+            };
+            assert_eq!(
+                map_level(level, &config),
+                mapped,
+                "threshold {} pressure {:?} gamma {} level {}",
+                threshold,
+                fixed,
+                gamma,
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn beams_are_inverted_before_they_are_reordered() {
+        let _globals = crate::lock_globals();
+        // (invert, map, proxy beams, mapped beams)
+        let cases = [
+            (false, "0,1,2,3,4,5", 0b000_001, 0b000_001),
+            (true, "0,1,2,3,4,5", 0b111_110, 0b000_001),
+            // Only the beam bits are inverted
+            (true, "0,1,2,3,4,5", 0b1111_1111, 0b1100_0000),
+            (true, "5,4,3,2,1,0", 0b111_110, 0b100_000),
+            (false, "5,4,3,2,1,0", 0b111_110, 0b011_111),
+        ];
+        for (invert, map, beams, mapped) in cases {
+            config::set_for_test(Config {
+                beam_invert: invert,
+                beam_map: BitMap::parse(map).unwrap(),
+                beam_min_hold: Duration::ZERO,
+                ..Config::default()
+            });
+            assert_eq!(
+                map_beams(beams),
+                mapped,
+                "invert {} map {} beams {:#010b}",
+                invert,
+                map,
+                beams
+            );
+        }
+        config::set_for_test(Config::default());
+    }
+
+    #[test]
+    fn dead_cells_are_masked_by_proxy_numbering_before_reordering() {
+        let _globals = crate::lock_globals();
+        config::set_for_test(Config {
+            slider_mask: CellMask::parse("0,3=2").unwrap(),
+            slider_reverse: true,
+            ..Config::default()
+        });
+        let mapped = map_pressure(pressure(&[255, 50, 70, 0]));
+        config::set_for_test(Config::default());
+
+        assert_eq!(&mapped[28..], &[70, 70, 50, 0]);
+        assert!(mapped[..28].iter().all(|&level| level == 0));
+    }
+
+    #[test]
+    fn held_beam_follows_its_latest_state_once_the_hold_runs_out() {
+        let min_hold = Duration::from_millis(30);
+        let start = Instant::now();
+        let mut hold = BeamHold {
+            beams: 0,
+            latest: 0b01,
+            changed_at: [None; BEAM_COUNT],
+        };
+        assert_eq!(hold.update(start, min_hold), 0b01);
+
+        // Cleared again by the next report, but still held
+        hold.latest = 0;
+        assert_eq!(
+            hold.update(start + Duration::from_millis(10), min_hold),
+            0b01
+        );
+
+        // No new report comes, yet the game's next read sees it clear
+        assert_eq!(hold.update(start + min_hold, min_hold), 0);
+    }
+}
@@ -0,0 +1,1872 @@
+//! Proxy IO thread
+//!
+//! One background thread owns the connection to the chuniio proxy and does all
+//! socket work: polling the full IO state, forwarding LED frames, serving
+//! requests from the exports and reconnecting. The exports only read the state
+//! it publishes or hand it work through a command channel, so the game thread
+//! never waits on the socket and no two threads ever interleave on the stream.
+//!
+//! In push mode the proxy streams its state instead, and a second thread does
+//! all the receiving: it applies the streamed state as it arrives and hands
+//! responses to the IO thread, which keeps sending.
+//!
+//! Once reconnection has kept failing for a while the connection goes offline:
+//! the proxy's inputs are released, LED datagrams can carry an ambient
+//! pattern, requests fail without logging, and reconnection goes on at its usual
+//! spacing until the proxy is back, when everything resumes as before.
+
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io, mem,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TryRecvError},
+        Mutex, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, error, info, warn};
+
+use windows::Win32::Networking::WinSock::{
+    recv, send, WSAGetLastError, SEND_RECV_FLAGS, SOCKET, WSAETIMEDOUT,
+};
+
+use crate::{
+    coin::CoinMode,
+    config, focus,
+    led::{self, LedOutput},
+    logging::PROTOCOL_TARGET,
+    protocol::{
+        error_name, frame_checksum, Capabilities, ChuniMessage, FrameBuffer, FrameHeader, Framing,
+        CHECKSUM_LEN, ERROR_UNSUPPORTED, PROTOCOL_VERSION,
+    },
+    stats, threading,
+    timing::{AdaptiveInterval, PreciseSleeper, WakeEvent},
+    transport::{self, Endpoint, Link, MAX_SEND_BUFFERS},
+    watchdog, ERROR_LOG_INTERVAL_SECS,
+};
+
+/// Default socket path for chuniio proxy under Wine; native Windows uses
+/// `DISCOVERED_SOCKET_NAME` in the temporary directory instead
+const DEFAULT_SOCKET_PATH: &str = "/tmp/chuniio_proxy.sock";
+
+/// Environment variable for socket path override
+pub const SOCKET_PATH_ENV: &str = "CHUNIIO_PROXY_SOCKET";
+
+/// Command line argument of the host process overriding every other socket
+/// path setting, as `--chuniio-proxy-socket=<path>` or followed by the path
+const SOCKET_PATH_ARG: &str = "--chuniio-proxy-socket";
+
+/// Separates the paths of a socket path setting listing several to try
+const SOCKET_PATH_SEPARATOR: char = ';';
+
+/// Socket file name looked for by auto-discovery
+const DISCOVERED_SOCKET_NAME: &str = "chuniio_proxy.sock";
+
+/// Environment variable naming a directory searched by auto-discovery
+const RUNTIME_DIR_ENV: &str = "XDG_RUNTIME_DIR";
+
+/// Log one in this many hot-path poll messages
+const POLL_LOG_SAMPLE_RATE: u64 = 1000;
+
+/// Initial capacity of a batch: every LED board and a few requests
+const BATCH_CAPACITY: usize = 1024;
+
+/// Size of the response receive buffer
+const RECV_BUFFER_LEN: usize = 1024;
+
+/// How long a request waits for its response in push mode, where responses
+/// arrive through the push reader thread
+const PUSH_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for the IO thread to exit when stopping it
+const IO_STOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Work handed to the IO thread by the exports
+pub enum Command {
+    /// Send a request and deliver its response (or `None` on failure)
+    Request(ChuniMessage, SyncSender<Option<ChuniMessage>>),
+    /// Go offline at once, as when the proxy didn't answer at JVS init
+    GoOffline,
+}
+
+/// Handles for talking to the running IO thread
+struct IoThread {
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+static IO_THREAD: Mutex<Option<IoThread>> = Mutex::new(None);
+
+/// Signalled whenever the IO thread has new work (commands, LED frames, shutdown)
+static WAKE: OnceLock<Option<WakeEvent>> = OnceLock::new();
+
+/// Set to make the IO thread exit
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Set by the push reader thread when the proxy has streamed new state
+static PUSHED: AtomicBool = AtomicBool::new(false);
+
+/// `Goodbye` framed for the current connection and the socket it goes out on,
+/// for when the process exits and the IO thread is already gone
+static GOODBYE: Mutex<Option<(SOCKET, Vec<u8>)>> = Mutex::new(None);
+
+fn wake_event() -> Option<&'static WakeEvent> {
+    WAKE.get_or_init(|| match WakeEvent::new() {
+        Ok(event) => Some(event),
+        Err(e) => {
+            error!("Failed to create IO wake event: {:?}", e);
+            None
+        }
+    })
+    .as_ref()
+}
+
+/// Start the IO thread if it is not already running
+pub fn start() {
+    let Ok(mut io_thread) = IO_THREAD.lock() else {
+        error!("Failed to acquire IO thread lock");
+        return;
+    };
+    if io_thread.is_some() {
+        return;
+    }
+    let Some(wake) = wake_event() else {
+        return;
+    };
+
+    let (commands, receiver) = mpsc::channel();
+    SHUTDOWN.store(false, Ordering::SeqCst);
+    match thread::Builder::new()
+        .name("chuniio-io".into())
+        .spawn(move || run(receiver, wake))
+    {
+        Ok(thread) => {
+            *io_thread = Some(IoThread {
+                commands,
+                thread: Some(thread),
+            });
+            watchdog::start();
+        }
+        Err(e) => error!("Failed to spawn IO thread: {:?}", e),
+    }
+}
+
+/// Signal the IO thread to exit and wait up to `IO_STOP_TIMEOUT` for it,
+/// joining it afterwards if `join` is set
+///
+/// Joining is not possible under the loader lock (DLL detach), so `join` must
+/// be false there; waiting for the thread function to return is still safe.
+pub fn stop(join: bool) {
+    let thread = match IO_THREAD.lock() {
+        Ok(mut io_thread) => io_thread.take().and_then(|mut io| io.thread.take()),
+        Err(_) => {
+            error!("IO stop: could not acquire IO thread lock");
+            return;
+        }
+    };
+    let Some(thread) = thread else {
+        return;
+    };
+
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    watchdog::stop();
+    wake();
+
+    if !threading::wait_for_exit(&thread, Instant::now() + IO_STOP_TIMEOUT, || {}) {
+        warn!(
+            "IO thread did not stop within {:?}, detaching it",
+            IO_STOP_TIMEOUT
+        );
+    } else if join {
+        let _ = thread.join();
+        debug!("IO thread joined");
+    }
+}
+
+/// Send `Goodbye` on the current connection from a DLL detach at process
+/// exit, where every other thread, the IO thread included, has already been
+/// killed; a lock held by a killed thread means there is nothing to send
+pub fn goodbye_at_exit() {
+    let Some((sock, frame)) = GOODBYE
+        .try_lock()
+        .ok()
+        .and_then(|mut goodbye| goodbye.take())
+    else {
+        return;
+    };
+    // The IO thread may have been killed in the middle of a send, in which
+    // case the proxy sees a broken frame and the connection close instead
+    unsafe { send(sock, &frame, SEND_RECV_FLAGS(0)) };
+}
+
+/// Cut the IO thread's current sleep short
+pub fn wake() {
+    if let Some(wake) = WAKE.get().and_then(Option::as_ref) {
+        wake.signal();
+    }
+}
+
+/// Queue a command for the IO thread, returning false if it is not running
+pub fn submit(command: Command) -> bool {
+    let sent = IO_THREAD
+        .lock()
+        .ok()
+        .and_then(|io_thread| {
+            io_thread
+                .as_ref()
+                .map(|io| io.commands.send(command).is_ok())
+        })
+        .unwrap_or(false);
+    if sent {
+        wake();
+    }
+    sent
+}
+
+/// Send a request through the IO thread and wait up to `timeout` for the response
+pub fn request(message: ChuniMessage, timeout: Duration) -> Option<ChuniMessage> {
+    let (reply, response) = mpsc::sync_channel(1);
+    if !submit(Command::Request(message, reply)) {
+        return None;
+    }
+    response.recv_timeout(timeout).ok().flatten()
+}
+
+/// IO thread body: poll the state at the configured rate, and in between serve
+/// commands and LED frames as soon as they arrive
+fn run(commands: Receiver<Command>, wake: &'static WakeEvent) {
+    let config = config::get();
+    let poll_interval = config.slider_poll_interval();
+    debug!("IO thread started (poll interval {:?})", poll_interval);
+    threading::apply_to_current_thread("IO", &config.io_thread.or(config.slider_thread));
+
+    let sleeper = PreciseSleeper::new(config.wait_strategy, config.spin_window);
+    let mut connection = Connection::new();
+    let mut pipeline = StatePipeline::new(config.pipeline_depth);
+    let mut interval = poll_schedule(config);
+    let mut config_generation = config::generation();
+    let mut leds = LedOutput::new();
+    let mut slider = crate::SliderDelivery::new();
+
+    unsafe {
+        if connection.connect() {
+            // Test the connection with a ping
+            debug!("Testing connection with ping...");
+            match connection.request(&ChuniMessage::Ping) {
+                Some(response) => info!("Ping test successful: {:?}", response),
+                None => error!("Ping test failed - connection may be unstable"),
+            }
+        } else {
+            warn!("Failed to connect to chuniio proxy - will keep retrying");
+            connection.reconnect.failed(config);
+        }
+    }
+
+    let mut next_poll = Instant::now();
+    let mut in_background = false;
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        // Poll rates and slider delivery follow reloads; the pipeline depth,
+        // wait strategy and thread scheduling are fixed for the thread's lifetime
+        config::maybe_reload();
+        if config::generation() != config_generation {
+            config_generation = config::generation();
+            interval = poll_schedule(config::get());
+            slider.configure(config::get());
+            next_poll = next_poll.min(Instant::now() + config::get().slider_poll_interval());
+        }
+
+        while let Ok(command) = commands.try_recv() {
+            unsafe {
+                // Responses to pipelined polls come first on the stream
+                pipeline.drain(&mut connection);
+                match command {
+                    Command::Request(message, reply) => {
+                        let _ = reply.try_send(connection.request_with_recovery(&message));
+                    }
+                    Command::GoOffline => connection.start_offline(),
+                }
+            }
+        }
+
+        // State streamed by the proxy goes to the game as soon as it arrives
+        if PUSHED.swap(false, Ordering::Relaxed) {
+            connection.heard();
+            next_poll = Instant::now();
+        }
+
+        // A proxy that has gone quiet is pinged, and dropped once it misses
+        // too many pings
+        if connection.heartbeat_due() {
+            unsafe {
+                pipeline.drain(&mut connection);
+                connection.heartbeat();
+            }
+        }
+
+        // On the backup proxy, the primary is checked for now and then
+        if connection.fail_back_due() {
+            unsafe {
+                pipeline.drain(&mut connection);
+                connection.fail_back();
+            }
+        }
+
+        // In the background, poll at a trickle and optionally blank the LEDs;
+        // both return to normal as soon as the game is focused again
+        let background = config::get().watch_focus() && !focus::is_focused();
+        if background != in_background {
+            in_background = background;
+            debug!(
+                "Game window {}",
+                if background {
+                    "in background"
+                } else {
+                    "focused"
+                }
+            );
+            leds.set_blanked(background && config::get().background_blank_leds);
+            if !background {
+                next_poll = Instant::now();
+            }
+        }
+
+        // A tick's LED frames and poll request go out together when the proxy
+        // takes batches; the batch is sent before waiting for the response
+        connection.begin_batch();
+        leds.set_offline(connection.is_offline());
+        let led_deadline = leds.flush(&mut connection);
+
+        let now = Instant::now();
+        if now >= next_poll {
+            unsafe {
+                // Synchronize full IO state from proxy (includes slider)
+                pipeline.poll(&mut connection);
+                let active = slider.deliver();
+                let background_interval = config::get()
+                    .background_poll_interval()
+                    .filter(|_| in_background);
+                next_poll = now + background_interval.unwrap_or_else(|| interval.next(active));
+            }
+        }
+
+        connection.end_batch();
+        stats::maybe_report();
+
+        let wake_at = led_deadline.map_or(next_poll, |deadline| deadline.min(next_poll));
+        sleeper.sleep_or_wake(wake_at.saturating_duration_since(Instant::now()), wake);
+    }
+
+    // The game is done with us; the proxy can blank the LEDs and reset its
+    // state rather than wait for the connection to drop
+    unsafe { connection.say_goodbye() };
+
+    debug!("IO thread stopped");
+}
+
+/// Polling schedule for the configured full-speed and idle rates
+fn poll_schedule(config: &config::Config) -> AdaptiveInterval {
+    AdaptiveInterval::new(
+        config.slider_poll_interval(),
+        config.idle_poll_interval(),
+        config.idle_after,
+    )
+}
+
+/// Spacing of reconnection attempts while the proxy is unreachable: an
+/// exponential backoff, then only occasional probes (or none) once the
+/// configured give-up time has passed, each delay randomly spread
+#[derive(Default)]
+struct Reconnect {
+    /// Failed attempts since the connection was lost
+    failures: u32,
+    /// When the first of those attempts failed
+    since: Option<Instant>,
+    /// Earliest time for the next attempt
+    next_attempt: Option<Instant>,
+    /// Given up with probing disabled: no more attempts
+    stopped: bool,
+}
+
+impl Reconnect {
+    /// Whether an attempt may be made now
+    fn ready(&self) -> bool {
+        !self.stopped && self.next_attempt.is_none_or(|at| Instant::now() >= at)
+    }
+
+    /// Schedule the next attempt after a failed one
+    fn failed(&mut self, config: &config::Config) {
+        let now = Instant::now();
+        let since = *self.since.get_or_insert(now);
+        self.failures = self.failures.saturating_add(1);
+
+        let given_up =
+            !config.reconnect_give_up.is_zero() && now - since >= config.reconnect_give_up;
+        let delay = if given_up {
+            if config.reconnect_probe_interval.is_zero() {
+                error!(
+                    "Proxy unreachable for {:?}, giving up reconnecting",
+                    now - since
+                );
+                self.stopped = true;
+                return;
+            }
+            config.reconnect_probe_interval
+        } else {
+            config
+                .reconnect_backoff
+                .saturating_mul(1 << self.failures.min(16).saturating_sub(1))
+                .min(config.reconnect_max_backoff)
+        };
+        self.next_attempt = Some(now + jitter(delay, config.reconnect_jitter_percent));
+    }
+
+    /// Reset after a successful connection
+    fn succeeded(&mut self) {
+        if let Some(since) = self.since {
+            info!(
+                "Reconnected after {} failed attempt(s) over {:?}",
+                self.failures,
+                since.elapsed()
+            );
+        }
+        *self = Self::default();
+    }
+}
+
+/// `delay` moved randomly by up to `percent` percent either way
+fn jitter(delay: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return delay;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    // Uniform in [-1, 1]
+    let unit = random as f64 / u64::MAX as f64 * 2.0 - 1.0;
+    delay.mul_f64(1.0 + unit * f64::from(percent.min(100)) / 100.0)
+}
+
+/// Connection to the chuniio proxy, owned by the IO thread
+pub struct Connection {
+    link: Option<Link>,
+    /// Second connection carrying only LED frames, if enabled, so they never
+    /// queue up in front of poll responses; no handshake is done on it, so it
+    /// has neither request IDs nor checksums
+    led_link: Option<Link>,
+    /// Incremented every time a new connection is established, so per-connection
+    /// caches (e.g. last LED frames sent) can be invalidated
+    epoch: u32,
+    /// Response receive buffer; responses are decoded directly out of it
+    recv_buffer: [u8; RECV_BUFFER_LEN],
+    /// Framing of the current connection, fixed when it is established
+    framing: Framing,
+    /// Received bytes not yet decoded, with length-prefixed framing
+    frames: FrameBuffer,
+    /// What the proxy on the current connection supports
+    capabilities: Capabilities,
+    /// Whether frame headers carry request IDs on the current connection
+    request_ids: bool,
+    /// ID of the last request sent, with request IDs
+    last_request_id: u16,
+    /// Whether frames end in a checksum on the current connection
+    checksums: bool,
+    /// Whether messages are being held back to go out as one `Batch`
+    batching: bool,
+    /// The `Batch` being held back: type, count and the messages, each after
+    /// its length; kept to reuse its allocation
+    batch: Vec<u8>,
+    /// Request ID of the batch, which the responses to the requests in it carry
+    batch_id: u16,
+    /// Responses forwarded by the push reader thread, with their request IDs,
+    /// while the proxy streams its state
+    push: Option<Receiver<(u16, ChuniMessage)>>,
+    /// When the proxy last answered or pushed its state
+    last_heard: Instant,
+    /// When the last heartbeat ping was sent
+    last_ping: Option<Instant>,
+    /// Heartbeat pings in a row the proxy hasn't answered
+    missed_pongs: u32,
+    /// Endpoint of the current connection
+    endpoint: Endpoint,
+    /// Whether the current connection is to the backup proxy
+    on_backup: bool,
+    /// When the primary proxy was last checked for while on the backup
+    last_failback_probe: Instant,
+    /// When the connection went offline, while it is
+    offline_since: Option<Instant>,
+    reconnect: Reconnect,
+}
+
+impl Connection {
+    /// A connection that has not been opened yet
+    pub fn new() -> Self {
+        Self {
+            link: None,
+            led_link: None,
+            epoch: 0,
+            recv_buffer: [0; RECV_BUFFER_LEN],
+            framing: Framing::default(),
+            frames: FrameBuffer::default(),
+            capabilities: Capabilities::ASSUMED,
+            request_ids: false,
+            last_request_id: 0,
+            checksums: false,
+            batching: false,
+            batch: Vec::with_capacity(BATCH_CAPACITY),
+            batch_id: 0,
+            push: None,
+            last_heard: Instant::now(),
+            last_ping: None,
+            missed_pongs: 0,
+            endpoint: Endpoint::Unix(None),
+            on_backup: false,
+            last_failback_probe: Instant::now(),
+            offline_since: None,
+            reconnect: Reconnect::default(),
+        }
+    }
+
+    /// Identifies the current connection; changes on every reconnect
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// What the proxy on the current connection supports
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Whether the proxy has been unreachable long enough to be offline
+    pub fn is_offline(&self) -> bool {
+        self.offline_since.is_some()
+    }
+
+    /// Go offline once reconnection has kept failing for the configured time
+    fn check_offline(&mut self) {
+        if self.offline_since.is_some() {
+            return;
+        }
+        let Some(since) = self.reconnect.since else {
+            return;
+        };
+        if since.elapsed() < config::get().offline_after {
+            return;
+        }
+        warn!(
+            "Proxy unreachable for {:?}, going offline until it is back",
+            since.elapsed()
+        );
+        self.go_offline();
+    }
+
+    /// Go offline without waiting for reconnection to fail for a while: the
+    /// connection is dropped and reconnection goes on at its usual spacing
+    fn start_offline(&mut self) {
+        if self.is_offline() {
+            return;
+        }
+        self.set_link(None);
+        self.led_link = None;
+        self.push = None;
+        self.reconnect.failed(config::get());
+        self.go_offline();
+    }
+
+    /// Release the proxy's inputs until it is back
+    fn go_offline(&mut self) {
+        self.offline_since = Some(Instant::now());
+        crate::release_proxy_state();
+    }
+
+    /// (Re)connect to the proxy, replacing the current link if any; the backup
+    /// endpoint is dialled if the primary one can't be reached
+    unsafe fn connect(&mut self) -> bool {
+        let config = config::get();
+        let primary = Endpoint::primary(config);
+        if let Some(link) = Link::open(&primary) {
+            if self.establish(link, primary, false) {
+                return true;
+            }
+        }
+        let Some(backup) = config.proxy_backup.clone() else {
+            return false;
+        };
+        let Some(link) = Link::open(&backup) else {
+            return false;
+        };
+        warn!("Primary proxy unreachable, failing over to {}", backup);
+        self.establish(link, backup, true)
+    }
+
+    /// Whether it is time to check if the primary proxy is back while on the
+    /// backup
+    fn fail_back_due(&self) -> bool {
+        let interval = config::get().failback_interval;
+        self.on_backup && !interval.is_zero() && self.last_failback_probe.elapsed() >= interval
+    }
+
+    /// Move back to the primary proxy if it can be reached again
+    unsafe fn fail_back(&mut self) {
+        self.last_failback_probe = Instant::now();
+        let primary = Endpoint::primary(config::get());
+        let Some(link) = Link::open(&primary) else {
+            return;
+        };
+        info!("Primary proxy is reachable again, failing back");
+        self.establish(link, primary, false);
+    }
+
+    /// Set up a new connection over `link` to `endpoint`, replacing the
+    /// current one, returning whether it is usable
+    unsafe fn establish(&mut self, link: Link, endpoint: Endpoint, backup: bool) -> bool {
+        self.set_link(Some(link));
+        self.endpoint = endpoint;
+        self.on_backup = backup;
+        self.last_failback_probe = Instant::now();
+        self.led_link = None;
+        self.framing = config::get().framing;
+        self.frames.clear();
+        self.capabilities = Capabilities::ASSUMED;
+        self.request_ids = false;
+        self.frames.set_request_ids(false);
+        self.checksums = false;
+        self.frames.set_checksums(false);
+        self.batching = false;
+        self.batch.clear();
+        self.push = None;
+        self.last_heard = Instant::now();
+        self.last_ping = None;
+        self.missed_pongs = 0;
+        if config::get().handshake && !self.handshake() {
+            self.set_link(None);
+            return false;
+        }
+        if self.epoch > 0 {
+            stats::increment(&stats::STATS.reconnects);
+        }
+        self.epoch = self.epoch.wrapping_add(1);
+        if config::get().remote_config {
+            self.fetch_remote_config();
+        }
+        if config::get().push {
+            self.subscribe();
+        }
+        if config::get().separate_led_connection {
+            self.led_link = Link::open(&self.endpoint);
+            if self.led_link.is_none() {
+                warn!("Cannot open a separate LED connection, sending LED frames with the rest");
+            }
+        }
+        self.prepare_goodbye();
+        true
+    }
+
+    /// Frame `Goodbye` for the current connection, for `goodbye_at_exit`;
+    /// only a plain socket can take it without the IO thread
+    fn prepare_goodbye(&self) {
+        let Some(Link::Socket(sock)) = self.link else {
+            return;
+        };
+        let data = &ChuniMessage::GOODBYE_FRAME;
+        let mut frame = Vec::with_capacity(data.len() + 4 + CHECKSUM_LEN);
+        if self.framing == Framing::Length {
+            // Nothing answers it, so with request IDs it carries 0
+            let header = FrameHeader::new(data.len(), self.request_ids.then_some(0));
+            frame.extend_from_slice(header.as_bytes());
+            frame.extend_from_slice(data);
+            if self.checksums {
+                frame.extend_from_slice(&frame_checksum(&[header.as_bytes(), data]));
+            }
+        } else {
+            frame.extend_from_slice(data);
+        }
+        if let Ok(mut goodbye) = GOODBYE.lock() {
+            *goodbye = Some((sock, frame));
+        }
+    }
+
+    /// Tell the proxy the game is exiting, as the last message on the
+    /// connection
+    unsafe fn say_goodbye(&mut self) {
+        if self.link.is_some() && self.send_request(&ChuniMessage::Goodbye).is_some() {
+            debug!("Said goodbye to the proxy");
+        }
+        self.set_link(None);
+    }
+
+    /// Replace the link, letting the watchdog and `goodbye_at_exit` know first
+    /// so neither ever uses a closed socket
+    fn set_link(&mut self, link: Option<Link>) {
+        if let Ok(mut goodbye) = GOODBYE.lock() {
+            *goodbye = None;
+        }
+        watchdog::watch(link.as_ref().and_then(Link::raw_socket));
+        self.link = link;
+    }
+
+    /// Note that the proxy answered or pushed its state
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+        watchdog::heard();
+    }
+
+    /// Whether the proxy has been quiet long enough to be pinged
+    fn heartbeat_due(&self) -> bool {
+        let interval = config::get().heartbeat_interval;
+        !interval.is_zero()
+            && self.link.is_some()
+            && self.last_heard.elapsed() >= interval
+            && self.last_ping.is_none_or(|sent| sent.elapsed() >= interval)
+    }
+
+    /// Ping the proxy, and drop the connection once it has missed too many
+    /// pings in a row, so a half-open connection is replaced before the game
+    /// notices; the usual recovery reconnects
+    unsafe fn heartbeat(&mut self) {
+        self.last_ping = Some(Instant::now());
+        if self.request(&ChuniMessage::Ping).is_some() {
+            self.missed_pongs = 0;
+            return;
+        }
+        self.missed_pongs += 1;
+        let allowed = config::get().heartbeat_misses;
+        if self.missed_pongs < allowed {
+            debug!(
+                "Proxy missed heartbeat {} of {}",
+                self.missed_pongs, allowed
+            );
+            return;
+        }
+        warn!(
+            "Proxy missed {} heartbeats in a row, dropping the connection",
+            self.missed_pongs
+        );
+        self.set_link(None);
+        self.led_link = None;
+        self.push = None;
+    }
+
+    /// Start a push reader thread on the socket and ask the proxy to stream its
+    /// state to it
+    unsafe fn subscribe(&mut self) {
+        if self.framing != Framing::Length || !self.capabilities.has(Capabilities::PUSH) {
+            warn!(
+                "Push mode needs length framing and a proxy that supports it (capabilities: {}), polling instead",
+                self.capabilities
+            );
+            return;
+        }
+        // The reader blocks in a receive while the IO thread sends, which
+        // only sockets allow
+        let Some(&Link::Socket(sock)) = self.link.as_ref() else {
+            warn!("Push mode needs a socket transport, polling instead");
+            return;
+        };
+
+        // Bytes already received belong to the stream the reader takes over
+        let frames = mem::take(&mut self.frames);
+        let (responses, receiver) = mpsc::channel();
+        if let Err(e) = thread::Builder::new()
+            .name("chuniio-push".into())
+            .spawn(move || read_pushed(sock, frames, responses))
+        {
+            error!("Failed to spawn push reader thread: {:?}", e);
+            return;
+        }
+        self.push = Some(receiver);
+        if self.send_request(&ChuniMessage::Subscribe).is_some() {
+            info!("Proxy is streaming its state");
+        }
+    }
+
+    /// Whether the push reader thread is still receiving; false if not in push
+    /// mode
+    fn push_alive(&self) -> bool {
+        let Some(responses) = &self.push else {
+            return false;
+        };
+        match responses.try_recv() {
+            Ok((_, response)) => {
+                debug!("Dropping unexpected response {:?}", response);
+                true
+            }
+            Err(TryRecvError::Empty) => true,
+            Err(TryRecvError::Disconnected) => false,
+        }
+    }
+
+    /// Exchange protocol versions and capabilities with the proxy, returning
+    /// whether it answered
+    unsafe fn handshake(&mut self) -> bool {
+        let supported = if config::get().frame_checksums {
+            Capabilities::SUPPORTED
+        } else {
+            Capabilities::SUPPORTED.without(Capabilities::CHECKSUMS)
+        };
+        let hello = ChuniMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: supported,
+        };
+        let (version, capabilities) = match self.request(&hello) {
+            Some(ChuniMessage::HelloAck {
+                version,
+                capabilities,
+            }) => (version, capabilities),
+            Some(ChuniMessage::Error {
+                code: ERROR_UNSUPPORTED,
+                ..
+            }) => {
+                info!(
+                    "Proxy does not support the handshake, assuming capabilities {}",
+                    self.capabilities
+                );
+                return true;
+            }
+            _ => {
+                warn!("Proxy did not answer the handshake");
+                return false;
+            }
+        };
+
+        if version != PROTOCOL_VERSION {
+            info!(
+                "Proxy speaks protocol version {}, this DLL version {}",
+                version, PROTOCOL_VERSION
+            );
+        }
+        self.capabilities = capabilities.intersect(supported);
+        info!("Proxy capabilities: {}", self.capabilities);
+
+        // Request IDs and checksums are part of the frame, so they need framing
+        let framed = self.framing == Framing::Length;
+        self.request_ids = framed && self.capabilities.has(Capabilities::REQUEST_IDS);
+        self.frames.set_request_ids(self.request_ids);
+        self.checksums = framed && self.capabilities.has(Capabilities::CHECKSUMS);
+        self.frames.set_checksums(self.checksums);
+        true
+    }
+
+    /// Ask the proxy for the settings it wants to set and apply them
+    unsafe fn fetch_remote_config(&mut self) {
+        let Some(ChuniMessage::RemoteConfigResponse {
+            slider_poll_rate_hz,
+            idle_poll_rate_hz,
+            led_brightness,
+            coin_mode,
+        }) = self.request(&ChuniMessage::RemoteConfigRead)
+        else {
+            warn!("Proxy did not send its settings");
+            return;
+        };
+
+        let mut values = Vec::new();
+        if let Some(hz) = slider_poll_rate_hz {
+            values.push((config::SLIDER_POLL_RATE_ENV.to_string(), hz.to_string()));
+        }
+        if let Some(hz) = idle_poll_rate_hz {
+            values.push((config::IDLE_POLL_RATE_ENV.to_string(), hz.to_string()));
+        }
+        if let Some(brightness) = led_brightness {
+            values.push((
+                config::LED_BRIGHTNESS_ENV.to_string(),
+                brightness.map(|percent| percent.to_string()).join(","),
+            ));
+        }
+        if let Some(id) = coin_mode {
+            match CoinMode::from_id(id) {
+                Some(mode) => {
+                    values.push((config::COIN_MODE_ENV.to_string(), mode.name().to_string()));
+                }
+                None => warn!("Ignoring unknown coin mode {} from the proxy", id),
+            }
+        }
+        debug!("Proxy settings: {:?}", values);
+        config::set_remote(values);
+    }
+
+    /// Attempt to recover socket connection if lost, unless the reconnection
+    /// backoff says to wait
+    unsafe fn recover(&mut self) -> bool {
+        if !self.reconnect.ready() {
+            self.check_offline();
+            return false;
+        }
+        debug!("Attempting to recover socket connection");
+        if self.connect() {
+            match self.offline_since.take() {
+                Some(since) => info!("Proxy is back after {:?} offline", since.elapsed()),
+                None => info!("Socket connection recovered successfully"),
+            }
+            self.reconnect.succeeded();
+            return true;
+        }
+        self.reconnect.failed(config::get());
+        self.check_offline();
+
+        if !self.is_offline() {
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                warn!("Failed to recover socket connection")
+            );
+        }
+        false
+    }
+
+    /// Send a message with automatic connection recovery
+    unsafe fn request_with_recovery(&mut self, message: &ChuniMessage) -> Option<ChuniMessage> {
+        // Offline, requests fail quietly until the proxy is back
+        if self.is_offline() {
+            return if self.recover() {
+                self.request(message)
+            } else {
+                None
+            };
+        }
+        if self.link.is_some() {
+            let result = self.request(message);
+            if result.is_some() || ChuniMessage::response_len(message.message_type()).is_none() {
+                return result;
+            }
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!(
+                    "request_with_recovery: request failed for {:?}, attempting recovery",
+                    message
+                )
+            );
+        } else {
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                warn!("request_with_recovery: no socket, attempting recovery")
+            );
+        }
+
+        // If we get here, either no connection or send failed
+        if self.recover() {
+            debug!(
+                "Retrying message send after connection recovery: {:?}",
+                message
+            );
+            return self.request(message);
+        }
+        log_throttled!(
+            ERROR_LOG_INTERVAL_SECS,
+            error!(
+                "request_with_recovery: failed to send message after recovery: {:?}",
+                message
+            )
+        );
+        None
+    }
+
+    /// Read the full IO state, in one request if the proxy supports it and
+    /// otherwise from separate JVS, coin and slider reads combined into a
+    /// `JvsFullStateReadResponse`
+    unsafe fn read_full_state(&mut self) -> Option<ChuniMessage> {
+        if self.capabilities.has(Capabilities::FULL_STATE) {
+            return self.request_with_recovery(&ChuniMessage::JvsFullStateRead);
+        }
+        let Some(ChuniMessage::JvsPollResponse { opbtn, beams }) =
+            self.request_with_recovery(&ChuniMessage::JvsPoll)
+        else {
+            return None;
+        };
+        let Some(ChuniMessage::CoinCounterReadResponse { count }) =
+            self.request(&ChuniMessage::CoinCounterRead)
+        else {
+            return None;
+        };
+        let Some(ChuniMessage::SliderStateReadResponse { pressure }) =
+            self.request(&ChuniMessage::SliderStateRead)
+        else {
+            return None;
+        };
+        Some(ChuniMessage::JvsFullStateReadResponse {
+            opbtn,
+            beams,
+            pressure,
+            coin_counter: count,
+        })
+    }
+
+    /// Send `message` and wait for its response, if it expects one
+    unsafe fn request(&mut self, message: &ChuniMessage) -> Option<ChuniMessage> {
+        let started = Instant::now();
+        let request_id = self.send_request(message)?;
+        if ChuniMessage::response_len(message.message_type()).is_some() {
+            self.recv_response(message, request_id, started)
+        } else {
+            debug!(
+                target: PROTOCOL_TARGET,
+                "Message sent (no response expected): {:?}", message
+            );
+            None
+        }
+    }
+
+    /// Send `message` without reading any response, returning its request ID
+    /// (0 without request IDs) if it was sent
+    unsafe fn send_request(&mut self, message: &ChuniMessage) -> Option<u16> {
+        self.link.as_ref()?;
+
+        // Fixed requests are preserialized; everything else goes through a stack buffer
+        let mut buffer = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+        let data = match message.fixed_frame() {
+            Some(frame) => frame,
+            None => {
+                let len = message.serialize_into(&mut buffer);
+                &buffer[..len]
+            }
+        };
+        // Polls are sent at up to 1kHz, so only a sample of them is logged
+        if is_poll(message) {
+            log_every_n!(
+                POLL_LOG_SAMPLE_RATE,
+                debug!(
+                    target: PROTOCOL_TARGET,
+                    "Sending message (1 in {} logged): {:?} ({} bytes)",
+                    POLL_LOG_SAMPLE_RATE,
+                    message,
+                    data.len()
+                )
+            );
+        } else {
+            debug!(
+                target: PROTOCOL_TARGET,
+                "Sending message: {:?} ({} bytes)",
+                message,
+                data.len()
+            );
+        }
+        if let Some(request_id) = self.add_to_batch(&[data]) {
+            stats::record_sent(message.message_type());
+            return Some(request_id);
+        }
+        let request_id = self.next_request_id();
+        if !self.send_frame(data, request_id) {
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!("send_request: failed to send message {:?}", message)
+            );
+            return None;
+        }
+        stats::record_sent(message.message_type());
+        Some(request_id)
+    }
+
+    /// Send one message, framed as configured, returning whether it was sent
+    fn send_frame(&mut self, data: &[u8], request_id: u16) -> bool {
+        if self.framing == Framing::Raw {
+            return self.send_buffers(&[data]);
+        }
+        let header = FrameHeader::new(data.len(), self.request_ids.then_some(request_id));
+        let checksum = frame_checksum(&[header.as_bytes(), data]);
+        let parts = [header.as_bytes(), data, &checksum];
+        let count = if self.checksums { 3 } else { 2 };
+        self.send_buffers(&parts[..count])
+    }
+
+    /// Hold back the messages sent from now on until `end_batch`, to send them
+    /// as one `Batch`, if the proxy supports it
+    pub fn begin_batch(&mut self) {
+        if self.link.is_some() && self.capabilities.has(Capabilities::BATCH) {
+            self.batching = true;
+        }
+    }
+
+    /// Add a message, given in parts, to the batch being held back, returning
+    /// the batch's request ID; `None` if there is no batch (or it is full) and
+    /// the message must be sent on its own
+    fn add_to_batch(&mut self, parts: &[&[u8]]) -> Option<u16> {
+        if !self.batching {
+            return None;
+        }
+        if self.batch.is_empty() {
+            self.batch.extend([ChuniMessage::BATCH, 0]);
+            self.batch_id = self.next_request_id();
+        } else if self.batch[1] == u8::MAX {
+            return None;
+        }
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        self.batch.extend((len as u16).to_le_bytes());
+        parts
+            .iter()
+            .for_each(|part| self.batch.extend_from_slice(part));
+        self.batch[1] += 1;
+        Some(self.batch_id)
+    }
+
+    /// Send the batch being held back, if any, returning false if sending it
+    /// failed
+    pub fn end_batch(&mut self) -> bool {
+        self.batching = false;
+        if self.batch.is_empty() {
+            return true;
+        }
+        let batch = mem::take(&mut self.batch);
+        let count = batch[1];
+        let data = match count {
+            // A lone message goes out as itself
+            1 => &batch[ChuniMessage::BATCH_HEADER_LEN + ChuniMessage::BATCH_ENTRY_PREFIX_LEN..],
+            _ => &batch[..],
+        };
+        let sent = self.send_frame(data, self.batch_id);
+        if sent && count > 1 {
+            stats::record_sent(ChuniMessage::BATCH);
+        }
+        self.batch = batch;
+        self.batch.clear();
+        sent
+    }
+
+    /// ID for the next request, skipping 0 (unsolicited messages); always 0
+    /// without request IDs
+    fn next_request_id(&mut self) -> u16 {
+        if !self.request_ids {
+            return 0;
+        }
+        self.last_request_id = self.last_request_id.checked_add(1).unwrap_or(1);
+        self.last_request_id
+    }
+
+    /// Read the response to `request`, sent at `started` with `request_id`
+    ///
+    /// Exactly the response is consumed, so responses to later requests
+    /// already in flight stay in the socket (or the frame buffer) for the
+    /// following reads. With request IDs, responses to earlier requests that
+    /// were given up on are skipped.
+    unsafe fn recv_response(
+        &mut self,
+        request: &ChuniMessage,
+        request_id: u16,
+        started: Instant,
+    ) -> Option<ChuniMessage> {
+        // The request may still be held back in a batch
+        if !self.end_batch() {
+            return None;
+        }
+        let received = match self.framing {
+            _ if self.push.is_some() => Ok(self.recv_pushed(request, request_id)?),
+            Framing::Raw => self.recv_raw(request)?,
+            Framing::Length => self.recv_framed(request, request_id)?,
+        };
+
+        match received {
+            Ok(response) => {
+                self.heard();
+                stats::record_received(response.message_type());
+                if let ChuniMessage::Error { code, message } = &response {
+                    self.proxy_error(request, *code, message);
+                }
+                if let Some(histogram) = crate::latency::histogram_for(request) {
+                    histogram.record(started.elapsed());
+                    crate::latency::maybe_report();
+                }
+                if is_poll(request) {
+                    log_every_n!(
+                        POLL_LOG_SAMPLE_RATE,
+                        debug!(
+                            target: PROTOCOL_TARGET,
+                            "Received response from chuniio proxy (1 in {} logged): {:?}",
+                            POLL_LOG_SAMPLE_RATE, response
+                        )
+                    );
+                } else {
+                    debug!(
+                        target: PROTOCOL_TARGET,
+                        "Received response from chuniio proxy: {:?}", response
+                    );
+                }
+                Some(response)
+            }
+            Err(e) => {
+                log_throttled!(
+                    ERROR_LOG_INTERVAL_SECS,
+                    error!(
+                        target: PROTOCOL_TARGET,
+                        "recv_response: failed to deserialize response for {:?}: {:?}",
+                        request, e
+                    )
+                );
+                None
+            }
+        }
+    }
+
+    /// Log an error the proxy answered `request` with, and stop sending what it
+    /// doesn't support where there is another way
+    fn proxy_error(&mut self, request: &ChuniMessage, code: u8, message: &str) {
+        log_throttled!(
+            ERROR_LOG_INTERVAL_SECS,
+            warn!(
+                "Proxy rejected {:?}: {} ({})",
+                request,
+                error_name(code),
+                message
+            )
+        );
+        if code == ERROR_UNSUPPORTED && matches!(request, ChuniMessage::JvsFullStateRead) {
+            info!("Proxy does not support full-state reads, polling with separate requests");
+            self.capabilities = self.capabilities.without(Capabilities::FULL_STATE);
+        }
+    }
+
+    /// Receive a bare response, by the length its request implies; `None` if
+    /// the socket failed
+    unsafe fn recv_raw(&mut self, request: &ChuniMessage) -> Option<io::Result<ChuniMessage>> {
+        let link = self.link.as_mut()?;
+        let response_len = ChuniMessage::response_len(request.message_type())?;
+        let buffer = &mut self.recv_buffer;
+
+        let mut bytes_received = 0;
+        while bytes_received < response_len {
+            let received = link.recv(&mut buffer[bytes_received..response_len]);
+            if received <= 0 {
+                recv_failed(request, received);
+                return None;
+            }
+            bytes_received += received as usize;
+        }
+        Some(ChuniMessage::deserialize(&buffer[..response_len]))
+    }
+
+    /// Receive the length-prefixed response with `request_id`, reading until
+    /// it has fully arrived; `None` if the socket failed
+    unsafe fn recv_framed(
+        &mut self,
+        request: &ChuniMessage,
+        request_id: u16,
+    ) -> Option<io::Result<ChuniMessage>> {
+        let link = self.link.as_mut()?;
+        loop {
+            match self.frames.next_message() {
+                Ok(Some((id, response))) if id != request_id => {
+                    skip_response(id, request_id, &response)
+                }
+                Ok(Some((_, response))) => return Some(Ok(response)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            let received = link.recv(&mut self.recv_buffer);
+            if received <= 0 {
+                recv_failed(request, received);
+                return None;
+            }
+            self.frames.extend(&self.recv_buffer[..received as usize]);
+        }
+    }
+
+    /// Wait for the push reader to forward the response with `request_id`;
+    /// `None` if it doesn't arrive in time or the stream is gone
+    fn recv_pushed(&self, request: &ChuniMessage, request_id: u16) -> Option<ChuniMessage> {
+        let responses = self.push.as_ref()?;
+        let deadline = Instant::now() + PUSH_RESPONSE_TIMEOUT;
+        loop {
+            match responses.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((id, response)) if id != request_id => skip_response(id, request_id, &response),
+                Ok((_, response)) => return Some(response),
+                Err(_) => {
+                    recv_failed(request, 0);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Send messages, each given as a header and data buffer, in a single
+    /// send, without waiting for a response, returning whether they
+    /// were sent; they go over the separate LED connection if there is one
+    pub fn send_messages(&mut self, messages: &[[&[u8]; 2]]) -> bool {
+        debug_assert!(messages.len() <= led::LED_BOARD_COUNT);
+        let separate = self.led_link.is_some();
+        if self.batching && !separate {
+            for message in messages {
+                if self.add_to_batch(message).is_none() {
+                    return false;
+                }
+            }
+            return true;
+        }
+        // Nothing answers these, so with request IDs they all carry 0
+        let request_id = (self.request_ids && !separate).then_some(0);
+        let frame_headers: [_; led::LED_BOARD_COUNT] = std::array::from_fn(|index| {
+            messages
+                .get(index)
+                .map_or_else(FrameHeader::default, |[header, data]| {
+                    FrameHeader::new(header.len() + data.len(), request_id)
+                })
+        });
+        let checksums: [_; led::LED_BOARD_COUNT] = std::array::from_fn(|index| {
+            messages
+                .get(index)
+                .map_or([0; CHECKSUM_LEN], |&[header, data]| {
+                    frame_checksum(&[frame_headers[index].as_bytes(), header, data])
+                })
+        });
+        let mut buffers: [&[u8]; MAX_SEND_BUFFERS] = [&[]; MAX_SEND_BUFFERS];
+        let mut count = 0;
+        for (index, message) in messages.iter().enumerate() {
+            if self.framing == Framing::Length {
+                buffers[count] = frame_headers[index].as_bytes();
+                count += 1;
+            }
+            buffers[count..count + 2].copy_from_slice(message);
+            count += 2;
+            if self.checksums && !separate {
+                buffers[count] = &checksums[index];
+                count += 1;
+            }
+        }
+        if separate {
+            return self.send_led_buffers(&buffers[..count]);
+        }
+        self.send_buffers(&buffers[..count])
+    }
+
+    /// Send buffers over the separate LED connection, returning whether they
+    /// were sent; if it fails, LED frames go over the main connection until
+    /// the next reconnect
+    fn send_led_buffers(&mut self, buffers: &[&[u8]]) -> bool {
+        let Some(link) = &mut self.led_link else {
+            return false;
+        };
+        if !link.send(buffers) {
+            stats::increment(&stats::STATS.send_failures);
+            warn!("Separate LED connection failed, sending LED frames with the rest");
+            self.led_link = None;
+            return false;
+        }
+        true
+    }
+
+    /// Send buffers over the link, returning whether they were sent
+    fn send_buffers(&mut self, buffers: &[&[u8]]) -> bool {
+        let Some(link) = &mut self.link else {
+            return false;
+        };
+        if !link.send(buffers) {
+            stats::increment(&stats::STATS.send_failures);
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!("send_buffers: failed to send {} buffers", buffers.len())
+            );
+            return false;
+        }
+        true
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.set_link(None);
+    }
+}
+
+/// Push reader thread body: decode the stream until the socket closes, applying
+/// streamed state and forwarding everything else to the IO thread as responses
+fn read_pushed(sock: SOCKET, mut frames: FrameBuffer, responses: Sender<(u16, ChuniMessage)>) {
+    let mut buffer = [0u8; RECV_BUFFER_LEN];
+    'stream: loop {
+        loop {
+            match frames.next_message() {
+                Ok(Some((_, state @ ChuniMessage::JvsFullStateReadResponse { .. }))) => {
+                    stats::record_received(state.message_type());
+                    crate::apply_full_state(Some(state));
+                    PUSHED.store(true, Ordering::Relaxed);
+                    wake();
+                }
+                Ok(Some(response)) => {
+                    if responses.send(response).is_err() {
+                        break 'stream;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!(target: PROTOCOL_TARGET, "Push stream out of sync: {}", e);
+                    break 'stream;
+                }
+            }
+        }
+        let received = unsafe { recv(sock, &mut buffer, SEND_RECV_FLAGS(0)) };
+        // The proxy only pushes when something changes, so a receive
+        // timeout on a quiet stream is not a failure
+        if received < 0 && unsafe { WSAGetLastError() } == WSAETIMEDOUT {
+            continue;
+        }
+        if received <= 0 {
+            break;
+        }
+        frames.extend(&buffer[..received as usize]);
+    }
+    debug!("Push reader stopped");
+    // Dropping `responses` tells the IO thread the stream is gone
+    wake();
+}
+
+/// Log a response skipped while waiting for the one with `expected_id`
+///
+/// Errors about messages that get no response (e.g. LED updates) arrive this
+/// way too, with ID 0.
+fn skip_response(id: u16, expected_id: u16, response: &ChuniMessage) {
+    if let ChuniMessage::Error { code, message } = response {
+        log_throttled!(
+            ERROR_LOG_INTERVAL_SECS,
+            warn!(
+                "Proxy reported {} for an earlier message ({})",
+                error_name(*code),
+                message
+            )
+        );
+        return;
+    }
+    debug!(
+        target: PROTOCOL_TARGET,
+        "Skipping response to request {} while waiting for {}: {:?}", id, expected_id, response
+    );
+}
+
+/// Count and log a failed receive of the response to `request`
+fn recv_failed(request: &ChuniMessage, received: i32) {
+    stats::increment(&stats::STATS.send_failures);
+    log_throttled!(
+        ERROR_LOG_INTERVAL_SECS,
+        error!(
+            "recv_response: failed to receive response for {:?} (received {} bytes)",
+            request, received
+        )
+    );
+}
+
+/// Whether `message` is one of the requests sent at polling rate
+fn is_poll(message: &ChuniMessage) -> bool {
+    matches!(
+        message,
+        ChuniMessage::JvsPoll
+            | ChuniMessage::CoinCounterRead
+            | ChuniMessage::SliderStateRead
+            | ChuniMessage::JvsFullStateRead
+    )
+}
+
+/// Socket paths and source last logged by `get_socket_paths`
+static RESOLVED_SOCKET_PATH: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+/// Resolve the socket paths to try, in order, logging where they came from
+/// whenever that changes
+///
+/// A setting may list several paths separated by `SOCKET_PATH_SEPARATOR`, for
+/// a proxy whose socket location depends on how it was started.
+pub fn get_socket_paths() -> Vec<String> {
+    let mut candidates = socket_path_candidates();
+    let (path, source) = candidates.remove(0);
+
+    if let Ok(mut resolved) = RESOLVED_SOCKET_PATH.lock() {
+        let current = Some((path.clone(), source.clone()));
+        if *resolved != current {
+            info!("Using proxy socket {} from {}", path, source);
+            for (other, other_source) in candidates.iter().filter(|(other, _)| *other != path) {
+                info!("  overrides {} from {}", other, other_source);
+            }
+            *resolved = current;
+        }
+    }
+    split_socket_paths(&path)
+}
+
+/// Paths listed in a socket path setting, in order
+fn split_socket_paths(value: &str) -> Vec<String> {
+    value
+        .split(SOCKET_PATH_SEPARATOR)
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Every socket path given, in order of precedence, each with where it came
+/// from: the host's command line, the environment, the config sources,
+/// auto-discovery, and finally the default
+fn socket_path_candidates() -> Vec<(String, String)> {
+    let mut candidates = Vec::new();
+    if let Some(path) = command_line_socket_path() {
+        candidates.push((path, "command line".to_string()));
+    }
+    candidates.extend(config::lookup_all(SOCKET_PATH_ENV));
+    if let Some(path) = discover_socket_path() {
+        candidates.push((path, "auto-discovery".to_string()));
+    }
+    candidates.push((default_socket_path(), "default".to_string()));
+    candidates
+}
+
+/// Socket path used when none is given: `DEFAULT_SOCKET_PATH` under Wine, and
+/// on native Windows, which has no `/tmp`, the same name in the temporary
+/// directory
+pub fn default_socket_path() -> String {
+    if transport::is_wine() {
+        DEFAULT_SOCKET_PATH.to_string()
+    } else {
+        std::env::temp_dir()
+            .join(DISCOVERED_SOCKET_NAME)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Socket path passed to the host process as `SOCKET_PATH_ARG`
+fn command_line_socket_path() -> Option<String> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == SOCKET_PATH_ARG {
+            return args.next();
+        }
+        if let Some(path) = arg
+            .strip_prefix(SOCKET_PATH_ARG)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+/// Existing socket file named `DISCOVERED_SOCKET_NAME` next to the DLL or in
+/// `RUNTIME_DIR_ENV`, where a proxy started alongside the game puts it
+fn discover_socket_path() -> Option<String> {
+    let dirs = [
+        config::module_dir(),
+        config::get_env_var(RUNTIME_DIR_ENV).map(PathBuf::from),
+    ];
+    dirs.into_iter()
+        .flatten()
+        .map(|dir| dir.join(DISCOVERED_SOCKET_NAME))
+        .find(|path| path.exists())
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+/// Keeps up to `depth` full-state requests in flight, so the socket round trip
+/// overlaps with the game's slider callback
+///
+/// With a depth above one, each poll consumes the response to a request sent on
+/// an earlier poll, i.e. the state is up to `depth - 1` poll intervals old in
+/// exchange for never waiting on the proxy.
+struct StatePipeline {
+    depth: usize,
+    /// Send times and request IDs of the requests in flight, oldest first
+    in_flight: VecDeque<(Instant, u16)>,
+    /// Connection the requests in flight were sent on
+    epoch: u32,
+}
+
+impl StatePipeline {
+    fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            in_flight: VecDeque::with_capacity(depth),
+            epoch: 0,
+        }
+    }
+
+    /// Synchronize the full IO state, topping the pipeline back up first
+    unsafe fn poll(&mut self, connection: &mut Connection) {
+        if connection.push.is_some() {
+            // The proxy streams the state; only make sure it still does
+            self.in_flight.clear();
+            if !connection.push_alive() && !connection.recover() {
+                apply_state(connection, None);
+            }
+            return;
+        }
+
+        if self.depth <= 1
+            || connection.link.is_none()
+            || !connection.capabilities.has(Capabilities::FULL_STATE)
+        {
+            // The blocking path also handles reconnection
+            if connection.link.is_some() {
+                self.drain(connection);
+            }
+            self.in_flight.clear();
+            let state = connection.read_full_state();
+            apply_state(connection, state);
+            return;
+        }
+
+        if connection.epoch != self.epoch {
+            // Responses to requests sent on an old connection will never arrive
+            self.in_flight.clear();
+            self.epoch = connection.epoch;
+        }
+
+        while self.in_flight.len() < self.depth {
+            let Some(request_id) = connection.send_request(&ChuniMessage::JvsFullStateRead) else {
+                self.reset(connection);
+                return;
+            };
+            self.in_flight.push_back((Instant::now(), request_id));
+        }
+
+        self.receive_one(connection);
+    }
+
+    /// Consume the responses to every request in flight, so the stream is free
+    /// for a different request
+    unsafe fn drain(&mut self, connection: &mut Connection) {
+        while !self.in_flight.is_empty() {
+            if !self.receive_one(connection) {
+                return;
+            }
+        }
+    }
+
+    /// Receive and apply the oldest response in flight
+    unsafe fn receive_one(&mut self, connection: &mut Connection) -> bool {
+        let (started, request_id) = self
+            .in_flight
+            .pop_front()
+            .unwrap_or_else(|| (Instant::now(), 0));
+        match connection.recv_response(&ChuniMessage::JvsFullStateRead, request_id, started) {
+            Some(response) => {
+                crate::apply_full_state(Some(response));
+                true
+            }
+            None => {
+                self.reset(connection);
+                false
+            }
+        }
+    }
+
+    /// Drop the requests in flight and reconnect, since the stream position is unknown
+    unsafe fn reset(&mut self, connection: &mut Connection) {
+        self.in_flight.clear();
+        connection.recover();
+        self.epoch = connection.epoch;
+    }
+}
+
+/// Apply a full-state response, or note that none came; offline, the inputs
+/// stay released and nothing is logged
+fn apply_state(connection: &Connection, response: Option<ChuniMessage>) {
+    if response.is_none() && connection.is_offline() {
+        return;
+    }
+    crate::apply_full_state(response);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::input;
+
+    fn reconnect_config() -> config::Config {
+        config::Config {
+            reconnect_backoff: Duration::from_millis(100),
+            reconnect_max_backoff: Duration::from_millis(350),
+            reconnect_give_up: Duration::ZERO,
+            reconnect_probe_interval: Duration::from_secs(30),
+            reconnect_jitter_percent: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Delay `failed` schedules, measured from just before the call
+    fn next_delay(reconnect: &mut Reconnect, config: &config::Config) -> Duration {
+        let before = Instant::now();
+        reconnect.failed(config);
+        reconnect.next_attempt.unwrap() - before
+    }
+
+    fn assert_close(delay: Duration, expected: Duration) {
+        assert!(
+            delay >= expected && delay < expected + Duration::from_millis(50),
+            "delay {:?}, expected {:?}",
+            delay,
+            expected
+        );
+    }
+
+    #[test]
+    fn reconnect_backs_off_exponentially_up_to_the_cap() {
+        let config = reconnect_config();
+        let mut reconnect = Reconnect::default();
+        assert!(reconnect.ready());
+
+        for expected_ms in [100, 200, 350, 350] {
+            assert_close(
+                next_delay(&mut reconnect, &config),
+                Duration::from_millis(expected_ms),
+            );
+            assert!(!reconnect.ready());
+        }
+
+        reconnect.succeeded();
+        assert!(reconnect.ready());
+        assert_close(
+            next_delay(&mut reconnect, &config),
+            Duration::from_millis(100),
+        );
+    }
+
+    #[test]
+    fn reconnect_probes_after_giving_up() {
+        let config = config::Config {
+            reconnect_give_up: Duration::from_millis(1),
+            ..reconnect_config()
+        };
+        let mut reconnect = Reconnect::default();
+        reconnect.failed(&config);
+        thread::sleep(Duration::from_millis(5));
+        assert_close(
+            next_delay(&mut reconnect, &config),
+            config.reconnect_probe_interval,
+        );
+        assert!(!reconnect.stopped);
+    }
+
+    #[test]
+    fn reconnect_stops_after_giving_up_without_probes() {
+        let config = config::Config {
+            reconnect_give_up: Duration::from_millis(1),
+            reconnect_probe_interval: Duration::ZERO,
+            ..reconnect_config()
+        };
+        let mut reconnect = Reconnect::default();
+        reconnect.failed(&config);
+        thread::sleep(Duration::from_millis(5));
+        reconnect.failed(&config);
+        assert!(reconnect.stopped);
+        assert!(!reconnect.ready());
+    }
+
+    #[test]
+    fn socket_path_settings_list_paths_in_order() {
+        assert_eq!(
+            split_socket_paths("/tmp/chuniio_proxy.sock"),
+            ["/tmp/chuniio_proxy.sock"]
+        );
+        assert_eq!(
+            split_socket_paths(" /run/user/1000/chuniio_proxy.sock ; /tmp/chuniio_proxy.sock;"),
+            [
+                "/run/user/1000/chuniio_proxy.sock",
+                "/tmp/chuniio_proxy.sock"
+            ]
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_its_spread() {
+        let delay = Duration::from_millis(1000);
+        assert_eq!(jitter(delay, 0), delay);
+        for _ in 0..100 {
+            let jittered = jitter(delay, 20);
+            assert!(
+                jittered >= Duration::from_millis(800) && jittered <= Duration::from_millis(1200),
+                "{:?}",
+                jittered
+            );
+        }
+    }
+
+    /// Stand-in for the proxy: takes one full-state request per connection and
+    /// answers it with the next of `responses`, or not at all for `None`, then
+    /// hangs up
+    fn mock_proxy(responses: Vec<Option<ChuniMessage>>) -> (u16, JoinHandle<()>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 1];
+                stream.read_exact(&mut request).unwrap();
+                assert_eq!(request[0], ChuniMessage::JvsFullStateRead.message_type());
+                if let Some(response) = response {
+                    let mut frame = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+                    let len = response.serialize_into(&mut frame);
+                    stream.write_all(&frame[..len]).unwrap();
+                }
+            }
+        });
+        (port, server)
+    }
+
+    fn full_state(opbtn: u8, beams: u8, cell: u8) -> ChuniMessage {
+        ChuniMessage::JvsFullStateReadResponse {
+            opbtn,
+            beams,
+            pressure: [cell; 32],
+            coin_counter: 0,
+        }
+    }
+
+    /// Buttons, beams and slider as the game would read them
+    fn game_state() -> (u8, u8, [u8; 32]) {
+        let state = crate::GLOBAL_STATE.lock().unwrap();
+        (
+            state.jvs_state.opbtn,
+            state.jvs_state.beams,
+            state.slider_pressure,
+        )
+    }
+
+    #[test]
+    fn polls_mock_proxy_and_expires_stale_state_across_reconnect() {
+        let _globals = crate::lock_globals();
+        let (port, server) = mock_proxy(vec![
+            Some(full_state(0x01, 0x05, 0x40)),
+            None,
+            Some(full_state(0x02, 0x0A, 0x90)),
+        ]);
+        let stale_after = Duration::from_millis(50);
+        config::set_for_test(config::Config {
+            proxy_host: "127.0.0.1".to_string(),
+            proxy_port: port,
+            stale_state_timeout: stale_after,
+            ..Default::default()
+        });
+
+        let mut connection = Connection::new();
+        let mut pipeline = StatePipeline::new(1);
+        unsafe {
+            assert!(connection.connect());
+            pipeline.poll(&mut connection);
+        }
+        assert_eq!(
+            game_state(),
+            (
+                input::map_opbtn(0x01),
+                input::map_beams(0x05),
+                input::map_pressure([0x40; 32])
+            )
+        );
+        let first_epoch = connection.epoch();
+
+        // The proxy hung up, and the next connection gets no answer either:
+        // once the state is older than the timeout it is released
+        thread::sleep(stale_after * 2);
+        unsafe { pipeline.poll(&mut connection) };
+        assert_ne!(connection.epoch(), first_epoch);
+        assert_eq!(game_state(), (0, 0, [0; 32]));
+
+        // A new connection picks up where the proxy is now
+        unsafe { pipeline.poll(&mut connection) };
+        assert_eq!(
+            game_state(),
+            (
+                input::map_opbtn(0x02),
+                input::map_beams(0x0A),
+                input::map_pressure([0x90; 32])
+            )
+        );
+        server.join().unwrap();
+        config::set_for_test(config::Config::default());
+    }
+}
@@ -0,0 +1,218 @@
+//! Keyboard input
+//!
+//! Keys on the game PC that work alongside the proxy, so the operator menu and
+//! credits stay usable during bring-up or while the proxy is offline. Keys are
+//! read with `GetAsyncKeyState` whenever the game polls, like segatools' own
+//! keyboard input, and only count while the game window is focused.
+//!
+//! Two keyboard rows can also play the slider: by default `QWERTYUI` covers the
+//! top row of cells and `ASDFGHJK` the bottom row, each key two columns wide,
+//! and key presses are merged with the proxy's pressure. Keys can likewise
+//! block the IR beams for air notes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use windows::Win32::UI::Input::KeyboardAndMouse::GetAsyncKeyState;
+
+use crate::{
+    config, focus,
+    input::{SLIDER_CELLS, TOUCH_PRESSURE},
+};
+
+/// Key names accepted besides letters, digits (`0`-`9`), `F1`-`F24` and
+/// `NUMPAD0`-`NUMPAD9`
+const NAMED_KEYS: [(&str, u8); 29] = [
+    ("SPACE", 0x20),
+    ("ENTER", 0x0D),
+    ("ESC", 0x1B),
+    ("TAB", 0x09),
+    ("BACKSPACE", 0x08),
+    ("SHIFT", 0x10),
+    ("CTRL", 0x11),
+    ("ALT", 0x12),
+    ("INSERT", 0x2D),
+    ("DELETE", 0x2E),
+    ("HOME", 0x24),
+    ("END", 0x23),
+    ("PAGEUP", 0x21),
+    ("PAGEDOWN", 0x22),
+    ("LEFT", 0x25),
+    ("UP", 0x26),
+    ("RIGHT", 0x27),
+    ("DOWN", 0x28),
+    ("SEMICOLON", 0xBA),
+    ("EQUALS", 0xBB),
+    ("COMMA", 0xBC),
+    ("MINUS", 0xBD),
+    ("PERIOD", 0xBE),
+    ("SLASH", 0xBF),
+    ("BACKQUOTE", 0xC0),
+    ("LBRACKET", 0xDB),
+    ("BACKSLASH", 0xDC),
+    ("RBRACKET", 0xDD),
+    ("QUOTE", 0xDE),
+];
+
+/// Virtual-key code of the first function key
+const VK_F1: u8 = 0x70;
+
+/// Number of function keys
+const FUNCTION_KEYS: u8 = 24;
+
+/// Windows virtual key, or none
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualKey(u8);
+
+impl VirtualKey {
+    /// No key; never pressed
+    pub const NONE: Self = Self(0);
+
+    /// Function keys, the defaults for the operator buttons and coin
+    pub const F1: Self = Self(VK_F1);
+    pub const F2: Self = Self(VK_F1 + 1);
+    pub const F3: Self = Self(VK_F1 + 2);
+
+    /// Parse a key: a letter, a digit, `F1`-`F24`, a name such as `SPACE` or
+    /// `SEMICOLON`, a virtual-key code in hex (`0x70`), or `none`
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_uppercase();
+        if value.is_empty() || value == "NONE" {
+            return Some(Self::NONE);
+        }
+        if let [c] = value.as_bytes() {
+            if c.is_ascii_alphanumeric() {
+                return Some(Self(*c));
+            }
+        }
+        if let Some(hex) = value.strip_prefix("0X") {
+            return u8::from_str_radix(hex, 16).ok().map(Self);
+        }
+        if let Some(number) = value.strip_prefix('F').and_then(|n| n.parse::<u8>().ok()) {
+            return (1..=FUNCTION_KEYS)
+                .contains(&number)
+                .then(|| Self(VK_F1 + number - 1));
+        }
+        if let Some(digit) = value
+            .strip_prefix("NUMPAD")
+            .and_then(|n| n.parse::<u8>().ok())
+        {
+            return (digit <= 9).then(|| Self(0x60 + digit));
+        }
+        NAMED_KEYS
+            .iter()
+            .find(|(name, _)| *name == value)
+            .map(|&(_, code)| Self(code))
+    }
+
+    /// Key as accepted by `parse`
+    pub fn name(self) -> String {
+        match self.0 {
+            0 => "none".to_string(),
+            c if c.is_ascii_uppercase() || c.is_ascii_digit() => (c as char).to_string(),
+            c if (VK_F1..VK_F1 + FUNCTION_KEYS).contains(&c) => format!("F{}", c - VK_F1 + 1),
+            c if (0x60..=0x69).contains(&c) => format!("NUMPAD{}", c - 0x60),
+            c => NAMED_KEYS
+                .iter()
+                .find(|(_, code)| *code == c)
+                .map_or_else(|| format!("0x{:02X}", c), |(name, _)| name.to_string()),
+        }
+    }
+
+    /// Whether the key is held down right now
+    pub fn is_pressed(self) -> bool {
+        self.0 != 0 && unsafe { GetAsyncKeyState(i32::from(self.0)) } as u16 & 0x8000 != 0
+    }
+}
+
+/// Whether the coin key was down at the previous check
+static COIN_KEY_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Operator button bits (TEST, SERVICE) for the configured keys held down
+pub fn opbtn() -> u8 {
+    if !focus::is_focused() {
+        return 0;
+    }
+    let config = config::get();
+    u8::from(config.key_test.is_pressed()) | u8::from(config.key_service.is_pressed()) << 1
+}
+
+/// Whether the coin key has been pressed since the previous check
+pub fn coin_pressed() -> bool {
+    let down = focus::is_focused() && config::get().key_coin.is_pressed();
+    !COIN_KEY_DOWN.swap(down, Ordering::Relaxed) && down
+}
+
+/// One key per slot, e.g. per slider cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMap<const N: usize>([VirtualKey; N]);
+
+impl<const N: usize> KeyMap<N> {
+    /// No keys
+    pub const NONE: Self = Self([VirtualKey::NONE; N]);
+
+    /// Parse one comma-separated key per slot (see `VirtualKey::parse`), or a
+    /// single key for every slot
+    pub fn parse(value: &str) -> Option<Self> {
+        let keys = value
+            .split(',')
+            .map(VirtualKey::parse)
+            .collect::<Option<Vec<_>>>()?;
+        match keys[..] {
+            [key] => Some(Self([key; N])),
+            _ => keys.try_into().ok().map(Self),
+        }
+    }
+
+    /// Map as accepted by `parse`
+    pub fn name(&self) -> String {
+        match self.0[..] {
+            [first, ..] if self.0.iter().all(|&key| key == first) => first.name(),
+            _ => self.0.map(VirtualKey::name).join(","),
+        }
+    }
+}
+
+impl KeyMap<SLIDER_CELLS> {
+    /// `QWERTYUI` over the top row and `ASDFGHJK` over the bottom row; cells
+    /// alternate top and bottom starting from the right
+    pub const SLIDER_ROWS: Self = {
+        const TOP: &[u8; 8] = b"QWERTYUI";
+        const BOTTOM: &[u8; 8] = b"ASDFGHJK";
+        let mut keys = [VirtualKey::NONE; SLIDER_CELLS];
+        let mut cell = 0;
+        while cell < SLIDER_CELLS {
+            let key = 7 - cell / 4;
+            keys[cell] = VirtualKey(if cell % 2 == 0 { TOP[key] } else { BOTTOM[key] });
+            cell += 1;
+        }
+        Self(keys)
+    };
+}
+
+/// IR beam bits for the configured air keys held down
+pub fn beams() -> u8 {
+    let keys = &config::get().key_air;
+    if *keys == KeyMap::NONE || !focus::is_focused() {
+        return 0;
+    }
+    keys.0
+        .iter()
+        .enumerate()
+        .filter(|(_, key)| key.is_pressed())
+        .fold(0, |beams, (beam, _)| beams | 1 << beam)
+}
+
+/// Slider pressure from the keyboard, `None` unless the keyboard slider is
+/// enabled and the game window is focused
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    let config = config::get();
+    if !config.keyboard_slider || !focus::is_focused() {
+        return None;
+    }
+    Some(
+        config
+            .key_slider
+            .0
+            .map(|key| if key.is_pressed() { TOUCH_PRESSURE } else { 0 }),
+    )
+}
@@ -0,0 +1,159 @@
+//! Request latency histograms
+//!
+//! Every request/response round trip to the proxy is recorded into a per-request
+//! histogram, and p50/p99/max are logged periodically so missed notes can be
+//! attributed to the bridge (or ruled out).
+//!
+//! The histograms are HDR-style log-linear: values below 16µs get their own
+//! bucket, above that every power of two is split into 16 sub-buckets, which
+//! keeps the relative error under ~6% from 1µs up to ~16s with fixed memory and
+//! lock-free recording.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tracing::info;
+
+use crate::{protocol::ChuniMessage, timing::Periodic};
+
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Octaves above the linear range (16µs .. 2^24µs ≈ 16s)
+const OCTAVES: usize = 20;
+const BUCKET_COUNT: usize = SUB_BUCKETS + OCTAVES * SUB_BUCKETS;
+
+/// Lock-free latency histogram with microsecond resolution
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKET_COUNT],
+    max_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKET_COUNT],
+            max_us: AtomicU64::new(0),
+        }
+    }
+
+    /// Record one sample
+    pub fn record(&self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[bucket_index(us)].fetch_add(1, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the current counts and reset the histogram
+    fn drain(&self) -> Summary {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.swap(0, Ordering::Relaxed))
+            .collect();
+        let max_us = self.max_us.swap(0, Ordering::Relaxed);
+        let total: u64 = counts.iter().sum();
+
+        let percentile = |p: f64| {
+            let target = ((total as f64) * p).ceil().max(1.0) as u64;
+            let mut seen = 0;
+            for (index, count) in counts.iter().enumerate() {
+                seen += count;
+                if seen >= target {
+                    return bucket_upper_bound(index).min(max_us);
+                }
+            }
+            max_us
+        };
+
+        Summary {
+            count: total,
+            p50_us: percentile(0.50),
+            p99_us: percentile(0.99),
+            max_us,
+        }
+    }
+}
+
+struct Summary {
+    count: u64,
+    p50_us: u64,
+    p99_us: u64,
+    max_us: u64,
+}
+
+fn bucket_index(us: u64) -> usize {
+    if us < SUB_BUCKETS as u64 {
+        return us as usize;
+    }
+
+    let msb = 63 - us.leading_zeros();
+    let octave = (msb - SUB_BUCKET_BITS) as usize;
+    if octave >= OCTAVES {
+        return BUCKET_COUNT - 1;
+    }
+
+    let sub_bucket = ((us >> (msb - SUB_BUCKET_BITS)) as usize) & (SUB_BUCKETS - 1);
+    SUB_BUCKETS + octave * SUB_BUCKETS + sub_bucket
+}
+
+/// Largest value that falls into bucket `index`
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+
+    let octave = (index - SUB_BUCKETS) / SUB_BUCKETS;
+    let sub_bucket = ((index - SUB_BUCKETS) % SUB_BUCKETS) as u64;
+    let lower = (SUB_BUCKETS as u64 + sub_bucket) << octave;
+    lower + (1u64 << octave) - 1
+}
+
+/// Request types that get a latency histogram
+const TRACKED_REQUESTS: [&str; 5] = [
+    "JvsPoll",
+    "CoinCounterRead",
+    "SliderStateRead",
+    "Ping",
+    "JvsFullStateRead",
+];
+
+static HISTOGRAMS: [LatencyHistogram; TRACKED_REQUESTS.len()] =
+    [const { LatencyHistogram::new() }; TRACKED_REQUESTS.len()];
+
+/// Histogram for a request message, if its latency is tracked
+pub fn histogram_for(message: &ChuniMessage) -> Option<&'static LatencyHistogram> {
+    let index = match message {
+        ChuniMessage::JvsPoll => 0,
+        ChuniMessage::CoinCounterRead => 1,
+        ChuniMessage::SliderStateRead => 2,
+        ChuniMessage::Ping => 3,
+        ChuniMessage::JvsFullStateRead => 4,
+        _ => return None,
+    };
+    Some(&HISTOGRAMS[index])
+}
+
+static REPORT: Periodic = Periodic::new();
+
+/// Log a latency summary if the configured report interval has elapsed
+///
+/// Cheap enough to call after every request; only one caller per interval
+/// actually writes the report.
+pub fn maybe_report() {
+    if !REPORT.ready(crate::config::get().latency_report_interval) {
+        return;
+    }
+
+    for (name, histogram) in TRACKED_REQUESTS.iter().zip(HISTOGRAMS.iter()) {
+        let summary = histogram.drain();
+        if summary.count == 0 {
+            continue;
+        }
+        info!(
+            "Latency {}: n={} p50={}us p99={}us max={}us",
+            name, summary.count, summary.p50_us, summary.p99_us, summary.max_us
+        );
+    }
+}
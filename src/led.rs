@@ -0,0 +1,658 @@
+//! LED output
+//!
+//! LED frames submitted by the game are copied once into a reusable per-board
+//! buffer and forwarded to the chuniio proxy by the IO thread, so
+//! `chuni_io_led_set_colors` never touches the socket.
+//!
+//! The queue is bounded: each board holds at most one frame waiting in its slot
+//! and one frame held by the IO thread. When the proxy stalls, newer frames
+//! replace older undelivered ones (drop-oldest) instead of accumulating and
+//! replaying stale lighting in a burst once the proxy catches up. Every replaced
+//! frame is counted in [`STATS`](crate::stats::STATS).
+//!
+//! Frames can also go out as UDP datagrams, one message each, instead of over
+//! the proxy connection. A lost frame is simply replaced by the next, and a
+//! stalled stream can then never hold lighting back; a datagram that can't
+//! be sent at once is dropped.
+//!
+//! While the proxy is offline, datagrams can carry a slow ambient pattern
+//! drawn here instead of the game's frames, and the game's latest frames go
+//! out again once it is back. This is for a UDP LED listener on the proxy's
+//! host that outlives the proxy itself; without datagrams there is nowhere to
+//! send the pattern, and local LED outputs keep showing the game's frames.
+//! With the pattern off, datagrams keep carrying the game's frames while the
+//! proxy is offline.
+
+use std::{
+    io, mem,
+    net::UdpSocket,
+    sync::{Mutex, Once},
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::{
+    io::Connection,
+    protocol::ChuniMessage,
+    stats::{self, STATS},
+    ERROR_LOG_INTERVAL_SECS,
+};
+
+/// Number of LED boards exposed through the chuniio API
+pub const LED_BOARD_COUNT: usize = 3;
+
+/// RGB data size in bytes for each LED board
+/// Board 0: 53 LEDs * 3 bytes = 159 bytes (billboard left)
+/// Board 1: 63 LEDs * 3 bytes = 189 bytes (billboard right)
+/// Board 2: 31 LEDs * 3 bytes = 93 bytes (slider)
+pub const LED_BOARD_SIZES: [usize; LED_BOARD_COUNT] = [159, 189, 93];
+
+/// Byte order an LED controller expects, as the position of red, green and blue
+/// in each output pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorOrder([usize; 3]);
+
+impl ColorOrder {
+    /// Order of the frames the game submits
+    pub const RGB: Self = Self([0, 1, 2]);
+
+    /// Parse an order name made of `r`, `g` and `b` once each (`rgb`, `grb`, ...)
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        if value.len() != 3 {
+            return None;
+        }
+        let mut order = [0; 3];
+        let mut seen = [false; 3];
+        for (slot, channel) in order.iter_mut().zip(value.chars()) {
+            let index = "rgb".find(channel)?;
+            if mem::replace(&mut seen[index], true) {
+                return None;
+            }
+            *slot = index;
+        }
+        Some(Self(order))
+    }
+
+    /// Order name as accepted by `parse`
+    pub fn name(self) -> String {
+        self.0.iter().map(|&index| ['r', 'g', 'b'][index]).collect()
+    }
+
+    /// Reorder the channels of every pixel in `frame` in place
+    fn apply(self, frame: &mut [u8]) {
+        if self == Self::RGB {
+            return;
+        }
+        for pixel in frame.chunks_exact_mut(3) {
+            let rgb = [pixel[0], pixel[1], pixel[2]];
+            for (out, &index) in pixel.iter_mut().zip(self.0.iter()) {
+                *out = rgb[index];
+            }
+        }
+    }
+}
+
+/// Lookup table mapping each channel value to its output value with brightness
+/// scaling (`brightness_percent`) and gamma correction applied
+fn level_curve(brightness_percent: u8, gamma: f32) -> [u8; 256] {
+    let scale = f32::from(brightness_percent.min(100)) / 100.0;
+    std::array::from_fn(|level| {
+        let normalized = level as f32 / 255.0;
+        (normalized.powf(gamma) * scale * 255.0).round() as u8
+    })
+}
+
+/// Latest frame submitted for a board
+struct LedSlot {
+    /// RGB data, reused between frames
+    frame: Vec<u8>,
+    /// Whether `frame` has not been picked up by the IO thread yet
+    pending: bool,
+}
+
+impl LedSlot {
+    const fn new() -> Self {
+        Self {
+            frame: Vec::new(),
+            pending: false,
+        }
+    }
+}
+
+static LED_SLOTS: Mutex<[LedSlot; LED_BOARD_COUNT]> =
+    Mutex::new([LedSlot::new(), LedSlot::new(), LedSlot::new()]);
+
+static LED_BUFFERS_INIT: Once = Once::new();
+
+/// Allocate the per-board buffers and make sure the IO thread is running
+pub fn init() {
+    LED_BUFFERS_INIT.call_once(|| {
+        if let Ok(mut slots) = LED_SLOTS.lock() {
+            for (slot, &size) in slots.iter_mut().zip(LED_BOARD_SIZES.iter()) {
+                slot.frame = vec![0u8; size];
+            }
+        }
+    });
+    crate::io::start();
+}
+
+/// Copy an RGB frame into the board's pending buffer and wake the IO thread
+///
+/// Returns false without copying if the buffers are currently locked, so the
+/// game thread is never blocked by the IO thread.
+pub unsafe fn submit_frame(board: usize, rgb: *const u8) -> bool {
+    // Frames for disabled boards are never copied or sent
+    if !crate::config::get().led_enabled[board] {
+        return true;
+    }
+
+    let Ok(mut slots) = LED_SLOTS.try_lock() else {
+        return false;
+    };
+
+    let slot = &mut slots[board];
+    if slot.pending {
+        stats::increment(&STATS.led_frames_dropped);
+    }
+    slot.frame.clear();
+    slot.frame
+        .extend_from_slice(std::slice::from_raw_parts(rgb, LED_BOARD_SIZES[board]));
+    slot.pending = true;
+    drop(slots);
+
+    crate::io::wake();
+    true
+}
+
+/// Minimum time between two sends to `board`, if rate limited
+fn min_send_interval(board: usize) -> Option<Duration> {
+    match crate::config::get().led_max_rate_hz[board] {
+        0 => None,
+        hz => Some(Duration::from_secs(1) / hz),
+    }
+}
+
+/// Time between two frames of the offline ambient pattern
+const AMBIENT_FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time the ambient pattern takes to drift once around the color wheel
+const AMBIENT_PERIOD: Duration = Duration::from_secs(20);
+
+/// Brightest channel level of the ambient pattern
+const AMBIENT_LEVEL: f32 = 48.0;
+
+/// Fill `frame` with the ambient pattern at `phase`, in turns: a dim color
+/// wave along the board
+fn ambient_frame(frame: &mut [u8], phase: f32) {
+    let pixels = (frame.len() / 3).max(1) as f32;
+    for (index, pixel) in frame.chunks_exact_mut(3).enumerate() {
+        let hue = phase + index as f32 / pixels;
+        for (channel, level) in pixel.iter_mut().enumerate() {
+            // Red, green and blue peak a third of a turn apart
+            let angle = (hue - channel as f32 / 3.0) * std::f32::consts::TAU;
+            *level = ((angle.cos() + 1.0) / 2.0 * AMBIENT_LEVEL).round() as u8;
+        }
+    }
+}
+
+/// Host LED datagrams go to when the proxy is not reached over TCP
+const LOCAL_HOST: &str = "127.0.0.1";
+
+/// Socket sending LED frames as datagrams
+struct LedDatagrams {
+    /// Host and port it sends to
+    target: (String, u16),
+    socket: UdpSocket,
+}
+
+impl LedDatagrams {
+    /// Open a non-blocking socket sending to `host` and `port`
+    fn open(host: &str, port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect((host, port))?;
+        socket.set_nonblocking(true)?;
+        info!("Sending LED frames as datagrams to {}:{}", host, port);
+        Ok(Self {
+            target: (host.to_string(), port),
+            socket,
+        })
+    }
+
+    /// Send one message, header and data, as a datagram; false if it was
+    /// dropped
+    fn send(&self, header: &[u8], data: &[u8]) -> bool {
+        let mut datagram = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+        let len = header.len() + data.len();
+        datagram[..header.len()].copy_from_slice(header);
+        datagram[header.len()..len].copy_from_slice(data);
+        self.socket.send(&datagram[..len]).is_ok()
+    }
+}
+
+/// LED frames owned by the IO thread between submission and sending
+///
+/// A frame that arrives while its board is still rate limited replaces the
+/// frame waiting to be sent, so only the latest frame goes out.
+pub struct LedOutput {
+    /// Frames owned by the IO thread; swapped with the shared slots so that
+    /// neither side allocates once both buffers have been sized
+    frames: [Vec<u8>; LED_BOARD_COUNT],
+    /// Whether `frames[board]` holds a frame that has not been sent yet
+    held: [bool; LED_BOARD_COUNT],
+    /// Earliest time each board may be sent to again
+    next_send: [Option<Instant>; LED_BOARD_COUNT],
+    /// Last frame successfully sent per board on the current connection, used to
+    /// suppress byte-identical resubmissions (e.g. static billboard content)
+    last_sent: [Vec<u8>; LED_BOARD_COUNT],
+    /// Connection `last_sent` refers to
+    last_epoch: u32,
+    /// Brightness and gamma lookup table per board, if not the identity
+    curves: [Option<[u8; 256]>; LED_BOARD_COUNT],
+    /// Configuration generation `curves` was built from
+    curves_generation: Option<u32>,
+    /// Send black frames instead of the game's frames
+    blanked: bool,
+    /// Datagram socket frames go out on instead of the connection, if enabled
+    datagrams: Option<LedDatagrams>,
+    /// When the proxy went offline, while it is
+    offline_since: Option<Instant>,
+    /// Ambient pattern frames, reused between frames
+    ambient: [Vec<u8>; LED_BOARD_COUNT],
+    /// When the next ambient pattern frame is due
+    next_ambient: Instant,
+}
+
+impl LedOutput {
+    pub fn new() -> Self {
+        Self {
+            frames: Default::default(),
+            held: [false; LED_BOARD_COUNT],
+            next_send: [None; LED_BOARD_COUNT],
+            last_sent: Default::default(),
+            last_epoch: 0,
+            curves: [None; LED_BOARD_COUNT],
+            curves_generation: None,
+            blanked: false,
+            datagrams: None,
+            offline_since: None,
+            ambient: Default::default(),
+            next_ambient: Instant::now(),
+        }
+    }
+
+    /// Replace the game's frames with black ones (while `blanked`) or go back to
+    /// forwarding them
+    pub fn set_blanked(&mut self, blanked: bool) {
+        self.blanked = blanked;
+    }
+
+    /// Switch to the ambient pattern while the proxy is offline, and back to
+    /// the game's frames once it is not
+    pub fn set_offline(&mut self, offline: bool) {
+        if offline == self.offline_since.is_some() {
+            return;
+        }
+        if offline {
+            self.offline_since = Some(Instant::now());
+            self.next_ambient = Instant::now();
+            return;
+        }
+        self.offline_since = None;
+        // The ambient pattern replaced whatever was shown, so every board's
+        // latest frame goes out again
+        self.last_sent.iter_mut().for_each(Vec::clear);
+        for (held, frame) in self.held.iter_mut().zip(&self.frames) {
+            *held = !frame.is_empty();
+        }
+    }
+
+    /// Take pending frames out of the shared slots and send every frame that is
+    /// due, returning when the earliest rate-limited frame will be due
+    pub fn flush(&mut self, connection: &mut Connection) -> Option<Instant> {
+        let config = crate::config::get();
+        if self.curves_generation != Some(crate::config::generation()) {
+            self.curves_generation = Some(crate::config::generation());
+            for (board, curve) in self.curves.iter_mut().enumerate() {
+                let (brightness, gamma) = (config.led_brightness[board], config.led_gamma[board]);
+                *curve = (brightness < 100 || gamma != 1.0).then(|| level_curve(brightness, gamma));
+            }
+        }
+
+        if let Ok(mut slots) = LED_SLOTS.lock() {
+            for (board, slot) in slots.iter_mut().enumerate() {
+                if slot.pending {
+                    if self.held[board] {
+                        stats::increment(&STATS.led_frames_dropped);
+                    }
+                    mem::swap(&mut slot.frame, &mut self.frames[board]);
+                    slot.pending = false;
+                    self.held[board] = true;
+                    let frame = &mut self.frames[board];
+                    config.led_color_order[board].apply(frame);
+                    if let Some(curve) = &self.curves[board] {
+                        frame
+                            .iter_mut()
+                            .for_each(|level| *level = curve[*level as usize]);
+                    }
+                }
+            }
+        }
+
+        // While blanked every board shows black; byte-identical resubmissions are
+        // suppressed below, so black is sent once per board
+        if self.blanked {
+            for (board, frame) in self.frames.iter_mut().enumerate() {
+                frame.resize(LED_BOARD_SIZES[board], 0);
+                frame.fill(0);
+                self.held[board] = true;
+            }
+        }
+
+        self.update_datagrams(config);
+
+        // While offline the ambient pattern replaces the game's frames, which
+        // keep being taken in to go out once the proxy is back. Without it,
+        // datagrams keep carrying the game's frames; the connection is down
+        // and holds them instead.
+        if let Some(since) = self.offline_since.filter(|_| !self.blanked) {
+            self.datagrams.as_ref()?;
+            if config.offline_ambient {
+                return self.flush_ambient(since, config);
+            }
+        }
+
+        // A new connection may be a restarted proxy that has lost its LED state
+        if connection.epoch() != self.last_epoch {
+            self.last_epoch = connection.epoch();
+            self.last_sent.iter_mut().for_each(Vec::clear);
+        }
+
+        let now = Instant::now();
+        let enabled = config.led_enabled;
+        let mut due = [false; LED_BOARD_COUNT];
+        for (board, due) in due.iter_mut().enumerate() {
+            // A board disabled by a config reload, or that the proxy doesn't
+            // support, drops the frame it was holding
+            if !enabled[board] || !connection.capabilities().led_board(board) {
+                self.held[board] = false;
+            }
+            if !self.held[board] || self.next_send[board].is_some_and(|t| t > now) {
+                continue;
+            }
+            self.held[board] = false;
+            *due = self.frames[board] != self.last_sent[board];
+            self.next_send[board] = min_send_interval(board).map(|interval| now + interval);
+        }
+
+        // Header and RGB data go out in one vectored send, straight from the frame
+        // buffers; with coalescing every due board shares a single send
+        let coalesce = config.led_coalesce;
+        let mut batch = [0usize; LED_BOARD_COUNT];
+        let mut batch_len = 0;
+        for board in (0..LED_BOARD_COUNT).filter(|&board| due[board]) {
+            batch[batch_len] = board;
+            batch_len += 1;
+            let last_due = !due[board + 1..].contains(&true);
+            if !coalesce || last_due {
+                self.send_batch(&batch[..batch_len], connection);
+                batch_len = 0;
+            }
+        }
+
+        (0..LED_BOARD_COUNT)
+            .filter(|&board| self.held[board])
+            .filter_map(|board| self.next_send[board])
+            .min()
+    }
+
+    /// Send the ambient pattern as datagrams, returning when its next frame is
+    /// due
+    fn flush_ambient(&mut self, since: Instant, config: &crate::config::Config) -> Option<Instant> {
+        let datagrams = self.datagrams.as_ref()?;
+        let now = Instant::now();
+        if now < self.next_ambient {
+            return Some(self.next_ambient);
+        }
+        self.next_ambient = now + AMBIENT_FRAME_INTERVAL;
+
+        let phase = (now - since).as_secs_f32() / AMBIENT_PERIOD.as_secs_f32();
+        for (board, frame) in self.ambient.iter_mut().enumerate() {
+            if !config.led_enabled[board] {
+                continue;
+            }
+            frame.resize(LED_BOARD_SIZES[board], 0);
+            ambient_frame(frame, phase);
+            config.led_color_order[board].apply(frame);
+            if let Some(curve) = &self.curves[board] {
+                frame
+                    .iter_mut()
+                    .for_each(|level| *level = curve[*level as usize]);
+            }
+            let header = ChuniMessage::led_update_header(board as u8, frame.len());
+            if datagrams.send(&header, frame) {
+                stats::record_sent(ChuniMessage::LED_UPDATE);
+            } else {
+                stats::increment(&STATS.led_frames_dropped);
+            }
+        }
+        Some(self.next_ambient)
+    }
+
+    /// Open, replace or close the datagram socket to match the configuration
+    fn update_datagrams(&mut self, config: &crate::config::Config) {
+        let target = (config.led_udp_port != 0).then(|| {
+            let host = match config.proxy_host.as_str() {
+                "" => LOCAL_HOST,
+                host => host,
+            };
+            (host, config.led_udp_port)
+        });
+        let current = self
+            .datagrams
+            .as_ref()
+            .map(|datagrams| (datagrams.target.0.as_str(), datagrams.target.1));
+        if current == target {
+            return;
+        }
+        self.datagrams = target.and_then(|(host, port)| {
+            LedDatagrams::open(host, port)
+                .inspect_err(|e| {
+                    log_throttled!(
+                        ERROR_LOG_INTERVAL_SECS,
+                        warn!(
+                            "Cannot send LED datagrams to {}:{}: {}, using the connection",
+                            host, port, e
+                        )
+                    )
+                })
+                .ok()
+        });
+    }
+
+    /// Send the frames of `boards` in a single vectored send, or as one
+    /// datagram each
+    fn send_batch(&mut self, boards: &[usize], connection: &mut Connection) {
+        let headers: [[u8; 3]; LED_BOARD_COUNT] = std::array::from_fn(|board| {
+            ChuniMessage::led_update_header(board as u8, self.frames[board].len())
+        });
+        if let Some(datagrams) = &self.datagrams {
+            for &board in boards {
+                if !datagrams.send(&headers[board], &self.frames[board]) {
+                    stats::increment(&STATS.led_frames_dropped);
+                    continue;
+                }
+                stats::record_sent(ChuniMessage::LED_UPDATE);
+                self.last_sent[board].clear();
+                self.last_sent[board].extend_from_slice(&self.frames[board]);
+            }
+            return;
+        }
+        let mut messages: [[&[u8]; 2]; LED_BOARD_COUNT] = [[&[]; 2]; LED_BOARD_COUNT];
+        for (message, &board) in messages.iter_mut().zip(boards) {
+            *message = [&headers[board], &self.frames[board]];
+        }
+
+        if !connection.send_messages(&messages[..boards.len()]) {
+            return;
+        }
+        for &board in boards {
+            stats::record_sent(ChuniMessage::LED_UPDATE);
+            self.last_sent[board].clear();
+            self.last_sent[board].extend_from_slice(&self.frames[board]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{self, Config};
+
+    #[test]
+    fn color_order_reorders_every_whole_pixel() {
+        // (order, frame, reordered)
+        let cases: [(&str, &[u8], &[u8]); 4] = [
+            ("rgb", &[1, 2, 3, 4, 5, 6], &[1, 2, 3, 4, 5, 6]),
+            ("grb", &[1, 2, 3, 4, 5, 6], &[2, 1, 3, 5, 4, 6]),
+            ("BGR", &[1, 2, 3, 4, 5, 6], &[3, 2, 1, 6, 5, 4]),
+            // A trailing partial pixel is left alone
+            ("brg", &[1, 2, 3, 4], &[3, 1, 2, 4]),
+        ];
+        for (name, frame, reordered) in cases {
+            let order = ColorOrder::parse(name).unwrap();
+            assert_eq!(ColorOrder::parse(&order.name()), Some(order));
+            let mut frame = frame.to_vec();
+            order.apply(&mut frame);
+            assert_eq!(frame, reordered, "{}", name);
+        }
+
+        for invalid in ["", "rg", "rgg", "rgbw", "rgx"] {
+            assert_eq!(ColorOrder::parse(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn level_curve_keeps_black_and_scales_full_brightness() {
+        // (brightness, gamma, level 0, level 128, level 255)
+        let cases = [
+            (100, 1.0, 0, 128, 255),
+            (50, 1.0, 0, 64, 128),
+            (0, 1.0, 0, 0, 0),
+            (100, 2.2, 0, 56, 255),
+            (25, 2.2, 0, 14, 64),
+            // Brightness past 100% is capped
+            (150, 1.0, 0, 128, 255),
+        ];
+        for (brightness, gamma, black, middle, full) in cases {
+            let curve = level_curve(brightness, gamma);
+            assert_eq!(
+                (curve[0], curve[128], curve[255]),
+                (black, middle, full),
+                "brightness {} gamma {}",
+                brightness,
+                gamma
+            );
+        }
+    }
+
+    /// A listener for LED datagrams, with `output` sending frames for board 0
+    /// to it at most `max_rate_hz` times a second (0 for no limit)
+    fn datagram_output(max_rate_hz: u32) -> (UdpSocket, LedOutput) {
+        let listener = UdpSocket::bind((LOCAL_HOST, 0)).unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        config::set_for_test(Config {
+            led_enabled: [true, false, false],
+            led_max_rate_hz: [max_rate_hz, 0, 0],
+            led_udp_port: listener.local_addr().unwrap().port(),
+            ..Config::default()
+        });
+        (listener, LedOutput::new())
+    }
+
+    /// A frame for board 0 with every channel at `level`
+    fn submit(level: u8) {
+        let frame = [level; LED_BOARD_SIZES[0]];
+        assert!(unsafe { submit_frame(0, frame.as_ptr()) });
+    }
+
+    /// The level of the next frame received, `None` if none comes
+    fn received(listener: &UdpSocket) -> Option<u8> {
+        let mut datagram = [0; ChuniMessage::MAX_SERIALIZED_LEN];
+        let len = listener.recv(&mut datagram).ok()?;
+        assert_eq!(
+            datagram[..3],
+            ChuniMessage::led_update_header(0, LED_BOARD_SIZES[0])
+        );
+        assert_eq!(len, 3 + LED_BOARD_SIZES[0]);
+        Some(datagram[3])
+    }
+
+    #[test]
+    fn identical_frame_is_not_resent() {
+        let _globals = crate::lock_globals();
+        let (listener, mut output) = datagram_output(0);
+        let mut connection = Connection::new();
+
+        submit(10);
+        output.flush(&mut connection);
+        assert_eq!(received(&listener), Some(10));
+
+        submit(10);
+        output.flush(&mut connection);
+        assert_eq!(received(&listener), None);
+
+        submit(20);
+        output.flush(&mut connection);
+        assert_eq!(received(&listener), Some(20));
+
+        config::set_for_test(Config::default());
+    }
+
+    #[test]
+    fn game_frames_keep_going_out_as_datagrams_while_offline_without_ambient() {
+        let _globals = crate::lock_globals();
+        let (listener, mut output) = datagram_output(0);
+        assert!(!config::get().offline_ambient);
+        let mut connection = Connection::new();
+        output.set_offline(true);
+
+        submit(10);
+        output.flush(&mut connection);
+        assert_eq!(received(&listener), Some(10));
+
+        submit(20);
+        output.flush(&mut connection);
+        assert_eq!(received(&listener), Some(20));
+
+        config::set_for_test(Config::default());
+    }
+
+    #[test]
+    fn latest_frame_wins_inside_the_rate_window() {
+        let _globals = crate::lock_globals();
+        let (listener, mut output) = datagram_output(10);
+        let mut connection = Connection::new();
+
+        submit(10);
+        assert!(output.flush(&mut connection).is_none());
+        assert_eq!(received(&listener), Some(10));
+
+        // Both arrive before the board may be sent to again
+        submit(20);
+        output.flush(&mut connection);
+        submit(30);
+        let due = output.flush(&mut connection).unwrap();
+        assert_eq!(received(&listener), None);
+
+        std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        assert!(output.flush(&mut connection).is_none());
+        assert_eq!(received(&listener), Some(30));
+        assert_eq!(received(&listener), None);
+
+        config::set_for_test(Config::default());
+    }
+}
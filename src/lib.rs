@@ -25,61 +25,88 @@
 #![allow(clippy::missing_safety_doc)]
 
 use std::{
-    ffi::{c_void, CString},
-    mem,
+    ffi::c_void,
     sync::{
         atomic::{AtomicBool, AtomicU16, Ordering},
         Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use tracing::{debug, error, info, warn};
 
-use winapi::{
-    shared::{
-        minwindef::{BOOL, DWORD, HINSTANCE, LPVOID, TRUE},
-        winerror::{E_FAIL, S_OK},
-    },
-    um::{
-        processenv::GetEnvironmentVariableA,
-        winnt::{DLL_PROCESS_ATTACH, DLL_PROCESS_DETACH, HRESULT},
-    },
-};
+use input::JvsContention;
+use logging::{LED_TARGET, SLIDER_TARGET};
 
-use windows::Win32::Networking::WinSock::{
-    closesocket, connect, recv, send, socket, WSACleanup, WSAStartup, AF_UNIX, SEND_RECV_FLAGS,
-    SOCKADDR, SOCKET, SOCKET_ERROR, SOCK_STREAM, WSADATA,
+use windows::{
+    core::HRESULT,
+    Win32::{
+        Foundation::{BOOL, E_FAIL, HINSTANCE, S_OK, TRUE},
+        System::SystemServices::{DLL_PROCESS_ATTACH, DLL_PROCESS_DETACH},
+    },
 };
 
-mod protocol;
+// Declared first so its macros are visible in every other module
+#[macro_use]
+mod logging;
+mod brokenithm;
+mod chain;
+mod coin;
+mod config;
+mod dinput;
+mod focus;
+mod hid;
+mod input;
+mod io;
+mod keyboard;
+mod latency;
+mod led;
+mod mouse;
+pub mod protocol;
+mod registry;
+mod segatools;
+mod serial;
+mod sources;
+mod stats;
+mod tasoller;
+mod threading;
+mod timing;
+#[cfg(feature = "tls")]
+mod tls;
+mod touch;
+mod transport;
+mod watchdog;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod wsproto;
+mod xinput;
 use protocol::*;
 
-/// Default socket path for chuniio proxy
-const DEFAULT_SOCKET_PATH: &str = "/tmp/chuniio_proxy.sock";
+/// Minimum seconds between repeats of the same connection error in the log
+const ERROR_LOG_INTERVAL_SECS: u64 = 60;
+
+/// How long to wait for a running slider callback to return when stopping the slider
+const SLIDER_STOP_TIMEOUT: Duration = Duration::from_millis(500);
 
-/// Environment variable for socket path override
-const SOCKET_PATH_ENV: &str = "CHUNIIO_PROXY_SOCKET";
+/// How long `chuni_io_jvs_init` waits for the proxy to answer its test poll
+const JVS_INIT_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Global state for the DLL
 struct GlobalState {
-    /// Socket connection to chuniio proxy
-    socket: Option<SOCKET>,
     /// Current JVS state (operator buttons and IR beams)
     jvs_state: JvsState,
-    /// Coin counter
-    coin_counter: AtomicU16,
-    /// Whether the slider is active
-    slider_active: AtomicBool,
-    /// Slider callback function
+    /// Slider callback function, while the slider is started
     slider_callback: Option<SliderCallbackFn>,
+    /// Incremented by every slider start, so a new start always gets a callback
+    slider_generation: u32,
     /// Current slider pressure data
     slider_pressure: [u8; 32],
+    /// When the proxy last sent the state, `None` before it has or once the
+    /// state was cleared as stale
+    synced_at: Option<Instant>,
     /// LED subsystem initialization state
     led_initialized: bool,
-    /// LED board states for each board (0=billboard left, 1=billboard right, 2=slider)
-    led_board_states: [Vec<u8>; 3],
 }
 
 #[derive(Default)]
@@ -91,262 +118,179 @@ struct JvsState {
 type SliderCallbackFn = unsafe extern "C" fn(data: *const u8);
 
 static GLOBAL_STATE: Mutex<GlobalState> = Mutex::new(GlobalState {
-    socket: None,
     jvs_state: JvsState { opbtn: 0, beams: 0 },
-    coin_counter: AtomicU16::new(0),
-    slider_active: AtomicBool::new(false),
     slider_callback: None,
+    slider_generation: 0,
     slider_pressure: [0; 32],
+    synced_at: None,
     led_initialized: false,
-    led_board_states: [Vec::new(), Vec::new(), Vec::new()],
 });
 
-// Guard to keep the file appender alive
-static mut _LOG_GUARD: Option<tracing_appender::non_blocking::WorkerGuard> = None;
+/// Last JVS state stored in `GLOBAL_STATE`, as `opbtn << 8 | beams`, for
+/// `chuni_io_jvs_poll` to fall back on while the lock is held
+static JVS_SNAPSHOT: AtomicU16 = AtomicU16::new(0);
 
-/// Initialize Winsock and connect to the chuniio proxy socket
-unsafe fn init_socket_connection() -> Option<SOCKET> {
-    debug!("Initializing socket connection to chuniio proxy");
+/// Set while the IO thread is inside the game's slider callback
+static SLIDER_IN_CALLBACK: AtomicBool = AtomicBool::new(false);
 
-    // Initialize Winsock
-    let mut wsadata: WSADATA = mem::zeroed();
-    if WSAStartup(0x0202, &mut wsadata) != 0 {
-        error!("Failed to initialize Winsock");
-        return None;
-    }
-
-    // Create Unix domain socket
-    let sock = match socket(AF_UNIX.into(), SOCK_STREAM, 0) {
-        Ok(s) => {
-            debug!("Created Unix domain socket");
-            s
-        }
-        Err(e) => {
-            error!("Failed to create socket: {:?}", e);
-            WSACleanup();
-            return None;
+/// Store a `JvsFullStateRead` response in GlobalState
+fn apply_full_state(response: Option<ChuniMessage>) {
+    if let Some(ChuniMessage::JvsFullStateReadResponse {
+        opbtn,
+        beams,
+        coin_counter,
+        pressure,
+    }) = response
+    {
+        if let Ok(mut state) = GLOBAL_STATE.lock() {
+            state.jvs_state.opbtn = input::map_opbtn(opbtn);
+            state.jvs_state.beams = input::map_beams(beams);
+            JVS_SNAPSHOT.store(
+                u16::from(state.jvs_state.opbtn) << 8 | u16::from(state.jvs_state.beams),
+                Ordering::Relaxed,
+            );
+            state.slider_pressure = input::map_pressure(pressure);
+            state.synced_at = Some(Instant::now());
+            coin::update(coin_counter, state.slider_pressure.iter().any(|&p| p != 0));
+            debug!("GlobalState synchronized from proxy: opbtn={:02x}, beams={:02x}, coin_counter={}, slider_pressure[..4]={:?}", opbtn, beams, coin_counter, &pressure[..4]);
         }
-    };
-
-    // Get socket path from environment or use default
-    let socket_path = get_socket_path();
-    debug!("Connecting to socket path: {}", socket_path);
-    let socket_path_cstring = CString::new(socket_path).ok()?;
-
-    // Create sockaddr_un structure for Unix socket
-    let mut addr: [u8; 110] = [0; 110]; // sockaddr_un size
-    addr[0] = AF_UNIX as u8; // sa_family
-    addr[1] = 0;
-
-    // Copy the path starting at offset 2
-    let path_bytes = socket_path_cstring.as_bytes();
-    for (i, &byte) in path_bytes.iter().enumerate() {
-        if i + 2 < addr.len() {
-            addr[i + 2] = byte;
+    } else {
+        log_throttled!(
+            ERROR_LOG_INTERVAL_SECS,
+            warn!("Failed to synchronize full IO state from proxy")
+        );
+        let timeout = config::get().stale_state_timeout;
+        if let Ok(mut state) = GLOBAL_STATE.lock() {
+            if state.expire_stale(Instant::now(), timeout) {
+                warn!(
+                    "No state from the proxy for {:?}, releasing all inputs",
+                    timeout
+                );
+            }
         }
     }
+}
 
-    // Connect to the Unix socket
-    if connect(sock, addr.as_ptr() as *const SOCKADDR, addr.len() as i32) == SOCKET_ERROR {
-        error!("Failed to connect to chuniio proxy socket");
-        closesocket(sock);
-        WSACleanup();
-        return None;
+/// Release every input from the proxy at once, as it goes offline
+fn release_proxy_state() {
+    if let Ok(mut state) = GLOBAL_STATE.lock() {
+        state.release();
     }
-
-    info!("Successfully connected to chuniio proxy socket");
-    Some(sock)
 }
 
-/// Get socket path from environment variable or use default
-fn get_socket_path() -> String {
-    unsafe {
-        let mut buffer = [0u8; 260]; // MAX_PATH
-        let env_var = CString::new(SOCKET_PATH_ENV).unwrap();
-        let len = GetEnvironmentVariableA(
-            env_var.as_ptr(),
-            buffer.as_mut_ptr() as *mut i8,
-            buffer.len() as u32,
-        );
+/// Held by tests that go through `GLOBAL_STATE`, the coin counters or the
+/// current configuration, so they take turns; each starts with the proxy's
+/// inputs released, no coins counted and no local source holding a channel
+#[cfg(test)]
+fn lock_globals() -> std::sync::MutexGuard<'static, ()> {
+    static GLOBALS: Mutex<()> = Mutex::new(());
+    let guard = GLOBALS.lock().unwrap_or_else(|e| e.into_inner());
+    release_proxy_state();
+    JVS_SNAPSHOT.store(0, Ordering::Relaxed);
+    coin::reset();
+    sources::reset();
+    brokenithm::set_for_test(None);
+    guard
+}
 
-        if len > 0 && len < buffer.len() as u32 {
-            if let Ok(path) = CString::new(&buffer[..len as usize]) {
-                if let Ok(path_str) = path.to_str() {
-                    return path_str.to_string();
-                }
-            }
+impl GlobalState {
+    /// Release the buttons, beams and slider if the proxy last sent them more
+    /// than `timeout` before `now` (zero = never), returning whether they were
+    /// released just now
+    fn expire_stale(&mut self, now: Instant, timeout: Duration) -> bool {
+        let Some(synced_at) = self.synced_at else {
+            return false;
+        };
+        if timeout.is_zero() || now.saturating_duration_since(synced_at) < timeout {
+            return false;
         }
+        self.release();
+        true
     }
 
-    DEFAULT_SOCKET_PATH.to_string()
-}
-
-/// Attempt to recover socket connection if lost
-unsafe fn recover_connection() -> bool {
-    debug!("Attempting to recover socket connection");
-
-    if let Some(new_sock) = init_socket_connection() {
-        if let Ok(mut state) = GLOBAL_STATE.lock() {
-            // Close old socket if it exists
-            if let Some(old_sock) = state.socket.take() {
-                closesocket(old_sock);
-            }
-
-            state.socket = Some(new_sock);
-            info!("Socket connection recovered successfully");
-            return true;
-        }
+    /// Release the buttons, beams and slider from the proxy
+    fn release(&mut self) {
+        self.synced_at = None;
+        self.jvs_state = JvsState::default();
+        self.slider_pressure = [0; 32];
+        JVS_SNAPSHOT.store(0, Ordering::Relaxed);
     }
+}
 
-    warn!("Failed to recover socket connection");
-    false
+/// Hands slider pressure to the game's callback on the IO thread
+struct SliderDelivery {
+    /// Skip the callback when the pressure is unchanged, up to `max_quiet`
+    on_change: bool,
+    max_quiet: Duration,
+    /// Pressure passed to the last callback, and the start it was delivered for
+    last_pressure: Option<([u8; 32], u32)>,
+    last_call: Instant,
+    last_jvs: (u8, u8),
 }
 
-/// Send a message with automatic connection recovery
-unsafe fn send_message_with_recovery(message: &ChuniMessage) -> Option<ChuniMessage> {
-    // Always drop the lock before network I/O
-    let sock = {
-        if let Ok(state) = GLOBAL_STATE.lock() {
-            state.socket
-        } else {
-            error!("send_message_with_recovery: failed to acquire global state lock");
-            return None;
-        }
-    };
-    if let Some(sock) = sock {
-        let result = send_message(sock, message);
-        if result.is_some() {
-            return result;
-        } else {
-            error!(
-                "send_message_with_recovery: send_message failed for {:?}, attempting recovery",
-                message
-            );
-        }
-    } else {
-        warn!("send_message_with_recovery: no socket, attempting recovery");
-    }
-    // If we get here, either no connection or send failed
-    if recover_connection() {
-        let sock = {
-            if let Ok(state) = GLOBAL_STATE.lock() {
-                state.socket
-            } else {
-                error!("send_message_with_recovery: failed to acquire global state lock after recovery");
-                return None;
-            }
+impl SliderDelivery {
+    fn new() -> Self {
+        let mut delivery = Self {
+            on_change: false,
+            max_quiet: Duration::ZERO,
+            last_pressure: None,
+            last_call: Instant::now(),
+            last_jvs: (0, 0),
         };
-        if let Some(sock) = sock {
-            debug!(
-                "Retrying message send after connection recovery: {:?}",
-                message
-            );
-            return send_message(sock, message);
-        }
+        delivery.configure(config::get());
+        delivery
     }
-    error!(
-        "send_message_with_recovery: failed to send message after recovery: {:?}",
-        message
-    );
-    None
-}
 
-unsafe fn send_message(sock: SOCKET, message: &ChuniMessage) -> Option<ChuniMessage> {
-    let data = message.serialize();
-    match message {
-        ChuniMessage::JvsPoll
-        | ChuniMessage::CoinCounterRead
-        | ChuniMessage::SliderStateRead
-        | ChuniMessage::JvsFullStateRead => {}
-        _ => debug!("Sending message: {:?} ({} bytes)", message, data.len()),
+    /// Apply the callback settings from `config`
+    fn configure(&mut self, config: &config::Config) {
+        self.on_change = config.slider_callback_on_change;
+        self.max_quiet = config.slider_max_quiet;
     }
-    if send(sock, &data, SEND_RECV_FLAGS(0)) == SOCKET_ERROR {
-        error!("send_message: failed to send message {:?}", message);
-        return None;
-    }
-    match message {
-        ChuniMessage::JvsPoll
-        | ChuniMessage::CoinCounterRead
-        | ChuniMessage::SliderStateRead
-        | ChuniMessage::Ping
-        | ChuniMessage::JvsFullStateRead => {
-            let mut buffer = [0u8; 1024];
-            let bytes_received = recv(sock, &mut buffer, SEND_RECV_FLAGS(0));
-            if bytes_received > 0 {
-                match ChuniMessage::deserialize(&buffer[..bytes_received as usize]) {
-                    Ok(response) => {
-                        match response {
-                            ChuniMessage::JvsPollResponse { .. }
-                            | ChuniMessage::CoinCounterReadResponse { .. }
-                            | ChuniMessage::SliderStateReadResponse { .. }
-                            | ChuniMessage::Pong
-                            | ChuniMessage::JvsFullStateReadResponse { .. } => {}
-                            _ => debug!("Received response from chuniio proxy: {:?}", response),
-                        }
-                        Some(response)
-                    }
-                    Err(e) => {
-                        error!(
-                            "send_message: failed to deserialize response for {:?}: {:?}",
-                            message, e
-                        );
-                        None
-                    }
-                }
-            } else {
-                error!(
-                    "send_message: failed to receive response for {:?} (received {} bytes)",
-                    message, bytes_received
-                );
-                None
+
+    /// Hand the latest slider pressure to the game's callback if the slider is
+    /// started; called after every state poll
+    ///
+    /// Returns whether any input is active (a touch, or a JVS change since the
+    /// previous call), for the idle slowdown.
+    unsafe fn deliver(&mut self) -> bool {
+        // Local sources are read before locking
+        let local = sources::SliderInputs::read();
+
+        // Copy the slider data out and release the lock before calling into the
+        // game, so a slow callback can't hold up JVS reads
+        let snapshot = GLOBAL_STATE.lock().ok().map(|state| {
+            let pressure = local.merge(state.slider_pressure);
+            let callback = state.slider_callback.filter(|_| {
+                !self.on_change
+                    || self.last_pressure != Some((pressure, state.slider_generation))
+                    || self.last_call.elapsed() >= self.max_quiet
+            });
+            if callback.is_some() {
+                SLIDER_IN_CALLBACK.store(true, Ordering::SeqCst);
             }
-        }
-        _ => {
-            debug!("Message sent (no response expected): {:?}", message);
-            None
-        }
-    }
-}
+            (
+                callback,
+                pressure,
+                state.slider_generation,
+                (state.jvs_state.opbtn, state.jvs_state.beams),
+            )
+        });
+        let Some((callback, pressure, generation, jvs)) = snapshot else {
+            return false;
+        };
 
-unsafe fn send_message_fire_and_forget(message: &ChuniMessage) {
-    let sock = {
-        if let Ok(state) = GLOBAL_STATE.lock() {
-            state.socket
-        } else {
-            error!("send_message_fire_and_forget: failed to acquire global state lock");
-            return;
+        // Call callback with updated slider data
+        if let Some(callback) = callback {
+            callback(pressure.as_ptr());
+            SLIDER_IN_CALLBACK.store(false, Ordering::SeqCst);
+            self.last_pressure = Some((pressure, generation));
+            self.last_call = Instant::now();
         }
-    };
-    if let Some(sock) = sock {
-        let data = message.serialize();
-        if send(sock, &data, SEND_RECV_FLAGS(0)) == SOCKET_ERROR {
-            error!(
-                "send_message_fire_and_forget: failed to send message {:?}",
-                message
-            );
-        }
-    }
-}
 
-/// Synchronize the full IO state from the proxy and update GlobalState
-unsafe fn sync_full_io_state_from_proxy() {
-    let response = send_message_with_recovery(&ChuniMessage::JvsFullStateRead);
-    if let Some(ChuniMessage::JvsFullStateReadResponse {
-        opbtn,
-        beams,
-        coin_counter,
-        pressure,
-    }) = response
-    {
-        if let Ok(mut state) = GLOBAL_STATE.lock() {
-            state.jvs_state.opbtn = opbtn;
-            state.jvs_state.beams = beams;
-            state.coin_counter.store(coin_counter, Ordering::Relaxed);
-            state.slider_pressure = pressure;
-            debug!("GlobalState synchronized from proxy: opbtn={:02x}, beams={:02x}, coin_counter={}, slider_pressure[..4]={:?}", opbtn, beams, coin_counter, &pressure[..4]);
-        }
-    } else {
-        warn!("Failed to synchronize full IO state from proxy");
+        // Any touch or JVS change counts as activity for the idle slowdown
+        let active = pressure.iter().any(|&p| p != 0) || jvs != self.last_jvs;
+        self.last_jvs = jvs;
+        active
     }
 }
 
@@ -358,62 +302,36 @@ unsafe fn sync_full_io_state_from_proxy() {
 #[allow(non_snake_case)]
 pub unsafe extern "system" fn DllMain(
     _hinst_dll: HINSTANCE,
-    fdw_reason: DWORD,
-    _lpv_reserved: LPVOID,
+    fdw_reason: u32,
+    lpv_reserved: *mut c_void,
 ) -> BOOL {
     match fdw_reason {
-        x if x == DLL_PROCESS_ATTACH => {
-            // Create log file appender in current directory
-            let file_appender = tracing_appender::rolling::never(".", "chuniio-backflow.log");
-            let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-
-            // Store the guard to keep the appender alive
-            _LOG_GUARD = Some(guard);
-
-            // Create an env filter that defaults to "trace" level if RUST_LOG is not set
-            let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("trace"));
-
-            // Initialize tracing subscriber for logging to file
-            let _ = tracing_subscriber::fmt()
-                .with_env_filter(env_filter)
-                .with_target(false)
-                .with_thread_ids(false)
-                .with_file(false)
-                .with_line_number(false)
-                .with_writer(non_blocking)
-                .try_init();
+        DLL_PROCESS_ATTACH => {
+            logging::init();
 
+            // The IO thread (and with it the connection) is started by the first
+            // init call rather than under the loader lock
             info!("chuniio-backflow DLL loaded");
-
-            // Initialize connection to chuniio proxy
-            if let Some(sock) = init_socket_connection() {
-                if let Ok(mut state) = GLOBAL_STATE.lock() {
-                    state.socket = Some(sock);
-                    info!("Successfully connected to chuniio proxy");
-
-                    // Test the connection with a ping
-                    debug!("Testing connection with ping...");
-                    let ping_message = ChuniMessage::Ping;
-                    if let Some(response) = send_message(sock, &ping_message) {
-                        info!("Ping test successful: {:?}", response);
-                    } else {
-                        error!("Ping test failed - connection may be unstable");
-                    }
-                } else {
-                    error!("Failed to acquire global state lock");
-                }
-            } else {
-                warn!("Failed to connect to chuniio proxy - will retry on API calls");
-            }
         }
-        x if x == DLL_PROCESS_DETACH => {
-            // Cleanup
-            if let Ok(mut state) = GLOBAL_STATE.lock() {
-                if let Some(sock) = state.socket.take() {
-                    closesocket(sock);
-                    WSACleanup();
-                }
+        DLL_PROCESS_DETACH => {
+            info!("Final stats: {}", stats::STATS.summary());
+
+            // On FreeLibrary, stop the device readers and the IO thread (which
+            // says goodbye to the proxy and closes the socket) before their
+            // code is unmapped, each waiting a bounded time. When the
+            // process is terminating (lpv_reserved non-null) every other thread
+            // has already been killed, so there is nothing to wait for and the
+            // goodbye is sent from here.
+            if lpv_reserved.is_null() {
+                mouse::shutdown();
+                touch::shutdown();
+                brokenithm::stop();
+                serial::stop();
+                tasoller::stop();
+                hid::stop();
+                io::stop(false);
+            } else {
+                io::goodbye_at_exit();
             }
         }
         _ => {}
@@ -430,35 +348,43 @@ pub unsafe extern "system" fn DllMain(
 pub unsafe extern "C" fn chuni_io_jvs_init() -> HRESULT {
     debug!("chuni_io_jvs_init called - starting JVS initialization");
 
-    // Connection should already be established in DllMain
-    if let Ok(state) = GLOBAL_STATE.lock() {
-        if state.socket.is_some() {
-            debug!("JVS subsystem initialized successfully");
-
-            // Test connectivity immediately after init
-            debug!("Testing immediate JVS poll after init...");
-            if let Some(sock) = state.socket {
-                let test_message = ChuniMessage::JvsPoll;
-                if let Some(response) = send_message(sock, &test_message) {
-                    info!("Immediate JVS poll test successful: {:?}", response);
-                } else {
-                    error!("Immediate JVS poll test failed");
-                }
-            }
+    let config = config::get();
+    if config.strict && !config.problems.is_empty() {
+        for problem in &config.problems {
+            error!("Config problem: {}", problem);
+        }
+        error!(
+            "JVS init failed: {} config problem(s) in strict mode",
+            config.problems.len()
+        );
+        return E_FAIL;
+    }
+
+    io::start();
+    brokenithm::start();
+    tasoller::start();
+    hid::start();
+    chain::jvs_init();
+
+    // Test connectivity through the IO thread
+    debug!("Testing immediate JVS poll after init...");
+    match io::request(ChuniMessage::JvsPoll, JVS_INIT_TIMEOUT) {
+        Some(response) => {
+            info!("Immediate JVS poll test successful: {:?}", response);
 
             // Note: In the reference implementation, JVS init also creates the LED mutex
             // Since we don't use Windows mutexes, we'll handle LED synchronization in Rust
             debug!("LED synchronization mutex equivalent created");
 
             info!("JVS and LED synchronization initialized");
-            return S_OK;
-        } else {
-            error!("JVS init failed: no socket connection");
-            return E_FAIL;
+            S_OK
+        }
+        None => {
+            // The game can run on local inputs until the proxy answers
+            warn!("No response from chuniio proxy at JVS init, starting offline until it is back");
+            io::submit(io::Command::GoOffline);
+            S_OK
         }
-    } else {
-        error!("JVS init failed: could not acquire global state lock");
-        return E_FAIL;
     }
 }
 
@@ -470,29 +396,30 @@ pub unsafe extern "C" fn chuni_io_jvs_poll(opbtn: *mut u8, beams: *mut u8) {
         return;
     }
 
-    // First, return current cached state
+    // The IO thread keeps the cached state current
     if let Ok(state) = GLOBAL_STATE.try_lock() {
         *opbtn = state.jvs_state.opbtn;
         *beams = state.jvs_state.beams;
-
-        // If we have a connection, try to update state quickly
-        if state.socket.is_some() {
-            drop(state); // Release lock before socket operation
-
-            // Synchronize full IO state from proxy
-            sync_full_io_state_from_proxy();
-
-            // Return updated state
-            if let Ok(state) = GLOBAL_STATE.try_lock() {
-                *opbtn = state.jvs_state.opbtn;
-                *beams = state.jvs_state.beams;
-            }
+        // Released inputs are not held
+        if state.synced_at.is_some() {
+            *beams = input::expire_beam_hold(*beams);
         }
     } else {
-        // If we can't get lock immediately, return empty state
-        *opbtn = 0;
-        *beams = 0;
+        match config::get().jvs_contention {
+            JvsContention::Last => {
+                let [last_opbtn, last_beams] = JVS_SNAPSHOT.load(Ordering::Relaxed).to_be_bytes();
+                *opbtn = last_opbtn;
+                *beams = last_beams;
+            }
+            JvsContention::Zero => {
+                *opbtn = 0;
+                *beams = 0;
+            }
+        }
     }
+    chain::jvs_poll();
+    *opbtn = sources::opbtn(*opbtn);
+    *beams = sources::beams(*beams);
 }
 
 /// Read coin counter
@@ -503,27 +430,8 @@ pub unsafe extern "C" fn chuni_io_jvs_read_coin_counter(total: *mut u16) {
         return;
     }
 
-    // First, return current cached coin count
-    if let Ok(state) = GLOBAL_STATE.try_lock() {
-        let current_count = state.coin_counter.load(Ordering::Relaxed);
-        *total = current_count;
-
-        // If we have a connection, try to update count quickly
-        if state.socket.is_some() {
-            drop(state); // Release lock before socket operation
-
-            // Synchronize full IO state from proxy
-            sync_full_io_state_from_proxy();
-
-            // Return updated count
-            if let Ok(state) = GLOBAL_STATE.try_lock() {
-                *total = state.coin_counter.load(Ordering::Relaxed);
-            }
-        }
-    } else {
-        // If we can't get lock immediately, return 0
-        *total = 0;
-    }
+    // The count is refreshed by every full-state sync on the IO thread
+    *total = coin::total();
 }
 
 // ============================================================================
@@ -533,88 +441,93 @@ pub unsafe extern "C" fn chuni_io_jvs_read_coin_counter(total: *mut u16) {
 /// Initialize slider subsystem
 #[no_mangle]
 pub unsafe extern "C" fn chuni_io_slider_init() -> HRESULT {
-    debug!("chuni_io_slider_init called");
+    debug!(target: SLIDER_TARGET, "chuni_io_slider_init called");
 
     // In the reference implementation, slider_init calls led_output_init because of slider LEDs
     // We'll ensure LED subsystem is initialized here too
     if let Ok(mut state) = GLOBAL_STATE.lock() {
         if !state.led_initialized {
-            debug!("LED subsystem not yet initialized, initializing now for slider LEDs");
+            debug!(
+                target: SLIDER_TARGET,
+                "LED subsystem not yet initialized, initializing now for slider LEDs"
+            );
 
-            // Initialize LED board state buffers with correct sizes
-            state.led_board_states[0] = vec![0u8; 159];
-            state.led_board_states[1] = vec![0u8; 189];
-            state.led_board_states[2] = vec![0u8; 93];
+            led::init();
 
             state.led_initialized = true;
-            debug!("LED subsystem initialized via slider init");
+            debug!(target: SLIDER_TARGET, "LED subsystem initialized via slider init");
         }
+        serial::start();
+        chain::slider_init();
 
-        info!("Slider subsystem initialized successfully");
-        return S_OK;
+        info!(target: SLIDER_TARGET, "Slider subsystem initialized successfully");
+        S_OK
     } else {
-        error!("Slider init failed: could not acquire global state lock");
-        return E_FAIL;
+        error!(target: SLIDER_TARGET, "Slider init failed: could not acquire global state lock");
+        E_FAIL
     }
 }
 
 /// Start slider input polling with callback
 #[no_mangle]
 pub unsafe extern "C" fn chuni_io_slider_start(callback: *const c_void) {
-    debug!("chuni_io_slider_start called with callback: {:?}", callback);
+    debug!(target: SLIDER_TARGET, "chuni_io_slider_start called with callback: {:?}", callback);
 
     if callback.is_null() {
-        warn!("Slider start called with null callback");
+        warn!(target: SLIDER_TARGET, "Slider start called with null callback");
         return;
     }
 
-    debug!("Starting slider input polling");
+    debug!(target: SLIDER_TARGET, "Starting slider input polling");
 
-    let callback_fn = std::mem::transmute::<_, SliderCallbackFn>(callback);
+    let callback_fn = std::mem::transmute::<*const c_void, SliderCallbackFn>(callback);
 
     if let Ok(mut state) = GLOBAL_STATE.lock() {
-        if state.slider_active.load(Ordering::SeqCst) {
-            debug!("Slider already active, returning");
+        if state.slider_callback.is_some() {
+            debug!(target: SLIDER_TARGET, "Slider already active, returning");
             return; // Already running
         }
-
         state.slider_callback = Some(callback_fn);
-        state.slider_active.store(true, Ordering::SeqCst);
-
-        let _sock = state.socket;
-        drop(state); // Release lock before spawning thread
-
-        // Spawn slider polling thread
-        thread::spawn(move || {
-            debug!("Slider polling thread started");
-            while GLOBAL_STATE
-                .lock()
-                .map(|s| s.slider_active.load(Ordering::SeqCst))
-                .unwrap_or(false)
-            {
-                // Synchronize full IO state from proxy (includes slider)
-                sync_full_io_state_from_proxy();
-
-                // Call callback with updated slider data
-                if let Ok(state) = GLOBAL_STATE.lock() {
-                    if let Some(callback) = state.slider_callback {
-                        callback(state.slider_pressure.as_ptr());
-                    }
-                }
-
-                thread::sleep(Duration::from_millis(1)); // ~1000Hz polling rate
-            }
-            debug!("Slider polling thread stopped");
-        });
+        state.slider_generation = state.slider_generation.wrapping_add(1);
     }
+
+    // The IO thread calls the callback after every state poll
+    io::start();
+    chain::slider_start();
 }
 
 /// Stop slider input polling
 #[no_mangle]
 pub unsafe extern "C" fn chuni_io_slider_stop() {
-    debug!("chuni_io_slider_stop called");
-    if let Ok(state) = GLOBAL_STATE.lock() {
-        state.slider_active.store(false, Ordering::SeqCst);
+    debug!(target: SLIDER_TARGET, "chuni_io_slider_stop called");
+    stop_slider();
+    chain::slider_stop();
+}
+
+/// Stop calling the slider callback, waiting up to `SLIDER_STOP_TIMEOUT` for a
+/// call already in progress to return
+fn stop_slider() {
+    match GLOBAL_STATE.lock() {
+        Ok(mut state) => state.slider_callback = None,
+        Err(_) => {
+            error!(target: SLIDER_TARGET, "Slider stop: could not acquire global state lock");
+            return;
+        }
+    }
+
+    // The flag is only ever set under the lock, so once the callback has been
+    // cleared no new call can start
+    let deadline = Instant::now() + SLIDER_STOP_TIMEOUT;
+    while SLIDER_IN_CALLBACK.load(Ordering::SeqCst) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    if SLIDER_IN_CALLBACK.load(Ordering::SeqCst) {
+        warn!(
+            target: SLIDER_TARGET,
+            "Slider callback did not return within {:?}",
+            SLIDER_STOP_TIMEOUT
+        );
     }
 }
 
@@ -622,31 +535,28 @@ pub unsafe extern "C" fn chuni_io_slider_stop() {
 // LED Output Functions
 // ============================================================================
 
-/// Initialize LED subsystem
 /// Initialize LED subsystem
 #[no_mangle]
 pub unsafe extern "C" fn chuni_io_led_init() -> HRESULT {
+    chain::led_init();
+
     if let Ok(mut state) = GLOBAL_STATE.try_lock() {
         if state.led_initialized {
             return S_OK;
         }
 
-        // Initialize LED board state buffers with correct sizes
-        // Board 0: 53 LEDs * 3 bytes = 159 bytes (billboard left)
-        // Board 1: 63 LEDs * 3 bytes = 189 bytes (billboard right)
-        // Board 2: 31 LEDs * 3 bytes = 93 bytes (slider)
-        state.led_board_states[0] = vec![0u8; 159];
-        state.led_board_states[1] = vec![0u8; 189];
-        state.led_board_states[2] = vec![0u8; 93];
+        // Allocate the per-board LED buffers and start the IO thread
+        led::init();
 
         state.led_initialized = true;
-        info!("LED boards initialized successfully");
-        return S_OK;
+        info!(target: LED_TARGET, "LED boards initialized successfully");
+        S_OK
     } else {
         warn!(
+            target: LED_TARGET,
             "LED init: could not acquire global state lock immediately, returning success anyway"
         );
-        return S_OK; // Return success like reference implementation does
+        S_OK // Return success like reference implementation does
     }
 }
 
@@ -657,6 +567,10 @@ pub unsafe extern "C" fn chuni_io_slider_set_leds(rgb: *const u8) {
         return;
     }
 
+    // A serial slider gets the game's LED data unchanged
+    serial::set_leds(std::slice::from_raw_parts(rgb, led::LED_BOARD_SIZES[2]));
+    chain::slider_set_leds(rgb);
+
     // In the reference implementation, this calls led_output_update(2, rgb)
     // So we forward to our LED board function for board 2 (slider)
     chuni_io_led_set_colors(2, rgb);
@@ -670,41 +584,22 @@ pub unsafe extern "C" fn chuni_io_led_set_colors(board: u8, rgb: *const u8) {
         return;
     }
 
-    if board > 2 {
+    if board as usize >= led::LED_BOARD_COUNT {
         return;
     }
+    chain::led_set_colors(board, rgb);
 
     // Try to acquire lock with timeout to avoid blocking game thread
-    if let Ok(mut state) = GLOBAL_STATE.try_lock() {
+    if let Ok(state) = GLOBAL_STATE.try_lock() {
         // Ensure LED subsystem is initialized
         if !state.led_initialized {
             return;
         }
+        drop(state);
 
-        // Get correct RGB data size based on board
-        let rgb_len = match board {
-            0 => 159,    // Board 0: 53 LEDs * 3 bytes = 159 bytes (billboard left)
-            1 => 189,    // Board 1: 63 LEDs * 3 bytes = 189 bytes (billboard right)
-            2 => 93,     // Board 2: 31 LEDs * 3 bytes = 93 bytes (slider)
-            _ => return, // Already validated above
-        };
-
-        // Copy RGB data to our internal buffer (like the reference implementation does)
-        let rgb_data = std::slice::from_raw_parts(rgb, rgb_len).to_vec();
-        state.led_board_states[board as usize] = rgb_data.clone();
-
-        // Send LED data to proxy (like reference sends to named pipe)
-        if state.socket.is_some() {
-            let message = ChuniMessage::LedUpdate { board, rgb_data };
-
-            // Drop the lock before sending to avoid deadlock
-            drop(state);
-
-            // Send asynchronously without waiting for response (fire-and-forget like named pipe)
-            std::thread::spawn(move || {
-                unsafe { send_message_fire_and_forget(&message) };
-            });
-        }
+        // Copy RGB data into the board's buffer (like the reference implementation does);
+        // the LED worker forwards it to the proxy (fire-and-forget like the named pipe)
+        led::submit_frame(board as usize, rgb);
     }
     // If we can't get the lock immediately, just silently fail like the reference does
 
@@ -723,3 +618,77 @@ pub extern "C" fn chuni_io_get_api_version() -> u16 {
 }
 
 // ============================================================================
+// Extension Functions
+// ============================================================================
+
+/// Version of the extension ABI, the `chuniio_backflow_*` exports: bumped when
+/// one of them changes incompatibly, while new exports keep it unchanged
+pub const CHUNIIO_BACKFLOW_ABI_VERSION: u32 = 1;
+
+/// Get the extension ABI version, so loaders can check that the
+/// `chuniio_backflow_*` exports they use are compatible before calling them
+#[no_mangle]
+pub extern "C" fn chuniio_backflow_get_abi_version() -> u32 {
+    CHUNIIO_BACKFLOW_ABI_VERSION
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synced_state(synced_at: Instant) -> GlobalState {
+        GlobalState {
+            jvs_state: JvsState {
+                opbtn: 0x01,
+                beams: 0x3F,
+            },
+            slider_callback: None,
+            slider_generation: 0,
+            slider_pressure: [0x80; 32],
+            synced_at: Some(synced_at),
+            led_initialized: false,
+        }
+    }
+
+    #[test]
+    fn fresh_state_is_kept() {
+        let now = Instant::now();
+        let mut state = synced_state(now);
+        assert!(!state.expire_stale(now + Duration::from_millis(99), Duration::from_millis(100)));
+        assert_eq!(state.jvs_state.beams, 0x3F);
+        assert_eq!(state.slider_pressure, [0x80; 32]);
+    }
+
+    #[test]
+    fn stale_state_is_released_once() {
+        let now = Instant::now();
+        let mut state = synced_state(now);
+        let later = now + Duration::from_millis(100);
+        assert!(state.expire_stale(later, Duration::from_millis(100)));
+        assert_eq!((state.jvs_state.opbtn, state.jvs_state.beams), (0, 0));
+        assert_eq!(state.slider_pressure, [0; 32]);
+        assert!(!state.expire_stale(later, Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn released_state_is_not_expired_again() {
+        let now = Instant::now();
+        let mut state = synced_state(now);
+        state.release();
+        assert_eq!((state.jvs_state.opbtn, state.jvs_state.beams), (0, 0));
+        assert_eq!(state.slider_pressure, [0; 32]);
+        assert!(!state.expire_stale(now + Duration::from_secs(1), Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn zero_timeout_keeps_state() {
+        let now = Instant::now();
+        let mut state = synced_state(now);
+        assert!(!state.expire_stale(now + Duration::from_secs(3600), Duration::ZERO));
+        assert_eq!(state.slider_pressure, [0x80; 32]);
+    }
+}
@@ -0,0 +1,616 @@
+//! Logging setup
+//!
+//! With the default `file-logging` feature, logs are written to
+//! `chuniio-backflow.log` in the current directory, or to `CHUNIIO_LOG_PATH`.
+//! When that location cannot be written (a read-only game folder), the log goes
+//! to the same file name in `%LOCALAPPDATA%\chuniio-backflow` instead, or the
+//! temp directory if that cannot be created. `CHUNIIO_LOG_ROTATION`
+//! starts a new file every day or at a size limit, keeping the newest
+//! `CHUNIIO_LOG_MAX_FILES` files. Without the feature,
+//! `tracing-appender` is not compiled in and logs go to stderr.
+//!
+//! `CHUNIIO_LOG_SINKS` sends the log to stderr and/or the debugger
+//! (`OutputDebugStringA`, shown by DebugView and Wine's `+debugstr` channel)
+//! instead of or in addition to the file. The
+//! `minimal-logging` feature additionally compiles out all debug/trace events.
+//!
+//! Formatted lines are handed to a dedicated writer thread through a bounded
+//! queue. A logging thread never blocks on the queue or on disk IO: when the
+//! queue is full the line is dropped and counted, so bursts of debug logging
+//! cannot stall the polling threads.
+//!
+//! `CHUNIIO_LOG=off` disables logging completely; no log file is created.
+//!
+//! Hot paths (1kHz polling) use `log_every_n!` and `log_throttled!` so
+//! diagnostics survive without flooding the log.
+
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        OnceLock,
+    },
+    thread,
+    time::Instant,
+};
+
+#[cfg(feature = "file-logging")]
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "file-logging")]
+use tracing_appender::rolling::{self, RollingFileAppender, Rotation};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use windows::{core::PCSTR, Win32::System::Diagnostics::Debug::OutputDebugStringA};
+
+/// Setting for the log filter (`RUST_LOG` syntax); takes precedence over `RUST_LOG`
+pub const LOG_FILTER_ENV: &str = "CHUNIIO_LOG";
+
+/// Log target for protocol messages (requests, responses, malformed data)
+pub const PROTOCOL_TARGET: &str = "chuniio_backflow::protocol";
+
+/// Log target for the slider exports and callback delivery
+pub const SLIDER_TARGET: &str = "chuniio_backflow::slider";
+
+/// Log target for the LED exports and output
+pub const LED_TARGET: &str = "chuniio_backflow::led";
+
+/// Short subsystem names accepted in `LOG_FILTER_ENV`, with the log target each
+/// stands for
+const SUBSYSTEMS: [(&str, &str); 5] = [
+    ("conn", "chuniio_backflow::io"),
+    ("slider", SLIDER_TARGET),
+    ("led", LED_TARGET),
+    ("protocol", PROTOCOL_TARGET),
+    ("config", "chuniio_backflow::config"),
+];
+
+/// `LOG_FILTER_ENV` value that disables logging without creating any sink
+const LOG_OFF: &str = "off";
+
+/// Setting for where log lines go: a comma-separated list of `file`, `stderr`
+/// and `debugger`
+pub const LOG_SINKS_ENV: &str = "CHUNIIO_LOG_SINKS";
+
+/// Log sinks used when `LOG_SINKS_ENV` is not set
+#[cfg(feature = "file-logging")]
+pub const DEFAULT_LOG_SINKS: &str = "file";
+#[cfg(not(feature = "file-logging"))]
+pub const DEFAULT_LOG_SINKS: &str = "stderr";
+
+/// Setting for the log file path; `%VAR%` environment references are expanded
+pub const LOG_PATH_ENV: &str = "CHUNIIO_LOG_PATH";
+
+/// Log file path used when `LOG_PATH_ENV` is not set, relative to the current
+/// directory
+pub const DEFAULT_LOG_PATH: &str = "chuniio-backflow.log";
+
+/// Setting for log file rotation: `never`, `daily` or `size`
+pub const LOG_ROTATION_ENV: &str = "CHUNIIO_LOG_ROTATION";
+
+/// Setting for the size in MiB at which `size` rotation starts a new file
+pub const LOG_MAX_SIZE_MB_ENV: &str = "CHUNIIO_LOG_MAX_SIZE_MB";
+
+/// Default size at which `size` rotation starts a new file
+pub const DEFAULT_LOG_MAX_SIZE_MB: u64 = 64;
+
+/// Setting for how many log files rotation keeps, including the current one
+pub const LOG_MAX_FILES_ENV: &str = "CHUNIIO_LOG_MAX_FILES";
+
+/// Default number of log files kept by rotation
+pub const DEFAULT_LOG_MAX_FILES: usize = 5;
+
+/// Maximum number of formatted lines waiting for the writer thread
+const LOG_QUEUE_LINES: usize = 16 * 1024;
+
+/// Lines dropped because the queue was full
+static LINES_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Number of log lines dropped so far because the writer could not keep up
+pub fn lines_dropped() -> u64 {
+    LINES_DROPPED.load(Ordering::Relaxed)
+}
+
+/// Install the global tracing subscriber
+pub fn init() {
+    let directives = crate::config::lookup(LOG_FILTER_ENV);
+
+    // Logging switched off entirely: no writer thread and no log file
+    if directives
+        .as_deref()
+        .is_some_and(|directives| directives.trim().eq_ignore_ascii_case(LOG_OFF))
+    {
+        let _ = tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::new());
+        return;
+    }
+
+    // Filter from CHUNIIO_LOG (environment or config file), then RUST_LOG,
+    // defaulting to "trace"
+    let env_filter = directives
+        .map(|directives| expand_subsystems(&directives))
+        .and_then(|directives| tracing_subscriber::EnvFilter::try_new(directives).ok())
+        .or_else(|| tracing_subscriber::EnvFilter::try_from_default_env().ok())
+        .unwrap_or_else(|| tracing_subscriber::EnvFilter::new("trace"));
+
+    let mut problems = Vec::new();
+    let mut sinks: Vec<Box<dyn Write + Send>> = Vec::new();
+    #[cfg(feature = "file-logging")]
+    let mut log_path = None;
+
+    let names =
+        crate::config::lookup(LOG_SINKS_ENV).unwrap_or_else(|| DEFAULT_LOG_SINKS.to_string());
+    for name in names
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+    {
+        match name.as_str() {
+            #[cfg(feature = "file-logging")]
+            "file" => {
+                let (sink, path) = open_log_file(&mut problems);
+                sinks.push(sink);
+                log_path = Some(path);
+            }
+            #[cfg(not(feature = "file-logging"))]
+            "file" => problems.push("File logging is not included in this build".to_string()),
+            "stderr" => sinks.push(Box::new(io::stderr())),
+            "debugger" => sinks.push(Box::new(DebuggerSink)),
+            "" => {}
+            _ => problems.push(format!(
+                "Ignoring unknown log sink {:?} in {}",
+                name, LOG_SINKS_ENV
+            )),
+        }
+    }
+
+    let Some(queue) = LogQueue::spawn(Box::new(Tee(sinks))) else {
+        return;
+    };
+
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .with_target(false)
+        .with_thread_ids(false)
+        .with_file(false)
+        .with_line_number(false)
+        .with_writer(queue)
+        .try_init();
+
+    // Problems with the log settings can only be reported once logging works
+    for problem in problems {
+        tracing::warn!("{}", problem);
+    }
+    #[cfg(feature = "file-logging")]
+    if let Some(path) = log_path {
+        tracing::info!("Logging to {}", path.display());
+    }
+}
+
+/// Writes every line to each of several sinks
+struct Tee(Vec<Box<dyn Write + Send>>);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for sink in &mut self.0 {
+            let _ = sink.write_all(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sink in &mut self.0 {
+            let _ = sink.flush();
+        }
+        Ok(())
+    }
+}
+
+/// Sends each line to an attached debugger through `OutputDebugStringA`
+struct DebuggerSink;
+
+impl Write for DebuggerSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut line = Vec::with_capacity(buf.len() + 1);
+        line.extend_from_slice(buf);
+        line.push(0);
+        unsafe { OutputDebugStringA(PCSTR(line.as_ptr())) };
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Open the log file with the configured location and rotation, returning the
+/// sink and the path of the current file; problems with the settings are added
+/// to `problems`
+#[cfg(feature = "file-logging")]
+fn open_log_file(problems: &mut Vec<String>) -> (Box<dyn Write + Send>, PathBuf) {
+    let (dir, file_name) = log_file_location(problems);
+    let path = dir.join(&file_name);
+
+    let max_files = match crate::config::lookup(LOG_MAX_FILES_ENV) {
+        Some(value) => match value.trim().parse::<usize>() {
+            Ok(files) if files > 0 => files,
+            _ => {
+                problems.push(format!(
+                    "Ignoring invalid {}: {:?}",
+                    LOG_MAX_FILES_ENV, value
+                ));
+                DEFAULT_LOG_MAX_FILES
+            }
+        },
+        None => DEFAULT_LOG_MAX_FILES,
+    };
+    let max_size_mb = match crate::config::lookup(LOG_MAX_SIZE_MB_ENV) {
+        Some(value) => match value.trim().parse::<u64>() {
+            Ok(mb) if mb > 0 => mb,
+            _ => {
+                problems.push(format!(
+                    "Ignoring invalid {}: {:?}",
+                    LOG_MAX_SIZE_MB_ENV, value
+                ));
+                DEFAULT_LOG_MAX_SIZE_MB
+            }
+        },
+        None => DEFAULT_LOG_MAX_SIZE_MB,
+    };
+
+    let rotation = crate::config::lookup(LOG_ROTATION_ENV);
+    match rotation
+        .as_deref()
+        .map(|r| r.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("never") => {}
+        Some("daily") => {
+            // Dated files: chuniio-backflow.2024-01-31.log
+            let name = Path::new(&file_name);
+            let mut builder = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .max_log_files(max_files);
+            if let Some(stem) = name.file_stem() {
+                builder = builder.filename_prefix(stem.to_string_lossy());
+            }
+            if let Some(extension) = name.extension() {
+                builder = builder.filename_suffix(extension.to_string_lossy());
+            }
+            match builder.build(&dir) {
+                Ok(appender) => return (Box::new(appender), path),
+                Err(e) => problems.push(format!("Daily log rotation unavailable: {}", e)),
+            }
+        }
+        Some("size") => match SizeRotatingFile::open(path.clone(), max_size_mb << 20, max_files) {
+            Ok(file) => return (Box::new(file), path),
+            Err(e) => problems.push(format!("Size log rotation unavailable: {}", e)),
+        },
+        Some(_) => problems.push(format!(
+            "Ignoring invalid {}: {:?}",
+            LOG_ROTATION_ENV,
+            rotation.unwrap_or_default()
+        )),
+    }
+
+    (Box::new(rolling::never(&dir, &file_name)), path)
+}
+
+/// Replace subsystem names in filter directives with their log targets, so
+/// `conn=debug,led=warn` filters the connection layer and the LED path
+fn expand_subsystems(directives: &str) -> String {
+    directives
+        .split(',')
+        .map(|directive| {
+            let directive = directive.trim();
+            let (name, level) = match directive.split_once('=') {
+                Some((name, level)) => (name, Some(level)),
+                None => (directive, None),
+            };
+            let target = SUBSYSTEMS
+                .iter()
+                .find(|(subsystem, _)| subsystem.eq_ignore_ascii_case(name.trim()))
+                .map(|(_, target)| target);
+            match (target, level) {
+                (Some(target), Some(level)) => format!("{}={}", target, level),
+                (Some(target), None) => target.to_string(),
+                (None, _) => directive.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Directory and file name to log to: the configured path if it can be opened
+/// for writing, otherwise the same file name in `config::user_dir()`
+/// (`%LOCALAPPDATA%\chuniio-backflow`)
+#[cfg(feature = "file-logging")]
+fn log_file_location(problems: &mut Vec<String>) -> (PathBuf, OsString) {
+    let path = PathBuf::from(
+        crate::config::lookup(LOG_PATH_ENV)
+            .map(|path| crate::config::expand_env(path.trim()))
+            .unwrap_or_else(|| DEFAULT_LOG_PATH.to_string()),
+    );
+    let file_name = path
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_else(|| OsString::from(DEFAULT_LOG_PATH));
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let writable = fs::create_dir_all(&dir).and_then(|_| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(&file_name))
+    });
+    match writable {
+        Ok(_) => (dir, file_name),
+        Err(e) => {
+            let user_dir = crate::config::user_dir();
+            problems.push(format!(
+                "Cannot write log file {}: {}, using {} instead",
+                path.display(),
+                e,
+                user_dir.display()
+            ));
+            (user_dir, file_name)
+        }
+    }
+}
+
+/// Log file that is renamed to `<name>.1` once it reaches a size limit, shifting
+/// older files up to `<name>.<max_files - 1>` and deleting the oldest
+#[cfg(feature = "file-logging")]
+struct SizeRotatingFile {
+    path: PathBuf,
+    /// Current file; `None` only if reopening it after a rotation failed
+    file: Option<File>,
+    /// Bytes in the current file
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+#[cfg(feature = "file-logging")]
+impl SizeRotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file: Some(file),
+            written,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn numbered(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    /// Move the current file aside and start a new one; if it cannot be moved
+    /// (e.g. held open by a viewer), keep appending until the next limit
+    fn rotate(&mut self) -> io::Result<()> {
+        // Windows cannot rename an open file
+        self.file = None;
+        let mut rotated = true;
+        if self.max_files > 1 {
+            let _ = fs::remove_file(self.numbered(self.max_files - 1));
+            for index in (1..self.max_files - 1).rev() {
+                let _ = fs::rename(self.numbered(index), self.numbered(index + 1));
+            }
+            rotated = fs::rename(&self.path, self.numbered(1)).is_ok();
+        }
+        self.written = 0;
+        self.file = Some(if rotated {
+            File::create(&self.path)?
+        } else {
+            OpenOptions::new().append(true).open(&self.path)?
+        });
+        Ok(())
+    }
+}
+
+#[cfg(feature = "file-logging")]
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Lines arrive whole from the writer thread, so files never split a line
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let file = self.file.as_mut().ok_or(io::ErrorKind::NotFound)?;
+        let written = file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().map_or(Ok(()), Write::flush)
+    }
+}
+
+/// Bounded queue of formatted lines in front of the writer thread
+struct LogQueue {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl LogQueue {
+    /// Start the writer thread draining into `sink`
+    fn spawn(sink: Box<dyn Write + Send>) -> Option<Self> {
+        let (sender, receiver) = mpsc::sync_channel(LOG_QUEUE_LINES);
+        thread::Builder::new()
+            .name("chuniio-log".to_string())
+            .spawn(move || write_lines(receiver, sink))
+            .ok()?;
+        Some(Self { sender })
+    }
+}
+
+/// Writer thread body: write lines as they arrive, flushing whenever the queue
+/// runs empty
+fn write_lines(receiver: Receiver<Vec<u8>>, mut sink: Box<dyn Write + Send>) {
+    while let Ok(line) = receiver.recv() {
+        let _ = sink.write_all(&line);
+        while let Ok(line) = receiver.try_recv() {
+            let _ = sink.write_all(&line);
+        }
+        let _ = sink.flush();
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogQueue {
+    type Writer = QueuedLine<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        QueuedLine {
+            sender: &self.sender,
+            line: Vec::new(),
+        }
+    }
+}
+
+/// Collects one formatted event and enqueues it when dropped
+struct QueuedLine<'a> {
+    sender: &'a SyncSender<Vec<u8>>,
+    line: Vec<u8>,
+}
+
+impl Write for QueuedLine<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for QueuedLine<'_> {
+    fn drop(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        if self
+            .sender
+            .try_send(std::mem::take(&mut self.line))
+            .is_err()
+        {
+            LINES_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Passes one in every `n` calls
+pub struct LogSampler {
+    every: u64,
+    count: AtomicU64,
+}
+
+impl LogSampler {
+    pub const fn new(every: u64) -> Self {
+        Self {
+            every,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether this call should be logged (the first call always is)
+    pub fn sample(&self) -> bool {
+        self.count
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.every.max(1))
+    }
+}
+
+/// Reference point for throttle timestamps
+static LOG_EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Passes at most one call per interval and counts the calls it suppressed
+pub struct LogThrottle {
+    interval_ms: u64,
+    /// Milliseconds since `LOG_EPOCH` of the last passed call, plus one (0 = never)
+    last_ms: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl LogThrottle {
+    pub const fn new(interval_secs: u64) -> Self {
+        Self {
+            interval_ms: interval_secs * 1000,
+            last_ms: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of calls suppressed since the last passed call if this
+    /// call should be logged, or `None` if it is suppressed
+    pub fn allow(&self) -> Option<u64> {
+        let now_ms = LOG_EPOCH.get_or_init(Instant::now).elapsed().as_millis() as u64 + 1;
+        let last_ms = self.last_ms.load(Ordering::Relaxed);
+
+        if last_ms != 0 && now_ms - last_ms < self.interval_ms {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if self
+            .last_ms
+            .compare_exchange(last_ms, now_ms, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// Log only one in every `n` executions of this call site
+///
+/// `log_every_n!(1000, debug!("Polled {}", x))`
+macro_rules! log_every_n {
+    ($n:expr, $level:ident!($($arg:tt)+)) => {{
+        static SAMPLER: $crate::logging::LogSampler = $crate::logging::LogSampler::new($n);
+        if SAMPLER.sample() {
+            tracing::$level!($($arg)+);
+        }
+    }};
+}
+
+/// Log at most once per `secs` seconds from this call site, reporting how many
+/// occurrences were suppressed in between
+///
+/// `log_throttled!(60, error!("Send failed: {:?}", e))`
+macro_rules! log_throttled {
+    ($secs:expr, $level:ident!(target: $target:expr, $($arg:tt)+)) => {{
+        static THROTTLE: $crate::logging::LogThrottle = $crate::logging::LogThrottle::new($secs);
+        if let Some(suppressed) = THROTTLE.allow() {
+            if suppressed > 0 {
+                tracing::$level!(target: $target, suppressed, $($arg)+);
+            } else {
+                tracing::$level!(target: $target, $($arg)+);
+            }
+        }
+    }};
+    ($secs:expr, $level:ident!($($arg:tt)+)) => {{
+        static THROTTLE: $crate::logging::LogThrottle = $crate::logging::LogThrottle::new($secs);
+        if let Some(suppressed) = THROTTLE.allow() {
+            if suppressed > 0 {
+                tracing::$level!(suppressed, $($arg)+);
+            } else {
+                tracing::$level!($($arg)+);
+            }
+        }
+    }};
+}
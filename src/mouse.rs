@@ -0,0 +1,178 @@
+//! Mouse input
+//!
+//! The mouse can stand in for the slider, as a zero-hardware way to try out
+//! charts: while the left button is held over the game window, a band of
+//! columns under the cursor is touched, following it across the window.
+//!
+//! It can also stand in for the air sensor: either the cursor's height while
+//! the right button is held, or a hand height raised and lowered with the
+//! scroll wheel, blocks the IR beam at that height. The wheel is read through a
+//! mouse hook on the game window's thread.
+
+use std::{
+    ffi::c_void,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, Ordering},
+};
+
+use tracing::{debug, warn};
+
+use windows::Win32::{
+    Foundation::{HINSTANCE, LPARAM, LRESULT, POINT, WPARAM},
+    UI::{
+        Input::KeyboardAndMouse::{GetAsyncKeyState, VIRTUAL_KEY, VK_LBUTTON, VK_RBUTTON},
+        WindowsAndMessaging::{
+            CallNextHookEx, GetCursorPos, GetForegroundWindow, GetWindowThreadProcessId,
+            SetWindowsHookExW, UnhookWindowsHookEx, HC_ACTION, HHOOK, MOUSEHOOKSTRUCTEX,
+            WHEEL_DELTA, WH_MOUSE, WM_MOUSEWHEEL,
+        },
+    },
+};
+
+use crate::{
+    config, focus,
+    input::{self, BEAM_COUNT, SLIDER_CELLS},
+};
+
+/// How the mouse drives the IR beams
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MouseAir {
+    /// Not at all
+    #[default]
+    Off,
+    /// The cursor's height over the game window while the right button is held
+    Position,
+    /// A hand height raised and lowered one beam per scroll wheel notch
+    Wheel,
+}
+
+impl MouseAir {
+    /// Parse a mode name (`off`, `position`, `wheel`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "position" => Some(Self::Position),
+            "wheel" => Some(Self::Wheel),
+            _ => None,
+        }
+    }
+
+    /// Mode name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Position => "position",
+            Self::Wheel => "wheel",
+        }
+    }
+}
+
+/// Wheel hook on the game window's thread, null until installed
+static WHEEL_HOOK: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Set once installing the wheel hook failed, so it is only reported once
+static WHEEL_HOOK_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Scroll wheel position in wheel units, from 0 (hand below the beams) to one
+/// notch per beam above that
+static WHEEL_POSITION: AtomicI32 = AtomicI32::new(0);
+
+/// Cursor position over the focused game window's client area, as fractions
+/// of its width and height from the top left; `None` outside it
+fn cursor_position() -> Option<(f32, f32)> {
+    if !focus::is_focused() {
+        return None;
+    }
+    let mut cursor = POINT::default();
+    unsafe {
+        GetCursorPos(&mut cursor).ok()?;
+        focus::client_position(GetForegroundWindow(), cursor)
+    }
+}
+
+fn button_held(button: VIRTUAL_KEY) -> bool {
+    unsafe { GetAsyncKeyState(i32::from(button.0)) as u16 & 0x8000 != 0 }
+}
+
+/// Slider pressure from the mouse, `None` unless the mouse slider is enabled
+/// and the button is held over the game window
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    let config = config::get();
+    if !config.mouse_slider || !button_held(VK_LBUTTON) {
+        return None;
+    }
+    let (x, _) = cursor_position()?;
+    Some(input::touch_band(x, config.mouse_slider_width))
+}
+
+/// IR beam bits for the hand height set with the mouse
+pub fn beams() -> u8 {
+    let beam = match config::get().mouse_air {
+        MouseAir::Off => return 0,
+        MouseAir::Position => {
+            if !button_held(VK_RBUTTON) {
+                return 0;
+            }
+            let Some((_, y)) = cursor_position() else {
+                return 0;
+            };
+            // Beam 0 is the lowest
+            ((1.0 - y) * BEAM_COUNT as f32) as usize
+        }
+        MouseAir::Wheel => {
+            install_wheel_hook();
+            let notches = WHEEL_POSITION.load(Ordering::Relaxed) / WHEEL_DELTA as i32;
+            match notches {
+                0 => return 0,
+                notches => notches as usize - 1,
+            }
+        }
+    };
+    1 << beam.min(BEAM_COUNT - 1)
+}
+
+/// Hook the game window's thread for wheel messages, once the window is found
+fn install_wheel_hook() {
+    if !WHEEL_HOOK.load(Ordering::Relaxed).is_null()
+        || WHEEL_HOOK_FAILED.load(Ordering::Relaxed)
+        || !focus::is_focused()
+    {
+        return;
+    }
+    unsafe {
+        let thread = GetWindowThreadProcessId(GetForegroundWindow(), None);
+        match SetWindowsHookExW(WH_MOUSE, Some(wheel_hook), HINSTANCE::default(), thread) {
+            Ok(hook) => {
+                debug!("Installed mouse wheel hook on thread {}", thread);
+                WHEEL_HOOK.store(hook.0, Ordering::Relaxed);
+            }
+            Err(e) => {
+                warn!("Cannot hook the mouse wheel, mouse air is disabled: {}", e);
+                WHEEL_HOOK_FAILED.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+unsafe extern "system" fn wheel_hook(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    if code == HC_ACTION as i32 && wparam.0 as u32 == WM_MOUSEWHEEL {
+        let info = &*(lparam.0 as *const MOUSEHOOKSTRUCTEX);
+        // The wheel delta is the high word, positive away from the user
+        let delta = i32::from((info.mouseData >> 16) as u16 as i16);
+        let top = (BEAM_COUNT as u32 * WHEEL_DELTA) as i32;
+        let _ = WHEEL_POSITION.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |position| {
+            Some((position + delta).clamp(0, top))
+        });
+    }
+    CallNextHookEx(HHOOK::default(), code, wparam, lparam)
+}
+
+/// Remove the wheel hook before the DLL is unloaded
+pub fn shutdown() {
+    let hook = WHEEL_HOOK.swap(ptr::null_mut(), Ordering::Relaxed);
+    if !hook.is_null() {
+        unsafe {
+            let _ = UnhookWindowsHookEx(HHOOK(hook));
+        }
+    }
+}
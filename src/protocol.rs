@@ -3,7 +3,10 @@
 //! This module defines the binary protocol messages used to communicate
 //! with Backflow's chuniio_proxy backend over Unix domain sockets.
 
-use std::io::{self, Cursor, Read};
+use std::{
+    fmt,
+    io::{self, Cursor, Read},
+};
 
 /// chuniio protocol message types
 #[derive(Debug, Clone)]
@@ -39,6 +42,141 @@ pub enum ChuniMessage {
         pressure: [u8; 32],
         coin_counter: u16,
     },
+    /// Request for the settings the proxy wants to set, sent after connecting
+    RemoteConfigRead,
+    /// Settings pushed by the proxy; `None` leaves a setting to the local
+    /// configuration
+    RemoteConfigResponse {
+        slider_poll_rate_hz: Option<u16>,
+        idle_poll_rate_hz: Option<u16>,
+        led_brightness: Option<[u8; 3]>,
+        /// 0 = normal, 1 = freeplay, 2 = auto
+        coin_mode: Option<u8>,
+    },
+    /// Handshake sent right after connecting, with our protocol version and
+    /// capabilities
+    Hello {
+        version: u16,
+        capabilities: Capabilities,
+    },
+    /// The proxy's protocol version and the capabilities it supports
+    HelloAck {
+        version: u16,
+        capabilities: Capabilities,
+    },
+    /// Ask the proxy to stream its state as unsolicited
+    /// `JvsFullStateReadResponse` messages instead of waiting to be polled
+    Subscribe,
+    /// The proxy's answer to a request it could not handle, in place of the
+    /// response; `code` is one of the `ERROR_*` codes
+    Error { code: u8, message: String },
+    /// Several messages sent as one; responses to the requests in it come
+    /// back separately, in order
+    Batch { messages: Vec<ChuniMessage> },
+    /// Last message before the connection closes because the game is exiting
+    Goodbye,
+}
+
+/// Flags in `RemoteConfigResponse` marking the settings it carries
+const REMOTE_SLIDER_POLL_RATE: u8 = 1 << 0;
+const REMOTE_IDLE_POLL_RATE: u8 = 1 << 1;
+const REMOTE_LED_BRIGHTNESS: u8 = 1 << 2;
+const REMOTE_COIN_MODE: u8 = 1 << 3;
+
+/// `Error` codes
+pub const ERROR_UNSUPPORTED: u8 = 0x01;
+pub const ERROR_BAD_BOARD: u8 = 0x02;
+pub const ERROR_INTERNAL: u8 = 0x03;
+
+/// Human-readable name for an `Error` code
+pub fn error_name(code: u8) -> &'static str {
+    match code {
+        ERROR_UNSUPPORTED => "unsupported message",
+        ERROR_BAD_BOARD => "bad board index",
+        ERROR_INTERNAL => "internal error",
+        _ => "unknown error",
+    }
+}
+
+/// Protocol version sent in `Hello`
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Features a peer supports, exchanged in `Hello` and `HelloAck` as a bitmask
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// LED boards 0-2, one bit each
+    const LED_BOARDS: u32 = 0b111;
+    /// Input streamed by the proxy instead of polled
+    pub const PUSH: u32 = 1 << 3;
+    /// `JvsFullStateRead`
+    pub const FULL_STATE: u32 = 1 << 4;
+    /// Request IDs in the frame header, echoed in responses
+    pub const REQUEST_IDS: u32 = 1 << 5;
+    /// CRC32 trailer on every frame
+    pub const CHECKSUMS: u32 = 1 << 6;
+    /// `Batch`
+    pub const BATCH: u32 = 1 << 7;
+
+    const NAMES: [(u32, &'static str); 8] = [
+        (1 << 0, "led0"),
+        (1 << 1, "led1"),
+        (1 << 2, "led2"),
+        (Self::PUSH, "push"),
+        (Self::FULL_STATE, "full-state"),
+        (Self::REQUEST_IDS, "request-ids"),
+        (Self::CHECKSUMS, "checksums"),
+        (Self::BATCH, "batch"),
+    ];
+
+    /// What this DLL supports, sent in `Hello`
+    pub const SUPPORTED: Self = Self(
+        Self::LED_BOARDS
+            | Self::PUSH
+            | Self::FULL_STATE
+            | Self::REQUEST_IDS
+            | Self::CHECKSUMS
+            | Self::BATCH,
+    );
+
+    /// What a proxy is assumed to support when no handshake is made
+    pub const ASSUMED: Self = Self(Self::LED_BOARDS | Self::FULL_STATE);
+
+    /// Whether every bit of `flag` is set
+    pub fn has(self, flag: u32) -> bool {
+        self.0 & flag == flag
+    }
+
+    /// These capabilities without `flag`
+    pub fn without(self, flag: u32) -> Self {
+        Self(self.0 & !flag)
+    }
+
+    /// Whether LED board `board` is supported
+    pub fn led_board(self, board: usize) -> bool {
+        self.has(1 << board)
+    }
+
+    /// Capabilities both sides support
+    pub fn intersect(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|&&(flag, _)| self.has(flag))
+            .map(|&(_, name)| name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
 }
 
 /// Message type IDs
@@ -56,57 +194,213 @@ impl ChuniMessage {
     pub const PONG: u8 = 0x09;
     pub const JVS_FULL_STATE_READ: u8 = 0x0C;
     pub const JVS_FULL_STATE_READ_RESPONSE: u8 = 0x0D;
+    pub const REMOTE_CONFIG_READ: u8 = 0x0E;
+    pub const REMOTE_CONFIG_RESPONSE: u8 = 0x0F;
+    pub const HELLO: u8 = 0x10;
+    pub const HELLO_ACK: u8 = 0x11;
+    pub const SUBSCRIBE: u8 = 0x12;
+    pub const ERROR: u8 = 0x13;
+    pub const BATCH: u8 = 0x14;
+    pub const GOODBYE: u8 = 0x15;
+
+    /// Message type ID of this message
+    pub fn message_type(&self) -> u8 {
+        match self {
+            ChuniMessage::JvsPoll => Self::JVS_POLL,
+            ChuniMessage::JvsPollResponse { .. } => Self::JVS_POLL_RESPONSE,
+            ChuniMessage::CoinCounterRead => Self::COIN_COUNTER_READ,
+            ChuniMessage::CoinCounterReadResponse { .. } => Self::COIN_COUNTER_READ_RESPONSE,
+            ChuniMessage::SliderInput { .. } => Self::SLIDER_INPUT,
+            ChuniMessage::SliderStateRead => Self::SLIDER_STATE_READ,
+            ChuniMessage::SliderStateReadResponse { .. } => Self::SLIDER_STATE_READ_RESPONSE,
+            ChuniMessage::SliderLedUpdate { .. } => Self::SLIDER_LED_UPDATE,
+            ChuniMessage::LedUpdate { .. } => Self::LED_UPDATE,
+            ChuniMessage::Ping => Self::PING,
+            ChuniMessage::Pong => Self::PONG,
+            ChuniMessage::JvsFullStateRead => Self::JVS_FULL_STATE_READ,
+            ChuniMessage::JvsFullStateReadResponse { .. } => Self::JVS_FULL_STATE_READ_RESPONSE,
+            ChuniMessage::RemoteConfigRead => Self::REMOTE_CONFIG_READ,
+            ChuniMessage::RemoteConfigResponse { .. } => Self::REMOTE_CONFIG_RESPONSE,
+            ChuniMessage::Hello { .. } => Self::HELLO,
+            ChuniMessage::HelloAck { .. } => Self::HELLO_ACK,
+            ChuniMessage::Subscribe => Self::SUBSCRIBE,
+            ChuniMessage::Error { .. } => Self::ERROR,
+            ChuniMessage::Batch { .. } => Self::BATCH,
+            ChuniMessage::Goodbye => Self::GOODBYE,
+        }
+    }
+
+    /// Human-readable name for a message type ID
+    pub fn type_name(message_type: u8) -> &'static str {
+        match message_type {
+            Self::JVS_POLL => "JvsPoll",
+            Self::JVS_POLL_RESPONSE => "JvsPollResponse",
+            Self::COIN_COUNTER_READ => "CoinCounterRead",
+            Self::COIN_COUNTER_READ_RESPONSE => "CoinCounterReadResponse",
+            Self::SLIDER_INPUT => "SliderInput",
+            Self::SLIDER_STATE_READ => "SliderStateRead",
+            Self::SLIDER_STATE_READ_RESPONSE => "SliderStateReadResponse",
+            Self::SLIDER_LED_UPDATE => "SliderLedUpdate",
+            Self::LED_UPDATE => "LedUpdate",
+            Self::PING => "Ping",
+            Self::PONG => "Pong",
+            Self::JVS_FULL_STATE_READ => "JvsFullStateRead",
+            Self::JVS_FULL_STATE_READ_RESPONSE => "JvsFullStateReadResponse",
+            Self::REMOTE_CONFIG_READ => "RemoteConfigRead",
+            Self::REMOTE_CONFIG_RESPONSE => "RemoteConfigResponse",
+            Self::HELLO => "Hello",
+            Self::HELLO_ACK => "HelloAck",
+            Self::SUBSCRIBE => "Subscribe",
+            Self::ERROR => "Error",
+            Self::BATCH => "Batch",
+            Self::GOODBYE => "Goodbye",
+            _ => "Unknown",
+        }
+    }
+
+    /// Build the `LedUpdate` header (type, board, length) for RGB data sent in a
+    /// separate buffer, so frames can go out without being copied behind the header
+    pub fn led_update_header(board: u8, rgb_len: usize) -> [u8; 3] {
+        [Self::LED_UPDATE, board, rgb_len as u8]
+    }
+
+    /// Largest possible serialized message (`LedUpdate` with 255 bytes of RGB
+    /// data), other than a `Batch`
+    pub const MAX_SERIALIZED_LEN: usize = 3 + u8::MAX as usize;
+
+    /// Size of the `Batch` header: type and message count
+    pub const BATCH_HEADER_LEN: usize = 2;
+
+    /// Size of the length before each message in a `Batch`
+    pub const BATCH_ENTRY_PREFIX_LEN: usize = 2;
+
+    /// Preserialized fixed-size requests, sent without any serialization work
+    pub const JVS_POLL_FRAME: [u8; 1] = [Self::JVS_POLL];
+    pub const COIN_COUNTER_READ_FRAME: [u8; 1] = [Self::COIN_COUNTER_READ];
+    pub const SLIDER_STATE_READ_FRAME: [u8; 1] = [Self::SLIDER_STATE_READ];
+    pub const PING_FRAME: [u8; 1] = [Self::PING];
+    pub const JVS_FULL_STATE_READ_FRAME: [u8; 1] = [Self::JVS_FULL_STATE_READ];
+    pub const REMOTE_CONFIG_READ_FRAME: [u8; 1] = [Self::REMOTE_CONFIG_READ];
+    pub const SUBSCRIBE_FRAME: [u8; 1] = [Self::SUBSCRIBE];
+    pub const GOODBYE_FRAME: [u8; 1] = [Self::GOODBYE];
+
+    /// Preserialized bytes for messages that carry no payload
+    pub fn fixed_frame(&self) -> Option<&'static [u8]> {
+        match self {
+            ChuniMessage::JvsPoll => Some(&Self::JVS_POLL_FRAME),
+            ChuniMessage::CoinCounterRead => Some(&Self::COIN_COUNTER_READ_FRAME),
+            ChuniMessage::SliderStateRead => Some(&Self::SLIDER_STATE_READ_FRAME),
+            ChuniMessage::Ping => Some(&Self::PING_FRAME),
+            ChuniMessage::JvsFullStateRead => Some(&Self::JVS_FULL_STATE_READ_FRAME),
+            ChuniMessage::RemoteConfigRead => Some(&Self::REMOTE_CONFIG_READ_FRAME),
+            ChuniMessage::Subscribe => Some(&Self::SUBSCRIBE_FRAME),
+            ChuniMessage::Goodbye => Some(&Self::GOODBYE_FRAME),
+            _ => None,
+        }
+    }
 
-    /// Serialize message to bytes
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut data = Vec::new();
+    /// Size of the response to a request of type `request_type`, for requests
+    /// that expect one
+    ///
+    /// Responses are read by length so that several of them can be in flight on
+    /// the stream at once.
+    pub fn response_len(request_type: u8) -> Option<usize> {
+        match request_type {
+            Self::JVS_POLL => Some(3),
+            Self::COIN_COUNTER_READ => Some(3),
+            Self::SLIDER_STATE_READ => Some(33),
+            Self::PING => Some(1),
+            Self::JVS_FULL_STATE_READ => Some(37),
+            Self::REMOTE_CONFIG_READ => Some(10),
+            Self::HELLO => Some(7),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes `serialize_into` writes for this message
+    pub fn serialized_len(&self) -> usize {
+        match self {
+            ChuniMessage::JvsPoll
+            | ChuniMessage::CoinCounterRead
+            | ChuniMessage::SliderStateRead
+            | ChuniMessage::Ping
+            | ChuniMessage::Pong
+            | ChuniMessage::JvsFullStateRead
+            | ChuniMessage::RemoteConfigRead
+            | ChuniMessage::Subscribe
+            | ChuniMessage::Goodbye => 1,
+            ChuniMessage::JvsPollResponse { .. } => 3,
+            ChuniMessage::CoinCounterReadResponse { .. } => 3,
+            ChuniMessage::SliderInput { .. } | ChuniMessage::SliderStateReadResponse { .. } => 33,
+            ChuniMessage::SliderLedUpdate { rgb_data } => 2 + rgb_data.len(),
+            ChuniMessage::LedUpdate { rgb_data, .. } => 3 + rgb_data.len(),
+            ChuniMessage::JvsFullStateReadResponse { .. } => 37,
+            ChuniMessage::RemoteConfigResponse { .. } => 10,
+            ChuniMessage::Hello { .. } | ChuniMessage::HelloAck { .. } => 7,
+            ChuniMessage::Error { message, .. } => 3 + message.len().min(u8::MAX as usize),
+            ChuniMessage::Batch { messages } => {
+                Self::BATCH_HEADER_LEN
+                    + messages
+                        .iter()
+                        .take(u8::MAX as usize)
+                        .map(|message| Self::BATCH_ENTRY_PREFIX_LEN + message.serialized_len())
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    /// Serialize message into `buf` without allocating, returning the number of bytes written
+    ///
+    /// `buf` must be at least `serialized_len()` bytes long; `MAX_SERIALIZED_LEN`
+    /// is always enough for anything but a `Batch`.
+    pub fn serialize_into(&self, buf: &mut [u8]) -> usize {
+        debug_assert!(buf.len() >= self.serialized_len());
+        let mut writer = FrameWriter { buf, len: 0 };
 
         match self {
             ChuniMessage::JvsPoll => {
-                data.push(Self::JVS_POLL);
+                writer.push(Self::JVS_POLL);
             }
             ChuniMessage::JvsPollResponse { opbtn, beams } => {
-                data.push(Self::JVS_POLL_RESPONSE);
-                data.push(*opbtn);
-                data.push(*beams);
+                writer.push(Self::JVS_POLL_RESPONSE);
+                writer.push(*opbtn);
+                writer.push(*beams);
             }
             ChuniMessage::CoinCounterRead => {
-                data.push(Self::COIN_COUNTER_READ);
+                writer.push(Self::COIN_COUNTER_READ);
             }
             ChuniMessage::CoinCounterReadResponse { count } => {
-                data.push(Self::COIN_COUNTER_READ_RESPONSE);
-                data.extend_from_slice(&count.to_le_bytes());
+                writer.push(Self::COIN_COUNTER_READ_RESPONSE);
+                writer.extend(&count.to_le_bytes());
             }
             ChuniMessage::SliderInput { pressure } => {
-                data.push(Self::SLIDER_INPUT);
-                data.extend_from_slice(pressure);
+                writer.push(Self::SLIDER_INPUT);
+                writer.extend(pressure);
             }
             ChuniMessage::SliderStateRead => {
-                data.push(Self::SLIDER_STATE_READ);
+                writer.push(Self::SLIDER_STATE_READ);
             }
             ChuniMessage::SliderStateReadResponse { pressure } => {
-                data.push(Self::SLIDER_STATE_READ_RESPONSE);
-                data.extend_from_slice(pressure);
+                writer.push(Self::SLIDER_STATE_READ_RESPONSE);
+                writer.extend(pressure);
             }
             ChuniMessage::SliderLedUpdate { rgb_data } => {
-                data.push(Self::SLIDER_LED_UPDATE);
-                data.push(rgb_data.len() as u8);
-                data.extend_from_slice(rgb_data);
+                writer.push(Self::SLIDER_LED_UPDATE);
+                writer.push(rgb_data.len() as u8);
+                writer.extend(rgb_data);
             }
             ChuniMessage::LedUpdate { board, rgb_data } => {
-                data.push(Self::LED_UPDATE);
-                data.push(*board);
-                data.push(rgb_data.len() as u8);
-                data.extend_from_slice(rgb_data);
+                writer.extend(&Self::led_update_header(*board, rgb_data.len()));
+                writer.extend(rgb_data);
             }
             ChuniMessage::Ping => {
-                data.push(Self::PING);
+                writer.push(Self::PING);
             }
             ChuniMessage::Pong => {
-                data.push(Self::PONG);
+                writer.push(Self::PONG);
             }
             ChuniMessage::JvsFullStateRead => {
-                data.push(Self::JVS_FULL_STATE_READ);
+                writer.push(Self::JVS_FULL_STATE_READ);
             }
             ChuniMessage::JvsFullStateReadResponse {
                 opbtn,
@@ -114,15 +408,77 @@ impl ChuniMessage {
                 pressure,
                 coin_counter,
             } => {
-                data.push(Self::JVS_FULL_STATE_READ_RESPONSE);
-                data.push(*opbtn);
-                data.push(*beams);
-                data.extend_from_slice(pressure);
-                data.extend_from_slice(&coin_counter.to_le_bytes());
+                writer.push(Self::JVS_FULL_STATE_READ_RESPONSE);
+                writer.push(*opbtn);
+                writer.push(*beams);
+                writer.extend(pressure);
+                writer.extend(&coin_counter.to_le_bytes());
+            }
+            ChuniMessage::RemoteConfigRead => {
+                writer.push(Self::REMOTE_CONFIG_READ);
+            }
+            ChuniMessage::RemoteConfigResponse {
+                slider_poll_rate_hz,
+                idle_poll_rate_hz,
+                led_brightness,
+                coin_mode,
+            } => {
+                let flag = |set: bool, flag: u8| if set { flag } else { 0 };
+                writer.push(Self::REMOTE_CONFIG_RESPONSE);
+                writer.push(
+                    flag(slider_poll_rate_hz.is_some(), REMOTE_SLIDER_POLL_RATE)
+                        | flag(idle_poll_rate_hz.is_some(), REMOTE_IDLE_POLL_RATE)
+                        | flag(led_brightness.is_some(), REMOTE_LED_BRIGHTNESS)
+                        | flag(coin_mode.is_some(), REMOTE_COIN_MODE),
+                );
+                writer.extend(&slider_poll_rate_hz.unwrap_or(0).to_le_bytes());
+                writer.extend(&idle_poll_rate_hz.unwrap_or(0).to_le_bytes());
+                writer.extend(&led_brightness.unwrap_or([0; 3]));
+                writer.push(coin_mode.unwrap_or(0));
+            }
+            ChuniMessage::Hello {
+                version,
+                capabilities,
+            } => {
+                writer.push(Self::HELLO);
+                writer.extend(&version.to_le_bytes());
+                writer.extend(&capabilities.0.to_le_bytes());
+            }
+            ChuniMessage::HelloAck {
+                version,
+                capabilities,
+            } => {
+                writer.push(Self::HELLO_ACK);
+                writer.extend(&version.to_le_bytes());
+                writer.extend(&capabilities.0.to_le_bytes());
+            }
+            ChuniMessage::Subscribe => {
+                writer.push(Self::SUBSCRIBE);
+            }
+            ChuniMessage::Goodbye => {
+                writer.push(Self::GOODBYE);
+            }
+            ChuniMessage::Error { code, message } => {
+                let message = &message.as_bytes()[..message.len().min(u8::MAX as usize)];
+                writer.push(Self::ERROR);
+                writer.push(*code);
+                writer.push(message.len() as u8);
+                writer.extend(message);
+            }
+            ChuniMessage::Batch { messages } => {
+                let messages = &messages[..messages.len().min(u8::MAX as usize)];
+                writer.push(Self::BATCH);
+                writer.push(messages.len() as u8);
+                for message in messages {
+                    let start = writer.len + Self::BATCH_ENTRY_PREFIX_LEN;
+                    let len = message.serialize_into(&mut writer.buf[start..]);
+                    writer.extend(&(len as u16).to_le_bytes());
+                    writer.len += len;
+                }
             }
         }
 
-        data
+        writer.len
     }
 
     /// Deserialize message from bytes
@@ -209,6 +565,76 @@ impl ChuniMessage {
                     coin_counter,
                 })
             }
+            Self::REMOTE_CONFIG_READ => Ok(ChuniMessage::RemoteConfigRead),
+            Self::REMOTE_CONFIG_RESPONSE => {
+                let mut flags = [0u8; 1];
+                let mut slider_bytes = [0u8; 2];
+                let mut idle_bytes = [0u8; 2];
+                let mut brightness = [0u8; 3];
+                let mut coin_mode = [0u8; 1];
+                cursor.read_exact(&mut flags)?;
+                cursor.read_exact(&mut slider_bytes)?;
+                cursor.read_exact(&mut idle_bytes)?;
+                cursor.read_exact(&mut brightness)?;
+                cursor.read_exact(&mut coin_mode)?;
+                let has = |flag: u8| flags[0] & flag != 0;
+                Ok(ChuniMessage::RemoteConfigResponse {
+                    slider_poll_rate_hz: has(REMOTE_SLIDER_POLL_RATE)
+                        .then(|| u16::from_le_bytes(slider_bytes)),
+                    idle_poll_rate_hz: has(REMOTE_IDLE_POLL_RATE)
+                        .then(|| u16::from_le_bytes(idle_bytes)),
+                    led_brightness: has(REMOTE_LED_BRIGHTNESS).then_some(brightness),
+                    coin_mode: has(REMOTE_COIN_MODE).then_some(coin_mode[0]),
+                })
+            }
+            Self::SUBSCRIBE => Ok(ChuniMessage::Subscribe),
+            Self::GOODBYE => Ok(ChuniMessage::Goodbye),
+            Self::ERROR => {
+                let mut code = [0u8; 1];
+                let mut len_bytes = [0u8; 1];
+                cursor.read_exact(&mut code)?;
+                cursor.read_exact(&mut len_bytes)?;
+
+                let mut message = vec![0u8; len_bytes[0] as usize];
+                cursor.read_exact(&mut message)?;
+                Ok(ChuniMessage::Error {
+                    code: code[0],
+                    message: String::from_utf8_lossy(&message).into_owned(),
+                })
+            }
+            Self::BATCH => {
+                let mut count = [0u8; 1];
+                cursor.read_exact(&mut count)?;
+
+                let mut messages = Vec::with_capacity(count[0] as usize);
+                for _ in 0..count[0] {
+                    let mut len_bytes = [0u8; 2];
+                    cursor.read_exact(&mut len_bytes)?;
+                    let mut message = vec![0u8; u16::from_le_bytes(len_bytes) as usize];
+                    cursor.read_exact(&mut message)?;
+                    messages.push(Self::deserialize(&message)?);
+                }
+                Ok(ChuniMessage::Batch { messages })
+            }
+            Self::HELLO | Self::HELLO_ACK => {
+                let mut version = [0u8; 2];
+                let mut capabilities = [0u8; 4];
+                cursor.read_exact(&mut version)?;
+                cursor.read_exact(&mut capabilities)?;
+                let version = u16::from_le_bytes(version);
+                let capabilities = Capabilities(u32::from_le_bytes(capabilities));
+                Ok(if message_type[0] == Self::HELLO {
+                    ChuniMessage::Hello {
+                        version,
+                        capabilities,
+                    }
+                } else {
+                    ChuniMessage::HelloAck {
+                        version,
+                        capabilities,
+                    }
+                })
+            }
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("Unknown message type: {}", message_type[0]),
@@ -216,3 +642,435 @@ impl ChuniMessage {
         }
     }
 }
+
+/// How messages are delimited on the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// Bare messages, each read by the length its type implies
+    #[default]
+    Raw,
+    /// Every message preceded by its length as a little-endian u16
+    Length,
+}
+
+impl Framing {
+    /// Parse a framing name (`raw`, `length`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "raw" => Some(Self::Raw),
+            "length" => Some(Self::Length),
+            _ => None,
+        }
+    }
+
+    /// Name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Length => "length",
+        }
+    }
+}
+
+/// Size of the length prefix of a framed message
+pub const FRAME_PREFIX_LEN: usize = 2;
+
+/// Size of the request ID after the length prefix, when request IDs are in use
+pub const REQUEST_ID_LEN: usize = 2;
+
+/// Largest frame header: length prefix and request ID
+pub const MAX_FRAME_HEADER_LEN: usize = FRAME_PREFIX_LEN + REQUEST_ID_LEN;
+
+/// Size of the checksum trailer after a framed message, when checksums are in use
+pub const CHECKSUM_LEN: usize = 4;
+
+/// CRC32 (IEEE) lookup table, one entry per byte value
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+/// Checksum trailer for a frame given in parts: the little-endian CRC32 of the
+/// header and message together
+pub fn frame_checksum(parts: &[&[u8]]) -> [u8; CHECKSUM_LEN] {
+    let crc = parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(!0u32, |crc, &byte| {
+            CRC32_TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8)
+        });
+    (!crc).to_le_bytes()
+}
+
+/// Header preceding a framed message: its length (type and payload) as a
+/// little-endian u16, then the request ID as another if request IDs are in use
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameHeader {
+    bytes: [u8; MAX_FRAME_HEADER_LEN],
+    len: usize,
+}
+
+impl FrameHeader {
+    pub fn new(message_len: usize, request_id: Option<u16>) -> Self {
+        let mut bytes = [0; MAX_FRAME_HEADER_LEN];
+        bytes[..FRAME_PREFIX_LEN].copy_from_slice(&(message_len as u16).to_le_bytes());
+        let len = match request_id {
+            Some(id) => {
+                bytes[FRAME_PREFIX_LEN..].copy_from_slice(&id.to_le_bytes());
+                MAX_FRAME_HEADER_LEN
+            }
+            None => FRAME_PREFIX_LEN,
+        };
+        Self { bytes, len }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Reassembles length-prefixed messages from the received byte stream, which
+/// may split a message across reads or put several in one
+#[derive(Default)]
+pub struct FrameBuffer {
+    data: Vec<u8>,
+    /// Whether frame headers carry a request ID
+    request_ids: bool,
+    /// Whether frames end in a checksum
+    checksums: bool,
+}
+
+impl FrameBuffer {
+    /// Expect a request ID in every frame header from now on
+    pub fn set_request_ids(&mut self, request_ids: bool) {
+        self.request_ids = request_ids;
+    }
+
+    /// Add received bytes
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Expect and verify a checksum after every frame from now on
+    pub fn set_checksums(&mut self, checksums: bool) {
+        self.checksums = checksums;
+    }
+
+    /// Drop any partial message, e.g. after reconnecting
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    /// Remove and decode the next message with its request ID (0 without
+    /// request IDs), `Ok(None)` until it has fully arrived
+    ///
+    /// A length no message can have, or a checksum that doesn't match, means
+    /// the stream is out of sync; the buffer is cleared and an error returned.
+    pub fn next_message(&mut self) -> io::Result<Option<(u16, ChuniMessage)>> {
+        let Some(prefix) = self.data.first_chunk::<FRAME_PREFIX_LEN>() else {
+            return Ok(None);
+        };
+        let len = usize::from(u16::from_le_bytes(*prefix));
+        if len == 0 || len > ChuniMessage::MAX_SERIALIZED_LEN {
+            self.data.clear();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid frame length: {}", len),
+            ));
+        }
+        let header_len = if self.request_ids {
+            MAX_FRAME_HEADER_LEN
+        } else {
+            FRAME_PREFIX_LEN
+        };
+        let end = header_len + len;
+        let trailer_len = if self.checksums { CHECKSUM_LEN } else { 0 };
+        if self.data.len() < end + trailer_len {
+            return Ok(None);
+        }
+        if self.checksums
+            && frame_checksum(&[&self.data[..end]]) != self.data[end..end + CHECKSUM_LEN]
+        {
+            self.data.clear();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Frame checksum mismatch",
+            ));
+        }
+        let request_id = if self.request_ids {
+            u16::from_le_bytes([self.data[FRAME_PREFIX_LEN], self.data[FRAME_PREFIX_LEN + 1]])
+        } else {
+            0
+        };
+        let message = ChuniMessage::deserialize(&self.data[header_len..end]);
+        self.data.drain(..end + trailer_len);
+        message.map(|message| Some((request_id, message)))
+    }
+}
+
+/// Appends bytes to a caller-provided buffer
+struct FrameWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl FrameWriter<'_> {
+    fn push(&mut self, byte: u8) {
+        self.buf[self.len] = byte;
+        self.len += 1;
+    }
+
+    fn extend(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_state_response() -> ChuniMessage {
+        let mut pressure = [0u8; 32];
+        pressure[0] = 0xFF;
+        pressure[31] = 0x80;
+        ChuniMessage::JvsFullStateReadResponse {
+            opbtn: 0x03,
+            beams: 0x2A,
+            pressure,
+            coin_counter: 0x1234,
+        }
+    }
+
+    #[test]
+    fn full_state_response_round_trips() {
+        let mut buf = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+        let len = full_state_response().serialize_into(&mut buf);
+        assert_eq!(
+            Some(len),
+            ChuniMessage::response_len(ChuniMessage::JvsFullStateRead.message_type())
+        );
+
+        match ChuniMessage::deserialize(&buf[..len]).unwrap() {
+            ChuniMessage::JvsFullStateReadResponse {
+                opbtn,
+                beams,
+                pressure,
+                coin_counter,
+            } => {
+                assert_eq!(opbtn, 0x03);
+                assert_eq!(beams, 0x2A);
+                assert_eq!(pressure[0], 0xFF);
+                assert_eq!(pressure[31], 0x80);
+                assert!(pressure[1..31].iter().all(|&cell| cell == 0));
+                assert_eq!(coin_counter, 0x1234);
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn full_state_response_layout() {
+        // Type, opbtn, beams, 32 pressure bytes, little-endian coin counter
+        let mut frame = vec![ChuniMessage::JVS_FULL_STATE_READ_RESPONSE, 0x01, 0x02];
+        frame.extend((0..32).map(|cell| cell as u8));
+        frame.extend([0x34, 0x12]);
+
+        let Ok(ChuniMessage::JvsFullStateReadResponse {
+            opbtn,
+            beams,
+            pressure,
+            coin_counter,
+        }) = ChuniMessage::deserialize(&frame)
+        else {
+            panic!("full-state response not decoded");
+        };
+        assert_eq!((opbtn, beams), (0x01, 0x02));
+        assert_eq!(pressure[5], 5);
+        assert_eq!(coin_counter, 0x1234);
+    }
+
+    #[test]
+    fn goodbye_is_a_bare_type_byte() {
+        let mut buf = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+        let len = ChuniMessage::Goodbye.serialize_into(&mut buf);
+        assert_eq!(&buf[..len], ChuniMessage::Goodbye.fixed_frame().unwrap());
+        assert_eq!(ChuniMessage::response_len(ChuniMessage::GOODBYE), None);
+        assert!(matches!(
+            ChuniMessage::deserialize(&[ChuniMessage::GOODBYE]),
+            Ok(ChuniMessage::Goodbye)
+        ));
+    }
+
+    #[test]
+    fn hello_ack_round_trips() {
+        let ack = ChuniMessage::HelloAck {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities(0b1_0101),
+        };
+        let mut buf = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+        let len = ack.serialize_into(&mut buf);
+        assert_eq!(Some(len), ChuniMessage::response_len(ChuniMessage::HELLO));
+
+        let Ok(ChuniMessage::HelloAck {
+            version,
+            capabilities,
+        }) = ChuniMessage::deserialize(&buf[..len])
+        else {
+            panic!("handshake acknowledgement not decoded");
+        };
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert!(capabilities.led_board(0) && !capabilities.led_board(1));
+        assert!(capabilities.has(Capabilities::FULL_STATE));
+        assert_eq!(capabilities.to_string(), "led0,led2,full-state");
+    }
+
+    #[test]
+    fn error_round_trips() {
+        let error = ChuniMessage::Error {
+            code: ERROR_BAD_BOARD,
+            message: "board 7".into(),
+        };
+        let mut buf = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+        let len = error.serialize_into(&mut buf);
+        assert_eq!(len, error.serialized_len());
+
+        let Ok(ChuniMessage::Error { code, message }) = ChuniMessage::deserialize(&buf[..len])
+        else {
+            panic!("error not decoded");
+        };
+        assert_eq!(code, ERROR_BAD_BOARD);
+        assert_eq!(message, "board 7");
+    }
+
+    #[test]
+    fn batch_round_trips() {
+        let batch = ChuniMessage::Batch {
+            messages: vec![
+                ChuniMessage::LedUpdate {
+                    board: 1,
+                    rgb_data: vec![0x10; 189],
+                },
+                ChuniMessage::JvsFullStateRead,
+            ],
+        };
+        let mut buf = vec![0u8; batch.serialized_len()];
+        assert_eq!(batch.serialize_into(&mut buf), buf.len());
+        // Type, count, then the LED update's length
+        assert_eq!(&buf[..4], &[ChuniMessage::BATCH, 2, 192, 0]);
+
+        let Ok(ChuniMessage::Batch { messages }) = ChuniMessage::deserialize(&buf) else {
+            panic!("batch not decoded");
+        };
+        assert!(matches!(
+            &messages[..],
+            [
+                ChuniMessage::LedUpdate { board: 1, rgb_data },
+                ChuniMessage::JvsFullStateRead
+            ] if rgb_data.len() == 189
+        ));
+    }
+
+    #[test]
+    fn frame_buffer_reassembles_split_and_joined_messages() {
+        let mut stream = Vec::new();
+        for message in [full_state_response(), ChuniMessage::Pong] {
+            let mut buf = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+            let len = message.serialize_into(&mut buf);
+            stream.extend(FrameHeader::new(len, None).as_bytes());
+            stream.extend(&buf[..len]);
+        }
+
+        let mut frames = FrameBuffer::default();
+        frames.extend(&stream[..20]);
+        assert!(frames.next_message().unwrap().is_none());
+        frames.extend(&stream[20..]);
+        assert!(matches!(
+            frames.next_message(),
+            Ok(Some((
+                0,
+                ChuniMessage::JvsFullStateReadResponse {
+                    coin_counter: 0x1234,
+                    ..
+                }
+            )))
+        ));
+        assert!(matches!(
+            frames.next_message(),
+            Ok(Some((0, ChuniMessage::Pong)))
+        ));
+        assert!(frames.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn frame_buffer_reads_request_ids() {
+        let mut frames = FrameBuffer::default();
+        frames.set_request_ids(true);
+        frames.extend(FrameHeader::new(1, Some(0x0102)).as_bytes());
+        frames.extend(&[ChuniMessage::PONG]);
+        assert!(matches!(
+            frames.next_message(),
+            Ok(Some((0x0102, ChuniMessage::Pong)))
+        ));
+    }
+
+    #[test]
+    fn frame_checksum_is_crc32() {
+        assert_eq!(
+            frame_checksum(&[b"1234", b"56789"]),
+            0xCBF4_3926u32.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn frame_buffer_verifies_checksums() {
+        let header = FrameHeader::new(1, None);
+        let checksum = frame_checksum(&[header.as_bytes(), &[ChuniMessage::PONG]]);
+        let mut frames = FrameBuffer::default();
+        frames.set_checksums(true);
+
+        frames.extend(header.as_bytes());
+        frames.extend(&[ChuniMessage::PONG]);
+        frames.extend(&checksum);
+        assert!(matches!(
+            frames.next_message(),
+            Ok(Some((0, ChuniMessage::Pong)))
+        ));
+
+        frames.extend(header.as_bytes());
+        frames.extend(&[ChuniMessage::PING]);
+        frames.extend(&checksum);
+        assert!(frames.next_message().is_err());
+    }
+
+    #[test]
+    fn frame_buffer_rejects_impossible_lengths() {
+        let mut frames = FrameBuffer::default();
+        frames.extend(&[0xFF, 0xFF, ChuniMessage::PONG]);
+        assert!(frames.next_message().is_err());
+        assert!(frames.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_full_state_response_is_rejected() {
+        let mut buf = [0u8; ChuniMessage::MAX_SERIALIZED_LEN];
+        let len = full_state_response().serialize_into(&mut buf);
+        assert!(ChuniMessage::deserialize(&buf[..len - 1]).is_err());
+        assert!(ChuniMessage::deserialize(&[]).is_err());
+    }
+}
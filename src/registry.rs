@@ -0,0 +1,156 @@
+//! Registry configuration
+//!
+//! Settings can also be stored as values under `HKCU\Software\chuniio-backflow`,
+//! which some launchers make easier to set in a Wine prefix than environment
+//! variables. Value names are the config file names without tables
+//! (`proxy_socket`, `led_max_rate`); string and DWORD values are accepted.
+
+use std::{
+    collections::HashMap,
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use windows::{
+    core::{w, PWSTR},
+    Win32::{
+        Foundation::{
+            ERROR_FILE_NOT_FOUND, ERROR_MORE_DATA, ERROR_NO_MORE_ITEMS, ERROR_SUCCESS, FILETIME,
+        },
+        System::Registry::{
+            RegCloseKey, RegEnumValueW, RegOpenKeyExW, RegQueryInfoKeyW, HKEY, HKEY_CURRENT_USER,
+            KEY_READ, REG_DWORD, REG_EXPAND_SZ, REG_SZ,
+        },
+    },
+};
+
+/// Key holding the settings, for log messages
+pub const KEY_NAME: &str = r"HKCU\Software\chuniio-backflow";
+
+/// Longest value name read, in UTF-16 units
+const MAX_NAME_LEN: usize = 256;
+
+/// Largest value read, in bytes
+const MAX_DATA_LEN: usize = 4096;
+
+/// FILETIME ticks (100ns since 1601) at the Unix epoch
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// Open registry key, closed on drop
+struct Key(HKEY);
+
+impl Key {
+    /// Open the settings key for reading, `None` if it does not exist
+    fn open() -> io::Result<Option<Self>> {
+        let mut key = HKEY::default();
+        let result = unsafe {
+            RegOpenKeyExW(
+                HKEY_CURRENT_USER,
+                w!(r"Software\chuniio-backflow"),
+                0,
+                KEY_READ,
+                &mut key,
+            )
+        };
+        match result {
+            ERROR_SUCCESS => Ok(Some(Self(key))),
+            ERROR_FILE_NOT_FOUND => Ok(None),
+            error => Err(io::Error::from_raw_os_error(error.0 as i32)),
+        }
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = RegCloseKey(self.0);
+        }
+    }
+}
+
+/// When the settings key was last written, `None` if it does not exist
+pub fn last_write_time() -> Option<SystemTime> {
+    let key = Key::open().ok()??;
+    let mut written = FILETIME::default();
+    let result = unsafe {
+        RegQueryInfoKeyW(
+            key.0,
+            PWSTR::null(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(&mut written),
+        )
+    };
+    if result != ERROR_SUCCESS {
+        return None;
+    }
+
+    let ticks = (u64::from(written.dwHighDateTime) << 32) | u64::from(written.dwLowDateTime);
+    let since_epoch = ticks.checked_sub(FILETIME_UNIX_EPOCH)?;
+    Some(UNIX_EPOCH + Duration::from_nanos(since_epoch.saturating_mul(100)))
+}
+
+/// Collect the values of the settings key into `values`, keyed by setting name;
+/// returns false if the key does not exist
+pub fn read(prefix: &str, values: &mut HashMap<String, String>) -> io::Result<bool> {
+    let Some(key) = Key::open()? else {
+        return Ok(false);
+    };
+
+    let mut name = [0u16; MAX_NAME_LEN];
+    let mut data = [0u8; MAX_DATA_LEN];
+    for index in 0.. {
+        let mut name_len = name.len() as u32;
+        let mut data_len = data.len() as u32;
+        let mut value_type = 0u32;
+        let result = unsafe {
+            RegEnumValueW(
+                key.0,
+                index,
+                PWSTR(name.as_mut_ptr()),
+                &mut name_len,
+                None,
+                Some(&mut value_type),
+                Some(data.as_mut_ptr()),
+                Some(&mut data_len),
+            )
+        };
+        match result {
+            ERROR_SUCCESS => {}
+            ERROR_NO_MORE_ITEMS => break,
+            // Names or values too long to be settings
+            ERROR_MORE_DATA => continue,
+            error => return Err(io::Error::from_raw_os_error(error.0 as i32)),
+        }
+
+        let data = &data[..data_len as usize];
+        let value = match value_type {
+            t if t == REG_SZ.0 || t == REG_EXPAND_SZ.0 => {
+                let units: Vec<u16> = data
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .take_while(|&unit| unit != 0)
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            t if t == REG_DWORD.0 && data.len() == 4 => {
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]).to_string()
+            }
+            _ => continue,
+        };
+
+        let name = String::from_utf16_lossy(&name[..name_len as usize]);
+        values.insert(
+            format!("{}_{}", prefix, name.trim().to_ascii_uppercase()),
+            value,
+        );
+    }
+    Ok(true)
+}
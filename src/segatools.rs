@@ -0,0 +1,221 @@
+//! segatools.ini compatibility
+//!
+//! Most setups already have a `segatools.ini`, so settings for this bridge can
+//! live in it too. Keys in its `[chuniio]` section use the config file names
+//! without tables (`proxy_socket = ...`, `led_max_rate = 30,30,60`); segatools
+//! ignores keys it does not know. Keys of segatools' own sections that have a
+//! meaning here are translated through [`SEGATOOLS_KEYS`] and
+//! [`SEGATOOLS_KEY_LISTS`]: the keyboard keys of `[io3]`, `[slider]` and
+//! `[ir]`, so the keys already set up for segatools' own chuniio keep working.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::PathBuf,
+};
+
+use crate::input::{BEAM_COUNT, SLIDER_CELLS};
+
+/// Environment variable segatools itself uses to locate its config file
+const PATH_ENV: &str = "SEGATOOLS_CONFIG_PATH";
+
+/// Default location, relative to the game's working directory like segatools
+const DEFAULT_PATH: &str = "segatools.ini";
+
+/// Section holding this bridge's own settings
+const BRIDGE_SECTION: &str = "chuniio";
+
+/// segatools' own `[chuniio]` keys (the chuniio DLL to load), not settings
+const RESERVED_KEYS: [&str; 3] = ["path", "path32", "path64"];
+
+/// segatools keys carried over into settings: (section, key, setting); the
+/// values are virtual-key codes
+const SEGATOOLS_KEYS: [(&str, &str, &str); 3] = [
+    ("io3", "test", "KEY_TEST"),
+    ("io3", "service", "KEY_SERVICE"),
+    ("io3", "coin", "KEY_COIN"),
+];
+
+/// Numbered segatools keys carried over into one list setting: (section, key
+/// prefix, setting, count); `cell1` is the first entry. Entries the file
+/// leaves out get no key.
+const SEGATOOLS_KEY_LISTS: [(&str, &str, &str, usize); 2] = [
+    ("slider", "cell", "KEY_SLIDER", SLIDER_CELLS),
+    ("ir", "ir", "KEY_AIR", BEAM_COUNT),
+];
+
+/// Location of segatools.ini
+pub fn path() -> PathBuf {
+    crate::config::get_env_var(PATH_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_PATH))
+}
+
+/// Collect the settings in an INI document into `values`, keyed by setting
+/// name, and the line each is set on into `lines`
+pub fn parse(
+    text: &str,
+    prefix: &str,
+    values: &mut HashMap<String, String>,
+    lines: &mut HashMap<String, usize>,
+) {
+    let mut section = String::new();
+    // Entries of each list in SEGATOOLS_KEY_LISTS and the first line setting one
+    let mut lists: Vec<(Vec<Option<String>>, usize)> = SEGATOOLS_KEY_LISTS
+        .iter()
+        .map(|&(_, _, _, count)| (vec![None; count], 0))
+        .collect();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_ascii_lowercase();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim().to_string();
+
+        if section == BRIDGE_SECTION {
+            if !RESERVED_KEYS.contains(&key.as_str()) {
+                let name = format!("{}_{}", prefix, key.to_ascii_uppercase());
+                lines.insert(name.clone(), index + 1);
+                values.insert(name, value);
+            }
+            continue;
+        }
+
+        let setting = SEGATOOLS_KEYS
+            .iter()
+            .find(|(s, k, _)| *s == section && *k == key)
+            .map(|(_, _, setting)| setting);
+        if let Some(setting) = setting {
+            translate(
+                prefix,
+                setting,
+                virtual_key(&value),
+                index + 1,
+                values,
+                lines,
+            );
+            continue;
+        }
+
+        for ((s, key_prefix, _, _), (entries, first_line)) in
+            SEGATOOLS_KEY_LISTS.iter().zip(&mut lists)
+        {
+            let entry = key
+                .strip_prefix(key_prefix)
+                .filter(|_| *s == section)
+                .and_then(|number| number.parse::<usize>().ok())
+                .and_then(|number| entries.get_mut(number.checked_sub(1)?));
+            if let Some(entry) = entry {
+                *entry = Some(virtual_key(&value));
+                if *first_line == 0 {
+                    *first_line = index + 1;
+                }
+            }
+        }
+    }
+
+    for ((_, _, setting, _), (entries, first_line)) in SEGATOOLS_KEY_LISTS.iter().zip(lists) {
+        if first_line != 0 {
+            let keys = entries
+                .into_iter()
+                .map(|entry| entry.unwrap_or_else(|| "none".to_string()))
+                .collect::<Vec<_>>()
+                .join(",");
+            translate(prefix, setting, keys, first_line, values, lines);
+        }
+    }
+}
+
+/// Set the translated `setting` unless `[chuniio]` sets it explicitly
+fn translate(
+    prefix: &str,
+    setting: &str,
+    value: String,
+    line: usize,
+    values: &mut HashMap<String, String>,
+    lines: &mut HashMap<String, usize>,
+) {
+    let name = format!("{}_{}", prefix, setting);
+    // Settings given explicitly in [chuniio] win over translated ones
+    if let Entry::Vacant(entry) = values.entry(name.clone()) {
+        entry.insert(value);
+        lines.insert(name, line);
+    }
+}
+
+/// A segatools virtual-key code, decimal or `0x` hex as Windows reads INI
+/// integers, in the hex form the key settings take; anything else is passed
+/// through to fail there
+fn virtual_key(value: &str) -> String {
+    match value.parse::<u8>() {
+        Ok(code) => format!("0x{:02X}", code),
+        Err(_) => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ini(text: &str) -> (HashMap<String, String>, HashMap<String, usize>) {
+        let mut values = HashMap::new();
+        let mut lines = HashMap::new();
+        parse(text, "CHUNIIO", &mut values, &mut lines);
+        (values, lines)
+    }
+
+    #[test]
+    fn reads_bridge_settings_from_chuniio_section() {
+        let (values, lines) = parse_ini(
+            "[chuniio]\npath=chuniio_backflow.dll\nproxy_socket = /tmp/proxy.sock\n\
+             ; comment\nLED_MAX_RATE=30,30,60\n[aime]\nenable=1\n",
+        );
+        assert_eq!(values.len(), 2, "{:?}", values);
+        assert_eq!(values["CHUNIIO_PROXY_SOCKET"], "/tmp/proxy.sock");
+        assert_eq!(values["CHUNIIO_LED_MAX_RATE"], "30,30,60");
+        assert_eq!(lines["CHUNIIO_LED_MAX_RATE"], 5);
+    }
+
+    #[test]
+    fn translates_segatools_keys() {
+        let (values, lines) = parse_ini(
+            "[io3]\ntest=0x70\nservice=113\ncoin=0x72\nir=0x20\n\
+             [slider]\ncell1=0x41\ncell32=0x5A\ncell33=0x42\ncell0=0x43\n\
+             [ir]\nir2=0x31\nir6=0x36\n",
+        );
+        assert_eq!(values["CHUNIIO_KEY_TEST"], "0x70");
+        assert_eq!(values["CHUNIIO_KEY_SERVICE"], "0x71", "decimal codes");
+        assert_eq!(values["CHUNIIO_KEY_COIN"], "0x72");
+        assert_eq!(lines["CHUNIIO_KEY_COIN"], 4);
+
+        let slider: Vec<_> = values["CHUNIIO_KEY_SLIDER"].split(',').collect();
+        assert_eq!(slider.len(), SLIDER_CELLS);
+        assert_eq!(slider[0], "0x41");
+        assert_eq!(slider[SLIDER_CELLS - 1], "0x5A");
+        assert!(slider[1..SLIDER_CELLS - 1].iter().all(|&key| key == "none"));
+        assert_eq!(lines["CHUNIIO_KEY_SLIDER"], 7);
+
+        assert_eq!(values["CHUNIIO_KEY_AIR"], "none,0x31,none,none,none,0x36");
+        assert_eq!(values.len(), 5, "{:?}", values);
+    }
+
+    #[test]
+    fn bridge_settings_win_over_translated_keys() {
+        for text in [
+            "[chuniio]\nkey_test=F5\n[io3]\ntest=0x70\n",
+            "[io3]\ntest=0x70\n[chuniio]\nkey_test=F5\n",
+        ] {
+            let (values, _) = parse_ini(text);
+            assert_eq!(values["CHUNIIO_KEY_TEST"], "F5", "{:?}", text);
+        }
+        let (values, _) = parse_ini("[chuniio]\nkey_air=none\n[ir]\nir1=0x31\n");
+        assert_eq!(values["CHUNIIO_KEY_AIR"], "none");
+    }
+}
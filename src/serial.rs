@@ -0,0 +1,367 @@
+//! Serial slider
+//!
+//! A slider that speaks the arcade slider's serial protocol (Yuancon,
+//! laverita and most open-source sliders) can be plugged into the game PC and
+//! read directly from its COM port, merged with the proxy's pressure. The
+//! slider LEDs the game sets are sent back to it.
+//!
+//! Frames are `0xFF`, a command, a payload length, the payload and a checksum
+//! that makes all the bytes sum to zero; `0xFF` and `0xFD` inside a frame are
+//! sent as `0xFD` followed by the byte minus one.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::windows::io::AsRawHandle,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{error, info, warn};
+
+use windows::Win32::{
+    Devices::Communication::{
+        GetCommState, SetCommState, SetCommTimeouts, COMMTIMEOUTS, DCB, NOPARITY, ONESTOPBIT,
+    },
+    Foundation::HANDLE,
+};
+
+use crate::{
+    config,
+    input::SLIDER_CELLS,
+    led::LED_BOARD_SIZES,
+    threading::{self, PendingRead},
+};
+
+/// Default baud rate of the arcade slider
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Slider LED data size: 31 LEDs, 3 bytes each
+const LED_DATA_LEN: usize = LED_BOARD_SIZES[2];
+
+const SYNC: u8 = 0xFF;
+const ESCAPE: u8 = 0xFD;
+
+const CMD_REPORT: u8 = 0x01;
+const CMD_SET_LED: u8 = 0x02;
+const CMD_AUTO_SCAN_START: u8 = 0x03;
+const CMD_RESET: u8 = 0x10;
+
+/// Brightness sent with LED data
+const LED_BRIGHTNESS: u8 = 0xFF;
+
+/// How long the last report counts without a new one
+const INPUT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a read waits for data, and so how often the thread checks for
+/// shutdown and LED updates
+const READ_TIMEOUT_MS: u32 = 10;
+
+/// Wait between attempts to open the port
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long `stop` waits for the reader thread to exit
+const STOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// DCB flags: binary mode, DTR and RTS on
+const DCB_FLAGS: u32 = 0x0001 | 0x0010 | 0x1000;
+
+/// Latest pressure report and when it arrived
+static REPORT: Mutex<Option<([u8; SLIDER_CELLS], Instant)>> = Mutex::new(None);
+
+/// LED data from the game not yet sent to the slider
+static PENDING_LEDS: Mutex<Option<[u8; LED_DATA_LEN]>> = Mutex::new(None);
+
+/// Set while the reader thread is running, cleared to stop it
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+static THREAD: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// The open port, so `stop` can cancel a read in progress
+static PORT: PendingRead = PendingRead::new();
+
+/// Slider pressure from the serial slider, `None` while it is not reporting
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    REPORT
+        .lock()
+        .ok()?
+        .filter(|(_, received)| received.elapsed() < INPUT_TIMEOUT)
+        .map(|(pressure, _)| pressure)
+}
+
+/// Queue slider LED data from the game for the serial slider
+pub fn set_leds(rgb: &[u8]) {
+    if !RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    if let (Ok(mut pending), Ok(data)) = (PENDING_LEDS.lock(), rgb.try_into()) {
+        *pending = Some(data);
+    }
+}
+
+/// Start reading the serial slider if one is configured and the reader is
+/// not already running
+pub fn start() {
+    let config = config::get();
+    if config.serial_slider.is_empty() || RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let port = config.serial_slider.clone();
+    let baud_rate = config.serial_slider_baud;
+    match thread::Builder::new()
+        .name("chuniio-serial".into())
+        .spawn(move || run(&port, baud_rate))
+    {
+        Ok(thread) => {
+            if let Ok(mut slot) = THREAD.lock() {
+                *slot = Some(thread);
+            }
+        }
+        Err(e) => {
+            error!("Failed to spawn serial slider thread: {:?}", e);
+            RUNNING.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Stop the reader thread, cancelling its read, and wait up to
+/// `STOP_TIMEOUT` for it to exit
+pub fn stop() {
+    RUNNING.store(false, Ordering::SeqCst);
+    let Some(thread) = THREAD.lock().ok().and_then(|mut slot| slot.take()) else {
+        return;
+    };
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    if !threading::wait_for_exit(&thread, deadline, || PORT.cancel()) {
+        warn!(
+            "Serial slider thread did not stop within {:?}, detaching it",
+            STOP_TIMEOUT
+        );
+    }
+}
+
+/// Reader thread body: keep the port open and read reports until stopped
+fn run(port: &str, baud_rate: u32) {
+    let mut reported_error = false;
+    while RUNNING.load(Ordering::SeqCst) {
+        match open(port, baud_rate) {
+            Ok(file) => {
+                info!("Reading serial slider on {} at {} baud", port, baud_rate);
+                reported_error = false;
+                match read_reports(file) {
+                    // Cancelled by `stop`
+                    Err(_) if !RUNNING.load(Ordering::SeqCst) => {}
+                    Err(e) => warn!("Serial slider on {} failed: {}", port, e),
+                    Ok(()) => {}
+                }
+            }
+            Err(e) => {
+                if !reported_error {
+                    warn!(
+                        "Cannot open serial slider on {}: {}, will keep trying",
+                        port, e
+                    );
+                    reported_error = true;
+                }
+            }
+        }
+        threading::sleep_while(&RUNNING, RETRY_INTERVAL);
+    }
+}
+
+/// Open `port` (e.g. `COM3`) and set it up for the slider
+fn open(port: &str, baud_rate: u32) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(format!(r"\\.\{}", port))?;
+    let handle = HANDLE(file.as_raw_handle());
+    unsafe {
+        let mut dcb = DCB {
+            DCBlength: std::mem::size_of::<DCB>() as u32,
+            ..Default::default()
+        };
+        GetCommState(handle, &mut dcb)?;
+        dcb.BaudRate = baud_rate;
+        dcb._bitfield = DCB_FLAGS;
+        dcb.ByteSize = 8;
+        dcb.Parity = NOPARITY;
+        dcb.StopBits = ONESTOPBIT;
+        SetCommState(handle, &dcb)?;
+
+        // Return what is available at once, or wait up to the timeout
+        let timeouts = COMMTIMEOUTS {
+            ReadIntervalTimeout: u32::MAX,
+            ReadTotalTimeoutMultiplier: u32::MAX,
+            ReadTotalTimeoutConstant: READ_TIMEOUT_MS,
+            WriteTotalTimeoutMultiplier: 0,
+            WriteTotalTimeoutConstant: 100,
+        };
+        SetCommTimeouts(handle, &timeouts)?;
+    }
+    Ok(file)
+}
+
+/// Start the slider's reports and read them until the port fails or the
+/// thread is stopped, sending LED updates in between
+fn read_reports(mut file: File) -> io::Result<()> {
+    let _reading = PORT.register(file.as_raw_handle());
+    file.write_all(&encode(CMD_RESET, &[]))?;
+    file.write_all(&encode(CMD_AUTO_SCAN_START, &[]))?;
+
+    let mut decoder = Decoder::default();
+    let mut buffer = [0u8; 256];
+    while RUNNING.load(Ordering::SeqCst) {
+        let leds = PENDING_LEDS
+            .lock()
+            .ok()
+            .and_then(|mut pending| pending.take());
+        if let Some(leds) = leds {
+            let mut payload = Vec::with_capacity(1 + LED_DATA_LEN);
+            payload.push(LED_BRIGHTNESS);
+            payload.extend_from_slice(&leds);
+            file.write_all(&encode(CMD_SET_LED, &payload))?;
+        }
+
+        let len = file.read(&mut buffer)?;
+        for &byte in &buffer[..len] {
+            let Some((command, payload)) = decoder.push(byte) else {
+                continue;
+            };
+            if command == CMD_REPORT {
+                if let (Ok(pressure), Ok(mut report)) = (payload.try_into(), REPORT.lock()) {
+                    *report = Some((pressure, Instant::now()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encode a frame, escaping everything after the sync byte
+fn encode(command: u8, payload: &[u8]) -> Vec<u8> {
+    let mut body = vec![command, payload.len() as u8];
+    body.extend_from_slice(payload);
+    let sum = body.iter().fold(SYNC, |sum, &byte| sum.wrapping_add(byte));
+    body.push(0u8.wrapping_sub(sum));
+
+    let mut frame = vec![SYNC];
+    for byte in body {
+        if byte == SYNC || byte == ESCAPE {
+            frame.extend_from_slice(&[ESCAPE, byte - 1]);
+        } else {
+            frame.push(byte);
+        }
+    }
+    frame
+}
+
+/// Reassembles frames from the received bytes
+#[derive(Default)]
+struct Decoder {
+    /// Unescaped bytes after the sync byte, `None` until one is seen
+    frame: Option<Vec<u8>>,
+    escaped: bool,
+}
+
+impl Decoder {
+    /// Add a received byte, returning the command and payload of a frame it
+    /// completes with a valid checksum
+    fn push(&mut self, byte: u8) -> Option<(u8, Vec<u8>)> {
+        if byte == SYNC {
+            self.frame = Some(Vec::new());
+            self.escaped = false;
+            return None;
+        }
+        let frame = self.frame.as_mut()?;
+        if byte == ESCAPE {
+            self.escaped = true;
+            return None;
+        }
+        frame.push(if self.escaped {
+            byte.wrapping_add(1)
+        } else {
+            byte
+        });
+        self.escaped = false;
+
+        // Command, length, payload, checksum
+        let len = usize::from(*frame.get(1)?);
+        if frame.len() < len + 3 {
+            return None;
+        }
+        let frame = self.frame.take()?;
+        let sum = frame.iter().fold(SYNC, |sum, &byte| sum.wrapping_add(byte));
+        (sum == 0).then(|| (frame[0], frame[2..len + 2].to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut decoder = Decoder::default();
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.push(byte))
+            .collect()
+    }
+
+    #[test]
+    fn escapes_sync_and_escape_bytes() {
+        assert_eq!(
+            encode(CMD_SET_LED, &[SYNC, ESCAPE, 0xFE]),
+            [SYNC, CMD_SET_LED, 3, ESCAPE, 0xFE, ESCAPE, 0xFC, 0xFE, 0x02]
+        );
+        assert_eq!(encode(CMD_RESET, &[]), [SYNC, CMD_RESET, 0, 0xF1]);
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let payloads: [&[u8]; 4] = [
+            &[],
+            &[1, 2, 3],
+            &[SYNC, ESCAPE, 0xFE, 0xFC, 0],
+            &[SYNC; SLIDER_CELLS],
+        ];
+        for payload in payloads {
+            let frame = encode(CMD_REPORT, payload);
+            assert_eq!(
+                decode(&frame),
+                [(CMD_REPORT, payload.to_vec())],
+                "{:02X?}",
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let mut frame = encode(CMD_REPORT, &[1, 2, 3]);
+        *frame.last_mut().unwrap() ^= 0x01;
+        assert_eq!(decode(&frame), []);
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let good = encode(CMD_REPORT, &[7; 4]);
+        let mut bytes = vec![0x12, ESCAPE, 0x34];
+        // A frame cut short by the next sync byte
+        bytes.extend_from_slice(&good[..4]);
+        bytes.extend_from_slice(&good);
+        // A frame with a bad checksum, then a good one
+        let mut bad = encode(CMD_REPORT, &[8; 4]);
+        *bad.last_mut().unwrap() = 0;
+        bytes.extend_from_slice(&bad);
+        bytes.extend_from_slice(&good);
+        assert_eq!(
+            decode(&bytes),
+            [(CMD_REPORT, vec![7; 4]), (CMD_REPORT, vec![7; 4])]
+        );
+    }
+}
@@ -0,0 +1,494 @@
+//! Input sources
+//!
+//! The proxy and the local sources (keyboard, mouse, controllers, touchscreen,
+//! network, directly attached hardware and a chain-loaded chuniio) are combined separately for each
+//! channel: the slider, the air and the operator buttons. Each channel has a
+//! policy:
+//!
+//! - `or`: every source at once, the highest pressure per cell and any bit set
+//! - `priority`: only the first source in the priority order with input
+//! - `exclusive`: the first source with input keeps the channel until it goes
+//!   idle, so a second source can't take over mid-touch
+//!
+//! A Brokenithm controller in `replace` mode takes the proxy's slider and air
+//! out of the merge while it is connected.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{
+    brokenithm, chain, config, dinput, hid, input::SLIDER_CELLS, keyboard, mouse, serial, tasoller,
+    touch, xinput,
+};
+
+/// Where input comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Proxy,
+    Keyboard,
+    Mouse,
+    Xinput,
+    Dinput,
+    Touch,
+    Brokenithm,
+    Serial,
+    Tasoller,
+    Hid,
+    Chain,
+}
+
+impl Source {
+    /// Every source, in the default priority order
+    const ALL: [Self; 11] = [
+        Self::Proxy,
+        Self::Keyboard,
+        Self::Mouse,
+        Self::Xinput,
+        Self::Dinput,
+        Self::Touch,
+        Self::Brokenithm,
+        Self::Serial,
+        Self::Tasoller,
+        Self::Hid,
+        Self::Chain,
+    ];
+
+    /// Parse a source name (`proxy`, `keyboard`, `mouse`, `xinput`, `dinput`,
+    /// `touch`, `brokenithm`, `serial`, `tasoller`, `hid`, `chain`)
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        Self::ALL.into_iter().find(|source| source.name() == value)
+    }
+
+    /// Name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Proxy => "proxy",
+            Self::Keyboard => "keyboard",
+            Self::Mouse => "mouse",
+            Self::Xinput => "xinput",
+            Self::Dinput => "dinput",
+            Self::Touch => "touch",
+            Self::Brokenithm => "brokenithm",
+            Self::Serial => "serial",
+            Self::Tasoller => "tasoller",
+            Self::Hid => "hid",
+            Self::Chain => "chain",
+        }
+    }
+}
+
+/// Priority order of the sources, highest first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceOrder(Vec<Source>);
+
+impl Default for SourceOrder {
+    fn default() -> Self {
+        Self(Source::ALL.to_vec())
+    }
+}
+
+impl SourceOrder {
+    /// Parse comma-separated source names, highest priority first; sources
+    /// left out follow in the default order
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut order = Vec::new();
+        for name in value.split(',').filter(|name| !name.trim().is_empty()) {
+            let source = Source::parse(name)?;
+            if order.contains(&source) {
+                return None;
+            }
+            order.push(source);
+        }
+        for source in Source::ALL {
+            if !order.contains(&source) {
+                order.push(source);
+            }
+        }
+        Some(Self(order))
+    }
+
+    /// Order as accepted by `parse`
+    pub fn name(&self) -> String {
+        self.0
+            .iter()
+            .map(|source| source.name())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Position of `source` in the order, 0 for the highest priority
+    fn rank(&self, source: Source) -> usize {
+        self.0
+            .iter()
+            .position(|&listed| listed == source)
+            .unwrap_or(self.0.len())
+    }
+}
+
+/// How the sources of a channel are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergePolicy {
+    /// Every source at once
+    #[default]
+    Or,
+    /// The highest-priority source with input
+    Priority,
+    /// The first source with input, until it goes idle
+    Exclusive,
+}
+
+impl MergePolicy {
+    /// Parse a policy name (`or`, `priority`, `exclusive`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "or" => Some(Self::Or),
+            "priority" => Some(Self::Priority),
+            "exclusive" => Some(Self::Exclusive),
+            _ => None,
+        }
+    }
+
+    /// Name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Or => "or",
+            Self::Priority => "priority",
+            Self::Exclusive => "exclusive",
+        }
+    }
+}
+
+/// A channel merged on its own
+#[derive(Debug, Clone, Copy)]
+enum Channel {
+    Slider,
+    Air,
+    Buttons,
+}
+
+/// Source holding each channel in `exclusive` mode, as its discriminant plus
+/// one, 0 for none
+static OWNERS: [AtomicU8; 3] = [AtomicU8::new(0), AtomicU8::new(0), AtomicU8::new(0)];
+
+/// Let go of every channel held in `exclusive` mode, for tests of the merge
+#[cfg(test)]
+pub fn reset() {
+    for owner in &OWNERS {
+        owner.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Local slider pressure, read before the proxy's so the state lock isn't held
+/// while polling devices
+pub struct SliderInputs([(Source, Option<[u8; SLIDER_CELLS]>); 10]);
+
+impl SliderInputs {
+    pub fn read() -> Self {
+        Self([
+            (Source::Keyboard, keyboard::slider_pressure()),
+            (Source::Mouse, mouse::slider_pressure()),
+            (Source::Xinput, xinput::slider_pressure()),
+            (Source::Dinput, dinput::slider_pressure()),
+            (Source::Touch, touch::slider_pressure()),
+            (Source::Brokenithm, brokenithm::slider_pressure()),
+            (Source::Serial, serial::slider_pressure()),
+            (Source::Tasoller, tasoller::slider_pressure()),
+            (Source::Hid, hid::slider_pressure()),
+            (Source::Chain, chain::slider_pressure()),
+        ])
+    }
+
+    /// Merge with the proxy's pressure
+    pub fn merge(&self, proxy: [u8; SLIDER_CELLS]) -> [u8; SLIDER_CELLS] {
+        let proxy = (!brokenithm::replaces_proxy()).then_some(proxy);
+        let inputs: Vec<_> = [(Source::Proxy, proxy)]
+            .into_iter()
+            .chain(self.0.iter().copied())
+            .collect();
+        merge(
+            Channel::Slider,
+            &inputs,
+            |pressure| pressure.iter().any(|&cell| cell != 0),
+            |mut merged, pressure| {
+                for (cell, touch) in merged.iter_mut().zip(pressure) {
+                    *cell = (*cell).max(touch);
+                }
+                merged
+            },
+            [0; SLIDER_CELLS],
+        )
+    }
+}
+
+/// Operator button bits (TEST, SERVICE) merged with the proxy's
+pub fn opbtn(proxy: u8) -> u8 {
+    let inputs = [
+        (Source::Proxy, Some(proxy)),
+        (Source::Keyboard, Some(keyboard::opbtn())),
+        (Source::Xinput, Some(xinput::opbtn())),
+        (Source::Dinput, Some(dinput::opbtn())),
+        (Source::Brokenithm, Some(brokenithm::opbtn())),
+        (Source::Tasoller, Some(tasoller::opbtn())),
+        (Source::Hid, Some(hid::opbtn())),
+        (Source::Chain, Some(chain::opbtn())),
+    ];
+    merge(
+        Channel::Buttons,
+        &inputs,
+        |&bits| bits != 0,
+        |a, b| a | b,
+        0,
+    )
+}
+
+/// IR beam bits merged with the proxy's
+pub fn beams(proxy: u8) -> u8 {
+    let proxy = (!brokenithm::replaces_proxy()).then_some(proxy);
+    let inputs = [
+        (Source::Proxy, proxy),
+        (Source::Keyboard, Some(keyboard::beams())),
+        (Source::Mouse, Some(mouse::beams())),
+        (Source::Xinput, Some(xinput::beams())),
+        (Source::Dinput, Some(dinput::beams())),
+        (Source::Brokenithm, Some(brokenithm::beams())),
+        (Source::Tasoller, Some(tasoller::beams())),
+        (Source::Hid, Some(hid::beams())),
+        (Source::Chain, Some(chain::beams())),
+    ];
+    merge(Channel::Air, &inputs, |&bits| bits != 0, |a, b| a | b, 0)
+}
+
+/// Combine the inputs of a channel by its policy; `None` is a source that has
+/// nothing to report, `active` tells input from idle, and `combine` adds one
+/// source's input to another's for `or`
+fn merge<T: Copy>(
+    channel: Channel,
+    inputs: &[(Source, Option<T>)],
+    active: fn(&T) -> bool,
+    combine: fn(T, T) -> T,
+    idle: T,
+) -> T {
+    let config = config::get();
+    let policy = match channel {
+        Channel::Slider => config.slider_policy,
+        Channel::Air => config.air_policy,
+        Channel::Buttons => config.button_policy,
+    };
+    let active_inputs = || {
+        inputs
+            .iter()
+            .filter_map(|&(source, input)| Some((source, input.filter(active)?)))
+    };
+    let first_active =
+        || active_inputs().min_by_key(|&(source, _)| config.source_priority.rank(source));
+
+    match policy {
+        MergePolicy::Or => inputs
+            .iter()
+            .filter_map(|&(_, input)| input)
+            .fold(idle, combine),
+        MergePolicy::Priority => first_active().map_or(idle, |(_, input)| input),
+        MergePolicy::Exclusive => {
+            let owner = &OWNERS[channel as usize];
+            let held = owner.load(Ordering::Relaxed);
+            if let Some((_, input)) = active_inputs().find(|&(source, _)| source as u8 + 1 == held)
+            {
+                return input;
+            }
+            let taken = first_active();
+            owner.store(
+                taken.map_or(0, |(source, _)| source as u8 + 1),
+                Ordering::Relaxed,
+            );
+            taken.map_or(idle, |(_, input)| input)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brokenithm::BrokenithmMode, config::Config, input::BEAM_COUNT};
+
+    /// One source's input to a merge
+    type Input<T> = (Source, Option<T>);
+
+    /// Merge operator button bits from `inputs` as the buttons channel would
+    fn buttons(inputs: &[Input<u8>]) -> u8 {
+        merge(Channel::Buttons, inputs, |&bits| bits != 0, |a, b| a | b, 0)
+    }
+
+    fn use_policy(policy: MergePolicy, source_priority: SourceOrder) {
+        config::set_for_test(Config {
+            slider_policy: policy,
+            air_policy: policy,
+            button_policy: policy,
+            source_priority,
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    fn parses_policies_and_orders() {
+        for policy in [
+            MergePolicy::Or,
+            MergePolicy::Priority,
+            MergePolicy::Exclusive,
+        ] {
+            assert_eq!(MergePolicy::parse(policy.name()), Some(policy));
+        }
+        assert_eq!(
+            MergePolicy::parse(" Exclusive "),
+            Some(MergePolicy::Exclusive)
+        );
+        assert_eq!(MergePolicy::parse("first"), None);
+
+        let order = SourceOrder::parse("touch, keyboard").unwrap();
+        assert_eq!(order.rank(Source::Touch), 0);
+        assert_eq!(order.rank(Source::Keyboard), 1);
+        assert_eq!(order.rank(Source::Proxy), 2);
+        assert_eq!(SourceOrder::parse(&order.name()), Some(order));
+        assert_eq!(SourceOrder::parse(""), Some(SourceOrder::default()));
+        assert_eq!(SourceOrder::parse("touch,touch"), None);
+        assert_eq!(SourceOrder::parse("joystick"), None);
+    }
+
+    #[test]
+    fn or_combines_every_source() {
+        let _globals = crate::lock_globals();
+        use_policy(MergePolicy::Or, SourceOrder::default());
+
+        let cases: [(&[Input<u8>], u8); 4] = [
+            (
+                &[(Source::Proxy, Some(0b01)), (Source::Keyboard, Some(0b10))],
+                0b11,
+            ),
+            (
+                &[(Source::Proxy, Some(0b01)), (Source::Keyboard, Some(0b01))],
+                0b01,
+            ),
+            (&[(Source::Proxy, None), (Source::Hid, Some(0b10))], 0b10),
+            (&[(Source::Proxy, None), (Source::Hid, Some(0))], 0),
+        ];
+        for (inputs, merged) in cases {
+            assert_eq!(buttons(inputs), merged, "{:?}", inputs);
+        }
+
+        let mut proxy = [0; SLIDER_CELLS];
+        proxy[0] = 200;
+        proxy[1] = 50;
+        let mut touch = [0; SLIDER_CELLS];
+        touch[1] = 120;
+        touch[31] = 80;
+        let slider = merge(
+            Channel::Slider,
+            &[(Source::Proxy, Some(proxy)), (Source::Touch, Some(touch))],
+            |pressure| pressure.iter().any(|&cell| cell != 0),
+            |mut merged, pressure| {
+                for (cell, touch) in merged.iter_mut().zip(pressure) {
+                    *cell = (*cell).max(touch);
+                }
+                merged
+            },
+            [0; SLIDER_CELLS],
+        );
+        assert_eq!(
+            (slider[0], slider[1], slider[2], slider[31]),
+            (200, 120, 0, 80)
+        );
+
+        config::set_for_test(Config::default());
+    }
+
+    #[test]
+    fn priority_takes_the_highest_ranked_source_with_input() {
+        let _globals = crate::lock_globals();
+        use_policy(
+            MergePolicy::Priority,
+            SourceOrder::parse("keyboard").unwrap(),
+        );
+
+        let cases: [(&[Input<u8>], u8); 4] = [
+            (
+                &[(Source::Proxy, Some(0b01)), (Source::Keyboard, Some(0b10))],
+                0b10,
+            ),
+            (
+                &[(Source::Proxy, Some(0b01)), (Source::Keyboard, Some(0))],
+                0b01,
+            ),
+            (
+                &[(Source::Chain, Some(0b10)), (Source::Proxy, Some(0b01))],
+                0b01,
+            ),
+            (&[(Source::Proxy, None), (Source::Keyboard, Some(0))], 0),
+        ];
+        for (inputs, merged) in cases {
+            assert_eq!(buttons(inputs), merged, "{:?}", inputs);
+        }
+
+        config::set_for_test(Config::default());
+    }
+
+    #[test]
+    fn exclusive_hands_off_only_when_the_holder_goes_idle() {
+        let _globals = crate::lock_globals();
+        use_policy(MergePolicy::Exclusive, SourceOrder::default());
+
+        // (proxy, keyboard) input in turn, and what the game sees
+        let steps = [
+            ((0, 0b10), 0b10),
+            // The keyboard keeps the channel over the higher-priority proxy
+            ((0b01, 0b10), 0b10),
+            ((0b01, 0b11), 0b11),
+            // and hands it off once idle
+            ((0b01, 0), 0b01),
+            ((0b01, 0b10), 0b01),
+            ((0, 0), 0),
+            // Nobody holds it after everything went idle
+            ((0b01, 0b10), 0b01),
+        ];
+        for ((proxy, keyboard), merged) in steps {
+            let inputs = [
+                (Source::Proxy, Some(proxy)),
+                (Source::Keyboard, Some(keyboard)),
+            ];
+            assert_eq!(buttons(&inputs), merged, "{:?}", inputs);
+        }
+
+        config::set_for_test(Config::default());
+    }
+
+    #[test]
+    fn brokenithm_replace_takes_the_place_of_the_proxy() {
+        let _globals = crate::lock_globals();
+        config::set_for_test(Config {
+            brokenithm: BrokenithmMode::Replace,
+            air_policy: MergePolicy::Exclusive,
+            ..Config::default()
+        });
+
+        // Without a controller the proxy holds the air
+        assert_eq!(beams(0b100000), 0b100000);
+
+        // A controller takes the proxy's air out of the merge, so the channel
+        // passes to it even in `exclusive` mode
+        let mut air = [0; BEAM_COUNT];
+        air[0] = 1;
+        brokenithm::set_for_test(Some((air, [0; SLIDER_CELLS])));
+        assert_eq!(beams(0b100000), 0b000001);
+
+        let mut slider = [0; SLIDER_CELLS];
+        slider[4] = 255;
+        brokenithm::set_for_test(Some((air, slider)));
+        let mut proxy = [0; SLIDER_CELLS];
+        proxy[20] = 255;
+        assert_eq!(SliderInputs::read().merge(proxy), slider);
+
+        // and gives it back once it disconnects and goes idle
+        brokenithm::set_for_test(None);
+        assert_eq!(beams(0b100000), 0b100000);
+
+        config::set_for_test(Config::default());
+    }
+}
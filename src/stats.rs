@@ -0,0 +1,110 @@
+//! Runtime statistics counters
+//!
+//! Counters are plain relaxed atomics so they can be bumped from the game
+//! thread and the background threads without any locking. A one-line summary
+//! is logged periodically so problems reported long after the fact ("the slider
+//! felt laggy at 2 AM") can be matched against what the bridge was doing.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tracing::info;
+
+use crate::{protocol::ChuniMessage, timing::Periodic};
+
+/// One counter slot per possible message type ID
+const MESSAGE_TYPE_SLOTS: usize = 256;
+
+/// Process-wide statistics
+pub struct Stats {
+    /// Messages sent to the proxy, indexed by message type ID
+    pub messages_sent: [AtomicU64; MESSAGE_TYPE_SLOTS],
+    /// Messages received from the proxy, indexed by message type ID
+    pub messages_received: [AtomicU64; MESSAGE_TYPE_SLOTS],
+    /// Sends or receives that failed at the socket level
+    pub send_failures: AtomicU64,
+    /// Successful reconnections to the proxy
+    pub reconnects: AtomicU64,
+    /// LED frames replaced by a newer frame before they could be sent
+    pub led_frames_dropped: AtomicU64,
+    /// Connections dropped by the watchdog because the proxy stopped answering
+    pub watchdog_resets: AtomicU64,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            messages_sent: [const { AtomicU64::new(0) }; MESSAGE_TYPE_SLOTS],
+            messages_received: [const { AtomicU64::new(0) }; MESSAGE_TYPE_SLOTS],
+            send_failures: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            led_frames_dropped: AtomicU64::new(0),
+            watchdog_resets: AtomicU64::new(0),
+        }
+    }
+
+    /// One-line summary of all counters since the process started
+    pub fn summary(&self) -> String {
+        let mut line = String::from("sent:");
+        append_per_type(&mut line, &self.messages_sent);
+        line.push_str(" received:");
+        append_per_type(&mut line, &self.messages_received);
+        let _ = write!(
+            line,
+            " send_failures={} reconnects={} led_frames_dropped={} watchdog_resets={} log_lines_dropped={}",
+            self.send_failures.load(Ordering::Relaxed),
+            self.reconnects.load(Ordering::Relaxed),
+            self.led_frames_dropped.load(Ordering::Relaxed),
+            self.watchdog_resets.load(Ordering::Relaxed),
+            crate::logging::lines_dropped()
+        );
+        line
+    }
+}
+
+fn append_per_type(line: &mut String, counters: &[AtomicU64; MESSAGE_TYPE_SLOTS]) {
+    let mut any = false;
+    for (message_type, counter) in counters.iter().enumerate() {
+        let count = counter.load(Ordering::Relaxed);
+        if count > 0 {
+            let _ = write!(
+                line,
+                " {}={}",
+                ChuniMessage::type_name(message_type as u8),
+                count
+            );
+            any = true;
+        }
+    }
+    if !any {
+        line.push_str(" none");
+    }
+}
+
+pub static STATS: Stats = Stats::new();
+
+/// Increment a counter by one
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a message of type `message_type` sent to the proxy
+pub fn record_sent(message_type: u8) {
+    increment(&STATS.messages_sent[message_type as usize]);
+}
+
+/// Count a message of type `message_type` received from the proxy
+pub fn record_received(message_type: u8) {
+    increment(&STATS.messages_received[message_type as usize]);
+}
+
+static REPORT: Periodic = Periodic::new();
+
+/// Log the statistics summary if the configured interval has elapsed
+pub fn maybe_report() {
+    if REPORT.ready(crate::config::get().stats_report_interval) {
+        info!("Stats: {}", STATS.summary());
+    }
+}
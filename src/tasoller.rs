@@ -0,0 +1,68 @@
+//! Tasoller
+//!
+//! A Tasoller plugged into the game PC (e.g. when dual-booting) can be read
+//! straight over raw HID instead of through the proxy, giving its slider
+//! pressure, air sensors and function buttons. Its input is merged with the
+//! proxy's.
+//!
+//! Each report, starting with its report ID, has the air sensors (bits 0-5)
+//! and the FN1 and FN2 buttons (bits 6 and 7) in byte 3, and the pressure of
+//! the 32 slider cells, in the game's order, in bytes 4-35.
+
+use crate::{
+    config,
+    hid::{DeviceId, HidReader},
+    input::SLIDER_CELLS,
+};
+
+const TASOLLER_ID: DeviceId = DeviceId {
+    vendor_id: 0x1CCF,
+    product_id: 0x2333,
+};
+
+const BUTTONS_OFFSET: usize = 3;
+const SLIDER_OFFSET: usize = 4;
+const REPORT_LEN: usize = SLIDER_OFFSET + SLIDER_CELLS;
+
+const AIR_MASK: u8 = 0x3F;
+/// FN1, used as SERVICE
+const FN1: u8 = 0x40;
+/// FN2, used as TEST
+const FN2: u8 = 0x80;
+
+static READER: HidReader = HidReader::new("Tasoller", "chuniio-tasoller");
+
+/// Start reading the Tasoller if it is enabled
+pub fn start() {
+    if config::get().tasoller {
+        READER.start(TASOLLER_ID, REPORT_LEN);
+    }
+}
+
+pub fn stop() {
+    READER.stop();
+}
+
+fn buttons() -> u8 {
+    READER
+        .with_report(|report| report.get(BUTTONS_OFFSET).copied().unwrap_or(0))
+        .unwrap_or(0)
+}
+
+/// Operator button bits (TEST, SERVICE) for the function buttons held down
+pub fn opbtn() -> u8 {
+    let buttons = buttons();
+    u8::from(buttons & FN2 != 0) | u8::from(buttons & FN1 != 0) << 1
+}
+
+/// IR beam bits for the air sensors blocked
+pub fn beams() -> u8 {
+    buttons() & AIR_MASK
+}
+
+/// Slider pressure from the Tasoller, `None` while it is not reporting
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    READER
+        .with_report(|report| report.get(SLIDER_OFFSET..REPORT_LEN)?.try_into().ok())
+        .flatten()
+}
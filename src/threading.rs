@@ -0,0 +1,202 @@
+//! Scheduling priority and CPU affinity for the background threads
+//!
+//! Raising the IO thread priority (and optionally pinning it to a core) keeps
+//! input latency stable while the game saturates the CPU.
+//!
+//! Also the pieces for stopping the threads from a DLL detach: waiting for a
+//! thread to return within a deadline, sleeping until a thread is stopped, and
+//! cancelling a read a thread is blocked in.
+
+use std::{
+    os::windows::io::RawHandle,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use windows::Win32::{
+    Foundation::HANDLE,
+    System::{
+        Threading::{
+            GetCurrentThread, SetThreadAffinityMask, SetThreadPriority, THREAD_PRIORITY,
+            THREAD_PRIORITY_ABOVE_NORMAL, THREAD_PRIORITY_HIGHEST, THREAD_PRIORITY_NORMAL,
+            THREAD_PRIORITY_TIME_CRITICAL,
+        },
+        IO::CancelIoEx,
+    },
+};
+
+/// How often a stopping thread is checked on
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How often a sleeping thread checks whether it was stopped
+const SLEEP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Thread priority levels that can be configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadPriority {
+    Normal,
+    AboveNormal,
+    Highest,
+    TimeCritical,
+}
+
+impl ThreadPriority {
+    /// Parse a priority name (`normal`, `above_normal`, `highest`, `time_critical`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "normal" => Some(Self::Normal),
+            "above_normal" => Some(Self::AboveNormal),
+            "highest" => Some(Self::Highest),
+            "time_critical" => Some(Self::TimeCritical),
+            _ => None,
+        }
+    }
+
+    /// Name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::AboveNormal => "above_normal",
+            Self::Highest => "highest",
+            Self::TimeCritical => "time_critical",
+        }
+    }
+
+    fn to_win32(self) -> THREAD_PRIORITY {
+        match self {
+            Self::Normal => THREAD_PRIORITY_NORMAL,
+            Self::AboveNormal => THREAD_PRIORITY_ABOVE_NORMAL,
+            Self::Highest => THREAD_PRIORITY_HIGHEST,
+            Self::TimeCritical => THREAD_PRIORITY_TIME_CRITICAL,
+        }
+    }
+}
+
+/// Scheduling settings for one background thread
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadConfig {
+    /// Priority to run the thread at (unchanged if unset)
+    pub priority: Option<ThreadPriority>,
+    /// Logical CPU index to pin the thread to (unpinned if unset)
+    pub cpu: Option<u32>,
+}
+
+impl ThreadConfig {
+    /// Fill the settings left unset here from `fallback`
+    pub fn or(self, fallback: ThreadConfig) -> ThreadConfig {
+        ThreadConfig {
+            priority: self.priority.or(fallback.priority),
+            cpu: self.cpu.or(fallback.cpu),
+        }
+    }
+}
+
+/// Apply `config` to the calling thread
+pub fn apply_to_current_thread(name: &str, config: &ThreadConfig) {
+    unsafe {
+        let thread = GetCurrentThread();
+
+        if let Some(priority) = config.priority {
+            match SetThreadPriority(thread, priority.to_win32()) {
+                Ok(()) => debug!("{} thread priority set to {:?}", name, priority),
+                Err(e) => warn!(
+                    "Failed to set {} thread priority to {:?}: {:?}",
+                    name, priority, e
+                ),
+            }
+        }
+
+        if let Some(cpu) = config.cpu {
+            if cpu >= usize::BITS {
+                warn!("Invalid CPU index {} for {} thread", cpu, name);
+            } else if SetThreadAffinityMask(thread, 1usize << cpu) == 0 {
+                warn!("Failed to pin {} thread to CPU {}", name, cpu);
+            } else {
+                debug!("{} thread pinned to CPU {}", name, cpu);
+            }
+        }
+    }
+}
+
+/// Wait until `deadline` for `thread` to return, calling `nudge` meanwhile to
+/// wake it from whatever it is blocked in; returns whether it returned.
+///
+/// Joining is not possible under the loader lock (DLL detach), but waiting
+/// for the thread function to return is; a thread that does not return in
+/// time is left running, detached.
+pub fn wait_for_exit(thread: &JoinHandle<()>, deadline: Instant, nudge: impl Fn()) -> bool {
+    while !thread.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        nudge();
+        thread::sleep(EXIT_POLL_INTERVAL);
+    }
+    true
+}
+
+/// Sleep for `duration`, returning early once `running` is cleared
+pub fn sleep_while(running: &AtomicBool, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while running.load(Ordering::SeqCst) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        thread::sleep(left.min(SLEEP_POLL_INTERVAL));
+    }
+}
+
+/// The device handle a thread is blocked reading, so another thread can
+/// cancel the read
+pub struct PendingRead {
+    /// Handle as an integer so it can be shared, 0 while none is registered
+    handle: Mutex<usize>,
+}
+
+/// Keeps a handle registered with a `PendingRead` until dropped
+pub struct PendingReadGuard<'a>(&'a PendingRead);
+
+impl PendingRead {
+    pub const fn new() -> Self {
+        Self {
+            handle: Mutex::new(0),
+        }
+    }
+
+    /// Register `handle` as the one being read until the guard is dropped;
+    /// the handle must stay open until then
+    pub fn register(&self, handle: RawHandle) -> PendingReadGuard<'_> {
+        if let Ok(mut registered) = self.handle.lock() {
+            *registered = handle as usize;
+        }
+        PendingReadGuard(self)
+    }
+
+    /// Cancel any read of the registered handle; the blocked read fails with
+    /// `ERROR_OPERATION_ABORTED`
+    pub fn cancel(&self) {
+        // Holding the lock keeps the handle from being closed meanwhile
+        if let Ok(registered) = self.handle.lock() {
+            if *registered != 0 {
+                unsafe {
+                    let _ = CancelIoEx(HANDLE(*registered as RawHandle), None);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PendingReadGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut registered) = self.0.handle.lock() {
+            *registered = 0;
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! High-resolution sleeping for the IO thread
+//!
+//! `thread::sleep` is rounded up to the scheduler tick (15.6ms by default on
+//! Windows and often at least 1ms under Wine), so a "1000Hz" loop built on it runs
+//! far slower than intended. A high-resolution waitable timer is used where the
+//! system provides one, falling back to raising the system timer resolution with
+//! `timeBeginPeriod` and waiting with a millisecond timeout. Either way the sleep
+//! also ends early when the thread's [`WakeEvent`] is signalled.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Once, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0},
+        Media::timeBeginPeriod,
+        System::Threading::{
+            CreateEventW, CreateWaitableTimerExW, SetEvent, SetWaitableTimer,
+            WaitForMultipleObjects, WaitForSingleObject, CREATE_WAITABLE_TIMER_HIGH_RESOLUTION,
+            INFINITE, TIMER_ALL_ACCESS,
+        },
+    },
+};
+
+/// TIMERR_NOERROR from timeapi.h
+const TIMERR_NOERROR: u32 = 0;
+
+static TIMER_RESOLUTION: Once = Once::new();
+
+/// Raise the system timer resolution to 1ms for the rest of the process lifetime
+fn raise_timer_resolution() {
+    TIMER_RESOLUTION.call_once(|| {
+        if unsafe { timeBeginPeriod(1) } == TIMERR_NOERROR {
+            debug!("System timer resolution raised to 1ms");
+        } else {
+            warn!("timeBeginPeriod(1) failed, sleeps may be coarse");
+        }
+    });
+}
+
+/// How a thread waits for its next deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WaitStrategy {
+    /// Block on the timer until the deadline (lowest CPU use)
+    #[default]
+    Park,
+    /// Block until shortly before the deadline and spin for the rest, trading
+    /// some CPU for a tighter cadence
+    SpinThenPark,
+}
+
+impl WaitStrategy {
+    /// Parse a strategy name (`park`, `spin`)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "park" => Some(Self::Park),
+            "spin" => Some(Self::SpinThenPark),
+            _ => None,
+        }
+    }
+
+    /// Name as accepted by `parse`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Park => "park",
+            Self::SpinThenPark => "spin",
+        }
+    }
+}
+
+/// Sleeps with sub-millisecond precision where supported
+///
+/// Owns a per-thread waitable timer, so create one inside the thread that uses it.
+pub struct PreciseSleeper {
+    /// High-resolution waitable timer, if the system supports it
+    timer: Option<HANDLE>,
+    /// Time before each deadline spent spinning instead of blocked
+    spin: Duration,
+}
+
+impl PreciseSleeper {
+    /// `spin` is the spin window used with [`WaitStrategy::SpinThenPark`]
+    pub fn new(strategy: WaitStrategy, spin: Duration) -> Self {
+        let spin = match strategy {
+            WaitStrategy::Park => Duration::ZERO,
+            WaitStrategy::SpinThenPark => spin,
+        };
+        let timer = unsafe {
+            CreateWaitableTimerExW(
+                None,
+                PCWSTR::null(),
+                CREATE_WAITABLE_TIMER_HIGH_RESOLUTION,
+                TIMER_ALL_ACCESS.0,
+            )
+        };
+
+        match timer {
+            Ok(timer) => {
+                debug!("Using high-resolution waitable timer for sleeps");
+                Self {
+                    timer: Some(timer),
+                    spin,
+                }
+            }
+            Err(e) => {
+                debug!(
+                    "High-resolution waitable timer unavailable ({:?}), falling back to timeBeginPeriod",
+                    e
+                );
+                raise_timer_resolution();
+                Self { timer: None, spin }
+            }
+        }
+    }
+
+    /// Sleep for `duration`, or until `wake` is signalled if that happens first
+    pub fn sleep_or_wake(&self, duration: Duration, wake: &WakeEvent) {
+        if duration.is_zero() {
+            return;
+        }
+
+        let deadline = Instant::now() + duration;
+        let park = duration.saturating_sub(self.spin);
+        if !park.is_zero() && self.park(park, wake) {
+            return;
+        }
+
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Block for `duration`, returning true if `wake` ended the wait early
+    fn park(&self, duration: Duration, wake: &WakeEvent) -> bool {
+        unsafe {
+            if let Some(timer) = self.timer {
+                let due_time = -((duration.as_nanos() / 100).max(1) as i64);
+                if SetWaitableTimer(timer, &due_time, 0, None, None, false).is_ok() {
+                    let result = WaitForMultipleObjects(&[timer, wake.0], false, INFINITE);
+                    return result.0 == WAIT_OBJECT_0.0 + 1;
+                }
+            }
+
+            // Millisecond resolution, relying on the raised system timer resolution
+            let millis = duration.as_micros().div_ceil(1000).min(u32::MAX as u128) as u32;
+            WaitForSingleObject(wake.0, millis) == WAIT_OBJECT_0
+        }
+    }
+}
+
+impl Drop for PreciseSleeper {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            unsafe {
+                let _ = CloseHandle(timer);
+            }
+        }
+    }
+}
+
+/// Auto-reset event used to cut a thread's sleep short when it has new work
+pub struct WakeEvent(HANDLE);
+
+// The event handle is only ever signalled and waited on, both thread safe
+unsafe impl Send for WakeEvent {}
+unsafe impl Sync for WakeEvent {}
+
+impl WakeEvent {
+    pub fn new() -> windows::core::Result<Self> {
+        unsafe { CreateEventW(None, false, false, PCWSTR::null()).map(Self) }
+    }
+
+    /// Wake the thread sleeping on this event (or make its next sleep return at once)
+    pub fn signal(&self) {
+        unsafe {
+            let _ = SetEvent(self.0);
+        }
+    }
+}
+
+impl Drop for WakeEvent {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.0);
+        }
+    }
+}
+
+/// Picks the poll interval for a polling loop: the normal interval while inputs
+/// are changing, a longer one after they have been idle for a while
+pub struct AdaptiveInterval {
+    active: Duration,
+    idle: Option<Duration>,
+    idle_after: Duration,
+    last_activity: Instant,
+    is_idle: bool,
+}
+
+impl AdaptiveInterval {
+    /// `idle` of `None` disables the slowdown
+    pub fn new(active: Duration, idle: Option<Duration>, idle_after: Duration) -> Self {
+        Self {
+            active,
+            idle,
+            idle_after,
+            last_activity: Instant::now(),
+            is_idle: false,
+        }
+    }
+
+    /// Record whether the last poll saw any activity and return the interval to
+    /// sleep before the next one; activity restores the normal interval at once
+    pub fn next(&mut self, active: bool) -> Duration {
+        let Some(idle) = self.idle else {
+            return self.active;
+        };
+
+        if active {
+            self.last_activity = Instant::now();
+            if self.is_idle {
+                self.is_idle = false;
+                debug!("Input activity, polling at full rate");
+            }
+        } else if !self.is_idle && self.last_activity.elapsed() >= self.idle_after {
+            self.is_idle = true;
+            debug!("Inputs idle, polling every {:?}", idle);
+        }
+
+        if self.is_idle {
+            idle
+        } else {
+            self.active
+        }
+    }
+}
+
+/// Reference point for `Periodic` timestamps
+static PERIODIC_EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Lock-free gate that opens at most once per interval, for periodic work
+/// (reports, summaries) triggered from hot paths instead of a dedicated thread
+pub struct Periodic {
+    /// Milliseconds since `PERIODIC_EPOCH` at which the gate last opened
+    last_ms: AtomicU64,
+}
+
+impl Periodic {
+    pub const fn new() -> Self {
+        Self {
+            last_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether `interval` has elapsed since the gate last opened; only one
+    /// concurrent caller gets `true`. A zero interval never opens.
+    pub fn ready(&self, interval: Duration) -> bool {
+        if interval.is_zero() {
+            return false;
+        }
+
+        let now_ms = PERIODIC_EPOCH
+            .get_or_init(Instant::now)
+            .elapsed()
+            .as_millis() as u64;
+        let last_ms = self.last_ms.load(Ordering::Relaxed);
+        if now_ms.saturating_sub(last_ms) < interval.as_millis() as u64 {
+            return false;
+        }
+
+        self.last_ms
+            .compare_exchange(last_ms, now_ms, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    }
+}
@@ -0,0 +1,221 @@
+//! TLS for remote transports
+//!
+//! With the `tls` feature, TCP links and WebSocket URLs (`wss://`) to the
+//! proxy can be wrapped in TLS. There are no built-in trust roots: the proxy
+//! is trusted through a CA certificate file, the SHA-256 fingerprint of its
+//! certificate, or both, so a proxy with a self-signed certificate on a venue
+//! network can be pinned exactly.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    os::windows::io::AsRawSocket,
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{
+        ring as provider, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+    },
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+
+use windows::Win32::Networking::WinSock::SOCKET;
+
+use crate::{config, transport::Fingerprint};
+
+/// A TLS client connection over TCP
+pub struct TlsStream {
+    stream: StreamOwned<ClientConnection, TcpStream>,
+    /// Outgoing data being joined into one write; kept to reuse its allocation
+    outgoing: Vec<u8>,
+}
+
+impl TlsStream {
+    /// Run the TLS handshake with `host` over `stream` within `timeout`,
+    /// verifying the proxy's certificate as configured
+    pub fn connect(mut stream: TcpStream, host: &str, timeout: Duration) -> io::Result<Self> {
+        let config = config::get();
+        let client = Arc::new(client_config(config)?);
+        let name = match config.tls_server_name.as_str() {
+            "" => host,
+            name => name,
+        };
+        let name = ServerName::try_from(name.to_string())
+            .map_err(|e| invalid(&format!("invalid TLS server name {}: {}", name, e)))?;
+        let mut connection = ClientConnection::new(client, name).map_err(io::Error::other)?;
+
+        // Finish the handshake here, so a refused certificate fails the
+        // connection attempt rather than its first request
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(Self {
+            stream: StreamOwned::new(connection, stream),
+            outgoing: Vec::new(),
+        })
+    }
+
+    /// The underlying TCP connection
+    pub fn tcp(&self) -> &TcpStream {
+        &self.stream.sock
+    }
+
+    /// Socket of the underlying TCP connection
+    pub fn raw_socket(&self) -> SOCKET {
+        SOCKET(self.tcp().as_raw_socket() as usize)
+    }
+
+    /// Bound how long a receive or send may wait, `None` for no limit
+    pub fn set_timeouts(
+        &self,
+        recv_timeout: Option<Duration>,
+        send_timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        self.tcp().set_read_timeout(recv_timeout)?;
+        self.tcp().set_write_timeout(send_timeout)
+    }
+
+    /// Send the buffers, joined, so they go out in as few records as possible
+    pub fn send(&mut self, buffers: &[&[u8]]) -> io::Result<()> {
+        self.outgoing.clear();
+        for buffer in buffers {
+            self.outgoing.extend_from_slice(buffer);
+        }
+        self.stream.write_all(&self.outgoing)?;
+        self.stream.flush()
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            // A proxy that closes without a close_notify is still closed
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Client settings trusting the proxy through the configured CA file and
+/// fingerprint
+fn client_config(config: &config::Config) -> io::Result<ClientConfig> {
+    let provider = Arc::new(provider::default_provider());
+    let ca = match config.tls_ca.as_str() {
+        "" => None,
+        path => Some(ca_verifier(path, &provider)?),
+    };
+    if ca.is_none() && config.tls_fingerprint.is_none() {
+        return Err(invalid(
+            "TLS needs CHUNIIO_TLS_CA or CHUNIIO_TLS_FINGERPRINT to trust the proxy",
+        ));
+    }
+    let verifier = PinnedVerifier {
+        ca,
+        fingerprint: config.tls_fingerprint,
+        algorithms: provider.signature_verification_algorithms,
+    };
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Verifier for certificates issued by the CAs in the PEM file at `path`
+fn ca_verifier(
+    path: &str,
+    provider: &Arc<rustls::crypto::CryptoProvider>,
+) -> io::Result<Arc<WebPkiServerVerifier>> {
+    let unreadable = |e: &dyn std::fmt::Display| invalid(&format!("TLS CA file {}: {}", path, e));
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path).map_err(|e| unreadable(&e))? {
+        roots
+            .add(cert.map_err(|e| unreadable(&e))?)
+            .map_err(|e| unreadable(&e))?;
+    }
+    WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| unreadable(&e))
+}
+
+/// Accepts the proxy's certificate if it chains to the configured CAs and has
+/// the configured fingerprint, whichever of the two are set
+#[derive(Debug)]
+struct PinnedVerifier {
+    ca: Option<Arc<WebPkiServerVerifier>>,
+    fingerprint: Option<Fingerprint>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(ca) = &self.ca {
+            ca.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if let Some(fingerprint) = self.fingerprint {
+            let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+            if digest.as_ref() != fingerprint.0 {
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
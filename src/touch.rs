@@ -0,0 +1,204 @@
+//! Touchscreen input
+//!
+//! On touchscreen laptops and tablets the bottom of the game window can act as
+//! the slider: each contact there touches the column under it. Contacts are
+//! read from the pointer (`WM_POINTER*`) and `WM_TOUCH` messages posted to the
+//! game window, through a message hook on its thread; the game still gets the
+//! messages as before.
+
+use std::{
+    ffi::c_void,
+    mem, ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, Ordering},
+        Mutex,
+    },
+};
+
+use tracing::{debug, warn};
+
+use windows::Win32::{
+    Foundation::{HINSTANCE, LPARAM, LRESULT, POINT, WPARAM},
+    UI::{
+        Input::Touch::{GetTouchInputInfo, HTOUCHINPUT, TOUCHEVENTF_UP, TOUCHINPUT},
+        WindowsAndMessaging::{
+            CallNextHookEx, GetForegroundWindow, GetWindowThreadProcessId, SetWindowsHookExW,
+            UnhookWindowsHookEx, HC_ACTION, HHOOK, MSG, PM_REMOVE, WH_GETMESSAGE,
+            WM_POINTERCAPTURECHANGED, WM_POINTERDOWN, WM_POINTERUP, WM_POINTERUPDATE, WM_TOUCH,
+        },
+    },
+};
+
+use crate::{
+    config, focus,
+    input::{self, SLIDER_CELLS},
+};
+
+/// Most contacts tracked at once
+const MAX_CONTACTS: usize = 10;
+
+/// Most `WM_TOUCH` inputs read from one message
+const MAX_TOUCH_INPUTS: usize = 16;
+
+/// Pointer message flag set while the pointer is in contact
+const POINTER_MESSAGE_FLAG_INCONTACT: u32 = 0x0004;
+
+/// Contact on the game window, at fractions of its width and height
+#[derive(Debug, Clone, Copy)]
+struct Contact {
+    id: u32,
+    x: f32,
+    y: f32,
+}
+
+static CONTACTS: Mutex<[Option<Contact>; MAX_CONTACTS]> = Mutex::new([None; MAX_CONTACTS]);
+
+/// Message hook on the game window's thread, null until installed
+static MESSAGE_HOOK: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Set once installing the message hook failed, so it is only reported once
+static MESSAGE_HOOK_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Slider pressure from the touchscreen, `None` unless the touch slider is
+/// enabled and the game window is focused
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    let config = config::get();
+    if !config.touch_slider {
+        return None;
+    }
+    install_message_hook();
+    let mut contacts = CONTACTS.lock().ok()?;
+    // Lifts are missed while another window has focus
+    if !focus::is_focused() {
+        *contacts = [None; MAX_CONTACTS];
+        return None;
+    }
+
+    let top = 1.0 - f32::from(config.touch_slider_height) / 100.0;
+    let mut pressure = [0; SLIDER_CELLS];
+    for contact in contacts.iter().flatten().filter(|contact| contact.y >= top) {
+        for (cell, touch) in pressure.iter_mut().zip(input::touch_band(contact.x, 1)) {
+            *cell = (*cell).max(touch);
+        }
+    }
+    Some(pressure)
+}
+
+/// Hook the game window's thread for touch messages, once the window is found
+fn install_message_hook() {
+    if !MESSAGE_HOOK.load(Ordering::Relaxed).is_null()
+        || MESSAGE_HOOK_FAILED.load(Ordering::Relaxed)
+        || !focus::is_focused()
+    {
+        return;
+    }
+    unsafe {
+        let thread = GetWindowThreadProcessId(GetForegroundWindow(), None);
+        match SetWindowsHookExW(
+            WH_GETMESSAGE,
+            Some(message_hook),
+            HINSTANCE::default(),
+            thread,
+        ) {
+            Ok(hook) => {
+                debug!("Installed touch message hook on thread {}", thread);
+                MESSAGE_HOOK.store(hook.0, Ordering::Relaxed);
+            }
+            Err(e) => {
+                warn!(
+                    "Cannot hook touch messages, touch slider is disabled: {}",
+                    e
+                );
+                MESSAGE_HOOK_FAILED.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+unsafe extern "system" fn message_hook(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    // Messages only peeked at are seen again when they are removed
+    if code == HC_ACTION as i32 && wparam.0 as u32 == PM_REMOVE.0 {
+        let message = &*(lparam.0 as *const MSG);
+        match message.message {
+            WM_POINTERDOWN | WM_POINTERUPDATE | WM_POINTERUP => pointer_message(message),
+            WM_POINTERCAPTURECHANGED => update_contact(pointer_id(message.wParam), None),
+            WM_TOUCH => touch_message(message),
+            _ => {}
+        }
+    }
+    CallNextHookEx(HHOOK::default(), code, wparam, lparam)
+}
+
+fn pointer_id(wparam: WPARAM) -> u32 {
+    wparam.0 as u32 & 0xFFFF
+}
+
+fn pointer_message(message: &MSG) {
+    let flags = (message.wParam.0 as u32) >> 16;
+    let in_contact = message.message != WM_POINTERUP && flags & POINTER_MESSAGE_FLAG_INCONTACT != 0;
+    // Screen coordinates, as signed words
+    let point = POINT {
+        x: i32::from(message.lParam.0 as u16 as i16),
+        y: i32::from((message.lParam.0 >> 16) as u16 as i16),
+    };
+    let position = in_contact
+        .then(|| focus::client_position(message.hwnd, point))
+        .flatten();
+    update_contact(pointer_id(message.wParam), position);
+}
+
+unsafe fn touch_message(message: &MSG) {
+    let count = (message.wParam.0 & 0xFFFF).min(MAX_TOUCH_INPUTS);
+    let mut inputs = [TOUCHINPUT::default(); MAX_TOUCH_INPUTS];
+    // The handle stays open for the game's own window procedure
+    if GetTouchInputInfo(
+        HTOUCHINPUT(message.lParam.0 as *mut c_void),
+        &mut inputs[..count],
+        mem::size_of::<TOUCHINPUT>() as i32,
+    )
+    .is_err()
+    {
+        return;
+    }
+    for input in &inputs[..count] {
+        // Hundredths of a pixel in screen coordinates
+        let point = POINT {
+            x: input.x / 100,
+            y: input.y / 100,
+        };
+        let position = (!input.dwFlags.contains(TOUCHEVENTF_UP))
+            .then(|| focus::client_position(message.hwnd, point))
+            .flatten();
+        update_contact(input.dwID, position);
+    }
+}
+
+/// Move contact `id` to `position`, or lift it for `None`
+fn update_contact(id: u32, position: Option<(f32, f32)>) {
+    let Ok(mut contacts) = CONTACTS.lock() else {
+        return;
+    };
+    let index = contacts
+        .iter()
+        .position(|contact| contact.is_some_and(|contact| contact.id == id));
+    match (index, position) {
+        (Some(index), None) => contacts[index] = None,
+        (Some(index), Some((x, y))) => contacts[index] = Some(Contact { id, x, y }),
+        (None, Some((x, y))) => {
+            if let Some(free) = contacts.iter_mut().find(|contact| contact.is_none()) {
+                *free = Some(Contact { id, x, y });
+            }
+        }
+        (None, None) => {}
+    }
+}
+
+/// Remove the message hook before the DLL is unloaded
+pub fn shutdown() {
+    let hook = MESSAGE_HOOK.swap(ptr::null_mut(), Ordering::Relaxed);
+    if !hook.is_null() {
+        unsafe {
+            let _ = UnhookWindowsHookEx(HHOOK(hook));
+        }
+    }
+}
@@ -0,0 +1,723 @@
+//! Transports to the proxy
+//!
+//! The proxy is reached over its Unix domain socket by default, over TCP when
+//! a proxy host is configured, or over a Windows named pipe when a pipe is
+//! configured, for a bridge daemon running natively on Windows where there is
+//! no `/tmp` to put a socket in. A game running in a Windows VM can reach a
+//! proxy on the host over vsock, through the virtio-win socket driver. Builds
+//! with the `websocket` feature can also reach it at a `ws://` URL. All of
+//! them carry the same byte stream. Builds with the `tls` feature can wrap
+//! TCP and WebSocket links in TLS.
+//!
+//! A backup endpoint, given as a URI, is dialled when the configured one is
+//! unreachable.
+
+use std::{
+    ffi::CString,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    mem,
+    net::{TcpStream, ToSocketAddrs},
+    os::windows::io::IntoRawSocket,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use tracing::{debug, error, info, warn};
+
+use windows::{
+    core::{s, w, PSTR},
+    Win32::{
+        Networking::WinSock::{
+            closesocket, connect, recv, setsockopt, socket, WSACleanup, WSASend, WSAStartup,
+            ADDRESS_FAMILY, AF_UNIX, SEND_RECV_FLAGS, SOCKADDR, SOCKADDR_UN, SOCKET, SOCKET_ERROR,
+            SOCK_STREAM, SOL_SOCKET, SO_RCVTIMEO, SO_SNDTIMEO, WSABUF, WSADATA,
+        },
+        System::LibraryLoader::{GetModuleHandleW, GetProcAddress},
+    },
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+#[cfg(feature = "websocket")]
+use crate::websocket::{WebSocket, WsUrl};
+use crate::{config, io::get_socket_paths, led, ERROR_LOG_INTERVAL_SECS};
+
+/// Default TCP port of the proxy, used when only a host is configured
+pub const DEFAULT_PROXY_PORT: u16 = 5730;
+
+/// Address family the virtio-win socket driver registers for vsock
+const AF_VSOCK: u16 = 40;
+
+/// Context ID of the VM host
+pub const VSOCK_HOST_CID: u32 = 2;
+
+/// Prefix of a named pipe path, added to a bare pipe name
+const PIPE_PREFIX: &str = r"\\.\pipe\";
+
+/// How long a TCP connection attempt to one address may take
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maximum number of buffers in one vectored send (frame header, message
+/// header, data and checksum per LED board)
+pub const MAX_SEND_BUFFERS: usize = 4 * led::LED_BOARD_COUNT;
+
+/// A vsock address: the context ID of a VM (or the host) and a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VsockAddress {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl VsockAddress {
+    /// Parse `<cid>:<port>`, or just `<port>` on the host
+    pub fn parse(value: &str) -> Option<Self> {
+        let (cid, port) = match value.trim().split_once(':') {
+            Some((cid, port)) => (cid.trim().parse().ok()?, port),
+            None => (VSOCK_HOST_CID, value),
+        };
+        Some(Self {
+            cid,
+            port: port.trim().parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for VsockAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.cid, self.port)
+    }
+}
+
+/// SHA-256 fingerprint of the proxy's TLS certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl Fingerprint {
+    /// Parse 64 hex digits, optionally separated by colons as `openssl x509
+    /// -fingerprint` prints them
+    pub fn parse(value: &str) -> Option<Self> {
+        let digits: Vec<u8> = value.trim().bytes().filter(|&byte| byte != b':').collect();
+        if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        let mut fingerprint = [0u8; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(Self(fingerprint))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, byte) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the proxy listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Unix domain socket at the given path, or the configured socket paths
+    Unix(Option<String>),
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    /// TCP wrapped in TLS
+    Tls {
+        host: String,
+        port: u16,
+    },
+    Vsock(VsockAddress),
+    /// Named pipe, by path or bare name
+    Pipe(String),
+    /// `ws://` or `wss://` URL
+    WebSocket(String),
+}
+
+impl Endpoint {
+    /// Endpoint the transport settings point at: a pipe, URL, vsock address
+    /// or TCP host if set, in that order, and the Unix domain socket otherwise;
+    /// the TCP host is reached over TLS if that's enabled
+    pub fn primary(config: &config::Config) -> Self {
+        if !config.proxy_pipe.is_empty() {
+            Self::Pipe(config.proxy_pipe.clone())
+        } else if !config.proxy_url.is_empty() {
+            Self::WebSocket(config.proxy_url.clone())
+        } else if let Some(address) = config.proxy_vsock {
+            Self::Vsock(address)
+        } else if !config.proxy_host.is_empty() && config.tls {
+            Self::Tls {
+                host: config.proxy_host.clone(),
+                port: config.proxy_port,
+            }
+        } else if !config.proxy_host.is_empty() {
+            Self::Tcp {
+                host: config.proxy_host.clone(),
+                port: config.proxy_port,
+            }
+        } else {
+            Self::Unix(None)
+        }
+    }
+
+    /// Parse a URI: `unix://<path>`, `tcp://<host>[:<port>]`,
+    /// `tls://<host>[:<port>]`, `vsock://[<cid>:]<port>`, `pipe://<name>`,
+    /// `ws://...` or `wss://...`; a bare path is a Unix domain socket, or a
+    /// named pipe under `\\.\pipe\`
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.is_empty() {
+            return None;
+        }
+        if value.starts_with("ws://") || value.starts_with("wss://") {
+            return Some(Self::WebSocket(value.to_string()));
+        }
+        if let Some(path) = value.strip_prefix("unix://") {
+            return (!path.is_empty()).then(|| Self::Unix(Some(path.to_string())));
+        }
+        if let Some(name) = value.strip_prefix("pipe://") {
+            return (!name.is_empty()).then(|| Self::Pipe(name.to_string()));
+        }
+        if let Some(address) = value.strip_prefix("vsock://") {
+            return VsockAddress::parse(address).map(Self::Vsock);
+        }
+        if let Some(authority) = value.strip_prefix("tcp://") {
+            let (host, port) = parse_authority(authority)?;
+            return Some(Self::Tcp { host, port });
+        }
+        if let Some(authority) = value.strip_prefix("tls://") {
+            let (host, port) = parse_authority(authority)?;
+            return Some(Self::Tls { host, port });
+        }
+        if value.to_ascii_lowercase().starts_with(PIPE_PREFIX) {
+            return Some(Self::Pipe(value.to_string()));
+        }
+        Some(Self::Unix(Some(value.to_string())))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unix(None) => write!(f, "the proxy socket"),
+            Self::Unix(Some(path)) => write!(f, "unix://{}", path),
+            Self::Tcp { host, port } if host.contains(':') => {
+                write!(f, "tcp://[{}]:{}", host, port)
+            }
+            Self::Tcp { host, port } => write!(f, "tcp://{}:{}", host, port),
+            Self::Tls { host, port } if host.contains(':') => {
+                write!(f, "tls://[{}]:{}", host, port)
+            }
+            Self::Tls { host, port } => write!(f, "tls://{}:{}", host, port),
+            Self::Vsock(address) => write!(f, "vsock://{}", address),
+            Self::Pipe(name) => write!(f, "pipe://{}", name),
+            Self::WebSocket(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Split `<host>[:<port>]`, with the host of an IPv6 address in brackets and
+/// the default proxy port if none is given
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !host.ends_with(':') => (host, port.parse().ok()?),
+        _ => (authority, DEFAULT_PROXY_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+/// `sockaddr_vm`, as the virtio-win driver lays it out
+#[repr(C)]
+struct SockaddrVm {
+    family: u16,
+    reserved: u16,
+    port: u32,
+    cid: u32,
+    zero: [u8; 4],
+}
+
+/// Whether the DLL runs under Wine, detected once
+static WINE: OnceLock<bool> = OnceLock::new();
+
+/// Whether the DLL runs under Wine rather than native Windows
+///
+/// Wine's ntdll exports `wine_get_version`; the native one doesn't.
+pub fn is_wine() -> bool {
+    *WINE.get_or_init(|| unsafe {
+        GetModuleHandleW(w!("ntdll.dll"))
+            .is_ok_and(|ntdll| GetProcAddress(ntdll, s!("wine_get_version")).is_some())
+    })
+}
+
+/// An open connection to the proxy, closed when dropped
+pub enum Link {
+    /// Unix domain or TCP socket
+    Socket(SOCKET),
+    /// Named pipe client end
+    Pipe(File),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    #[cfg(feature = "websocket")]
+    WebSocket(WebSocket),
+}
+
+impl Link {
+    /// Connect to the proxy at `endpoint`
+    pub fn open(endpoint: &Endpoint) -> Option<Self> {
+        let config = config::get();
+        let link = match endpoint {
+            Endpoint::Pipe(name) => open_pipe(name),
+            Endpoint::WebSocket(url) => open_websocket(url, config.tcp_nodelay),
+            Endpoint::Tls { host, port } => open_tls(host, *port, config.tcp_nodelay),
+            _ => unsafe { open_socket(endpoint, config.tcp_nodelay) },
+        };
+        if let Some(link) = &link {
+            info!("Successfully connected to chuniio proxy");
+            link.set_timeouts(config.recv_timeout, config.send_timeout);
+        }
+        link
+    }
+
+    /// Socket under the link, which can be shut down from another thread;
+    /// `None` for a pipe
+    pub fn raw_socket(&self) -> Option<SOCKET> {
+        match self {
+            Self::Socket(sock) => Some(*sock),
+            Self::Pipe(_) => None,
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => Some(stream.raw_socket()),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(websocket) => Some(websocket.raw_socket()),
+        }
+    }
+
+    /// Bound how long a receive or send may wait, zero for no limit; pipes
+    /// can't time out and always wait
+    fn set_timeouts(&self, recv_timeout: Duration, send_timeout: Duration) {
+        let result = match self {
+            Self::Socket(sock) => {
+                let set = |option, timeout: Duration| {
+                    // Milliseconds as a DWORD, 0 for no timeout
+                    let ms = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+                    unsafe { setsockopt(*sock, SOL_SOCKET, option, Some(&ms.to_ne_bytes())) }
+                };
+                if set(SO_RCVTIMEO, recv_timeout) == SOCKET_ERROR
+                    || set(SO_SNDTIMEO, send_timeout) == SOCKET_ERROR
+                {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            }
+            Self::Pipe(_) => Ok(()),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.set_timeouts(
+                (!recv_timeout.is_zero()).then_some(recv_timeout),
+                (!send_timeout.is_zero()).then_some(send_timeout),
+            ),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(websocket) => websocket.set_timeouts(
+                (!recv_timeout.is_zero()).then_some(recv_timeout),
+                (!send_timeout.is_zero()).then_some(send_timeout),
+            ),
+        };
+        if let Err(e) = result {
+            warn!("Cannot set proxy connection timeouts: {}", e);
+        }
+    }
+
+    /// Send buffers, with a single WSASend call on a socket, returning whether
+    /// they were all sent
+    pub fn send(&mut self, buffers: &[&[u8]]) -> bool {
+        debug_assert!(buffers.len() <= MAX_SEND_BUFFERS);
+        match self {
+            Self::Socket(sock) => {
+                let mut wsa_buffers = [WSABUF::default(); MAX_SEND_BUFFERS];
+                let count = buffers.len().min(MAX_SEND_BUFFERS);
+                for (wsa_buffer, buffer) in wsa_buffers.iter_mut().zip(buffers) {
+                    *wsa_buffer = WSABUF {
+                        len: buffer.len() as u32,
+                        buf: PSTR(buffer.as_ptr() as *mut u8),
+                    };
+                }
+                let mut bytes_sent = 0u32;
+                let result = unsafe {
+                    WSASend(
+                        *sock,
+                        &wsa_buffers[..count],
+                        Some(&mut bytes_sent),
+                        0,
+                        None,
+                        None,
+                    )
+                };
+                result != SOCKET_ERROR
+            }
+            // Pipes are in byte mode, so the writes join up into one stream
+            Self::Pipe(pipe) => buffers
+                .iter()
+                .all(|buffer| (&*pipe).write_all(buffer).is_ok()),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.send(buffers).is_ok(),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(websocket) => websocket.send(buffers).is_ok(),
+        }
+    }
+
+    /// Receive whatever has arrived into `buffer`, waiting for something;
+    /// returns the byte count as Winsock's `recv` does: 0 when the proxy
+    /// closed the connection and negative on failure
+    pub fn recv(&mut self, buffer: &mut [u8]) -> i32 {
+        match self {
+            Self::Socket(sock) => unsafe { recv(*sock, buffer, SEND_RECV_FLAGS(0)) },
+            Self::Pipe(pipe) => (&*pipe).read(buffer).map_or(-1, |len| len as i32),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.read(buffer).map_or(-1, |len| len as i32),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(websocket) => websocket.recv(buffer).map_or(-1, |len| len as i32),
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        if let Self::Socket(sock) = self {
+            unsafe {
+                closesocket(*sock);
+                WSACleanup();
+            }
+        }
+    }
+}
+
+/// Open the named pipe `name`, either a full `\\.\pipe\...` path or a bare
+/// pipe name
+fn open_pipe(name: &str) -> Option<Link> {
+    let path = if name.starts_with(r"\\") {
+        name.to_string()
+    } else {
+        format!("{}{}", PIPE_PREFIX, name)
+    };
+    debug!("Opening named pipe: {}", path);
+    match OpenOptions::new().read(true).write(true).open(&path) {
+        Ok(pipe) => Some(Link::Pipe(pipe)),
+        Err(e) => {
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!("Failed to open chuniio proxy pipe {}: {}", path, e)
+            );
+            None
+        }
+    }
+}
+
+/// Connect to the proxy at a `ws://` or `wss://` URL
+#[cfg(feature = "websocket")]
+fn open_websocket(url: &str, nodelay: bool) -> Option<Link> {
+    let Some(parsed) = WsUrl::parse(url) else {
+        error!(
+            "Invalid proxy URL {}, expected ws:// or wss://host[:port][/path]",
+            url
+        );
+        return None;
+    };
+    match WebSocket::connect(&parsed, TCP_CONNECT_TIMEOUT, nodelay) {
+        Ok(websocket) => Some(Link::WebSocket(websocket)),
+        Err(e) => {
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!("Failed to connect to chuniio proxy at {}: {}", url, e)
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "websocket"))]
+fn open_websocket(url: &str, _nodelay: bool) -> Option<Link> {
+    log_throttled!(
+        ERROR_LOG_INTERVAL_SECS,
+        error!(
+            "Cannot connect to {}: this build has no WebSocket support (the websocket feature)",
+            url
+        )
+    );
+    None
+}
+
+/// Connect to the proxy over TCP and run the TLS handshake
+#[cfg(feature = "tls")]
+fn open_tls(host: &str, port: u16, nodelay: bool) -> Option<Link> {
+    let stream = connect_tcp(host, port, nodelay)?;
+    match TlsStream::connect(stream, host, TCP_CONNECT_TIMEOUT) {
+        Ok(stream) => Some(Link::Tls(Box::new(stream))),
+        Err(e) => {
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!(
+                    "TLS handshake with chuniio proxy at {}:{} failed: {}",
+                    host, port, e
+                )
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn open_tls(host: &str, port: u16, _nodelay: bool) -> Option<Link> {
+    log_throttled!(
+        ERROR_LOG_INTERVAL_SECS,
+        error!(
+            "Cannot connect to tls://{}:{}: this build has no TLS support (the tls feature)",
+            host, port
+        )
+    );
+    None
+}
+
+/// Initialize Winsock and connect to the socket at `endpoint`
+unsafe fn open_socket(endpoint: &Endpoint, nodelay: bool) -> Option<Link> {
+    debug!("Initializing socket connection to chuniio proxy");
+
+    // Initialize Winsock
+    let mut wsadata: WSADATA = mem::zeroed();
+    if WSAStartup(0x0202, &mut wsadata) != 0 {
+        error!("Failed to initialize Winsock");
+        return None;
+    }
+
+    let sock = match endpoint {
+        Endpoint::Vsock(address) => connect_vsock(*address),
+        Endpoint::Tcp { host, port } => connect_tcp(host, *port, nodelay)
+            .map(|stream| SOCKET(stream.into_raw_socket() as usize)),
+        Endpoint::Unix(Some(path)) => connect_unix(std::slice::from_ref(path)),
+        _ => connect_unix(&get_socket_paths()),
+    };
+    match sock {
+        // The link does the matching WSACleanup when dropped
+        Some(sock) => Some(Link::Socket(sock)),
+        None => {
+            WSACleanup();
+            None
+        }
+    }
+}
+
+/// Connect to the proxy's Unix domain socket, trying each path in order
+unsafe fn connect_unix(socket_paths: &[String]) -> Option<SOCKET> {
+    let sock = socket_paths.iter().find_map(|path| connect_unix_path(path));
+    if sock.is_none() {
+        log_throttled!(
+            ERROR_LOG_INTERVAL_SECS,
+            error!(
+                "Failed to connect to chuniio proxy socket at {}",
+                socket_paths.join(", ")
+            )
+        );
+    }
+    sock
+}
+
+/// Connect to the Unix domain socket at `socket_path`
+unsafe fn connect_unix_path(socket_path: &str) -> Option<SOCKET> {
+    debug!("Connecting to socket path: {}", socket_path);
+    let Some(addr) = unix_address(socket_path) else {
+        error!(
+            "Proxy socket path {} is too long or contains a NUL",
+            socket_path
+        );
+        return None;
+    };
+
+    // Create Unix domain socket
+    let sock = match socket(AF_UNIX.into(), SOCK_STREAM, 0) {
+        Ok(s) => {
+            debug!("Created Unix domain socket");
+            s
+        }
+        Err(e) => {
+            error!("Failed to create socket: {:?}", e);
+            return None;
+        }
+    };
+
+    // Connect to the Unix socket
+    if connect(
+        sock,
+        &addr as *const SOCKADDR_UN as *const SOCKADDR,
+        mem::size_of::<SOCKADDR_UN>() as i32,
+    ) == SOCKET_ERROR
+    {
+        closesocket(sock);
+        return None;
+    }
+    Some(sock)
+}
+
+/// Socket address of the Unix domain socket at `path`, which Wine maps to the
+/// host's filesystem and native Windows takes as a Windows path; `None` if it
+/// doesn't fit with its terminating NUL
+fn unix_address(path: &str) -> Option<SOCKADDR_UN> {
+    let path = CString::new(path).ok()?;
+    let path = path.as_bytes_with_nul();
+    let mut addr = SOCKADDR_UN {
+        sun_family: ADDRESS_FAMILY(AF_UNIX),
+        sun_path: [0; 108],
+    };
+    if path.len() > addr.sun_path.len() {
+        return None;
+    }
+    for (dst, &src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = src as i8;
+    }
+    Some(addr)
+}
+
+/// Connect to the proxy over vsock
+unsafe fn connect_vsock(address: VsockAddress) -> Option<SOCKET> {
+    let sock = match socket(AF_VSOCK.into(), SOCK_STREAM, 0) {
+        Ok(sock) => sock,
+        Err(e) => {
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!(
+                    "Failed to create vsock socket, is the virtio-win socket driver installed? {:?}",
+                    e
+                )
+            );
+            return None;
+        }
+    };
+    debug!("Connecting to proxy at vsock {}", address);
+    let addr = SockaddrVm {
+        family: AF_VSOCK,
+        reserved: 0,
+        port: address.port,
+        cid: address.cid,
+        zero: [0; 4],
+    };
+    if connect(
+        sock,
+        &addr as *const SockaddrVm as *const SOCKADDR,
+        mem::size_of::<SockaddrVm>() as i32,
+    ) == SOCKET_ERROR
+    {
+        log_throttled!(
+            ERROR_LOG_INTERVAL_SECS,
+            error!("Failed to connect to chuniio proxy at vsock {}", address)
+        );
+        closesocket(sock);
+        return None;
+    }
+    Some(sock)
+}
+
+/// Connect to the proxy over TCP, trying each address `host` resolves to
+fn connect_tcp(host: &str, port: u16, nodelay: bool) -> Option<TcpStream> {
+    let addrs = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!("Cannot resolve proxy host {}: {}", host, e)
+            );
+            return None;
+        }
+    };
+    for addr in addrs {
+        debug!("Connecting to proxy at {}", addr);
+        match TcpStream::connect_timeout(&addr, TCP_CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                // Polls are a few bytes each; Nagle's algorithm would hold
+                // them back waiting for more
+                if let Err(e) = stream.set_nodelay(nodelay) {
+                    warn!("Cannot set TCP_NODELAY on the proxy connection: {}", e);
+                }
+                return Some(stream);
+            }
+            Err(e) => log_throttled!(
+                ERROR_LOG_INTERVAL_SECS,
+                error!("Failed to connect to chuniio proxy at {}: {}", addr, e)
+            ),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_endpoints() {
+        assert_eq!(
+            Endpoint::parse("tcp://192.168.1.20:5731"),
+            Some(Endpoint::Tcp {
+                host: "192.168.1.20".to_string(),
+                port: 5731
+            })
+        );
+        assert_eq!(
+            Endpoint::parse("tcp://backup.local"),
+            Some(Endpoint::Tcp {
+                host: "backup.local".to_string(),
+                port: DEFAULT_PROXY_PORT
+            })
+        );
+        assert_eq!(
+            Endpoint::parse("vsock://5730"),
+            Some(Endpoint::Vsock(VsockAddress {
+                cid: VSOCK_HOST_CID,
+                port: 5730
+            }))
+        );
+        assert_eq!(
+            Endpoint::parse(r"\\.\pipe\chuniio"),
+            Some(Endpoint::Pipe(r"\\.\pipe\chuniio".to_string()))
+        );
+        assert_eq!(
+            Endpoint::parse("/tmp/backup.sock"),
+            Some(Endpoint::Unix(Some("/tmp/backup.sock".to_string())))
+        );
+        assert_eq!(
+            Endpoint::parse("tls://[::1]:5731"),
+            Some(Endpoint::Tls {
+                host: "::1".to_string(),
+                port: 5731
+            })
+        );
+        assert_eq!(
+            Endpoint::parse("wss://proxy.local/chuniio"),
+            Some(Endpoint::WebSocket("wss://proxy.local/chuniio".to_string()))
+        );
+        assert_eq!(Endpoint::parse("tcp://"), None);
+        assert_eq!(Endpoint::parse("tls://"), None);
+        assert_eq!(Endpoint::parse(""), None);
+    }
+
+    #[test]
+    fn parses_fingerprints() {
+        let colons = "AB:".repeat(31) + "CD";
+        let fingerprint = Fingerprint::parse(&colons).unwrap();
+        assert_eq!(fingerprint.0[0], 0xAB);
+        assert_eq!(fingerprint.0[31], 0xCD);
+        assert_eq!(fingerprint.to_string(), colons);
+        assert_eq!(
+            Fingerprint::parse(&colons.replace(':', "").to_lowercase()),
+            Some(fingerprint)
+        );
+        assert_eq!(Fingerprint::parse("AB:CD"), None);
+        assert_eq!(Fingerprint::parse(&"ZZ".repeat(32)), None);
+    }
+}
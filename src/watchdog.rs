@@ -0,0 +1,118 @@
+//! Proxy hang watchdog
+//!
+//! A thread of its own watches how long ago the proxy last answered. Past the
+//! configured threshold it shuts the connection's socket down, which fails
+//! whatever send or receive the IO thread is stuck in, so the usual recovery
+//! dials the proxy again. The game keeps seeing the last state meanwhile (for
+//! up to `CHUNIIO_STALE_STATE_MS`), which carries it through short proxy
+//! restarts and hangs.
+//!
+//! Named pipe links have no socket to shut down (`Link::raw_socket` is `None`)
+//! and are not watched: since pipes can't time out either, a proxy that hangs
+//! on a pipe keeps the IO thread waiting until it answers or closes the pipe.
+//!
+//! The IO thread registers each new socket and unregisters it before closing
+//! it, both under the same lock the watchdog holds while shutting a socket
+//! down, so a closed handle is never touched.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{error, warn};
+
+use windows::Win32::Networking::WinSock::{shutdown, SD_BOTH, SOCKET};
+
+use crate::{config, stats};
+
+/// Longest time between two checks
+const MAX_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Socket of the current connection, if it can be shut down
+static WATCHED: Mutex<Option<SOCKET>> = Mutex::new(None);
+
+/// When the proxy last answered, in milliseconds since `START`
+static LAST_HEARD_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Base of `LAST_HEARD_MS`
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Set between a `start` that spawned the watchdog thread and the next `stop`
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Bumped by every start and stop; a watchdog thread exits once it no longer
+/// matches the value it was started with, so a thread still asleep from
+/// before a `stop` never runs alongside the one a later `start` spawns
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+fn now_ms() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Watch `sock` from now on, or nothing (as for pipe links); must be called
+/// with `None` before the watched socket is closed
+pub fn watch(sock: Option<SOCKET>) {
+    if let Ok(mut watched) = WATCHED.lock() {
+        *watched = sock;
+        LAST_HEARD_MS.store(now_ms(), Ordering::Relaxed);
+    }
+}
+
+/// Note that the proxy answered
+pub fn heard() {
+    LAST_HEARD_MS.store(now_ms(), Ordering::Relaxed);
+}
+
+/// Start the watchdog thread if it is enabled and not already running
+pub fn start() {
+    if config::get().watchdog_timeout.is_zero() || RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    if let Err(e) = thread::Builder::new()
+        .name("chuniio-watchdog".into())
+        .spawn(move || run(generation))
+    {
+        error!("Failed to spawn watchdog thread: {:?}", e);
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Ask the watchdog thread to exit; it notices at its next check
+pub fn stop() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+fn run(generation: u32) {
+    while GENERATION.load(Ordering::SeqCst) == generation {
+        // A reload may disable the watchdog; it then only idles
+        let timeout = config::get().watchdog_timeout;
+        if timeout.is_zero() {
+            thread::sleep(MAX_CHECK_INTERVAL);
+            continue;
+        }
+        thread::sleep((timeout / 4).clamp(Duration::from_millis(1), MAX_CHECK_INTERVAL));
+        let silent =
+            Duration::from_millis(now_ms().saturating_sub(LAST_HEARD_MS.load(Ordering::Relaxed)));
+        if silent < timeout {
+            continue;
+        }
+        let Ok(mut watched) = WATCHED.lock() else {
+            continue;
+        };
+        if let Some(sock) = watched.take() {
+            warn!(
+                "Proxy has not answered for {:?}, dropping the connection",
+                silent
+            );
+            stats::increment(&stats::STATS.watchdog_resets);
+            unsafe { shutdown(sock, SD_BOTH) };
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! WebSocket transport
+//!
+//! With the `websocket` feature the proxy can be reached at a `ws://` URL, or
+//! a `wss://` one in builds that also have the `tls` feature,
+//! which is easier to put behind a reverse proxy or tunnel than a raw socket.
+//! The protocol byte stream is carried in binary messages: everything sent at
+//! once goes out as one message, and received messages are joined back into
+//! a stream, so message boundaries don't matter to either side.
+//!
+//! Only what a client needs is implemented: the opening handshake, masked
+//! binary frames out, and binary, ping and close frames in.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    os::windows::io::AsRawSocket,
+    time::Duration,
+};
+
+use tracing::debug;
+
+use windows::Win32::Networking::WinSock::SOCKET;
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+use crate::wsproto::{
+    self, base64, push_header, push_payload, MAX_HEADER_LEN, OPCODE_BINARY, OPCODE_CLOSE,
+    OPCODE_CONTINUATION, OPCODE_PING, OPCODE_PONG, OPCODE_TEXT,
+};
+
+/// Longest handshake response accepted
+const MAX_RESPONSE_LEN: usize = 4096;
+
+/// Largest control frame payload
+const MAX_CONTROL_LEN: usize = 125;
+
+/// A `ws://` or `wss://` URL split into its parts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsUrl {
+    /// `wss://`, over TLS
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl WsUrl {
+    /// Parse `ws://host[:port][/path]` or `wss://host[:port][/path]`
+    pub fn parse(url: &str) -> Option<Self> {
+        let url = url.trim();
+        let (tls, rest) = match url.strip_prefix("wss://") {
+            Some(rest) => (true, rest),
+            None => (false, url.strip_prefix("ws://")?),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        // A bracketed IPv6 address has colons of its own
+        let (host, port) = match authority.rfind(':') {
+            Some(index) if !authority[index..].contains(']') => {
+                (&authority[..index], authority[index + 1..].parse().ok()?)
+            }
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Connection a WebSocket runs over
+enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    /// Wrap `stream` in TLS for a `wss://` URL
+    fn new(stream: TcpStream, url: &WsUrl, timeout: Duration) -> io::Result<Self> {
+        if !url.tls {
+            return Ok(Self::Plain(stream));
+        }
+        #[cfg(feature = "tls")]
+        return TlsStream::connect(stream, &url.host, timeout).map(|tls| Self::Tls(Box::new(tls)));
+        #[cfg(not(feature = "tls"))]
+        {
+            let _ = timeout;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "wss:// needs a build with the tls feature",
+            ))
+        }
+    }
+
+    /// The underlying TCP connection
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Self::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.tcp(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A client connection that has completed the opening handshake
+pub struct WebSocket {
+    stream: Stream,
+    /// Payload bytes of the current data frame not yet received
+    remaining: u64,
+    /// Outgoing frame being built; kept to reuse its allocation
+    frame: Vec<u8>,
+}
+
+impl WebSocket {
+    /// Connect to `url` and complete the opening handshake within `timeout`
+    pub fn connect(url: &WsUrl, timeout: Duration, nodelay: bool) -> io::Result<Self> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host did not resolve");
+        for addr in (url.host.as_str(), url.port).to_socket_addrs()? {
+            debug!("Connecting to proxy at {}{}", addr, url.path);
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(nodelay)?;
+                    return Self::handshake(Stream::new(stream, url, timeout)?, url, timeout);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn handshake(mut stream: Stream, url: &WsUrl, timeout: Duration) -> io::Result<Self> {
+        let key = base64(&random_bytes::<16>());
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            url.path, url.host, url.port, key
+        )?;
+        stream.flush()?;
+
+        // Read the response a byte at a time so no frame data is read past it
+        stream.tcp().set_read_timeout(Some(timeout))?;
+        let mut response = Vec::new();
+        let mut byte = [0u8];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_RESPONSE_LEN {
+                return Err(invalid("handshake response too long"));
+            }
+            stream.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+        stream.tcp().set_read_timeout(None)?;
+
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(invalid(&format!("handshake refused: {}", status)));
+        }
+        Ok(Self {
+            stream,
+            remaining: 0,
+            frame: Vec::new(),
+        })
+    }
+
+    /// Socket of the underlying TCP connection
+    pub fn raw_socket(&self) -> SOCKET {
+        SOCKET(self.stream.tcp().as_raw_socket() as usize)
+    }
+
+    /// Bound how long a receive or send may wait, `None` for no limit
+    pub fn set_timeouts(
+        &self,
+        recv_timeout: Option<Duration>,
+        send_timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        self.stream.tcp().set_read_timeout(recv_timeout)?;
+        self.stream.tcp().set_write_timeout(send_timeout)
+    }
+
+    /// Send the buffers, joined, as one binary message
+    pub fn send(&mut self, buffers: &[&[u8]]) -> io::Result<()> {
+        let len: usize = buffers.iter().map(|buffer| buffer.len()).sum();
+        let mask = random_bytes::<4>();
+        self.frame.clear();
+        push_header(&mut self.frame, OPCODE_BINARY, len, Some(mask));
+        let payload = buffers.iter().flat_map(|buffer| buffer.iter());
+        push_payload(&mut self.frame, payload, Some(mask));
+        self.stream.write_all(&self.frame)?;
+        self.stream.flush()
+    }
+
+    /// Receive stream bytes into `buffer`, waiting for a data frame; 0 once
+    /// the server closes the connection
+    pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let mut header = [0u8; MAX_HEADER_LEN];
+            if let Err(e) = self.stream.read_exact(&mut header[..2]) {
+                return match e.kind() {
+                    io::ErrorKind::UnexpectedEof => Ok(0),
+                    _ => Err(e),
+                };
+            }
+            let header_len = wsproto::header_len([header[0], header[1]]);
+            self.stream.read_exact(&mut header[2..header_len])?;
+            let header = wsproto::parse_header(&header[..header_len])
+                .ok_or_else(|| invalid("short frame header"))?;
+            if header.mask.is_some() {
+                return Err(invalid("masked frame from server"));
+            }
+            let len = header.len;
+            match header.opcode {
+                OPCODE_CONTINUATION | OPCODE_BINARY | OPCODE_TEXT => self.remaining = len,
+                OPCODE_CLOSE => return Ok(0),
+                opcode @ (OPCODE_PING | OPCODE_PONG) => {
+                    if len > MAX_CONTROL_LEN as u64 {
+                        return Err(invalid("control frame too long"));
+                    }
+                    let mut payload = [0u8; MAX_CONTROL_LEN];
+                    let payload = &mut payload[..len as usize];
+                    self.stream.read_exact(payload)?;
+                    if opcode == OPCODE_PING {
+                        self.pong(payload)?;
+                    }
+                }
+                opcode => return Err(invalid(&format!("unknown opcode {:#x}", opcode))),
+            }
+        }
+        let len =
+            usize::try_from(self.remaining).map_or(buffer.len(), |left| left.min(buffer.len()));
+        let received = self.stream.read(&mut buffer[..len])?;
+        self.remaining -= received as u64;
+        Ok(received)
+    }
+
+    /// Answer a ping with its payload
+    fn pong(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mask = random_bytes::<4>();
+        let mut frame = Vec::with_capacity(MAX_HEADER_LEN + payload.len());
+        push_header(&mut frame, OPCODE_PONG, payload.len(), Some(mask));
+        push_payload(&mut frame, payload, Some(mask));
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+/// Unpredictable bytes for the handshake key and frame masks, from the
+/// randomly seeded keys of std's hasher
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    for chunk in bytes.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    bytes
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ws_urls() {
+        assert_eq!(
+            WsUrl::parse("ws://example.com:8080/chuniio"),
+            Some(WsUrl {
+                tls: false,
+                host: "example.com".to_string(),
+                port: 8080,
+                path: "/chuniio".to_string(),
+            })
+        );
+        assert_eq!(
+            WsUrl::parse("ws://[::1]"),
+            Some(WsUrl {
+                tls: false,
+                host: "::1".to_string(),
+                port: 80,
+                path: "/".to_string(),
+            })
+        );
+        assert_eq!(
+            WsUrl::parse("wss://example.com/chuniio"),
+            Some(WsUrl {
+                tls: true,
+                host: "example.com".to_string(),
+                port: 443,
+                path: "/chuniio".to_string(),
+            })
+        );
+        assert_eq!(WsUrl::parse("http://example.com"), None);
+        assert_eq!(WsUrl::parse("ws://:80"), None);
+    }
+}
@@ -0,0 +1,283 @@
+//! WebSocket protocol pieces
+//!
+//! Framing and the handshake's accept key, shared by the `websocket` client
+//! transport and the Brokenithm server. The server takes browser controllers
+//! in every build, so this module is not behind the feature.
+
+/// Frame opcodes
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+/// FIN bit of the first header byte
+const FIN: u8 = 0x80;
+/// Mask bit of the second header byte
+const MASKED: u8 = 0x80;
+
+/// Longest frame header: two bytes, an 8-byte length and a mask
+pub const MAX_HEADER_LEN: usize = 14;
+
+/// Suffix hashed with the client's key to accept a WebSocket
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// A parsed frame header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub opcode: u8,
+    /// Payload length
+    pub len: u64,
+    /// Masking key, for frames from a client
+    pub mask: Option<[u8; 4]>,
+    /// Bytes taken by the header itself
+    pub header_len: usize,
+}
+
+/// Length of the header that starts with these two bytes
+pub fn header_len(start: [u8; 2]) -> usize {
+    let len_field = match start[1] & 0x7F {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask = if start[1] & MASKED != 0 { 4 } else { 0 };
+    2 + len_field + mask
+}
+
+/// Parse the header at the start of `bytes`, `None` if it is cut short
+pub fn parse_header(bytes: &[u8]) -> Option<Header> {
+    let start: [u8; 2] = bytes.get(..2)?.try_into().ok()?;
+    let header_len = header_len(start);
+    let header = bytes.get(..header_len)?;
+    let (len, mask) = match start[1] & 0x7F {
+        126 => (
+            u16::from_be_bytes(header[2..4].try_into().ok()?).into(),
+            &header[4..],
+        ),
+        127 => (
+            u64::from_be_bytes(header[2..10].try_into().ok()?),
+            &header[10..],
+        ),
+        len => (len.into(), &header[2..]),
+    };
+    Some(Header {
+        opcode: start[0] & 0x0F,
+        len,
+        mask: mask.try_into().ok(),
+        header_len,
+    })
+}
+
+/// Append the header of an unfragmented frame, masked if `mask` is given
+pub fn push_header(frame: &mut Vec<u8>, opcode: u8, len: usize, mask: Option<[u8; 4]>) {
+    frame.push(FIN | opcode);
+    let flags = if mask.is_some() { MASKED } else { 0 };
+    if len < 126 {
+        frame.push(flags | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        frame.push(flags | 126);
+        frame.extend_from_slice(&len.to_be_bytes());
+    } else {
+        frame.push(flags | 127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    if let Some(mask) = mask {
+        frame.extend_from_slice(&mask);
+    }
+}
+
+/// Append a payload, applying `mask` if given; masking twice unmasks, so this
+/// also reads a client's payload
+pub fn push_payload<'a>(
+    frame: &mut Vec<u8>,
+    payload: impl IntoIterator<Item = &'a u8>,
+    mask: Option<[u8; 4]>,
+) {
+    let mask = mask.unwrap_or_default();
+    frame.extend(
+        payload
+            .into_iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask[index % 4]),
+    );
+}
+
+/// `Sec-WebSocket-Accept` value answering a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()))
+}
+
+/// SHA-1 digest, for the handshake only
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+/// Standard base64 with padding
+pub fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * index)
+        });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                let sextet = (bits >> (18 - 6 * index)) & 0x3F;
+                encoded.push(BASE64_ALPHABET[sextet as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn hashes_sha1_test_vectors() {
+        let cases = [
+            ("", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            ("abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            // 56 bytes: the length no longer fits in the first block
+            (
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            ),
+        ];
+        for (data, digest) in cases {
+            assert_eq!(hex(&sha1(data.as_bytes())), digest, "{:?}", data);
+        }
+        // Several blocks
+        assert_eq!(
+            hex(&sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn accepts_the_rfc_6455_sample_key() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn encodes_payload_lengths() {
+        let mut frame = Vec::new();
+        push_header(&mut frame, OPCODE_BINARY, 125, Some([1, 2, 3, 4]));
+        assert_eq!(frame, [0x82, 0xFD, 1, 2, 3, 4]);
+        frame.clear();
+        push_header(&mut frame, OPCODE_BINARY, 300, None);
+        assert_eq!(frame, [0x82, 126, 0x01, 0x2C]);
+        frame.clear();
+        push_header(&mut frame, OPCODE_BINARY, 70_000, None);
+        assert_eq!(frame, [0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+    }
+
+    #[test]
+    fn parses_headers_it_writes() {
+        for len in [0, 125, 126, 300, 70_000] {
+            for mask in [None, Some([0x37, 0xFA, 0x21, 0x3D])] {
+                let mut frame = Vec::new();
+                push_header(&mut frame, OPCODE_PING, len, mask);
+                assert_eq!(header_len([frame[0], frame[1]]), frame.len());
+                assert_eq!(
+                    parse_header(&frame),
+                    Some(Header {
+                        opcode: OPCODE_PING,
+                        len: len as u64,
+                        mask,
+                        header_len: frame.len(),
+                    }),
+                    "{} bytes, mask {:?}",
+                    len,
+                    mask
+                );
+                assert_eq!(parse_header(&frame[..frame.len() - 1]), None);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_the_rfc_6455_masked_sample() {
+        let frame = [
+            0x81, 0x85, 0x37, 0xFA, 0x21, 0x3D, 0x7F, 0x9F, 0x4D, 0x51, 0x58,
+        ];
+        let header = parse_header(&frame).unwrap();
+        assert_eq!(header.opcode, OPCODE_TEXT);
+        assert_eq!(header.len, 5);
+        let mut payload = Vec::new();
+        push_payload(&mut payload, &frame[header.header_len..], header.mask);
+        assert_eq!(payload, b"Hello");
+
+        let mut written = Vec::new();
+        push_header(&mut written, OPCODE_TEXT, 5, header.mask);
+        push_payload(&mut written, b"Hello", header.mask);
+        assert_eq!(written, frame);
+    }
+}
@@ -0,0 +1,111 @@
+//! XInput controller input
+//!
+//! A gamepad can stand in for the controller, e.g. on handhelds running the
+//! game under Wine: the left stick moves a touch along the slider, which the
+//! right trigger presses; pushing the right stick up raises a hand through the
+//! IR beams; BACK, START and Y press TEST, SERVICE and insert a coin. The first
+//! connected controller is used.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::Duration,
+};
+
+use tracing::info;
+
+use windows::Win32::{
+    Foundation::ERROR_SUCCESS,
+    UI::Input::XboxController::{
+        XInputGetState, XINPUT_GAMEPAD, XINPUT_GAMEPAD_BACK, XINPUT_GAMEPAD_BUTTON_FLAGS,
+        XINPUT_GAMEPAD_RIGHT_THUMB_DEADZONE, XINPUT_GAMEPAD_START,
+        XINPUT_GAMEPAD_TRIGGER_THRESHOLD, XINPUT_GAMEPAD_Y, XINPUT_STATE, XUSER_MAX_COUNT,
+    },
+};
+
+use crate::{
+    config,
+    input::{self, BEAM_COUNT, SLIDER_CELLS},
+    timing::Periodic,
+};
+
+/// How often the controller slots are searched while none is connected;
+/// querying an empty slot is slow
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Slot of the controller in use, `XUSER_MAX_COUNT` while none is connected
+static SLOT: AtomicU32 = AtomicU32::new(XUSER_MAX_COUNT);
+
+static SCAN: Periodic = Periodic::new();
+
+/// Whether the coin button was down at the previous check
+static COIN_BUTTON_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Current state of the controller in use, `None` if XInput is disabled or no
+/// controller is connected
+fn gamepad() -> Option<XINPUT_GAMEPAD> {
+    if !config::get().xinput {
+        return None;
+    }
+    let mut state = XINPUT_STATE::default();
+    let slot = SLOT.load(Ordering::Relaxed);
+    if slot < XUSER_MAX_COUNT && unsafe { XInputGetState(slot, &mut state) } == ERROR_SUCCESS.0 {
+        return Some(state.Gamepad);
+    }
+
+    SLOT.store(XUSER_MAX_COUNT, Ordering::Relaxed);
+    if !SCAN.ready(SCAN_INTERVAL) {
+        return None;
+    }
+    let slot = (0..XUSER_MAX_COUNT)
+        .find(|&slot| unsafe { XInputGetState(slot, &mut state) } == ERROR_SUCCESS.0)?;
+    info!("Using XInput controller {}", slot);
+    SLOT.store(slot, Ordering::Relaxed);
+    Some(state.Gamepad)
+}
+
+fn held(gamepad: &XINPUT_GAMEPAD, button: XINPUT_GAMEPAD_BUTTON_FLAGS) -> bool {
+    gamepad.wButtons.0 & button.0 != 0
+}
+
+/// Operator button bits (TEST, SERVICE) for the controller buttons held down
+pub fn opbtn() -> u8 {
+    gamepad().map_or(0, |gamepad| {
+        u8::from(held(&gamepad, XINPUT_GAMEPAD_BACK))
+            | u8::from(held(&gamepad, XINPUT_GAMEPAD_START)) << 1
+    })
+}
+
+/// Whether the coin button has been pressed since the previous check
+pub fn coin_pressed() -> bool {
+    let down = gamepad().is_some_and(|gamepad| held(&gamepad, XINPUT_GAMEPAD_Y));
+    !COIN_BUTTON_DOWN.swap(down, Ordering::Relaxed) && down
+}
+
+/// IR beam bits for the hand height set with the right stick
+pub fn beams() -> u8 {
+    let Some(gamepad) = gamepad() else {
+        return 0;
+    };
+    let deadzone = i32::from(XINPUT_GAMEPAD_RIGHT_THUMB_DEADZONE.0);
+    let up = i32::from(gamepad.sThumbRY);
+    if up <= deadzone {
+        return 0;
+    }
+    // Beam 0 is the lowest, just past the deadzone
+    let height = (up - deadzone) as f32 / (i32::from(i16::MAX) - deadzone) as f32;
+    1 << ((height * BEAM_COUNT as f32) as usize).min(BEAM_COUNT - 1)
+}
+
+/// Slider pressure from the controller, `None` unless the right trigger is
+/// pulled
+pub fn slider_pressure() -> Option<[u8; SLIDER_CELLS]> {
+    let gamepad = gamepad()?;
+    if u16::from(gamepad.bRightTrigger) <= XINPUT_GAMEPAD_TRIGGER_THRESHOLD.0 {
+        return None;
+    }
+    let position = (f32::from(gamepad.sThumbLX) + 32768.0) / 65535.0;
+    Some(input::touch_band(
+        position,
+        config::get().xinput_slider_width,
+    ))
+}